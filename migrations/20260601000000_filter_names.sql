@@ -0,0 +1,6 @@
+ALTER TABLE notification_filters
+  ADD COLUMN name text;
+
+CREATE UNIQUE INDEX idx_notification_filters_guild_name
+  ON notification_filters (guild_id, name)
+  WHERE name IS NOT NULL;
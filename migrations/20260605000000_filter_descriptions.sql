@@ -0,0 +1,2 @@
+ALTER TABLE notification_filters
+  ADD COLUMN description text;
@@ -0,0 +1,16 @@
+CREATE TABLE notification_filter_revisions (
+	id			bigint GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	filter_id	bigint NOT NULL REFERENCES notification_filters(id) ON DELETE CASCADE,
+	rule_yaml	text NOT NULL,
+	editor_id	bigint,
+	editor_name	text,
+	created_at	timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX idx_notification_filter_revisions_filter_id
+  ON notification_filter_revisions (filter_id, created_at DESC);
+
+-- Existing filters start their history with the definition they have now.
+INSERT INTO notification_filter_revisions (filter_id, rule_yaml, created_at)
+SELECT id, rule_yaml, created_at
+FROM notification_filters;
@@ -0,0 +1,19 @@
+-- Personal filters belong to a Discord user instead of a guild.
+ALTER TABLE notification_filters
+  ADD COLUMN owner_id bigint;
+
+CREATE INDEX idx_notification_filters_owner_id ON notification_filters (owner_id);
+
+CREATE UNIQUE INDEX idx_notification_filters_owner_name
+  ON notification_filters (owner_id, name)
+  WHERE owner_id IS NOT NULL AND name IS NOT NULL;
+
+CREATE TABLE user_watches (
+	user_id			bigint NOT NULL,
+	filter_id		bigint NOT NULL REFERENCES notification_filters(id) ON DELETE CASCADE,
+	include_nsfw	boolean NOT NULL DEFAULT false,
+	created_at		timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (user_id, filter_id)
+);
+
+CREATE INDEX idx_user_watches_filter_id ON user_watches (filter_id);
@@ -0,0 +1,16 @@
+CREATE TABLE channel_filters (
+	channel_id	bigint NOT NULL REFERENCES discord_channels(channel_id) ON DELETE CASCADE,
+	filter_id	bigint NOT NULL REFERENCES notification_filters(id) ON DELETE CASCADE,
+	created_at	timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (channel_id, filter_id)
+);
+
+CREATE INDEX idx_channel_filters_filter_id ON channel_filters (filter_id);
+
+INSERT INTO channel_filters (channel_id, filter_id)
+SELECT channel_id, filter_id
+FROM discord_channels
+WHERE filter_id IS NOT NULL;
+
+DROP INDEX idx_discord_channels_filter_id;
+ALTER TABLE discord_channels DROP COLUMN filter_id;
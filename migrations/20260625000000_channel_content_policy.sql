@@ -0,0 +1,5 @@
+-- NULL keeps the default: adult items go to NSFW channels, everything else
+-- to the other channels.
+ALTER TABLE discord_channels
+  ADD COLUMN content_policy text
+  CHECK (content_policy IN ('sfw_only', 'nsfw_only', 'both'));
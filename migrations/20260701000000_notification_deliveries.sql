@@ -0,0 +1,24 @@
+CREATE TABLE notification_deliveries (
+	item_id			bigint NOT NULL,
+	channel_id		bigint NOT NULL,
+	user_id			bigint,
+	status			text NOT NULL CHECK (status IN ('pending', 'sent', 'failed')),
+	message_id		bigint,
+	error			text,
+	attempts		integer NOT NULL DEFAULT 0,
+	next_attempt_at	timestamptz,
+	created_at		timestamptz NOT NULL DEFAULT now(),
+	updated_at		timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (item_id, channel_id)
+);
+
+CREATE INDEX idx_notification_deliveries_due
+  ON notification_deliveries (next_attempt_at)
+  WHERE status <> 'sent';
+
+-- A run is only done once its items went through notification; runs from
+-- before this migration are taken as done.
+ALTER TABLE fetch_runs
+  ADD COLUMN notified_at timestamptz;
+
+UPDATE fetch_runs SET notified_at = fetched_at;
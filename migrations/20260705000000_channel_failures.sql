@@ -0,0 +1,5 @@
+-- Consecutive failed deliveries; channels are disabled once too many fail.
+ALTER TABLE discord_channels
+  ADD COLUMN failure_count integer NOT NULL DEFAULT 0,
+  ADD COLUMN last_error text,
+  ADD COLUMN disabled_at timestamptz;
@@ -0,0 +1,7 @@
+-- Guilds the bot was removed from and channels deleted in Discord are kept
+-- but no longer notified.
+ALTER TABLE discord_guilds
+  ADD COLUMN active boolean NOT NULL DEFAULT true;
+
+ALTER TABLE discord_channels
+  ADD COLUMN active boolean NOT NULL DEFAULT true;
@@ -0,0 +1,13 @@
+-- Channels in a digest mode get one message per hour or day with the items
+-- matched since, which wait in digest_items until then.
+ALTER TABLE discord_channels
+  ADD COLUMN delivery_mode text NOT NULL DEFAULT 'immediate'
+  CHECK (delivery_mode IN ('immediate', 'hourly', 'daily')),
+  ADD COLUMN last_digest_at timestamptz;
+
+CREATE TABLE digest_items (
+	channel_id	bigint NOT NULL REFERENCES discord_channels(channel_id) ON DELETE CASCADE,
+	item_id		bigint NOT NULL,
+	created_at	timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (channel_id, item_id)
+);
@@ -0,0 +1,7 @@
+-- How notification messages look, as a MessageTemplate; a channel's template
+-- falls back to its guild's, and that to the built-in default.
+ALTER TABLE discord_guilds
+  ADD COLUMN message_template jsonb;
+
+ALTER TABLE discord_channels
+  ADD COLUMN message_template jsonb;
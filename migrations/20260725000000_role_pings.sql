@@ -0,0 +1,16 @@
+-- Roles mentioned when a channel's filter matches.
+ALTER TABLE channel_filters
+  ADD COLUMN role_ids bigint[] NOT NULL DEFAULT '{}';
+
+ALTER TABLE digest_items
+  ADD COLUMN role_ids bigint[] NOT NULL DEFAULT '{}';
+
+-- Roles members give themselves with `/booth subscribe`, mentioned wherever
+-- the filter matches in the guild.
+CREATE TABLE subscription_roles (
+	guild_id	bigint NOT NULL REFERENCES discord_guilds(guild_id) ON DELETE CASCADE,
+	filter_id	bigint NOT NULL REFERENCES notification_filters(id) ON DELETE CASCADE,
+	role_id		bigint NOT NULL,
+	created_at	timestamptz NOT NULL DEFAULT now(),
+	PRIMARY KEY (guild_id, filter_id)
+);
@@ -0,0 +1,9 @@
+-- Whether messages are published to the servers following the channel.
+-- Set from the channel kind on registration; NULL crossposts in announcement
+-- channels only.
+ALTER TABLE discord_channels
+  ADD COLUMN crosspost boolean;
+
+-- Why publishing a sent message failed, if it did.
+ALTER TABLE notification_deliveries
+  ADD COLUMN crosspost_error text;
@@ -0,0 +1,27 @@
+-- Changes found when known items are fetched again, e.g. price drops,
+-- posted to channels whose filters subscribe to their events.
+CREATE TABLE item_changes (
+	id			bigint GENERATED BY DEFAULT AS IDENTITY PRIMARY KEY,
+	item_id		bigint NOT NULL,
+	snapshot_id	bigint NOT NULL REFERENCES item_snapshots(id) ON DELETE CASCADE,
+	events		text[] NOT NULL,
+	summary		text NOT NULL,
+	created_at	timestamptz NOT NULL DEFAULT now()
+);
+
+CREATE INDEX idx_item_changes_item_id ON item_changes (item_id);
+
+-- When known items were last fetched again, so the least recently checked
+-- go first.
+CREATE TABLE item_checks (
+	item_id		bigint PRIMARY KEY,
+	checked_at	timestamptz NOT NULL DEFAULT now()
+);
+
+-- An item is delivered once as new (change_id 0) and once per change.
+ALTER TABLE notification_deliveries
+  ADD COLUMN change_id bigint NOT NULL DEFAULT 0;
+
+ALTER TABLE notification_deliveries
+  DROP CONSTRAINT notification_deliveries_pkey,
+  ADD PRIMARY KEY (item_id, channel_id, change_id);
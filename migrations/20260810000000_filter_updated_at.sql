@@ -0,0 +1,6 @@
+-- When the filter's definition or name last changed, so compiled filters
+-- can be cached until then.
+ALTER TABLE notification_filters
+  ADD COLUMN updated_at timestamptz NOT NULL DEFAULT now();
+
+UPDATE notification_filters SET updated_at = created_at;
@@ -0,0 +1,4 @@
+-- Where problems with the guild's notifications are reported, such as a
+-- channel being disabled. NULL tells the guild owner by DM instead.
+ALTER TABLE discord_guilds
+  ADD COLUMN admin_channel_id bigint;
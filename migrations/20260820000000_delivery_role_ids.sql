@@ -0,0 +1,3 @@
+-- Roles mentioned in a delivery, so that a retry pings them as well.
+ALTER TABLE notification_deliveries
+  ADD COLUMN role_ids bigint[] NOT NULL DEFAULT '{}';
@@ -0,0 +1,9 @@
+-- The avatar a filter made by `/avatar add` is for, which names the roles of
+-- `/booth subscribe`. NULL for other filters.
+ALTER TABLE notification_filters
+  ADD COLUMN avatar text;
+
+-- Filters made before this only recorded it in their description
+UPDATE notification_filters
+SET avatar = substring(description FROM 9)
+WHERE guild_id IS NOT NULL AND description LIKE 'Avatar: %';
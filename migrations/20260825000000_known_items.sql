@@ -0,0 +1,13 @@
+-- When each item was first snapshotted, so that recently published items
+-- can be found for a recheck without scanning every snapshot.
+CREATE TABLE known_items (
+	item_id			bigint PRIMARY KEY,
+	first_seen_at	timestamptz NOT NULL
+);
+
+CREATE INDEX idx_known_items_first_seen_at ON known_items (first_seen_at);
+
+INSERT INTO known_items (item_id, first_seen_at)
+SELECT item_id, min(fetched_at)
+FROM item_snapshots
+GROUP BY item_id;
@@ -0,0 +1,245 @@
+use std::{collections::HashMap, fmt, str::FromStr};
+
+use anyhow::{Result, anyhow};
+use serde::{Deserialize, Serialize};
+
+use crate::booth::item::{BoothItem, Variation};
+
+/// What happened to an item that a filter can subscribe to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ItemEvent {
+    /// The item was published; what filters subscribe to by default.
+    NewItem,
+    PriceDrop,
+    NewVariation,
+    BackInStock,
+    EndOfSale,
+}
+
+impl ItemEvent {
+    pub const ALL: [ItemEvent; 5] = [
+        ItemEvent::NewItem,
+        ItemEvent::PriceDrop,
+        ItemEvent::NewVariation,
+        ItemEvent::BackInStock,
+        ItemEvent::EndOfSale,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ItemEvent::NewItem => "new_item",
+            ItemEvent::PriceDrop => "price_drop",
+            ItemEvent::NewVariation => "new_variation",
+            ItemEvent::BackInStock => "back_in_stock",
+            ItemEvent::EndOfSale => "end_of_sale",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ItemEvent::NewItem => "New item",
+            ItemEvent::PriceDrop => "Price drop",
+            ItemEvent::NewVariation => "New variation",
+            ItemEvent::BackInStock => "Back in stock",
+            ItemEvent::EndOfSale => "End of sale",
+        }
+    }
+}
+
+impl fmt::Display for ItemEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ItemEvent {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        ItemEvent::ALL
+            .into_iter()
+            .find(|event| event.as_str() == s)
+            .ok_or_else(|| anyhow!("unknown item event `{s}`"))
+    }
+}
+
+/// Whether any field that change events are detected from differs between
+/// two versions of an item, i.e. whether `current` is worth a new snapshot.
+pub fn tracked_state_changed(previous: &BoothItem, current: &BoothItem) -> bool {
+    previous.price_amount != current.price_amount
+        || previous.is_sold_out != current.is_sold_out
+        || previous.is_end_of_sale != current.is_end_of_sale
+        || variation_prices(previous) != variation_prices(current)
+}
+
+/// The events between two versions of an item, each with a line describing
+/// it for the notification.
+pub fn detect_changes(previous: &BoothItem, current: &BoothItem) -> Vec<(ItemEvent, String)> {
+    let mut changes = vec![];
+
+    let mut price_drops = vec![];
+    if current.price_amount < previous.price_amount {
+        price_drops.push(format!(
+            "💸 値下げ: ¥{} → ¥{}",
+            previous.price_amount, current.price_amount
+        ));
+    }
+    // Variations can get cheaper while the item's lowest price stays; one
+    // that dropped just like the item, e.g. its only one, is not repeated
+    let previous_prices = variation_prices(previous);
+    for variation in &current.variations {
+        if let Some(&previous_price) = previous_prices.get(&variation_key(variation))
+            && variation.price < previous_price
+            && (previous_price, variation.price) != (previous.price_amount, current.price_amount)
+        {
+            price_drops.push(format!(
+                "💸 値下げ ({}): ¥{} → ¥{}",
+                variation.name.as_deref().unwrap_or("(no name)"),
+                previous_price,
+                variation.price
+            ));
+        }
+    }
+    if !price_drops.is_empty() {
+        changes.push((ItemEvent::PriceDrop, price_drops.join("\n")));
+    }
+
+    let known = previous_prices;
+    let added = current
+        .variations
+        .iter()
+        .filter(|variation| !known.contains_key(&variation_key(variation)))
+        .map(|variation| variation.name.as_deref().unwrap_or("(no name)"))
+        .collect::<Vec<_>>();
+    if !added.is_empty() {
+        changes.push((
+            ItemEvent::NewVariation,
+            format!("🆕 新しいバリエーション: {}", added.join(", ")),
+        ));
+    }
+
+    if previous.is_sold_out && !current.is_sold_out {
+        changes.push((ItemEvent::BackInStock, "📦 再入荷".to_string()));
+    }
+
+    if !previous.is_end_of_sale && current.is_end_of_sale {
+        changes.push((ItemEvent::EndOfSale, "🛑 販売終了".to_string()));
+    }
+
+    changes
+}
+
+/// The price of each variation by its key.
+fn variation_prices(item: &BoothItem) -> HashMap<String, i64> {
+    item.variations
+        .iter()
+        .map(|variation| (variation_key(variation), variation.price))
+        .collect()
+}
+
+/// Identifies a variation by ID, or by name where booth-db has no ID for it.
+fn variation_key(variation: &Variation) -> String {
+    if variation.id != 0 {
+        variation.id.to_string()
+    } else {
+        variation.name.clone().unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn item(price_amount: i64, variations: &[(u64, &str)]) -> BoothItem {
+        BoothItem {
+            price_amount,
+            variations: variations
+                .iter()
+                .map(|(id, name)| Variation {
+                    id: *id,
+                    name: Some(name.to_string()),
+                    ..Default::default()
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn detects_price_drops_and_new_variations() {
+        let previous = item(1500, &[(1, "Full")]);
+        let current = item(1200, &[(1, "Full set"), (2, "Texture")]);
+
+        let changes = detect_changes(&previous, &current);
+
+        assert_eq!(
+            changes,
+            vec![
+                (ItemEvent::PriceDrop, "💸 値下げ: ¥1500 → ¥1200".to_string()),
+                (
+                    ItemEvent::NewVariation,
+                    "🆕 新しいバリエーション: Texture".to_string()
+                ),
+            ]
+        );
+        assert!(tracked_state_changed(&previous, &current));
+    }
+
+    #[test]
+    fn detects_price_drops_of_variations() {
+        let priced = |full_price| {
+            let mut item = item(1000, &[(1, "Full"), (2, "Texture")]);
+            item.variations[0].price = full_price;
+            item.variations[1].price = 1000;
+            item
+        };
+        let previous = priced(3000);
+        let current = priced(2400);
+
+        assert!(tracked_state_changed(&previous, &current));
+        assert_eq!(
+            detect_changes(&previous, &current),
+            vec![(
+                ItemEvent::PriceDrop,
+                "💸 値下げ (Full): ¥3000 → ¥2400".to_string()
+            )]
+        );
+
+        // The only variation dropping with the item is reported once
+        let mut previous = item(1500, &[(1, "Full")]);
+        previous.variations[0].price = 1500;
+        let mut current = item(1200, &[(1, "Full")]);
+        current.variations[0].price = 1200;
+
+        assert_eq!(
+            detect_changes(&previous, &current),
+            vec![(ItemEvent::PriceDrop, "💸 値下げ: ¥1500 → ¥1200".to_string())]
+        );
+    }
+
+    #[test]
+    fn detects_stock_and_sale_changes() {
+        let mut previous = item(1500, &[]);
+        previous.is_sold_out = true;
+        let mut current = item(1800, &[]);
+        current.is_end_of_sale = true;
+
+        let events = detect_changes(&previous, &current)
+            .into_iter()
+            .map(|(event, _)| event)
+            .collect::<Vec<_>>();
+
+        assert_eq!(events, vec![ItemEvent::BackInStock, ItemEvent::EndOfSale]);
+    }
+
+    #[test]
+    fn ignores_untracked_changes() {
+        let previous = item(1500, &[(1, "Full")]);
+        let mut current = item(1500, &[(1, "Full")]);
+        current.wish_lists_count = 10;
+
+        assert!(detect_changes(&previous, &current).is_empty());
+        assert!(!tracked_state_changed(&previous, &current));
+    }
+}
@@ -194,6 +194,7 @@ impl BoothDbItemRow {
             name: self.title,
             published_at: self.published_at.to_rfc3339(),
             price: format!("JPY {}", self.price),
+            price_amount: self.price,
             purchase_limit: None,
             shipping_info: String::new(),
             small_stock: None,
@@ -211,7 +212,7 @@ impl BoothDbItemRow {
             share: Share::default(),
             shop: Shop {
                 name: self.shop_name,
-                subdomain: String::new(),
+                subdomain: shop_subdomain(&self.shop_url),
                 thumbnail_url: self.shop_thumbnail_url.unwrap_or_default(),
                 url: self.shop_url,
                 verified: false,
@@ -226,6 +227,20 @@ impl BoothDbItemRow {
     }
 }
 
+/// Extracts `foo` from a shop URL such as `https://foo.booth.pm/`.
+fn shop_subdomain(url: &str) -> String {
+    let host = url
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .split('/')
+        .next()
+        .unwrap_or_default();
+
+    host.strip_suffix(".booth.pm")
+        .unwrap_or_default()
+        .to_string()
+}
+
 #[derive(Debug, FromRow)]
 struct BoothDbVariationRow {
     json_variation_id: Option<i64>,
@@ -274,6 +289,9 @@ pub struct BoothItem {
     pub name: String,
     pub published_at: String,
     pub price: String,
+    /// Numeric price in JPY, as stored by booth-db.
+    #[serde(default)]
+    pub price_amount: i64,
     pub purchase_limit: Option<u64>,
     pub shipping_info: String,
     pub small_stock: Option<i64>,
@@ -313,6 +331,30 @@ pub struct BoothItem {
     pub variations: Vec<Variation>,
 }
 
+impl BoothItem {
+    /// Reads an item from a snapshot payload. Snapshots taken before
+    /// `price_amount` was stored get it parsed from the display price.
+    pub fn from_snapshot(payload: serde_json::Value) -> serde_json::Result<Self> {
+        let has_amount = payload.get("price_amount").is_some();
+        let mut item = serde_json::from_value::<BoothItem>(payload)?;
+        if !has_amount {
+            item.price_amount = parse_price(&item.price).unwrap_or_default();
+        }
+        Ok(item)
+    }
+}
+
+/// The first amount in a display price such as `¥ 1,500` or `JPY 500~`.
+fn parse_price(price: &str) -> Option<i64> {
+    let digits = price
+        .chars()
+        .skip_while(|c| !c.is_ascii_digit())
+        .take_while(|c| c.is_ascii_digit() || *c == ',')
+        .filter(char::is_ascii_digit)
+        .collect::<String>();
+    digits.parse().ok()
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BuyeeVariation {
     #[serde(flatten)]
@@ -400,15 +442,16 @@ pub struct DownloadFile {
     pub url: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 #[serde(rename_all = "snake_case")]
 pub enum VariationType {
     Digital,
+    #[default]
     #[serde(other)]
     Other,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Default)]
 pub struct Variation {
     pub buyee_html: Option<String>,
     pub downloadable: Option<Downloadable>,
@@ -429,3 +472,39 @@ pub struct Variation {
     #[serde(rename = "type")]
     pub kind: VariationType,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn old_snapshots_get_price_amount_from_price() {
+        let mut payload = serde_json::to_value(BoothItem {
+            price: "¥ 1,500～".to_string(),
+            ..Default::default()
+        })
+        .unwrap();
+        payload.as_object_mut().unwrap().remove("price_amount");
+
+        let item = BoothItem::from_snapshot(payload).unwrap();
+
+        assert_eq!(item.price_amount, 1500);
+        assert_eq!(parse_price("JPY 500"), Some(500));
+        assert_eq!(parse_price("無料"), None);
+    }
+
+    #[test]
+    fn stored_price_amount_is_kept() {
+        let payload = serde_json::to_value(BoothItem {
+            price: "¥ 1,500".to_string(),
+            price_amount: 1200,
+            ..Default::default()
+        })
+        .unwrap();
+
+        assert_eq!(
+            BoothItem::from_snapshot(payload).unwrap().price_amount,
+            1200
+        );
+    }
+}
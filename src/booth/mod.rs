@@ -1 +1,2 @@
+pub mod change;
 pub mod item;
@@ -2,10 +2,15 @@ use poise::serenity_prelude::{ChannelType, CreateChannel};
 
 use crate::{
     Context, Error,
-    database::{NewDiscordChannel, NewNotificationFilter},
-    filter::{Field, Filter, FilterGroup, Op, Pattern, Rule, TagMode},
+    commands::editor,
+    database::{DatabaseClient, NewDiscordChannel, NewNotificationFilter},
+    filter::{Expr, Field, Filter, FilterRef, Op, Pattern, Rule, TagMode},
 };
 
+/// Name of the guild filter every `/avatar add` filter includes, so that
+/// what counts as a VRChat item is defined in one place.
+const VRCHAT_FILTER_NAME: &str = "vrchat";
+
 #[poise::command(
     slash_command,
     rename = "avatar",
@@ -54,7 +59,8 @@ pub async fn channel_register(ctx: Context<'_>, channel_name: String) -> Result<
         channel_id: channel.id.get() as i64,
         guild_id: db_guild.guild_id,
         name: channel_name.clone(),
-        filter_id: None,
+        filter_ids: vec![],
+        crosspost: channel.kind == ChannelType::News,
     })
     .await?;
 
@@ -72,36 +78,20 @@ pub async fn add(
     create_nsfw: bool,
 ) -> Result<(), Error> {
     let db = ctx.data().db.clone();
-    let mut filter = Filter {
-        groups: vec![
-            FilterGroup {
-                rules: vec![Rule {
-                    field: Field::Tags,
-                    op: Op::Include,
-                    pattern: Pattern::Text { value: avatar_name },
-                    case_sensitive: false,
-                    regex_flags: None,
-                    tag_mode: Some(TagMode::Any),
-                }],
-            },
-            FilterGroup {
-                rules: vec![Rule {
-                    field: Field::Tags,
-                    op: Op::Include,
-                    pattern: Pattern::Text {
-                        value: "VRChat".to_string(),
-                    },
-                    case_sensitive: false,
-                    regex_flags: None,
-                    tag_mode: Some(TagMode::Any),
-                }],
-            },
-        ],
-        ..Default::default()
-    };
-
+    let description = format!("Avatar: {}", avatar_name);
+    let mut avatar = vec![Expr::Rule(Rule {
+        field: Field::Tags,
+        op: Op::Include,
+        pattern: Pattern::Text {
+            value: avatar_name.clone(),
+        },
+        case_sensitive: false,
+        regex_flags: None,
+        tag_mode: Some(TagMode::Any),
+        price_mode: None,
+    })];
     if let Some(item_id) = item_id {
-        filter.groups[0].rules.push(Rule {
+        avatar.push(Expr::Rule(Rule {
             field: Field::Description,
             op: Op::Include,
             pattern: Pattern::Text {
@@ -110,8 +100,17 @@ pub async fn add(
             case_sensitive: false,
             regex_flags: None,
             tag_mode: None,
-        });
+            price_mode: None,
+        }));
     }
+    let filter = Filter {
+        expr: Some(Expr::All(vec![
+            Expr::Ref(FilterRef::Name(VRCHAT_FILTER_NAME.to_string())),
+            Expr::Any(avatar),
+        ])),
+        schema_version: 2,
+        ..Default::default()
+    };
 
     let guild_id = ctx
         .guild_id()
@@ -160,10 +159,16 @@ pub async fn add(
         None
     };
 
+    ensure_vrchat_filter(ctx, &db, db_guild.guild_id).await?;
     let filter = db
         .create_notification_filter(NewNotificationFilter {
             guild_id: Some(db_guild.guild_id),
+            owner_id: None,
+            name: None,
+            description: Some(description),
             rule_yaml: serde_yaml::to_string(&filter)?,
+            avatar: Some(avatar_name),
+            editor: Some(editor(ctx)),
         })
         .await?;
     let filter_id = filter.id;
@@ -172,7 +177,8 @@ pub async fn add(
         channel_id: sfw_channel.id.get() as i64,
         guild_id: db_guild.guild_id,
         name: channel_name.clone(),
-        filter_id: Some(filter_id),
+        filter_ids: vec![filter_id],
+        crosspost: sfw_channel.kind == ChannelType::News,
     })
     .await?;
 
@@ -181,7 +187,9 @@ pub async fn add(
             channel_id: nsfw_channel_id,
             guild_id: db_guild.guild_id,
             name: format!("{channel_name}-nsfw"),
-            filter_id: Some(filter_id),
+            filter_ids: vec![filter_id],
+            // Created as an announcement channel above
+            crosspost: true,
         })
         .await?;
     }
@@ -190,3 +198,60 @@ pub async fn add(
 
     Ok(())
 }
+
+/// Creates the shared VRChat filter of the guild unless it already exists,
+/// e.g. from an earlier `/avatar add` or edited by hand.
+async fn ensure_vrchat_filter(
+    ctx: Context<'_>,
+    db: &DatabaseClient,
+    guild_id: i64,
+) -> Result<(), Error> {
+    if db
+        .get_notification_filter_by_name(guild_id, VRCHAT_FILTER_NAME)
+        .await?
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    let filter = Filter {
+        expr: Some(Expr::Rule(Rule {
+            field: Field::Tags,
+            op: Op::Include,
+            pattern: Pattern::Text {
+                value: "VRChat".to_string(),
+            },
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode: Some(TagMode::Any),
+            price_mode: None,
+        })),
+        schema_version: 2,
+        ..Default::default()
+    };
+    let created = db
+        .create_notification_filter(NewNotificationFilter {
+            guild_id: Some(guild_id),
+            owner_id: None,
+            name: Some(VRCHAT_FILTER_NAME.to_string()),
+            description: Some("Items for VRChat, included by /avatar add".to_string()),
+            rule_yaml: serde_yaml::to_string(&filter)?,
+            avatar: None,
+            editor: Some(editor(ctx)),
+        })
+        .await;
+
+    // Another `/avatar add` may have created it in the meantime
+    match created {
+        Ok(_) => Ok(()),
+        Err(_)
+            if db
+                .get_notification_filter_by_name(guild_id, VRCHAT_FILTER_NAME)
+                .await?
+                .is_some() =>
+        {
+            Ok(())
+        }
+        Err(e) => Err(e.into()),
+    }
+}
@@ -1,3 +1,15 @@
 pub mod avatar;
 pub mod notification;
 pub mod register;
+pub mod subscribe;
+pub mod watch;
+
+use crate::{Context, database::Editor};
+
+/// The invoking user, as recorded in filter revisions.
+pub fn editor(ctx: Context<'_>) -> Editor {
+    Editor {
+        user_id: ctx.author().id.get() as i64,
+        name: ctx.author().name.clone(),
+    }
+}
@@ -1,15 +1,34 @@
-use poise::serenity_prelude::ChannelId;
+use poise::serenity_prelude::{self as serenity, ChannelId};
 
-use crate::{Context, Error, database::NewNotificationFilter, filter::Filter};
+use crate::{
+    Context, Error,
+    commands::{
+        editor,
+        subscribe::{booth_subscribe, booth_unsubscribe},
+    },
+    database::{
+        ContentPolicy, DeliveryMode, DiscordChannel, NewNotificationFilter, NotificationFilter,
+        NotificationFilterRevision,
+    },
+    filter::{
+        Explanation, ExplanationNode, Filter, FilteringEngine,
+        diff::diff_lines,
+        library::{FilterLibrary, UNSAVED_FILTER_ID},
+        preview::{DEFAULT_PREVIEW_ITEMS, preview_filter},
+    },
+    template::parse_role_ids,
+};
+
+/// Discord's message length limit.
+const MESSAGE_LIMIT: usize = 2000;
 
 // Main command
 #[poise::command(
     slash_command,
     rename = "booth",
     guild_only,
-    subcommands("filter", "channel"),
-    subcommand_required,
-    owners_only
+    subcommands("filter", "channel", "booth_subscribe", "booth_unsubscribe"),
+    subcommand_required
 )]
 pub async fn booth_command(_ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
@@ -21,7 +40,17 @@ pub async fn booth_command(_ctx: Context<'_>) -> Result<(), Error> {
     slash_command,
     rename = "filter",
     guild_only,
-    subcommands("filter_add", "filter_list", "filter_view", "filter_delete"),
+    subcommands(
+        "filter_add",
+        "filter_list",
+        "filter_view",
+        "filter_test",
+        "filter_explain",
+        "filter_set_info",
+        "filter_history",
+        "filter_rollback",
+        "filter_delete"
+    ),
     subcommand_required,
     owners_only
 )]
@@ -34,8 +63,11 @@ pub async fn filter(_ctx: Context<'_>) -> Result<(), Error> {
 pub async fn filter_add(
     ctx: Context<'_>,
     #[description = "Filter definition in YAML or JSON format"] yaml: String,
+    #[description = "Name other filters can include with `ref: <name>`"] name: Option<String>,
+    #[description = "What the filter is for"] description: Option<String>,
 ) -> Result<(), Error> {
     let db = ctx.data().db.clone();
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
 
     // Parse YAML
     let filter: Filter = match serde_yaml::from_str(&yaml) {
@@ -51,23 +83,44 @@ pub async fn filter_add(
         }
     };
 
-    // Validation: filter must not be empty
-    if filter.groups.is_empty() {
-        ctx.say("❌ Filter must have at least one group").await?;
+    // Validation: report every problem before saving
+    if let Err(e) = filter.validate() {
+        ctx.say(format!("❌ Filter is invalid:\n```\n{}\n```", e))
+            .await?;
+        return Ok(());
+    }
+
+    let mut library = FilterLibrary::load(&db, guild_id).await?;
+    if let Err(e) = library.check_rename(UNSAVED_FILTER_ID, name.as_deref()) {
+        ctx.say(format!("❌ {}", e)).await?;
+        return Ok(());
+    }
+
+    // References must resolve before the filter can be used
+    library.insert(UNSAVED_FILTER_ID, name.as_deref(), filter.clone());
+    if let Err(e) = library.resolve(&filter, Some(UNSAVED_FILTER_ID)) {
+        ctx.say(format!("❌ Filter is invalid:\n```\n{}\n```", e))
+            .await?;
         return Ok(());
     }
 
     // Save filter
     let saved_filter = db
         .create_notification_filter(NewNotificationFilter {
-            guild_id: ctx.guild_id().map(|id| id.get() as i64),
+            guild_id: Some(guild_id),
+            owner_id: None,
+            name,
+            description,
             rule_yaml: serde_yaml::to_string(&filter)?,
+            avatar: None,
+            editor: Some(editor(ctx)),
         })
         .await?;
 
     ctx.say(format!(
-        "✅ Filter created successfully!\nFilter ID: `{}`\n\nYou can now assign this filter to a channel using:\n`/booth channel set-filter <channel> {}`",
-        saved_filter.id, saved_filter.id
+        "✅ Filter created successfully!\nFilter: `{}`\n\nYou can now add this filter to a channel using:\n`/booth channel add-filter <channel> {}`",
+        saved_filter.label(),
+        saved_filter.name.as_deref().map_or_else(|| saved_filter.id.to_string(), str::to_string)
     ))
     .await?;
 
@@ -97,12 +150,14 @@ pub async fn filter_edit(
     Ok(())
 }
 
-/// List all filters
+/// List this guild's filters
 #[poise::command(slash_command, rename = "list", guild_only, ephemeral, owners_only)]
 pub async fn filter_list(ctx: Context<'_>) -> Result<(), Error> {
     let db = ctx.data().db.clone();
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
 
-    let filters = db.get_all_notification_filters().await?;
+    let mut filters = db.get_notification_filters_by_guild(guild_id).await?;
+    filters.retain(|filter| filter.owner_id.is_none());
 
     if filters.is_empty() {
         ctx.say("No filters found.").await?;
@@ -114,15 +169,23 @@ pub async fn filter_list(ctx: Context<'_>) -> Result<(), Error> {
 
     for filter in filters.iter().take(10) {
         // Show only first 10
-        let preview = if filter.rule_yaml.len() > 100 {
-            format!("{}...", &filter.rule_yaml[..100])
+        let preview = if filter.rule_yaml.chars().count() > 100 {
+            format!(
+                "{}...",
+                filter.rule_yaml.chars().take(100).collect::<String>()
+            )
         } else {
             filter.rule_yaml.clone()
         };
 
         message.push_str(&format!(
-            "**Filter ID:** `{}`\n**Created:** <t:{}:R>\n```yaml\n{}\n```\n\n",
-            filter.id,
+            "**{}**\n{}**Created:** <t:{}:R>\n```yaml\n{}\n```\n\n",
+            filter.label(),
+            filter
+                .description
+                .as_ref()
+                .map(|description| format!("{}\n", description))
+                .unwrap_or_default(),
             filter.created_at.unix_timestamp(),
             preview
         ));
@@ -141,21 +204,22 @@ pub async fn filter_list(ctx: Context<'_>) -> Result<(), Error> {
 #[poise::command(slash_command, rename = "view", guild_only, ephemeral, owners_only)]
 pub async fn filter_view(
     ctx: Context<'_>,
-    #[description = "Filter ID to view"] filter_id: i64,
+    #[description = "Filter name or ID to view"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
 ) -> Result<(), Error> {
     let db = ctx.data().db.clone();
 
-    let filter = db.get_notification_filter(filter_id).await?;
-
-    match filter {
+    match find_filter(ctx, &filter).await? {
         Some(f) => {
+            let filter_id = f.id;
             // Check if linked to channels
             let channels = db
                 .get_channels_by_guild(ctx.guild_id().unwrap().get() as i64)
                 .await?;
             let linked_channels: Vec<_> = channels
                 .iter()
-                .filter(|c| c.filter_id == Some(filter_id))
+                .filter(|c| c.filter_ids.contains(&filter_id))
                 .collect();
 
             let channels_info = if linked_channels.is_empty() {
@@ -169,8 +233,12 @@ pub async fn filter_view(
             };
 
             ctx.say(format!(
-                "**🔍 Filter Details**\n\n**Filter ID:** `{}`\n**Created:** <t:{}:R>\n**Linked Channels:** {}\n\n**YAML Definition:**\n```yaml\n{}\n```",
-                f.id,
+                "**🔍 Filter Details**\n\n**Filter:** `{}`\n{}**Created:** <t:{}:R>\n**Linked Channels:** {}\n\n**YAML Definition:**\n```yaml\n{}\n```",
+                f.label(),
+                f.description
+                    .as_ref()
+                    .map(|description| format!("**Description:** {}\n", description))
+                    .unwrap_or_default(),
                 f.created_at.unix_timestamp(),
                 channels_info,
                 f.rule_yaml
@@ -178,11 +246,491 @@ pub async fn filter_view(
             .await?;
         }
         None => {
-            ctx.say(format!("❌ Filter with ID `{}` not found", filter_id))
+            ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Test a filter against recently seen items
+#[poise::command(slash_command, rename = "test", guild_only, ephemeral, owners_only)]
+pub async fn filter_test(
+    ctx: Context<'_>,
+    #[description = "Filter name or ID to test"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "Number of recent items to check (default 200)"]
+    #[min = 1]
+    #[max = 1000]
+    count: Option<usize>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+    let filter_id = saved_filter.id;
+
+    let filter: Filter = match serde_yaml::from_str(&saved_filter.rule_yaml) {
+        Ok(f) => f,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to parse filter definition: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let library = FilterLibrary::load(&db, ctx.guild_id().unwrap().get() as i64).await?;
+    let engine = match FilteringEngine::with_library(&filter, Some(filter_id), &library) {
+        Ok(engine) => engine,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to resolve filter references: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let preview = preview_filter(&db, &engine, count.unwrap_or(DEFAULT_PREVIEW_ITEMS)).await?;
+
+    let mut message = format!(
+        "**🧪 Filter Test**\n\nFilter `{}` matched **{}** of the last **{}** items.\n\n",
+        saved_filter.label(),
+        preview.matches.len(),
+        preview.scanned
+    );
+
+    for item in preview.matches.iter().take(10) {
+        // Show only first 10
+        let name = if item.name.chars().count() > 80 {
+            format!("{}...", item.name.chars().take(80).collect::<String>())
+        } else {
+            item.name.clone()
+        };
+
+        message.push_str(&format!(
+            "- [{}](<{}>) — {} / {}\n",
+            name, item.url, item.shop.name, item.price
+        ));
+    }
+
+    if preview.matches.len() > 10 {
+        message.push_str(&format!(
+            "\n*...and {} more items*",
+            preview.matches.len() - 10
+        ));
+    }
+
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Explain why an item did or did not match a filter
+#[poise::command(slash_command, rename = "explain", guild_only, ephemeral, owners_only)]
+pub async fn filter_explain(
+    ctx: Context<'_>,
+    #[description = "Booth item ID"] item_id: u64,
+    #[description = "Filter name or ID to check"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: Option<String>,
+    #[description = "Channel whose filter to check"] channel: Option<ChannelId>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let saved_filter = match (filter, channel) {
+        (Some(filter), _) => find_filter(ctx, &filter)
+            .await?
+            .ok_or_else(|| format!("❌ Filter `{}` not found", filter)),
+        (None, Some(channel)) => {
+            let Some(existing_channel) = db.get_discord_channel(channel.get() as i64).await? else {
+                ctx.say(format!(
+                    "❌ Channel <#{}> is not registered in the database.",
+                    channel.get()
+                ))
+                .await?;
+                return Ok(());
+            };
+            let filter_id = match existing_channel.filter_ids.as_slice() {
+                [] => {
+                    ctx.say(format!(
+                        "ℹ️ Channel <#{}> has no filter assigned.",
+                        channel.get()
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+                [filter_id] => *filter_id,
+                filter_ids => {
+                    ctx.say(format!(
+                        "ℹ️ Channel <#{}> has {} filters ({}), pick one with the `filter` option.",
+                        channel.get(),
+                        filter_ids.len(),
+                        filter_ids
+                            .iter()
+                            .map(|id| format!("`{}`", id))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))
+                    .await?;
+                    return Ok(());
+                }
+            };
+            db.get_notification_filter(filter_id)
+                .await?
+                .ok_or_else(|| format!("❌ Filter with ID `{}` not found", filter_id))
+        }
+        (None, None) => Err("❌ Please specify either a filter or a channel".to_string()),
+    };
+    let saved_filter = match saved_filter {
+        Ok(saved_filter) => saved_filter,
+        Err(message) => {
+            ctx.say(message).await?;
+            return Ok(());
+        }
+    };
+    let filter_id = saved_filter.id;
+
+    let filter: Filter = match serde_yaml::from_str(&saved_filter.rule_yaml) {
+        Ok(f) => f,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to parse filter definition: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    ctx.defer_ephemeral().await?;
+
+    let item = match ctx.data().booth_db.get_item(item_id).await {
+        Ok(item) => item,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to load item `{}`: {}", item_id, e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    let library = FilterLibrary::load(&db, ctx.guild_id().unwrap().get() as i64).await?;
+    let engine = match FilteringEngine::with_library(&filter, Some(filter_id), &library) {
+        Ok(engine) => engine,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to resolve filter references: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+    let explanation = engine.explain(&item);
+
+    let mut message = format!(
+        "**🔎 Filter Explain**\n\n**Item:** [{}](<{}>)\n**Filter:** `{}`\n**Result:** {}\n",
+        item.name,
+        item.url,
+        saved_filter.label(),
+        if explanation.matched {
+            "✅ matched"
+        } else {
+            "❌ not matched"
+        }
+    );
+
+    if let Some(channel) = channel {
+        let is_nsfw_channel = match channel.to_channel(&ctx.http()).await? {
+            serenity::Channel::Guild(channel) => channel.nsfw,
+            _ => false,
+        };
+        let policy = db
+            .get_discord_channel(channel.get() as i64)
+            .await?
+            .and_then(|registered| registered.content_policy())
+            .unwrap_or(ContentPolicy::default_for(is_nsfw_channel));
+        if item.is_adult && !is_nsfw_channel {
+            message.push_str(&format!(
+                "⚠️ This is an adult item, which is never posted to the non-NSFW channel <#{}>.\n",
+                channel.get()
+            ));
+        } else if !policy.allows(item.is_adult) {
+            message.push_str(&format!(
+                "⚠️ This is {} item, which is not posted to <#{}> with its content policy `{}`.\n",
+                if item.is_adult {
+                    "an adult"
+                } else {
+                    "not an adult"
+                },
+                channel.get(),
+                policy.label()
+            ));
+        }
+    }
+
+    message.push('\n');
+    render_explanation(&explanation, 0, &mut message);
+
+    if message.chars().count() > MESSAGE_LIMIT {
+        message = format!(
+            "{}...",
+            message.chars().take(MESSAGE_LIMIT - 3).collect::<String>()
+        );
+    }
+
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Append one line per node of an explanation tree, indenting children.
+fn render_explanation(explanation: &Explanation, depth: usize, out: &mut String) {
+    let indent = "\u{2003}".repeat(depth);
+    let (label, children): (&str, Vec<&Explanation>) = match &explanation.node {
+        ExplanationNode::All(children) => ("all", children.iter().collect()),
+        ExplanationNode::Any(children) => ("any", children.iter().collect()),
+        ExplanationNode::Not(child) => ("not", vec![child.as_ref()]),
+        ExplanationNode::Rule(rule) => {
+            let rule_text = rule.to_string();
+            let rule_text = if rule_text.chars().count() > 100 {
+                format!("{}...", rule_text.chars().take(100).collect::<String>())
+            } else {
+                rule_text
+            };
+            out.push_str(&format!(
+                "{}{} `{}`\n",
+                indent,
+                if explanation.matched { "✅" } else { "▫️" },
+                rule_text.replace('`', "'")
+            ));
+            return;
+        }
+    };
+
+    out.push_str(&format!(
+        "{}{} **{}**\n",
+        indent,
+        if explanation.matched { "✅" } else { "❌" },
+        label
+    ));
+    for child in children {
+        render_explanation(child, depth + 1, out);
+    }
+}
+
+/// Set a filter's name and description
+#[poise::command(slash_command, rename = "set-info", guild_only, ephemeral, owners_only)]
+pub async fn filter_set_info(
+    ctx: Context<'_>,
+    #[description = "Filter name or ID to update"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "New name, or - to remove it"] name: Option<String>,
+    #[description = "New description, or - to remove it"] description: Option<String>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    let updated_value = |value: Option<String>, current: &Option<String>| match value {
+        Some(value) if value == "-" => None,
+        Some(value) => Some(value),
+        None => current.clone(),
+    };
+    let name = updated_value(name, &saved_filter.name);
+    let description = updated_value(description, &saved_filter.description);
+
+    let library = FilterLibrary::load(&db, guild_id).await?;
+    if let Err(e) = library.check_rename(saved_filter.id, name.as_deref()) {
+        ctx.say(format!("❌ {}", e)).await?;
+        return Ok(());
+    }
+
+    let Some(updated) = db
+        .update_notification_filter_details(saved_filter.id, guild_id, name, description)
+        .await?
+    else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    ctx.say(format!("✅ Filter `{}` updated", updated.label()))
+        .await?;
+
+    Ok(())
+}
+
+/// Show the revision history of a filter
+#[poise::command(slash_command, rename = "history", guild_only, ephemeral, owners_only)]
+pub async fn filter_history(
+    ctx: Context<'_>,
+    #[description = "Filter name or ID"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "Revision ID to show the changes of"] revision: Option<i64>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    let revisions = db.get_filter_revisions(saved_filter.id).await?;
+    if revisions.is_empty() {
+        ctx.say(format!(
+            "No revisions recorded for filter `{}`.",
+            saved_filter.label()
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    let message = match revision {
+        Some(revision_id) => {
+            let Some(index) = revisions.iter().position(|r| r.id == revision_id) else {
+                ctx.say(format!(
+                    "❌ Revision `{}` not found for filter `{}`",
+                    revision_id,
+                    saved_filter.label()
+                ))
                 .await?;
+                return Ok(());
+            };
+            let previous = revisions
+                .get(index + 1)
+                .map_or("", |previous| previous.rule_yaml.as_str());
+            let diff = diff_lines(previous, &revisions[index].rule_yaml)
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join("\n");
+
+            format!(
+                "**📜 Revision `{}` of `{}`**\n{}\n```diff\n{}\n```",
+                revision_id,
+                saved_filter.label(),
+                revision_summary(&revisions[index]),
+                diff.replace("```", "'''")
+            )
         }
+        None => {
+            let mut message = format!(
+                "**📜 History of `{}` ({} revisions)**\n\n",
+                saved_filter.label(),
+                revisions.len()
+            );
+            for (index, revision) in revisions.iter().take(10).enumerate() {
+                message.push_str(&format!(
+                    "`{}` {}{}\n",
+                    revision.id,
+                    revision_summary(revision),
+                    if index == 0 { " (current)" } else { "" }
+                ));
+            }
+            if revisions.len() > 10 {
+                message.push_str(&format!(
+                    "*...and {} older revisions*\n",
+                    revisions.len() - 10
+                ));
+            }
+            message.push_str(
+                "\nUse `/booth filter history` with a revision ID to see what changed, or `/booth filter rollback` to restore one.",
+            );
+            message
+        }
+    };
+
+    let message = if message.chars().count() > MESSAGE_LIMIT {
+        // Close the diff block the cut leaves open
+        format!(
+            "{}...\n```",
+            message.chars().take(MESSAGE_LIMIT - 7).collect::<String>()
+        )
+    } else {
+        message
+    };
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Restore a filter to an earlier revision
+#[poise::command(slash_command, rename = "rollback", guild_only, ephemeral, owners_only)]
+pub async fn filter_rollback(
+    ctx: Context<'_>,
+    #[description = "Filter name or ID"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "Revision ID to restore"] revision: i64,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+    let Some(old) = db.get_filter_revision(saved_filter.id, revision).await? else {
+        ctx.say(format!(
+            "❌ Revision `{}` not found for filter `{}`",
+            revision,
+            saved_filter.label()
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    let restored: Filter = match serde_yaml::from_str(&old.rule_yaml) {
+        Ok(f) => f,
+        Err(e) => {
+            ctx.say(format!("❌ Failed to parse filter definition: {}", e))
+                .await?;
+            return Ok(());
+        }
+    };
+
+    // The old version must still fit the guild's filters as they are now
+    let mut library = FilterLibrary::load(&db, guild_id).await?;
+    library.insert(
+        saved_filter.id,
+        saved_filter.name.as_deref(),
+        restored.clone(),
+    );
+    let checked = restored
+        .validate()
+        .map_err(|e| e.to_string())
+        .and_then(|_| {
+            library
+                .resolve(&restored, Some(saved_filter.id))
+                .map_err(|e| e.to_string())
+        });
+    if let Err(e) = checked {
+        ctx.say(format!(
+            "❌ Revision `{}` can no longer be restored:\n```\n{}\n```",
+            revision, e
+        ))
+        .await?;
+        return Ok(());
     }
 
+    db.update_notification_filter(
+        saved_filter.id,
+        guild_id,
+        serde_yaml::to_string(&restored)?,
+        Some(&editor(ctx)),
+    )
+    .await?;
+
+    ctx.say(format!(
+        "✅ Filter `{}` has been restored to revision `{}`",
+        saved_filter.label(),
+        revision
+    ))
+    .await?;
+
     Ok(())
 }
 
@@ -190,17 +738,25 @@ pub async fn filter_view(
 #[poise::command(slash_command, rename = "delete", guild_only, ephemeral, owners_only)]
 pub async fn filter_delete(
     ctx: Context<'_>,
-    #[description = "Filter ID to delete"] filter_id: i64,
+    #[description = "Filter name or ID to delete"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
 ) -> Result<(), Error> {
     let db = ctx.data().db.clone();
 
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+    let filter_id = saved_filter.id;
+
     // Check if linked to channels
     let channels = db
         .get_channels_by_guild(ctx.guild_id().unwrap().get() as i64)
         .await?;
     let linked_channels: Vec<_> = channels
         .iter()
-        .filter(|c| c.filter_id == Some(filter_id))
+        .filter(|c| c.filter_ids.contains(&filter_id))
         .collect();
 
     if !linked_channels.is_empty() {
@@ -211,18 +767,40 @@ pub async fn filter_delete(
             .join(", ");
 
         ctx.say(format!(
-            "⚠️ Cannot delete filter `{}` because it is linked to the following channels:\n{}\n\nPlease clear the filter from these channels first using `/booth channel clear-filter`",
+            "⚠️ Cannot delete filter `{}` because it is linked to the following channels:\n{}\n\nPlease remove the filter from these channels first using `/booth channel remove-filter`",
             filter_id, channel_list
         ))
         .await?;
         return Ok(());
     }
 
+    // Check if included by other filters
+    let dependents = FilterLibrary::load(&db, ctx.guild_id().unwrap().get() as i64)
+        .await?
+        .dependents(filter_id);
+    if !dependents.is_empty() {
+        let filter_list = dependents
+            .iter()
+            .map(|id| format!("`{}`", id))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        ctx.say(format!(
+            "⚠️ Cannot delete filter `{}` because the following filters include it:\n{}\n\nPlease remove the `ref` from these filters first",
+            filter_id, filter_list
+        ))
+        .await?;
+        return Ok(());
+    }
+
     let deleted = db.delete_notification_filter(filter_id).await?;
 
     if deleted {
-        ctx.say(format!("✅ Filter `{}` deleted successfully", filter_id))
-            .await?;
+        ctx.say(format!(
+            "✅ Filter `{}` deleted successfully",
+            saved_filter.label()
+        ))
+        .await?;
     } else {
         ctx.say(format!("❌ Filter with ID `{}` not found", filter_id))
             .await?;
@@ -237,7 +815,17 @@ pub async fn filter_delete(
     slash_command,
     rename = "channel",
     guild_only,
-    subcommands("channel_set_filter", "channel_clear_filter", "channel_view"),
+    subcommands(
+        "channel_add_filter",
+        "channel_remove_filter",
+        "channel_clear_filter",
+        "channel_content_policy",
+        "channel_delivery_mode",
+        "channel_crosspost",
+        "channel_filter_roles",
+        "channel_enable",
+        "channel_view"
+    ),
     subcommand_required,
     owners_only
 )]
@@ -245,28 +833,28 @@ pub async fn channel(_ctx: Context<'_>) -> Result<(), Error> {
     Ok(())
 }
 
-/// Set filter for a channel
+/// Add a filter to a channel
 #[poise::command(
     slash_command,
-    rename = "set-filter",
+    rename = "add-filter",
     guild_only,
     ephemeral,
     owners_only
 )]
-pub async fn channel_set_filter(
+pub async fn channel_add_filter(
     ctx: Context<'_>,
     #[description = "Discord channel"] channel: ChannelId,
-    #[description = "Filter ID to assign"] filter_id: i64,
+    #[description = "Filter name or ID to add"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
 ) -> Result<(), Error> {
     let db = ctx.data().db.clone();
 
     // Check if filter exists
-    let filter = db.get_notification_filter(filter_id).await?;
-    if filter.is_none() {
-        ctx.say(format!("❌ Filter with ID `{}` not found", filter_id))
-            .await?;
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
         return Ok(());
-    }
+    };
 
     // Check if channel exists
     let existing_channel = db.get_discord_channel(channel.get() as i64).await?;
@@ -279,21 +867,76 @@ pub async fn channel_set_filter(
         return Ok(());
     }
 
-    // Set filter
-    db.update_channel_filter(channel.get() as i64, Some(filter_id))
+    // Add filter
+    let added = db
+        .add_channel_filter(channel.get() as i64, saved_filter.id)
         .await?;
 
-    ctx.say(format!(
-        "✅ Filter `{}` has been assigned to <#{}>\n\nThis channel will now use the specified filter for notifications.",
-        filter_id,
-        channel.get()
-    ))
-    .await?;
+    let message = if added {
+        format!(
+            "✅ Filter `{}` has been added to <#{}>\n\nItems matching any of the channel's filters will be posted there once.",
+            saved_filter.label(),
+            channel.get()
+        )
+    } else {
+        format!(
+            "ℹ️ Filter `{}` is already assigned to <#{}>.",
+            saved_filter.label(),
+            channel.get()
+        )
+    };
+
+    ctx.say(message).await?;
 
     Ok(())
 }
 
-/// Clear filter from a channel
+/// Remove a filter from a channel
+#[poise::command(
+    slash_command,
+    rename = "remove-filter",
+    guild_only,
+    ephemeral,
+    owners_only
+)]
+pub async fn channel_remove_filter(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+    #[description = "Filter name or ID to remove"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    let removed = db
+        .remove_channel_filter(channel.get() as i64, saved_filter.id)
+        .await?;
+
+    let message = if removed {
+        format!(
+            "✅ Filter `{}` has been removed from <#{}>",
+            saved_filter.label(),
+            channel.get()
+        )
+    } else {
+        format!(
+            "ℹ️ Filter `{}` is not assigned to <#{}>.",
+            saved_filter.label(),
+            channel.get()
+        )
+    };
+
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Clear all filters from a channel
 #[poise::command(
     slash_command,
     rename = "clear-filter",
@@ -318,15 +961,12 @@ pub async fn channel_clear_filter(
         return Ok(());
     }
 
-    let old_filter_id = existing_channel.unwrap().filter_id;
-
-    // Clear filter (set to None)
-    db.update_channel_filter(channel.get() as i64, None).await?;
+    let cleared = db.clear_channel_filters(channel.get() as i64).await?;
 
-    let message = if let Some(fid) = old_filter_id {
+    let message = if cleared > 0 {
         format!(
-            "✅ Filter `{}` has been cleared from <#{}>\n\nThis channel will now use the guild's fallback channel routing.",
-            fid,
+            "✅ {} filter(s) have been cleared from <#{}>\n\nThis channel will now use the guild's fallback channel routing.",
+            cleared,
             channel.get()
         )
     } else {
@@ -338,6 +978,299 @@ pub async fn channel_clear_filter(
     Ok(())
 }
 
+/// Which items a channel receives, as offered by `/booth channel content-policy`
+#[derive(Debug, poise::ChoiceParameter)]
+pub enum ContentPolicyChoice {
+    #[name = "Default (follow the channel's NSFW setting)"]
+    Default,
+    #[name = "SFW only"]
+    SfwOnly,
+    #[name = "NSFW only"]
+    NsfwOnly,
+    #[name = "SFW and NSFW"]
+    Both,
+}
+
+impl ContentPolicyChoice {
+    fn policy(&self) -> Option<ContentPolicy> {
+        match self {
+            Self::Default => None,
+            Self::SfwOnly => Some(ContentPolicy::SfwOnly),
+            Self::NsfwOnly => Some(ContentPolicy::NsfwOnly),
+            Self::Both => Some(ContentPolicy::Both),
+        }
+    }
+}
+
+/// Set whether a channel receives SFW items, NSFW items or both
+#[poise::command(
+    slash_command,
+    rename = "content-policy",
+    guild_only,
+    ephemeral,
+    owners_only
+)]
+pub async fn channel_content_policy(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+    #[description = "Items the channel receives"] policy: ContentPolicyChoice,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let updated = db
+        .update_channel_content_policy(channel.get() as i64, policy.policy())
+        .await?;
+    if !updated {
+        ctx.say(format!(
+            "❌ Channel <#{}> is not registered in the database.",
+            channel.get()
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    let is_nsfw_channel = match channel.to_channel(&ctx).await? {
+        serenity::Channel::Guild(channel) => channel.nsfw,
+        _ => false,
+    };
+    let policy = policy
+        .policy()
+        .unwrap_or(ContentPolicy::default_for(is_nsfw_channel));
+    let mut message = format!(
+        "✅ <#{}> now receives: **{}**",
+        channel.get(),
+        policy.label()
+    );
+    if policy.allows(true) && !is_nsfw_channel {
+        message.push_str(
+            "\n\n⚠️ This channel is not age-restricted, so NSFW items will not be posted until it is marked NSFW in Discord.",
+        );
+    }
+
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// When a channel gets its items, as offered by `/booth channel delivery-mode`
+#[derive(Debug, poise::ChoiceParameter)]
+pub enum DeliveryModeChoice {
+    #[name = "Immediately"]
+    Immediate,
+    #[name = "Hourly digest"]
+    Hourly,
+    #[name = "Daily digest"]
+    Daily,
+}
+
+impl DeliveryModeChoice {
+    fn mode(&self) -> DeliveryMode {
+        match self {
+            Self::Immediate => DeliveryMode::Immediate,
+            Self::Hourly => DeliveryMode::Hourly,
+            Self::Daily => DeliveryMode::Daily,
+        }
+    }
+}
+
+/// Set whether a channel gets each item right away or an hourly/daily digest
+#[poise::command(
+    slash_command,
+    rename = "delivery-mode",
+    guild_only,
+    ephemeral,
+    owners_only
+)]
+pub async fn channel_delivery_mode(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+    #[description = "When the channel gets its items"] mode: DeliveryModeChoice,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let updated = db
+        .update_channel_delivery_mode(channel.get() as i64, mode.mode())
+        .await?;
+    if !updated {
+        ctx.say(format!(
+            "❌ Channel <#{}> is not registered in the database.",
+            channel.get()
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    ctx.say(format!(
+        "✅ <#{}> now gets its items: **{}**",
+        channel.get(),
+        mode.mode().label()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Whether a channel's messages are crossposted, as offered by
+/// `/booth channel crosspost`
+#[derive(Debug, poise::ChoiceParameter)]
+pub enum CrosspostChoice {
+    #[name = "Default (announcement channels only)"]
+    Default,
+    #[name = "Crosspost"]
+    On,
+    #[name = "Don't crosspost"]
+    Off,
+}
+
+impl CrosspostChoice {
+    fn crosspost(&self) -> Option<bool> {
+        match self {
+            Self::Default => None,
+            Self::On => Some(true),
+            Self::Off => Some(false),
+        }
+    }
+}
+
+/// Set whether messages in an announcement channel are published to following servers
+#[poise::command(
+    slash_command,
+    rename = "crosspost",
+    guild_only,
+    ephemeral,
+    owners_only
+)]
+pub async fn channel_crosspost(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+    #[description = "Whether messages are crossposted"] crosspost: CrosspostChoice,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let updated = db
+        .update_channel_crosspost(channel.get() as i64, crosspost.crosspost())
+        .await?;
+    if !updated {
+        ctx.say(format!(
+            "❌ Channel <#{}> is not registered in the database.",
+            channel.get()
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    ctx.say(format!(
+        "✅ Crossposting in <#{}>: **{}**",
+        channel.get(),
+        crosspost_label(crosspost.crosspost())
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Set the roles pinged when a filter of a channel matches
+#[poise::command(
+    slash_command,
+    rename = "filter-roles",
+    guild_only,
+    ephemeral,
+    owners_only
+)]
+pub async fn channel_filter_roles(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+    #[description = "Filter name or ID"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "Roles to ping, e.g. @Role @Other (leave out to ping none)"] roles: Option<
+        String,
+    >,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let Some(saved_filter) = find_filter(ctx, &filter).await? else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    let role_ids = match parse_role_ids(roles.as_deref().unwrap_or_default()) {
+        Ok(role_ids) => role_ids
+            .into_iter()
+            .map(|role_id| role_id as i64)
+            .collect::<Vec<_>>(),
+        Err(e) => {
+            ctx.say(format!("❌ {}", e)).await?;
+            return Ok(());
+        }
+    };
+
+    let updated = db
+        .update_channel_filter_roles(channel.get() as i64, saved_filter.id, &role_ids)
+        .await?;
+    if !updated {
+        ctx.say(format!(
+            "❌ Filter `{}` is not assigned to <#{}>.",
+            saved_filter.label(),
+            channel.get()
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    let message = if role_ids.is_empty() {
+        format!(
+            "✅ Matches of `{}` in <#{}> no longer ping any role",
+            saved_filter.label(),
+            channel.get()
+        )
+    } else {
+        format!(
+            "✅ Matches of `{}` in <#{}> now ping {}",
+            saved_filter.label(),
+            channel.get(),
+            role_mentions(&role_ids)
+        )
+    };
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Re-enable a channel that was disabled after failed deliveries
+#[poise::command(slash_command, rename = "enable", guild_only, ephemeral, owners_only)]
+pub async fn channel_enable(
+    ctx: Context<'_>,
+    #[description = "Discord channel"] channel: ChannelId,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+
+    let Some(existing_channel) = db.get_discord_channel(channel.get() as i64).await? else {
+        ctx.say(format!(
+            "❌ Channel <#{}> is not registered in the database.",
+            channel.get()
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    if existing_channel.disabled_at.is_none() {
+        ctx.say(format!("ℹ️ Channel <#{}> is not disabled.", channel.get()))
+            .await?;
+        return Ok(());
+    }
+
+    db.enable_channel(channel.get() as i64).await?;
+
+    ctx.say(format!(
+        "✅ Notifications to <#{}> have been re-enabled",
+        channel.get()
+    ))
+    .await?;
+
+    Ok(())
+}
+
 /// View channel filter information
 #[poise::command(slash_command, rename = "view", guild_only, ephemeral, owners_only)]
 pub async fn channel_view(
@@ -351,31 +1284,49 @@ pub async fn channel_view(
 
     match channel_info {
         Some(ch) => {
-            let filter_info = if let Some(fid) = ch.filter_id {
-                let filter = db.get_notification_filter(fid).await?;
-                match filter {
-                    Some(f) => {
-                        format!(
-                            "**Filter ID:** `{}`\n**Filter Preview:**\n```yaml\n{}\n```",
-                            fid,
-                            if f.rule_yaml.len() > 200 {
-                                format!("{}...", &f.rule_yaml[..200])
+            let filter_info = if ch.filter_ids.is_empty() {
+                "No filter assigned (using fallback routing)".to_string()
+            } else {
+                let mut filters = db.get_notification_filters_by_ids(&ch.filter_ids).await?;
+                let bindings = db.get_channel_filters_by_guild(ch.guild_id).await?;
+                let pings = |filter_id: i64| {
+                    bindings
+                        .iter()
+                        .find(|b| b.channel_id == ch.channel_id && b.filter_id == filter_id)
+                        .filter(|b| !b.role_ids.is_empty())
+                        .map_or_else(String::new, |b| {
+                            format!("**Pings:** {}\n", role_mentions(&b.role_ids))
+                        })
+                };
+                ch.filter_ids
+                    .iter()
+                    .map(|fid| match filters.remove(fid) {
+                        Some(f) => format!(
+                            "**Filter:** `{}`\n{}**Filter Preview:**\n```yaml\n{}\n```",
+                            f.label(),
+                            pings(f.id),
+                            if f.rule_yaml.chars().count() > 200 {
+                                format!("{}...", f.rule_yaml.chars().take(200).collect::<String>())
                             } else {
                                 f.rule_yaml
                             }
-                        )
-                    }
-                    None => format!("⚠️ Filter ID `{}` (NOT FOUND - orphaned reference)", fid),
-                }
-            } else {
-                "No filter assigned (using fallback routing)".to_string()
+                        ),
+                        None => format!("⚠️ Filter ID `{}` (NOT FOUND - orphaned reference)", fid),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n")
             };
 
             ctx.say(format!(
-                "**🔍 Channel Information**\n\n**Channel:** <#{}>\n**Name:** `{}`\n**Created:** <t:{}:R>\n\n{}",
+                "**🔍 Channel Information**\n\n**Channel:** <#{}>\n**Name:** `{}`\n**Created:** <t:{}:R>\n{}**Content:** {}\n**Delivery:** {}\n**Crosspost:** {}\n\n{}",
                 ch.channel_id,
                 ch.name,
                 ch.created_at.unix_timestamp(),
+                channel_status(&ch),
+                ch.content_policy()
+                    .map_or("Default (follows the channel's NSFW setting)", ContentPolicy::label),
+                ch.delivery_mode().label(),
+                crosspost_label(ch.crosspost),
                 filter_info
             ))
             .await?;
@@ -391,3 +1342,119 @@ pub async fn channel_view(
 
     Ok(())
 }
+
+// ==================== Helpers ====================
+
+/// Suggests this guild's filters, matching the input against IDs, names and
+/// descriptions.
+pub(crate) async fn autocomplete_filter(
+    ctx: Context<'_>,
+    partial: &str,
+) -> Vec<serenity::AutocompleteChoice> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return vec![];
+    };
+    let Ok(filters) = ctx
+        .data()
+        .db
+        .get_notification_filters_by_guild(guild_id.get() as i64)
+        .await
+    else {
+        return vec![];
+    };
+
+    let partial = partial.trim().trim_start_matches('#').to_lowercase();
+    let contains = |value: &Option<String>| {
+        value
+            .as_ref()
+            .is_some_and(|value| value.to_lowercase().contains(&partial))
+    };
+
+    filters
+        .iter()
+        .filter(|f| {
+            f.id.to_string().starts_with(&partial) || contains(&f.name) || contains(&f.description)
+        })
+        .take(25)
+        .map(|f| {
+            let label = match &f.description {
+                Some(description) => format!("{} — {}", f.label(), description),
+                None => f.label(),
+            };
+            let label = if label.chars().count() > 100 {
+                format!("{}...", label.chars().take(97).collect::<String>())
+            } else {
+                label
+            };
+            serenity::AutocompleteChoice::new(label, f.id.to_string())
+        })
+        .collect()
+}
+
+/// Whether a channel is deleted, disabled or failing, as shown by `channel view`.
+fn channel_status(channel: &DiscordChannel) -> String {
+    let last_error = channel.last_error.as_deref().unwrap_or("unknown");
+    match channel.disabled_at {
+        _ if !channel.active => "**Status:** 🗑️ Deleted in Discord\n".to_string(),
+        Some(disabled_at) => format!(
+            "**Status:** ⛔ Disabled <t:{}:R> after {} failures, use `/booth channel enable` once fixed\n**Last Error:** `{}`\n",
+            disabled_at.unix_timestamp(),
+            channel.failure_count,
+            last_error
+        ),
+        None if channel.failure_count > 0 => format!(
+            "**Status:** ⚠️ {} failures in a row\n**Last Error:** `{}`\n",
+            channel.failure_count, last_error
+        ),
+        None => String::new(),
+    }
+}
+
+fn crosspost_label(crosspost: Option<bool>) -> &'static str {
+    match crosspost {
+        None => "Default (announcement channels only)",
+        Some(true) => "On",
+        Some(false) => "Off",
+    }
+}
+
+fn role_mentions(role_ids: &[i64]) -> String {
+    role_ids
+        .iter()
+        .map(|role_id| format!("<@&{}>", role_id))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// When and by whom a revision was saved.
+fn revision_summary(revision: &NotificationFilterRevision) -> String {
+    format!(
+        "<t:{}:R> by {}",
+        revision.created_at.unix_timestamp(),
+        revision
+            .editor_id
+            .map_or_else(|| "unknown".to_string(), |id| format!("<@{}>", id))
+    )
+}
+
+/// Looks up a guild filter by ID (`17` or `#17`) or by its name in this
+/// guild.
+pub(crate) async fn find_filter(
+    ctx: Context<'_>,
+    filter: &str,
+) -> Result<Option<NotificationFilter>, Error> {
+    let db = &ctx.data().db;
+    let filter = filter.trim();
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    if let Ok(id) = filter.trim_start_matches('#').parse::<i64>() {
+        // Other guilds' filters are out of reach, personal filters are
+        // managed with `/watch`
+        return Ok(db
+            .get_notification_filter(id)
+            .await?
+            .filter(|f| f.editable_in(guild_id)));
+    }
+
+    Ok(db.get_notification_filter_by_name(guild_id, filter).await?)
+}
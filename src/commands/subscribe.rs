@@ -0,0 +1,225 @@
+use poise::serenity_prelude::{self as serenity, EditRole, GuildId, RoleId};
+use tracing::warn;
+
+use crate::{
+    Context, Error,
+    database::{DiscordChannel, NotificationFilter},
+};
+
+/// Get pinged when new items for an avatar are posted
+#[poise::command(slash_command, rename = "subscribe", guild_only, ephemeral)]
+pub async fn booth_subscribe(
+    ctx: Context<'_>,
+    #[description = "Avatar to get pinged for"]
+    #[autocomplete = "autocomplete_avatar"]
+    avatar: String,
+) -> Result<(), Error> {
+    let guild_id = ctx.guild_id().unwrap();
+
+    let Some((filter, channels)) = find_avatar(ctx, &avatar).await? else {
+        ctx.say(format!(
+            "❌ No channel in this server posts items for `{}`",
+            avatar
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    let role_id = subscription_role(ctx, guild_id, &filter).await?;
+    ctx.http()
+        .add_member_role(
+            guild_id,
+            ctx.author().id,
+            role_id,
+            Some("Subscribed with /booth subscribe"),
+        )
+        .await?;
+
+    ctx.say(format!(
+        "✅ You will be pinged for new `{}` items in {}\nUse `/booth unsubscribe` to stop.",
+        avatar_name(&filter),
+        channel_mentions(&channels)
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Stop getting pinged for an avatar
+#[poise::command(slash_command, rename = "unsubscribe", guild_only, ephemeral)]
+pub async fn booth_unsubscribe(
+    ctx: Context<'_>,
+    #[description = "Avatar to stop getting pinged for"]
+    #[autocomplete = "autocomplete_avatar"]
+    avatar: String,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let guild_id = ctx.guild_id().unwrap();
+
+    let Some((filter, _)) = find_avatar(ctx, &avatar).await? else {
+        ctx.say(format!("❌ Avatar `{}` not found", avatar)).await?;
+        return Ok(());
+    };
+
+    let role_id = db
+        .get_subscription_role(guild_id.get() as i64, filter.id)
+        .await?;
+    let subscribed = match (role_id, ctx.author_member().await) {
+        (Some(role_id), Some(member)) => member.roles.contains(&RoleId::new(role_id as u64)),
+        _ => false,
+    };
+    let Some(role_id) = role_id.filter(|_| subscribed) else {
+        ctx.say(format!(
+            "ℹ️ You are not subscribed to `{}`",
+            avatar_name(&filter)
+        ))
+        .await?;
+        return Ok(());
+    };
+
+    ctx.http()
+        .remove_member_role(
+            guild_id,
+            ctx.author().id,
+            RoleId::new(role_id as u64),
+            Some("Unsubscribed with /booth unsubscribe"),
+        )
+        .await?;
+
+    ctx.say(format!(
+        "✅ You will no longer be pinged for `{}`",
+        avatar_name(&filter)
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Suggests the guild filters that are posted to a channel, by avatar name.
+async fn autocomplete_avatar(ctx: Context<'_>, partial: &str) -> Vec<serenity::AutocompleteChoice> {
+    let Ok(avatars) = avatars(ctx).await else {
+        return vec![];
+    };
+
+    let partial = partial.trim().to_lowercase();
+    avatars
+        .iter()
+        .map(|(filter, _)| (filter, avatar_name(filter)))
+        .filter(|(_, name)| name.to_lowercase().contains(&partial))
+        .take(25)
+        .map(|(filter, name)| {
+            let name = if name.chars().count() > 100 {
+                format!("{}...", name.chars().take(97).collect::<String>())
+            } else {
+                name
+            };
+            serenity::AutocompleteChoice::new(name, filter.id.to_string())
+        })
+        .collect()
+}
+
+/// The guild's filters that are posted to at least one of its channels,
+/// with those channels.
+async fn avatars(
+    ctx: Context<'_>,
+) -> Result<Vec<(NotificationFilter, Vec<DiscordChannel>)>, Error> {
+    let db = &ctx.data().db;
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    let mut channels = db.get_channels_by_guild(guild_id).await?;
+    channels.retain(|channel| channel.active && channel.disabled_at.is_none());
+
+    Ok(db
+        .get_notification_filters_by_guild(guild_id)
+        .await?
+        .into_iter()
+        .filter(|filter| filter.editable_in(guild_id))
+        .map(|filter| {
+            let posted_to = channels
+                .iter()
+                .filter(|channel| channel.filter_ids.contains(&filter.id))
+                .cloned()
+                .collect::<Vec<_>>();
+            (filter, posted_to)
+        })
+        .filter(|(_, posted_to)| !posted_to.is_empty())
+        .collect())
+}
+
+/// Looks up an avatar by filter ID, as suggested by autocomplete, or by
+/// avatar or filter name.
+async fn find_avatar(
+    ctx: Context<'_>,
+    avatar: &str,
+) -> Result<Option<(NotificationFilter, Vec<DiscordChannel>)>, Error> {
+    let avatar = avatar.trim().trim_start_matches('#');
+    let id = avatar.parse::<i64>().ok();
+    Ok(avatars(ctx).await?.into_iter().find(|(filter, _)| {
+        Some(filter.id) == id
+            || avatar_name(filter).eq_ignore_ascii_case(avatar)
+            || filter
+                .name
+                .as_deref()
+                .is_some_and(|name| name.eq_ignore_ascii_case(avatar))
+    }))
+}
+
+/// The role members of `filter`'s avatar get, created on first use. It is
+/// recreated if it was deleted in Discord. Of two roles created at the same
+/// time, the one saved first is kept and the other deleted again.
+async fn subscription_role(
+    ctx: Context<'_>,
+    guild_id: GuildId,
+    filter: &NotificationFilter,
+) -> Result<RoleId, Error> {
+    let db = &ctx.data().db;
+
+    let current = db
+        .get_subscription_role(guild_id.get() as i64, filter.id)
+        .await?;
+    if let Some(role_id) = current {
+        let role_id = RoleId::new(role_id as u64);
+        if guild_id.roles(&ctx.http()).await?.contains_key(&role_id) {
+            return Ok(role_id);
+        }
+    }
+
+    let role = guild_id
+        .create_role(
+            &ctx.http(),
+            EditRole::new()
+                .name(format!("🔔 {}", avatar_name(filter)))
+                .mentionable(true)
+                .audit_log_reason("Role for /booth subscribe"),
+        )
+        .await?;
+    let role_id = db
+        .set_subscription_role(
+            guild_id.get() as i64,
+            filter.id,
+            role.id.get() as i64,
+            current,
+        )
+        .await?;
+    let role_id = RoleId::new(role_id as u64);
+    if role_id != role.id
+        && let Err(e) = guild_id.delete_role(&ctx.http(), role.id).await
+    {
+        warn!("Failed to delete duplicate role {}: {}", role.id, e);
+    }
+
+    Ok(role_id)
+}
+
+/// The avatar a filter was made for by `/avatar add`, else its label.
+fn avatar_name(filter: &NotificationFilter) -> String {
+    filter.avatar.clone().unwrap_or_else(|| filter.label())
+}
+
+fn channel_mentions(channels: &[DiscordChannel]) -> String {
+    channels
+        .iter()
+        .map(|channel| format!("<#{}>", channel.channel_id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
@@ -0,0 +1,384 @@
+use poise::serenity_prelude as serenity;
+
+use crate::{
+    Context, Error,
+    commands::{
+        editor,
+        notification::{autocomplete_filter, find_filter},
+    },
+    database::{MAX_PERSONAL_FILTERS, MAX_WATCHES, NewNotificationFilter, NotificationFilter},
+    filter::{
+        Expr, Field, Filter, Op, Pattern, Rule, TagMode,
+        library::{FilterLibrary, UNSAVED_FILTER_ID},
+    },
+};
+
+#[poise::command(
+    slash_command,
+    rename = "watch",
+    subcommands("watch_add", "watch_subscribe", "watch_list", "watch_remove"),
+    subcommand_required
+)]
+pub async fn watch_command(_ctx: Context<'_>) -> Result<(), Error> {
+    Ok(())
+}
+
+/// Get new items matching your own filter by DM
+#[poise::command(slash_command, rename = "add", ephemeral)]
+pub async fn watch_add(
+    ctx: Context<'_>,
+    #[description = "Avatar the item must be tagged with"] avatar: Option<String>,
+    #[description = "Word the item name or description must contain"] keyword: Option<String>,
+    #[description = "Filter definition in YAML or JSON format, instead of avatar/keyword"]
+    yaml: Option<String>,
+    #[description = "Name for the watch"] name: Option<String>,
+    #[description = "Also send adult items (default: no)"] nsfw: Option<bool>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let user_id = ctx.author().id.get() as i64;
+
+    let (filter, description) = match (yaml, avatar, keyword) {
+        (Some(_), Some(_), _) | (Some(_), _, Some(_)) => {
+            ctx.say("❌ Give either a YAML filter or an avatar/keyword, not both")
+                .await?;
+            return Ok(());
+        }
+        (Some(yaml), None, None) => {
+            let filter: Filter = match serde_yaml::from_str(&yaml) {
+                Ok(f) => f,
+                Err(e) => {
+                    if let Ok(f) = serde_json::from_str(&yaml) {
+                        f
+                    } else {
+                        ctx.say(format!("❌ Failed to parse filter definition: {}", e))
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            };
+            (filter, None)
+        }
+        (None, None, None) => {
+            ctx.say("❌ Give an avatar, a keyword or a YAML filter to watch")
+                .await?;
+            return Ok(());
+        }
+        (None, avatar, keyword) => {
+            let description = [
+                avatar.as_ref().map(|avatar| format!("Avatar: {}", avatar)),
+                keyword
+                    .as_ref()
+                    .map(|keyword| format!("Keyword: {}", keyword)),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>()
+            .join(", ");
+            (
+                quick_filter(avatar.as_deref(), keyword.as_deref()),
+                Some(description),
+            )
+        }
+    };
+
+    if let Err(e) = filter.validate() {
+        ctx.say(format!("❌ Filter is invalid:\n```\n{}\n```", e))
+            .await?;
+        return Ok(());
+    }
+
+    if db.get_user_watches(user_id).await?.len() as i64 >= MAX_WATCHES {
+        ctx.say(format!(
+            "❌ You can watch at most {} filters, remove one with `/watch remove` first",
+            MAX_WATCHES
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    let personal = db.get_notification_filters_by_owner(user_id).await?;
+    if personal.len() as i64 >= MAX_PERSONAL_FILTERS {
+        ctx.say(format!(
+            "❌ You can have at most {} personal filters, remove a watch with `/watch remove` first",
+            MAX_PERSONAL_FILTERS
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    // Personal filters can include each other, but not guild filters
+    let mut library = FilterLibrary::from_saved(&personal);
+    if let Err(e) = library.check_rename(UNSAVED_FILTER_ID, name.as_deref()) {
+        ctx.say(format!("❌ {}", e)).await?;
+        return Ok(());
+    }
+    library.insert(UNSAVED_FILTER_ID, name.as_deref(), filter.clone());
+    if let Err(e) = library.resolve(&filter, Some(UNSAVED_FILTER_ID)) {
+        ctx.say(format!("❌ Filter is invalid:\n```\n{}\n```", e))
+            .await?;
+        return Ok(());
+    }
+
+    let saved_filter = db
+        .create_notification_filter(NewNotificationFilter {
+            guild_id: None,
+            owner_id: Some(user_id),
+            name,
+            description,
+            rule_yaml: serde_yaml::to_string(&filter)?,
+            avatar: None,
+            editor: Some(editor(ctx)),
+        })
+        .await?;
+    db.upsert_user_watch(user_id, saved_filter.id, nsfw.unwrap_or(false))
+        .await?;
+
+    ctx.say(format!(
+        "✅ Watching `{}`. New matching items will be sent to you by DM, make sure DMs from this bot are allowed.",
+        saved_filter.label()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// Get new items matching one of this server's filters by DM
+#[poise::command(slash_command, rename = "subscribe", guild_only, ephemeral)]
+pub async fn watch_subscribe(
+    ctx: Context<'_>,
+    #[description = "Filter name or ID"]
+    #[autocomplete = "autocomplete_filter"]
+    filter: String,
+    #[description = "Also send adult items (default: no)"] nsfw: Option<bool>,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let user_id = ctx.author().id.get() as i64;
+    let guild_id = ctx.guild_id().unwrap().get() as i64;
+
+    let Some(saved_filter) = find_filter(ctx, &filter)
+        .await?
+        .filter(|f| f.editable_in(guild_id))
+    else {
+        ctx.say(format!("❌ Filter `{}` not found", filter)).await?;
+        return Ok(());
+    };
+
+    let watches = db.get_user_watches(user_id).await?;
+    if watches.len() as i64 >= MAX_WATCHES
+        && !watches.iter().any(|w| w.filter_id == saved_filter.id)
+    {
+        ctx.say(format!(
+            "❌ You can watch at most {} filters, remove one with `/watch remove` first",
+            MAX_WATCHES
+        ))
+        .await?;
+        return Ok(());
+    }
+
+    db.upsert_user_watch(user_id, saved_filter.id, nsfw.unwrap_or(false))
+        .await?;
+
+    ctx.say(format!(
+        "✅ Subscribed to `{}`. New matching items will be sent to you by DM, make sure DMs from this bot are allowed.",
+        saved_filter.label()
+    ))
+    .await?;
+
+    Ok(())
+}
+
+/// List the filters you watch
+#[poise::command(slash_command, rename = "list", ephemeral)]
+pub async fn watch_list(ctx: Context<'_>) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let user_id = ctx.author().id.get() as i64;
+
+    let watches = db.get_user_watches(user_id).await?;
+    if watches.is_empty() {
+        ctx.say("You are not watching anything. Start with `/watch add`.")
+            .await?;
+        return Ok(());
+    }
+
+    let filter_ids = watches.iter().map(|w| w.filter_id).collect::<Vec<_>>();
+    let filters = db.get_notification_filters_by_ids(&filter_ids).await?;
+
+    let mut message = format!("**👀 Your watches ({} total)**\n\n", watches.len());
+    for watch in &watches {
+        let Some(filter) = filters.get(&watch.filter_id) else {
+            continue;
+        };
+        let source = match filter.guild_id {
+            Some(guild_id) => match db.get_discord_guild(guild_id).await? {
+                Some(guild) => format!("from {}", guild.name),
+                None => "from a server".to_string(),
+            },
+            None => "personal".to_string(),
+        };
+        message.push_str(&format!(
+            "**{}** ({}{})\n{}",
+            filter.label(),
+            source,
+            if watch.include_nsfw {
+                ", including NSFW"
+            } else {
+                ""
+            },
+            filter
+                .description
+                .as_ref()
+                .map_or_else(String::new, |description| format!("{}\n", description))
+        ));
+    }
+
+    ctx.say(message).await?;
+
+    Ok(())
+}
+
+/// Stop watching a filter
+#[poise::command(slash_command, rename = "remove", ephemeral)]
+pub async fn watch_remove(
+    ctx: Context<'_>,
+    #[description = "Watch name or ID"]
+    #[autocomplete = "autocomplete_watch"]
+    watch: String,
+) -> Result<(), Error> {
+    let db = ctx.data().db.clone();
+    let user_id = ctx.author().id.get() as i64;
+
+    let Some(filter) = find_watched_filter(ctx, &watch).await? else {
+        ctx.say(format!("❌ You are not watching `{}`", watch))
+            .await?;
+        return Ok(());
+    };
+
+    // The personal filter behind the watch goes too, so that it no longer
+    // counts against the limit. One that another of the user's filters
+    // includes is kept, with its watch, as it could not be reached otherwise.
+    if filter.owner_id == Some(user_id) {
+        let personal = db.get_notification_filters_by_owner(user_id).await?;
+        let dependents = FilterLibrary::from_saved(&personal).dependents(filter.id);
+        if !dependents.is_empty() {
+            let filter_list = personal
+                .iter()
+                .filter(|personal| dependents.contains(&personal.id))
+                .map(|personal| format!("`{}`", personal.label()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            ctx.say(format!(
+                "⚠️ Cannot remove `{}` because the following filters include it:\n{}\n\nRemove those watches first",
+                filter.label(),
+                filter_list
+            ))
+            .await?;
+            return Ok(());
+        }
+    }
+
+    db.delete_user_watch(user_id, filter.id).await?;
+    if filter.owner_id == Some(user_id) {
+        db.delete_notification_filter(filter.id).await?;
+    }
+
+    ctx.say(format!("✅ Stopped watching `{}`", filter.label()))
+        .await?;
+
+    Ok(())
+}
+
+// ==================== Helpers ====================
+
+/// Builds the filter behind `/watch add avatar:… keyword:…`: items tagged
+/// with the avatar whose name or description contains the keyword.
+fn quick_filter(avatar: Option<&str>, keyword: Option<&str>) -> Filter {
+    let rule = |field, value: &str, tag_mode| {
+        Expr::Rule(Rule {
+            field,
+            op: Op::Include,
+            pattern: Pattern::Text {
+                value: value.to_string(),
+            },
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode,
+            price_mode: None,
+        })
+    };
+
+    let mut children = vec![];
+    if let Some(avatar) = avatar {
+        children.push(rule(Field::Tags, avatar, Some(TagMode::Any)));
+    }
+    if let Some(keyword) = keyword {
+        children.push(Expr::Any(vec![
+            rule(Field::Name, keyword, None),
+            rule(Field::Description, keyword, None),
+        ]));
+    }
+
+    Filter {
+        groups: vec![],
+        expr: Some(Expr::All(children)),
+        events: vec![],
+        schema_version: 2,
+    }
+}
+
+async fn autocomplete_watch(ctx: Context<'_>, partial: &str) -> Vec<serenity::AutocompleteChoice> {
+    let Ok(filters) = watched_filters(ctx).await else {
+        return vec![];
+    };
+
+    let partial = partial.trim().trim_start_matches('#').to_lowercase();
+    filters
+        .iter()
+        .filter(|f| {
+            f.id.to_string().starts_with(&partial)
+                || f.label().to_lowercase().contains(&partial)
+                || f.description
+                    .as_ref()
+                    .is_some_and(|description| description.to_lowercase().contains(&partial))
+        })
+        .take(25)
+        .map(|f| {
+            let label = match &f.description {
+                Some(description) => format!("{} — {}", f.label(), description),
+                None => f.label(),
+            };
+            let label = if label.chars().count() > 100 {
+                format!("{}...", label.chars().take(97).collect::<String>())
+            } else {
+                label
+            };
+            serenity::AutocompleteChoice::new(label, f.id.to_string())
+        })
+        .collect()
+}
+
+/// The filters the invoking user watches.
+async fn watched_filters(ctx: Context<'_>) -> Result<Vec<NotificationFilter>, Error> {
+    let db = &ctx.data().db;
+    let watches = db.get_user_watches(ctx.author().id.get() as i64).await?;
+    let filter_ids = watches.iter().map(|w| w.filter_id).collect::<Vec<_>>();
+    let mut filters = db.get_notification_filters_by_ids(&filter_ids).await?;
+
+    Ok(filter_ids
+        .iter()
+        .filter_map(|id| filters.remove(id))
+        .collect())
+}
+
+/// Looks up a watched filter by ID (`17` or `#17`) or by name.
+async fn find_watched_filter(
+    ctx: Context<'_>,
+    watch: &str,
+) -> Result<Option<NotificationFilter>, Error> {
+    let watch = watch.trim();
+    let id = watch.trim_start_matches('#').parse::<i64>().ok();
+
+    Ok(watched_filters(ctx)
+        .await?
+        .into_iter()
+        .find(|f| Some(f.id) == id || f.name.as_deref() == Some(watch)))
+}
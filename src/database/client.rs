@@ -1,12 +1,25 @@
-use anyhow::Result;
+use anyhow::{Result, anyhow, bail};
 use sqlx::postgres::{PgPool, PgPoolOptions};
+use sqlx::types::Json;
 use sqlx::types::time::OffsetDateTime;
 use std::collections::HashMap;
 
 use super::models::{
-    DiscordChannel, DiscordGuild, FetchRun, ItemSnapshot, NewDiscordChannel, NewDiscordGuild,
-    NewFetchRun, NewItemSnapshot, NewNotificationFilter, NotificationFilter,
+    ChannelFilter, ContentPolicy, DeliveryMode, DigestItem, DiscordChannel, DiscordGuild, Editor,
+    FetchRun, ItemChange, ItemSnapshot, NewDiscordChannel, NewDiscordGuild, NewFetchRun,
+    NewItemChange, NewItemSnapshot, NewNotificationFilter, NotificationDelivery,
+    NotificationFilter, NotificationFilterRevision, UserWatch,
 };
+use crate::template::MessageTemplate;
+
+/// Most personal filters one user can have.
+pub const MAX_PERSONAL_FILTERS: i64 = 25;
+
+/// Most filters one user can watch at a time.
+pub const MAX_WATCHES: i64 = 10;
+
+const NOTIFICATION_DELIVERY_COLUMNS: &str = "item_id, channel_id, user_id, status, message_id, \
+    error, attempts, next_attempt_at, created_at, updated_at, crosspost_error, change_id, role_ids";
 
 /// Database client wrapper around sqlx::PgPool
 #[derive(Clone)]
@@ -31,6 +44,12 @@ impl DatabaseClient {
         Ok(())
     }
 
+    /// Wraps the pool of a test database, as given by `#[sqlx::test]`
+    #[cfg(test)]
+    pub fn from_pool(pool: PgPool) -> Self {
+        Self { pool }
+    }
+
     /// Get a reference to the underlying connection pool
     pub fn pool(&self) -> &PgPool {
         &self.pool
@@ -88,10 +107,90 @@ impl DatabaseClient {
         Ok(fetch_runs)
     }
 
-    /// Create a new item snapshot
+    /// Mark a fetch run as notified, so it is not replayed after a restart
+    pub async fn mark_fetch_run_notified(&self, id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE fetch_runs
+            SET notified_at = now()
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the latest fetch run whose items were notified
+    pub async fn get_latest_notified_fetch_run(&self) -> Result<Option<FetchRun>> {
+        let fetch_run = sqlx::query_as::<_, FetchRun>(
+            r#"
+            SELECT id, fetched_at, item_ids
+            FROM fetch_runs
+            WHERE notified_at IS NOT NULL
+            ORDER BY fetched_at DESC
+            LIMIT 1
+            "#,
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(fetch_run)
+    }
+
+    /// Create a new item snapshot, recording the item as known from then on
+    /// if it was not yet
     pub async fn create_item_snapshot(
         &self,
         new_snapshot: NewItemSnapshot,
+    ) -> Result<ItemSnapshot> {
+        let mut tx = self.pool.begin().await?;
+        let snapshot = Self::insert_item_snapshot(&mut tx, new_snapshot).await?;
+        tx.commit().await?;
+
+        Ok(snapshot)
+    }
+
+    /// Create a new item snapshot together with what changed about the item
+    /// since the previous one. Saved at once, as a snapshot saved without its
+    /// change would hide the change from later comparisons.
+    pub async fn create_item_snapshot_with_change(
+        &self,
+        new_snapshot: NewItemSnapshot,
+        new_change: NewItemChange,
+    ) -> Result<ItemChange> {
+        let mut tx = self.pool.begin().await?;
+
+        let snapshot = Self::insert_item_snapshot(&mut tx, new_snapshot).await?;
+        let events = new_change
+            .events
+            .iter()
+            .map(|event| event.as_str())
+            .collect::<Vec<_>>();
+        let change = sqlx::query_as::<_, ItemChange>(
+            r#"
+            INSERT INTO item_changes (item_id, snapshot_id, events, summary)
+            VALUES ($1, $2, $3, $4)
+            RETURNING id, item_id, snapshot_id, events, summary, created_at
+            "#,
+        )
+        .bind(snapshot.item_id)
+        .bind(snapshot.id)
+        .bind(events)
+        .bind(new_change.summary)
+        .fetch_one(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(change)
+    }
+
+    async fn insert_item_snapshot(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        new_snapshot: NewItemSnapshot,
     ) -> Result<ItemSnapshot> {
         let snapshot = sqlx::query_as!(
             ItemSnapshot,
@@ -104,7 +203,19 @@ impl DatabaseClient {
             new_snapshot.name,
             new_snapshot.payload
         )
-        .fetch_one(&self.pool)
+        .fetch_one(&mut **tx)
+        .await?;
+
+        sqlx::query!(
+            r#"
+            INSERT INTO known_items (item_id, first_seen_at)
+            VALUES ($1, $2)
+            ON CONFLICT (item_id) DO NOTHING
+            "#,
+            snapshot.item_id,
+            snapshot.fetched_at
+        )
+        .execute(&mut **tx)
         .await?;
 
         Ok(snapshot)
@@ -170,6 +281,94 @@ impl DatabaseClient {
         Ok(snapshots)
     }
 
+    /// Get the latest snapshot of each item fetched since `since`, for up to
+    /// `limit` items, the most recently fetched first
+    pub async fn get_latest_snapshots(
+        &self,
+        since: OffsetDateTime,
+        limit: i64,
+    ) -> Result<Vec<ItemSnapshot>> {
+        let snapshots = sqlx::query_as!(
+            ItemSnapshot,
+            r#"
+            SELECT id AS "id!", fetched_at AS "fetched_at!", item_id AS "item_id!",
+                   name AS "name!", payload AS "payload!"
+            FROM (
+                SELECT DISTINCT ON (item_id) id, fetched_at, item_id, name, payload
+                FROM item_snapshots
+                WHERE fetched_at >= $1
+                ORDER BY item_id, fetched_at DESC
+            ) latest
+            ORDER BY fetched_at DESC
+            LIMIT $2
+            "#,
+            since,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(snapshots)
+    }
+
+    /// Get items first seen since `since` to fetch again, the least recently
+    /// checked first
+    pub async fn get_items_to_recheck(
+        &self,
+        since: OffsetDateTime,
+        limit: i64,
+    ) -> Result<Vec<i64>> {
+        let item_ids = sqlx::query_scalar::<_, i64>(
+            r#"
+            SELECT k.item_id
+            FROM known_items k
+            LEFT JOIN item_checks c ON c.item_id = k.item_id
+            WHERE k.first_seen_at >= $1
+            ORDER BY c.checked_at NULLS FIRST, k.first_seen_at DESC
+            LIMIT $2
+            "#,
+        )
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(item_ids)
+    }
+
+    /// Record that items were fetched again
+    pub async fn mark_items_checked(&self, item_ids: &[i64]) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO item_checks (item_id)
+            SELECT UNNEST($1::bigint[])
+            ON CONFLICT (item_id) DO UPDATE
+            SET checked_at = now()
+            "#,
+        )
+        .bind(item_ids)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get an item change by ID
+    pub async fn get_item_change(&self, id: i64) -> Result<Option<ItemChange>> {
+        let change = sqlx::query_as::<_, ItemChange>(
+            r#"
+            SELECT id, item_id, snapshot_id, events, summary, created_at
+            FROM item_changes
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(change)
+    }
+
     /// Insert or update a Discord guild
     pub async fn upsert_discord_guild(&self, new_guild: NewDiscordGuild) -> Result<DiscordGuild> {
         let guild = sqlx::query_as!(
@@ -183,8 +382,12 @@ impl DatabaseClient {
                 fallback_channel_id = EXCLUDED.fallback_channel_id,
                 fallback_nsfw_channel_id = EXCLUDED.fallback_nsfw_channel_id,
                 general_category_id = EXCLUDED.general_category_id,
-                nsfw_category_id = EXCLUDED.nsfw_category_id
-            RETURNING guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id, general_category_id, nsfw_category_id
+                nsfw_category_id = EXCLUDED.nsfw_category_id,
+                active = true
+            RETURNING guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id,
+                   general_category_id, nsfw_category_id, active,
+                   message_template AS "message_template: Json<MessageTemplate>",
+                   admin_channel_id
             "#,
             new_guild.guild_id,
             new_guild.name,
@@ -204,7 +407,10 @@ impl DatabaseClient {
         let guild = sqlx::query_as!(
             DiscordGuild,
             r#"
-            SELECT guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id, general_category_id, nsfw_category_id
+            SELECT guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id,
+                   general_category_id, nsfw_category_id, active,
+                   message_template AS "message_template: Json<MessageTemplate>",
+                   admin_channel_id
             FROM discord_guilds
             WHERE guild_id = $1
             "#,
@@ -216,12 +422,15 @@ impl DatabaseClient {
         Ok(guild)
     }
 
-    /// Get all Discord guilds
+    /// Get all Discord guilds, including ones the bot is no longer in
     pub async fn get_all_discord_guilds(&self) -> Result<Vec<DiscordGuild>> {
         let guilds = sqlx::query_as!(
             DiscordGuild,
             r#"
-            SELECT guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id, general_category_id, nsfw_category_id
+            SELECT guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id,
+                   general_category_id, nsfw_category_id, active,
+                   message_template AS "message_template: Json<MessageTemplate>",
+                   admin_channel_id
             FROM discord_guilds
             ORDER BY name
             "#
@@ -232,31 +441,192 @@ impl DatabaseClient {
         Ok(guilds)
     }
 
-    /// Insert or update a Discord channel
+    /// Mark whether the bot is in a Discord guild, updating its name when it
+    /// is. Returns false for guilds that are not registered.
+    pub async fn set_guild_active(
+        &self,
+        guild_id: i64,
+        name: Option<&str>,
+        active: bool,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_guilds
+            SET active = $2,
+                name = COALESCE($3, name)
+            WHERE guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .bind(active)
+        .bind(name)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Reconcile a guild's channels with the ones that exist in Discord:
+    /// missing channels are marked inactive and dropped from the guild's
+    /// fallback and category settings. Returns the channels that were
+    /// deactivated.
+    pub async fn reconcile_guild_channels(
+        &self,
+        guild_id: i64,
+        existing_channel_ids: &[i64],
+    ) -> Result<Vec<i64>> {
+        let mut tx = self.pool.begin().await?;
+
+        let deactivated = sqlx::query_scalar::<_, i64>(
+            r#"
+            UPDATE discord_channels
+            SET active = false
+            WHERE guild_id = $1
+              AND active
+              AND NOT (channel_id = ANY($2))
+            RETURNING channel_id
+            "#,
+        )
+        .bind(guild_id)
+        .bind(existing_channel_ids)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET active = true
+            WHERE guild_id = $1
+              AND NOT active
+              AND channel_id = ANY($2)
+            "#,
+        )
+        .bind(guild_id)
+        .bind(existing_channel_ids)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE discord_guilds
+            SET
+                fallback_channel_id = CASE WHEN fallback_channel_id = ANY($2) THEN fallback_channel_id END,
+                fallback_nsfw_channel_id = CASE WHEN fallback_nsfw_channel_id = ANY($2) THEN fallback_nsfw_channel_id END,
+                general_category_id = CASE WHEN general_category_id = ANY($2) THEN general_category_id END,
+                nsfw_category_id = CASE WHEN nsfw_category_id = ANY($2) THEN nsfw_category_id END,
+                admin_channel_id = CASE WHEN admin_channel_id = ANY($2) THEN admin_channel_id END
+            WHERE guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .bind(existing_channel_ids)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(deactivated)
+    }
+
+    /// Handle a channel deleted in Discord: mark it inactive and drop it from
+    /// its guild's fallback and category settings
+    pub async fn deactivate_channel(&self, guild_id: i64, channel_id: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET active = false
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            UPDATE discord_guilds
+            SET
+                fallback_channel_id = NULLIF(fallback_channel_id, $2),
+                fallback_nsfw_channel_id = NULLIF(fallback_nsfw_channel_id, $2),
+                general_category_id = NULLIF(general_category_id, $2),
+                nsfw_category_id = NULLIF(nsfw_category_id, $2),
+                admin_channel_id = NULLIF(admin_channel_id, $2)
+            WHERE guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .bind(channel_id)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Insert or update a Discord channel, adding its filters to the ones it
+    /// already has. A registered channel keeps its crosspost setting.
     pub async fn upsert_discord_channel(
         &self,
         new_channel: NewDiscordChannel,
     ) -> Result<DiscordChannel> {
-        let channel = sqlx::query_as!(
-            DiscordChannel,
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
             r#"
-            INSERT INTO discord_channels (channel_id, guild_id, name, filter_id)
+            INSERT INTO discord_channels (channel_id, guild_id, name, crosspost)
             VALUES ($1, $2, $3, $4)
             ON CONFLICT (channel_id)
             DO UPDATE SET
                 guild_id = EXCLUDED.guild_id,
                 name = EXCLUDED.name,
-                filter_id = EXCLUDED.filter_id
-            RETURNING channel_id, guild_id, name, created_at, filter_id
+                active = true
             "#,
-            new_channel.channel_id,
-            new_channel.guild_id,
-            new_channel.name,
-            new_channel.filter_id
         )
-        .fetch_one(&self.pool)
+        .bind(new_channel.channel_id)
+        .bind(new_channel.guild_id)
+        .bind(&new_channel.name)
+        .bind(new_channel.crosspost)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO channel_filters (channel_id, filter_id)
+            SELECT $1, filter_id
+            FROM UNNEST($2::bigint[]) AS filter_id
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(new_channel.channel_id)
+        .bind(&new_channel.filter_ids)
+        .execute(&mut *tx)
         .await?;
 
+        let channel = sqlx::query_as!(
+            DiscordChannel,
+            r#"
+            SELECT c.channel_id, c.guild_id, c.name, c.created_at,
+                   ARRAY(
+                       SELECT cf.filter_id FROM channel_filters cf
+                       WHERE cf.channel_id = c.channel_id
+                       ORDER BY cf.created_at, cf.filter_id
+                   ) AS "filter_ids!",
+                   c.content_policy, c.failure_count, c.last_error, c.disabled_at, c.active,
+                   c.delivery_mode, c.last_digest_at,
+                   c.message_template AS "message_template: Json<MessageTemplate>",
+                   c.crosspost
+            FROM discord_channels c
+            WHERE c.channel_id = $1
+            "#,
+            new_channel.channel_id
+        )
+        .fetch_one(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
         Ok(channel)
     }
 
@@ -265,9 +635,18 @@ impl DatabaseClient {
         let channel = sqlx::query_as!(
             DiscordChannel,
             r#"
-            SELECT channel_id, guild_id, name, created_at, filter_id
-            FROM discord_channels
-            WHERE channel_id = $1
+            SELECT c.channel_id, c.guild_id, c.name, c.created_at,
+                   ARRAY(
+                       SELECT cf.filter_id FROM channel_filters cf
+                       WHERE cf.channel_id = c.channel_id
+                       ORDER BY cf.created_at, cf.filter_id
+                   ) AS "filter_ids!",
+                   c.content_policy, c.failure_count, c.last_error, c.disabled_at, c.active,
+                   c.delivery_mode, c.last_digest_at,
+                   c.message_template AS "message_template: Json<MessageTemplate>",
+                   c.crosspost
+            FROM discord_channels c
+            WHERE c.channel_id = $1
             "#,
             channel_id
         )
@@ -282,10 +661,19 @@ impl DatabaseClient {
         let channels = sqlx::query_as!(
             DiscordChannel,
             r#"
-            SELECT channel_id, guild_id, name, created_at, filter_id
-            FROM discord_channels
-            WHERE guild_id = $1
-            ORDER BY name
+            SELECT c.channel_id, c.guild_id, c.name, c.created_at,
+                   ARRAY(
+                       SELECT cf.filter_id FROM channel_filters cf
+                       WHERE cf.channel_id = c.channel_id
+                       ORDER BY cf.created_at, cf.filter_id
+                   ) AS "filter_ids!",
+                   c.content_policy, c.failure_count, c.last_error, c.disabled_at, c.active,
+                   c.delivery_mode, c.last_digest_at,
+                   c.message_template AS "message_template: Json<MessageTemplate>",
+                   c.crosspost
+            FROM discord_channels c
+            WHERE c.guild_id = $1
+            ORDER BY c.name
             "#,
             guild_id
         )
@@ -295,23 +683,46 @@ impl DatabaseClient {
         Ok(channels)
     }
 
-    /// Create a new notification filter
+    /// Create a new notification filter, recording it as its first revision
     pub async fn create_notification_filter(
         &self,
         new_filter: NewNotificationFilter,
     ) -> Result<NotificationFilter> {
+        let mut tx = self.pool.begin().await?;
+
+        if let Some(owner_id) = new_filter.owner_id {
+            Self::lock_user(&mut tx, owner_id).await?;
+            let count = sqlx::query_scalar::<_, i64>(
+                "SELECT count(*) FROM notification_filters WHERE owner_id = $1",
+            )
+            .bind(owner_id)
+            .fetch_one(&mut *tx)
+            .await?;
+            if count >= MAX_PERSONAL_FILTERS {
+                bail!("users can have at most {MAX_PERSONAL_FILTERS} personal filters");
+            }
+        }
+
         let filter = sqlx::query_as::<_, NotificationFilter>(
             r#"
-            INSERT INTO notification_filters (guild_id, rule_yaml)
-            VALUES ($1, $2)
-            RETURNING id, guild_id, rule_yaml, created_at
+            INSERT INTO notification_filters
+                (guild_id, owner_id, name, description, rule_yaml, avatar)
+            VALUES ($1, $2, $3, $4, $5, $6)
+            RETURNING id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
             "#,
         )
         .bind(new_filter.guild_id)
+        .bind(new_filter.owner_id)
+        .bind(new_filter.name)
+        .bind(new_filter.description)
         .bind(new_filter.rule_yaml)
-        .fetch_one(&self.pool)
+        .bind(new_filter.avatar)
+        .fetch_one(&mut *tx)
         .await?;
 
+        Self::insert_filter_revision(&mut tx, &filter, new_filter.editor.as_ref()).await?;
+        tx.commit().await?;
+
         Ok(filter)
     }
 
@@ -319,7 +730,7 @@ impl DatabaseClient {
     pub async fn get_notification_filter(&self, id: i64) -> Result<Option<NotificationFilter>> {
         let filter = sqlx::query_as::<_, NotificationFilter>(
             r#"
-            SELECT id, guild_id, rule_yaml, created_at
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
             FROM notification_filters
             WHERE id = $1
             "#,
@@ -331,129 +742,1043 @@ impl DatabaseClient {
         Ok(filter)
     }
 
-    /// Update the filter for a Discord channel
-    pub async fn update_channel_filter(
-        &self,
-        channel_id: i64,
-        filter_id: Option<i64>,
-    ) -> Result<DiscordChannel> {
-        let channel = sqlx::query_as!(
-            DiscordChannel,
+    /// Subscribe a Discord channel to a filter. Returns false if it already
+    /// was.
+    pub async fn add_channel_filter(&self, channel_id: i64, filter_id: i64) -> Result<bool> {
+        let result = sqlx::query(
             r#"
-            UPDATE discord_channels
-            SET filter_id = $2
-            WHERE channel_id = $1
-            RETURNING channel_id, guild_id, name, created_at, filter_id
+            INSERT INTO channel_filters (channel_id, filter_id)
+            VALUES ($1, $2)
+            ON CONFLICT DO NOTHING
             "#,
-            channel_id,
-            filter_id
         )
-        .fetch_one(&self.pool)
+        .bind(channel_id)
+        .bind(filter_id)
+        .execute(&self.pool)
         .await?;
 
-        Ok(channel)
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Delete a notification filter
-    pub async fn delete_notification_filter(&self, id: i64) -> Result<bool> {
-        let result = sqlx::query!(
+    /// Get the filter subscriptions of a guild's channels
+    pub async fn get_channel_filters_by_guild(&self, guild_id: i64) -> Result<Vec<ChannelFilter>> {
+        let channel_filters = sqlx::query_as::<_, ChannelFilter>(
             r#"
-            DELETE FROM notification_filters
-            WHERE id = $1
+            SELECT cf.channel_id, cf.filter_id, cf.role_ids, cf.created_at
+            FROM channel_filters cf
+            JOIN discord_channels c ON c.channel_id = cf.channel_id
+            WHERE c.guild_id = $1
+            ORDER BY cf.created_at, cf.filter_id
             "#,
-            id
         )
-        .execute(&self.pool)
+        .bind(guild_id)
+        .fetch_all(&self.pool)
         .await?;
 
-        Ok(result.rows_affected() > 0)
+        Ok(channel_filters)
     }
 
-    /// Get all notification filters
-    pub async fn get_all_notification_filters(&self) -> Result<Vec<NotificationFilter>> {
-        let filters = sqlx::query_as::<_, NotificationFilter>(
+    /// Set the roles mentioned when a channel's filter matches. Returns false
+    /// if the channel is not subscribed to the filter.
+    pub async fn update_channel_filter_roles(
+        &self,
+        channel_id: i64,
+        filter_id: i64,
+        role_ids: &[i64],
+    ) -> Result<bool> {
+        let result = sqlx::query(
             r#"
-            SELECT id, guild_id, rule_yaml, created_at
-            FROM notification_filters
-            ORDER BY created_at DESC
+            UPDATE channel_filters
+            SET role_ids = $3
+            WHERE channel_id = $1 AND filter_id = $2
             "#,
         )
-        .fetch_all(&self.pool)
+        .bind(channel_id)
+        .bind(filter_id)
+        .bind(role_ids)
+        .execute(&self.pool)
         .await?;
 
-        Ok(filters)
+        Ok(result.rows_affected() > 0)
     }
 
-    /// Get multiple notification filters by IDs in a single query
-    /// Returns a HashMap mapping filter ID to NotificationFilter for efficient lookups
-    pub async fn get_notification_filters_by_ids(
+    /// Get the self-service role of a filter in a guild
+    pub async fn get_subscription_role(
         &self,
-        ids: &[i64],
-    ) -> Result<HashMap<i64, NotificationFilter>> {
-        if ids.is_empty() {
-            return Ok(HashMap::new());
-        }
-
-        let filters = sqlx::query_as::<_, NotificationFilter>(
+        guild_id: i64,
+        filter_id: i64,
+    ) -> Result<Option<i64>> {
+        let role_id = sqlx::query_scalar::<_, i64>(
             r#"
-            SELECT id, guild_id, rule_yaml, created_at
-            FROM notification_filters
-            WHERE id = ANY($1)
+            SELECT role_id
+            FROM subscription_roles
+            WHERE guild_id = $1 AND filter_id = $2
             "#,
         )
-        .bind(ids)
-        .fetch_all(&self.pool)
+        .bind(guild_id)
+        .bind(filter_id)
+        .fetch_optional(&self.pool)
         .await?;
 
-        Ok(filters.into_iter().map(|f| (f.id, f)).collect())
+        Ok(role_id)
     }
 
-    /// Get notification filters for a specific guild.
-    pub async fn get_notification_filters_by_guild(
+    /// Get the self-service roles of a guild by filter ID
+    pub async fn get_subscription_roles_by_guild(
         &self,
         guild_id: i64,
-    ) -> Result<Vec<NotificationFilter>> {
-        let filters = sqlx::query_as::<_, NotificationFilter>(
+    ) -> Result<HashMap<i64, i64>> {
+        let roles = sqlx::query_as::<_, (i64, i64)>(
             r#"
-            SELECT id, guild_id, rule_yaml, created_at
-            FROM notification_filters
+            SELECT filter_id, role_id
+            FROM subscription_roles
             WHERE guild_id = $1
-            ORDER BY created_at DESC
             "#,
         )
         .bind(guild_id)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(filters)
+        Ok(roles.into_iter().collect())
     }
 
-    /// Update a notification filter definition.
-    pub async fn update_notification_filter(
+    /// Set the self-service role of a filter in a guild, replacing
+    /// `replacing` if it is the current one, e.g. a role deleted in Discord.
+    /// Returns the role that is set: another one if someone else set it
+    /// first, in which case `role_id` should be discarded.
+    pub async fn set_subscription_role(
+        &self,
+        guild_id: i64,
+        filter_id: i64,
+        role_id: i64,
+        replacing: Option<i64>,
+    ) -> Result<i64> {
+        let set = sqlx::query_scalar::<_, i64>(
+            r#"
+            INSERT INTO subscription_roles (guild_id, filter_id, role_id)
+            VALUES ($1, $2, $3)
+            ON CONFLICT (guild_id, filter_id)
+            DO UPDATE SET role_id = EXCLUDED.role_id
+            WHERE subscription_roles.role_id = $4
+            RETURNING role_id
+            "#,
+        )
+        .bind(guild_id)
+        .bind(filter_id)
+        .bind(role_id)
+        .bind(replacing)
+        .fetch_optional(&self.pool)
+        .await?;
+        if let Some(role_id) = set {
+            return Ok(role_id);
+        }
+
+        let current = self
+            .get_subscription_role(guild_id, filter_id)
+            .await?
+            .ok_or_else(|| anyhow!("subscription role of filter {filter_id} disappeared"))?;
+        Ok(current)
+    }
+
+    /// Unsubscribe a Discord channel from a filter
+    pub async fn remove_channel_filter(&self, channel_id: i64, filter_id: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM channel_filters
+            WHERE channel_id = $1 AND filter_id = $2
+            "#,
+        )
+        .bind(channel_id)
+        .bind(filter_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set which items a Discord channel receives; `None` restores the
+    /// default that follows the channel's NSFW flag
+    pub async fn update_channel_content_policy(
+        &self,
+        channel_id: i64,
+        content_policy: Option<ContentPolicy>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET content_policy = $2
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .bind(content_policy.map(ContentPolicy::as_str))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set whether messages to a Discord channel are crossposted; `None`
+    /// follows the channel kind
+    pub async fn update_channel_crosspost(
+        &self,
+        channel_id: i64,
+        crosspost: Option<bool>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET crosspost = $2
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .bind(crosspost)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set whether a Discord channel gets items right away or in digests
+    pub async fn update_channel_delivery_mode(
+        &self,
+        channel_id: i64,
+        delivery_mode: DeliveryMode,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET delivery_mode = $2
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .bind(delivery_mode.as_str())
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set the look of a Discord channel's notifications; `None` uses the
+    /// guild's template
+    pub async fn update_channel_message_template(
+        &self,
+        channel_id: i64,
+        message_template: Option<&MessageTemplate>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET message_template = $2
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .bind(message_template.map(Json))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Set the default look of a Discord guild's notifications; `None` uses
+    /// the built-in default
+    pub async fn update_guild_message_template(
+        &self,
+        guild_id: i64,
+        message_template: Option<&MessageTemplate>,
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_guilds
+            SET message_template = $2
+            WHERE guild_id = $1
+            "#,
+        )
+        .bind(guild_id)
+        .bind(message_template.map(Json))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Queue an item for the next digest of a channel, unless it was
+    /// delivered there already. Returns whether it was queued.
+    pub async fn queue_digest_item(
+        &self,
+        channel_id: i64,
+        item_id: i64,
+        role_ids: &[i64],
+    ) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            INSERT INTO digest_items (channel_id, item_id, role_ids)
+            SELECT $1, $2, $3
+            WHERE NOT EXISTS (
+                SELECT 1 FROM notification_deliveries
+                WHERE channel_id = $1 AND item_id = $2 AND change_id = 0
+            )
+            ON CONFLICT DO NOTHING
+            "#,
+        )
+        .bind(channel_id)
+        .bind(item_id)
+        .bind(role_ids)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Get the items waiting for a digest, oldest first
+    pub async fn get_digest_items(&self) -> Result<Vec<DigestItem>> {
+        let items = sqlx::query_as::<_, DigestItem>(
+            r#"
+            SELECT channel_id, item_id, created_at, role_ids
+            FROM digest_items
+            ORDER BY created_at, item_id
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(items)
+    }
+
+    /// Record that digest items were posted as `message_id`, moving them from
+    /// the digest queue to the delivery log
+    pub async fn mark_digest_items_sent(
+        &self,
+        channel_id: i64,
+        item_ids: &[i64],
+        message_id: i64,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            r#"
+            INSERT INTO notification_deliveries (item_id, channel_id, status, message_id, attempts)
+            SELECT item_id, $1, 'sent', $3, 1
+            FROM UNNEST($2::bigint[]) AS item_id
+            ON CONFLICT (item_id, channel_id, change_id) DO UPDATE
+            SET status = 'sent',
+                message_id = EXCLUDED.message_id,
+                error = NULL,
+                crosspost_error = NULL,
+                next_attempt_at = NULL,
+                updated_at = now()
+            "#,
+        )
+        .bind(channel_id)
+        .bind(item_ids)
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM digest_items
+            WHERE channel_id = $1 AND item_id = ANY($2)
+            "#,
+        )
+        .bind(channel_id)
+        .bind(item_ids)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Drop items from the digest queue of a channel
+    pub async fn remove_digest_items(&self, channel_id: i64, item_ids: &[i64]) -> Result<()> {
+        sqlx::query(
+            r#"
+            DELETE FROM digest_items
+            WHERE channel_id = $1 AND item_id = ANY($2)
+            "#,
+        )
+        .bind(channel_id)
+        .bind(item_ids)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record that a digest was posted to a channel
+    pub async fn mark_digest_posted(&self, channel_id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET last_digest_at = now()
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a failure for a Discord channel, returning its failures in a row
+    pub async fn record_channel_failure(&self, channel_id: i64, error: &str) -> Result<i32> {
+        let failure_count = sqlx::query_scalar::<_, i32>(
+            r#"
+            UPDATE discord_channels
+            SET failure_count = failure_count + 1,
+                last_error = $2
+            WHERE channel_id = $1
+            RETURNING failure_count
+            "#,
+        )
+        .bind(channel_id)
+        .bind(error)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(failure_count.unwrap_or_default())
+    }
+
+    /// Reset the failures of a Discord channel after a successful delivery
+    pub async fn reset_channel_failures(&self, channel_id: i64) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET failure_count = 0
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Stop notifying a Discord channel. Returns false if it was disabled
+    /// already.
+    pub async fn disable_channel(&self, channel_id: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET disabled_at = now()
+            WHERE channel_id = $1 AND disabled_at IS NULL
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Notify a disabled Discord channel again, starting its failure count
+    /// over
+    pub async fn enable_channel(&self, channel_id: i64) -> Result<bool> {
+        let result = sqlx::query(
+            r#"
+            UPDATE discord_channels
+            SET disabled_at = NULL,
+                failure_count = 0,
+                last_error = NULL
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Unsubscribe a Discord channel from all of its filters
+    pub async fn clear_channel_filters(&self, channel_id: i64) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM channel_filters
+            WHERE channel_id = $1
+            "#,
+        )
+        .bind(channel_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Delete a notification filter
+    pub async fn delete_notification_filter(&self, id: i64) -> Result<bool> {
+        let result = sqlx::query!(
+            r#"
+            DELETE FROM notification_filters
+            WHERE id = $1
+            "#,
+            id
+        )
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Get all notification filters, leaving out personal filters
+    pub async fn get_all_notification_filters(&self) -> Result<Vec<NotificationFilter>> {
+        let filters = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            FROM notification_filters
+            WHERE owner_id IS NULL
+            ORDER BY created_at DESC
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(filters)
+    }
+
+    /// Get multiple notification filters by IDs in a single query
+    /// Returns a HashMap mapping filter ID to NotificationFilter for efficient lookups
+    pub async fn get_notification_filters_by_ids(
+        &self,
+        ids: &[i64],
+    ) -> Result<HashMap<i64, NotificationFilter>> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let filters = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            FROM notification_filters
+            WHERE id = ANY($1)
+            "#,
+        )
+        .bind(ids)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(filters.into_iter().map(|f| (f.id, f)).collect())
+    }
+
+    /// Get notification filters for a specific guild.
+    pub async fn get_notification_filters_by_guild(
+        &self,
+        guild_id: i64,
+    ) -> Result<Vec<NotificationFilter>> {
+        let filters = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            FROM notification_filters
+            WHERE guild_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(guild_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(filters)
+    }
+
+    /// Update a notification filter definition, recording a revision when it
+    /// changed.
+    pub async fn update_notification_filter(
         &self,
         id: i64,
         guild_id: i64,
         rule_yaml: String,
+        editor: Option<&Editor>,
     ) -> Result<Option<NotificationFilter>> {
+        let mut tx = self.pool.begin().await?;
+
+        let previous = sqlx::query_scalar::<_, String>(
+            r#"
+            SELECT rule_yaml
+            FROM notification_filters
+            WHERE id = $1
+            FOR UPDATE
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&mut *tx)
+        .await?;
+
         let filter = sqlx::query_as::<_, NotificationFilter>(
             r#"
             UPDATE notification_filters
             SET rule_yaml = $3,
-                guild_id = COALESCE(guild_id, $2)
+                guild_id = COALESCE(guild_id, $2),
+                updated_at = now()
             WHERE id = $1
-              AND (guild_id = $2 OR guild_id IS NULL)
-            RETURNING id, guild_id, rule_yaml, created_at
+              AND (guild_id = $2 OR (guild_id IS NULL AND owner_id IS NULL))
+            RETURNING id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
             "#,
         )
         .bind(id)
         .bind(guild_id)
         .bind(rule_yaml)
+        .fetch_optional(&mut *tx)
+        .await?;
+
+        if let Some(filter) = &filter
+            && previous.as_ref() != Some(&filter.rule_yaml)
+        {
+            Self::insert_filter_revision(&mut tx, filter, editor).await?;
+        }
+        tx.commit().await?;
+
+        Ok(filter)
+    }
+
+    /// Serializes the transactions that count a user's filters or watches
+    /// against their limit, until `tx` ends.
+    async fn lock_user(tx: &mut sqlx::Transaction<'_, sqlx::Postgres>, user_id: i64) -> Result<()> {
+        sqlx::query("SELECT pg_advisory_xact_lock($1)")
+            .bind(user_id)
+            .execute(&mut **tx)
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_filter_revision(
+        tx: &mut sqlx::Transaction<'_, sqlx::Postgres>,
+        filter: &NotificationFilter,
+        editor: Option<&Editor>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO notification_filter_revisions (filter_id, rule_yaml, editor_id, editor_name)
+            VALUES ($1, $2, $3, $4)
+            "#,
+        )
+        .bind(filter.id)
+        .bind(&filter.rule_yaml)
+        .bind(editor.map(|editor| editor.user_id))
+        .bind(editor.map(|editor| editor.name.as_str()))
+        .execute(&mut **tx)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the revisions of a notification filter, newest first.
+    pub async fn get_filter_revisions(
+        &self,
+        filter_id: i64,
+    ) -> Result<Vec<NotificationFilterRevision>> {
+        let revisions = sqlx::query_as::<_, NotificationFilterRevision>(
+            r#"
+            SELECT id, filter_id, rule_yaml, editor_id, editor_name, created_at
+            FROM notification_filter_revisions
+            WHERE filter_id = $1
+            ORDER BY created_at DESC, id DESC
+            "#,
+        )
+        .bind(filter_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(revisions)
+    }
+
+    /// Get a single revision of a notification filter.
+    pub async fn get_filter_revision(
+        &self,
+        filter_id: i64,
+        revision_id: i64,
+    ) -> Result<Option<NotificationFilterRevision>> {
+        let revision = sqlx::query_as::<_, NotificationFilterRevision>(
+            r#"
+            SELECT id, filter_id, rule_yaml, editor_id, editor_name, created_at
+            FROM notification_filter_revisions
+            WHERE filter_id = $1
+              AND id = $2
+            "#,
+        )
+        .bind(filter_id)
+        .bind(revision_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(revision)
+    }
+
+    /// Get a notification filter of a guild by its name.
+    pub async fn get_notification_filter_by_name(
+        &self,
+        guild_id: i64,
+        name: &str,
+    ) -> Result<Option<NotificationFilter>> {
+        let filter = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            FROM notification_filters
+            WHERE guild_id = $1
+              AND name = $2
+            "#,
+        )
+        .bind(guild_id)
+        .bind(name)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(filter)
+    }
+
+    /// Update the name and description of a notification filter.
+    pub async fn update_notification_filter_details(
+        &self,
+        id: i64,
+        guild_id: i64,
+        name: Option<String>,
+        description: Option<String>,
+    ) -> Result<Option<NotificationFilter>> {
+        let filter = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            UPDATE notification_filters
+            SET name = $3,
+                description = $4,
+                guild_id = COALESCE(guild_id, $2),
+                updated_at = now()
+            WHERE id = $1
+              AND (guild_id = $2 OR (guild_id IS NULL AND owner_id IS NULL))
+            RETURNING id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            "#,
+        )
+        .bind(id)
+        .bind(guild_id)
+        .bind(name)
+        .bind(description)
         .fetch_optional(&self.pool)
         .await?;
 
         Ok(filter)
     }
 
+    /// Claim the delivery of an item, or of one of its changes if
+    /// `change_id` is not 0, to a channel before sending it. Returns
+    /// `None` if it was sent already, is being sent, or is waiting for its
+    /// next retry; pending deliveries last updated before `stale_before` are
+    /// taken as interrupted and claimed again. `role_ids` are kept for
+    /// retries to mention.
+    pub async fn claim_delivery(
+        &self,
+        item_id: i64,
+        change_id: i64,
+        channel_id: i64,
+        user_id: Option<i64>,
+        role_ids: &[i64],
+        stale_before: OffsetDateTime,
+    ) -> Result<Option<NotificationDelivery>> {
+        let delivery = sqlx::query_as::<_, NotificationDelivery>(&format!(
+            r#"
+            INSERT INTO notification_deliveries
+                (item_id, channel_id, user_id, status, attempts, change_id, role_ids)
+            VALUES ($1, $2, $3, 'pending', 1, $5, $6)
+            ON CONFLICT (item_id, channel_id, change_id) DO UPDATE
+            SET status = 'pending',
+                attempts = notification_deliveries.attempts + 1,
+                role_ids = EXCLUDED.role_ids,
+                updated_at = now()
+            WHERE (notification_deliveries.status = 'failed'
+                   AND notification_deliveries.next_attempt_at <= now())
+               OR (notification_deliveries.status = 'pending'
+                   AND notification_deliveries.updated_at < $4)
+            RETURNING {NOTIFICATION_DELIVERY_COLUMNS}
+            "#
+        ))
+        .bind(item_id)
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(stale_before)
+        .bind(change_id)
+        .bind(role_ids)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(delivery)
+    }
+
+    /// Record that a delivery was sent as `message_id`
+    pub async fn mark_delivery_sent(
+        &self,
+        item_id: i64,
+        change_id: i64,
+        channel_id: i64,
+        message_id: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE notification_deliveries
+            SET status = 'sent',
+                message_id = $3,
+                error = NULL,
+                crosspost_error = NULL,
+                next_attempt_at = NULL,
+                updated_at = now()
+            WHERE item_id = $1 AND channel_id = $2 AND change_id = $4
+            "#,
+        )
+        .bind(item_id)
+        .bind(channel_id)
+        .bind(message_id)
+        .bind(change_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record that the message sent for deliveries could not be crossposted
+    pub async fn mark_crosspost_failed(
+        &self,
+        channel_id: i64,
+        change_id: i64,
+        item_ids: &[i64],
+        error: &str,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE notification_deliveries
+            SET crosspost_error = $3,
+                updated_at = now()
+            WHERE channel_id = $1 AND item_id = ANY($2) AND change_id = $4
+            "#,
+        )
+        .bind(channel_id)
+        .bind(item_ids)
+        .bind(error)
+        .bind(change_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record that a delivery failed, to be retried at `next_attempt_at` or
+    /// never if `None`
+    pub async fn mark_delivery_failed(
+        &self,
+        item_id: i64,
+        change_id: i64,
+        channel_id: i64,
+        error: &str,
+        next_attempt_at: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE notification_deliveries
+            SET status = 'failed',
+                error = $3,
+                next_attempt_at = $4,
+                updated_at = now()
+            WHERE item_id = $1 AND channel_id = $2 AND change_id = $5
+            "#,
+        )
+        .bind(item_id)
+        .bind(channel_id)
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(change_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Put off a claimed delivery that was not tried, e.g. because an earlier
+    /// one to the same channel failed, until `next_attempt_at`. The claim
+    /// does not count as an attempt.
+    pub async fn postpone_delivery(
+        &self,
+        item_id: i64,
+        change_id: i64,
+        channel_id: i64,
+        error: &str,
+        next_attempt_at: Option<OffsetDateTime>,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE notification_deliveries
+            SET status = 'failed',
+                error = $3,
+                next_attempt_at = $4,
+                attempts = GREATEST(attempts - 1, 0),
+                updated_at = now()
+            WHERE item_id = $1 AND channel_id = $2 AND change_id = $5
+            "#,
+        )
+        .bind(item_id)
+        .bind(channel_id)
+        .bind(error)
+        .bind(next_attempt_at)
+        .bind(change_id)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get deliveries that are due for a retry: failed ones whose backoff
+    /// has passed and pending ones last updated before `stale_before`.
+    /// Deliveries to disabled channels wait until the channel is enabled;
+    /// those to channels or guilds the bot no longer posts in are left out.
+    pub async fn get_due_deliveries(
+        &self,
+        stale_before: OffsetDateTime,
+        limit: i64,
+    ) -> Result<Vec<NotificationDelivery>> {
+        let deliveries = sqlx::query_as::<_, NotificationDelivery>(&format!(
+            r#"
+            SELECT {NOTIFICATION_DELIVERY_COLUMNS}
+            FROM notification_deliveries d
+            WHERE ((status = 'failed' AND next_attempt_at <= now())
+                   OR (status = 'pending' AND updated_at < $1))
+              AND NOT EXISTS (
+                  SELECT 1
+                  FROM discord_channels c
+                  JOIN discord_guilds g ON g.guild_id = c.guild_id
+                  WHERE c.channel_id = d.channel_id
+                    AND (c.disabled_at IS NOT NULL OR NOT c.active OR NOT g.active)
+              )
+            ORDER BY created_at
+            LIMIT $2
+            "#
+        ))
+        .bind(stale_before)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(deliveries)
+    }
+
+    /// Get the personal filters of a user.
+    pub async fn get_notification_filters_by_owner(
+        &self,
+        owner_id: i64,
+    ) -> Result<Vec<NotificationFilter>> {
+        let filters = sqlx::query_as::<_, NotificationFilter>(
+            r#"
+            SELECT id, guild_id, owner_id, name, description, rule_yaml, avatar, created_at, updated_at
+            FROM notification_filters
+            WHERE owner_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(filters)
+    }
+
+    /// Watch a filter by DM, or update the NSFW setting of an existing watch.
+    pub async fn upsert_user_watch(
+        &self,
+        user_id: i64,
+        filter_id: i64,
+        include_nsfw: bool,
+    ) -> Result<UserWatch> {
+        let mut tx = self.pool.begin().await?;
+        Self::lock_user(&mut tx, user_id).await?;
+
+        // Updating an existing watch is fine at the limit
+        let watch = sqlx::query_as::<_, UserWatch>(
+            r#"
+            INSERT INTO user_watches (user_id, filter_id, include_nsfw)
+            SELECT $1, $2, $3
+            WHERE EXISTS (SELECT 1 FROM user_watches WHERE user_id = $1 AND filter_id = $2)
+               OR (SELECT count(*) FROM user_watches WHERE user_id = $1) < $4
+            ON CONFLICT (user_id, filter_id) DO UPDATE
+            SET include_nsfw = EXCLUDED.include_nsfw
+            RETURNING user_id, filter_id, include_nsfw, created_at
+            "#,
+        )
+        .bind(user_id)
+        .bind(filter_id)
+        .bind(include_nsfw)
+        .bind(MAX_WATCHES)
+        .fetch_optional(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        match watch {
+            Some(watch) => Ok(watch),
+            None => bail!("users can watch at most {MAX_WATCHES} filters"),
+        }
+    }
+
+    /// Get the watches of a user, oldest first.
+    pub async fn get_user_watches(&self, user_id: i64) -> Result<Vec<UserWatch>> {
+        let watches = sqlx::query_as::<_, UserWatch>(
+            r#"
+            SELECT user_id, filter_id, include_nsfw, created_at
+            FROM user_watches
+            WHERE user_id = $1
+            ORDER BY created_at
+            "#,
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(watches)
+    }
+
+    /// Get every watch of every user.
+    pub async fn get_all_user_watches(&self) -> Result<Vec<UserWatch>> {
+        let watches = sqlx::query_as::<_, UserWatch>(
+            r#"
+            SELECT user_id, filter_id, include_nsfw, created_at
+            FROM user_watches
+            ORDER BY user_id, created_at
+            "#,
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(watches)
+    }
+
+    /// Stop watching a filter. A personal filter of the user is deleted along
+    /// with the watch.
+    pub async fn delete_user_watch(&self, user_id: i64, filter_id: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let result = sqlx::query(
+            r#"
+            DELETE FROM user_watches
+            WHERE user_id = $1 AND filter_id = $2
+            "#,
+        )
+        .bind(user_id)
+        .bind(filter_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query(
+            r#"
+            DELETE FROM notification_filters
+            WHERE id = $2 AND owner_id = $1
+            "#,
+        )
+        .bind(user_id)
+        .bind(filter_id)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
     /// Delete a Discord channel registration.
     pub async fn delete_discord_channel(&self, channel_id: i64, guild_id: i64) -> Result<bool> {
         let result = sqlx::query(
@@ -478,6 +1803,7 @@ impl DatabaseClient {
         fallback_nsfw_channel_id: Option<i64>,
         general_category_id: Option<i64>,
         nsfw_category_id: Option<i64>,
+        admin_channel_id: Option<i64>,
     ) -> Result<DiscordGuild> {
         let guild = sqlx::query_as!(
             DiscordGuild,
@@ -487,15 +1813,20 @@ impl DatabaseClient {
                 fallback_channel_id = $2,
                 fallback_nsfw_channel_id = $3,
                 general_category_id = $4,
-                nsfw_category_id = $5
+                nsfw_category_id = $5,
+                admin_channel_id = $6
             WHERE guild_id = $1
-            RETURNING guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id, general_category_id, nsfw_category_id
+            RETURNING guild_id, name, created_at, fallback_channel_id, fallback_nsfw_channel_id,
+                   general_category_id, nsfw_category_id, active,
+                   message_template AS "message_template: Json<MessageTemplate>",
+                   admin_channel_id
             "#,
             guild_id,
             fallback_channel_id,
             fallback_nsfw_channel_id,
             general_category_id,
-            nsfw_category_id
+            nsfw_category_id,
+            admin_channel_id
         )
         .fetch_one(&self.pool)
         .await?;
@@ -503,3 +1834,6 @@ impl DatabaseClient {
         Ok(guild)
     }
 }
+
+#[cfg(test)]
+mod tests;
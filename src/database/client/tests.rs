@@ -0,0 +1,631 @@
+//! Behaviour of the queries against a real database. Each test gets a fresh
+//! database with the migrations applied; run them with
+//! `DATABASE_URL=postgres://... cargo test -- --ignored`.
+
+use std::time::Duration;
+
+use sqlx::PgPool;
+use sqlx::types::time::OffsetDateTime;
+
+use super::{DatabaseClient, MAX_PERSONAL_FILTERS, MAX_WATCHES};
+use crate::booth::change::ItemEvent;
+use crate::database::{
+    ContentPolicy, DeliveryMode, Editor, NewDiscordChannel, NewDiscordGuild, NewItemChange,
+    NewItemSnapshot, NewNotificationFilter,
+};
+
+const GUILD_ID: i64 = 1;
+const CHANNEL_ID: i64 = 10;
+
+async fn setup(pool: PgPool) -> DatabaseClient {
+    let db = DatabaseClient::from_pool(pool);
+    db.upsert_discord_guild(NewDiscordGuild {
+        guild_id: GUILD_ID,
+        name: "guild".to_string(),
+        fallback_channel_id: Some(CHANNEL_ID),
+        fallback_nsfw_channel_id: None,
+        general_category_id: None,
+        nsfw_category_id: None,
+    })
+    .await
+    .unwrap();
+    db.upsert_discord_channel(NewDiscordChannel {
+        channel_id: CHANNEL_ID,
+        guild_id: GUILD_ID,
+        name: "channel".to_string(),
+        filter_ids: vec![],
+        crosspost: false,
+    })
+    .await
+    .unwrap();
+    db
+}
+
+fn new_filter(name: Option<&str>, rule_yaml: &str) -> NewNotificationFilter {
+    NewNotificationFilter {
+        guild_id: Some(GUILD_ID),
+        owner_id: None,
+        name: name.map(str::to_string),
+        description: None,
+        rule_yaml: rule_yaml.to_string(),
+        avatar: None,
+        editor: Some(Editor {
+            user_id: 100,
+            name: "editor".to_string(),
+        }),
+    }
+}
+
+fn stale_before() -> OffsetDateTime {
+    OffsetDateTime::now_utc() - Duration::from_secs(120)
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn filter_names_are_unique_per_guild(pool: PgPool) {
+    let db = setup(pool).await;
+
+    let filter = db
+        .create_notification_filter(new_filter(Some("base"), "groups: []"))
+        .await
+        .unwrap();
+    assert!(
+        db.create_notification_filter(new_filter(Some("base"), "groups: []"))
+            .await
+            .is_err()
+    );
+
+    let found = db
+        .get_notification_filter_by_name(GUILD_ID, "base")
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(found.id, filter.id);
+    assert!(
+        db.get_notification_filter_by_name(2, "base")
+            .await
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn filter_updates_are_recorded_as_revisions(pool: PgPool) {
+    let db = setup(pool).await;
+    let filter = db
+        .create_notification_filter(new_filter(None, "groups: []"))
+        .await
+        .unwrap();
+
+    let editor = Editor {
+        user_id: 200,
+        name: "other".to_string(),
+    };
+    let updated = db
+        .update_notification_filter(filter.id, GUILD_ID, "events: []".to_string(), Some(&editor))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(updated.rule_yaml, "events: []");
+    assert!(updated.updated_at >= filter.updated_at);
+    // Filters of other guilds are not touched
+    assert!(
+        db.update_notification_filter(filter.id, 2, "groups: []".to_string(), None)
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    let revisions = db.get_filter_revisions(filter.id).await.unwrap();
+    assert_eq!(revisions.len(), 2);
+    assert_eq!(revisions[0].rule_yaml, "events: []");
+    assert_eq!(revisions[0].editor_id, Some(200));
+    assert_eq!(revisions[1].rule_yaml, "groups: []");
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn watches_are_kept_per_user(pool: PgPool) {
+    let db = setup(pool).await;
+    let filter = db
+        .create_notification_filter(NewNotificationFilter {
+            guild_id: None,
+            owner_id: Some(300),
+            ..new_filter(None, "groups: []")
+        })
+        .await
+        .unwrap();
+
+    db.upsert_user_watch(300, filter.id, false).await.unwrap();
+    let watch = db.upsert_user_watch(300, filter.id, true).await.unwrap();
+    assert!(watch.include_nsfw);
+    assert_eq!(db.get_user_watches(300).await.unwrap().len(), 1);
+    assert!(db.get_user_watches(301).await.unwrap().is_empty());
+
+    assert!(db.delete_user_watch(300, filter.id).await.unwrap());
+    assert!(!db.delete_user_watch(300, filter.id).await.unwrap());
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn personal_filters_and_watches_are_capped(pool: PgPool) {
+    let db = setup(pool).await;
+    let personal = || NewNotificationFilter {
+        guild_id: None,
+        owner_id: Some(300),
+        ..new_filter(None, "groups: []")
+    };
+
+    let mut filter_ids = vec![];
+    for _ in 0..MAX_PERSONAL_FILTERS {
+        filter_ids.push(db.create_notification_filter(personal()).await.unwrap().id);
+    }
+    assert!(db.create_notification_filter(personal()).await.is_err());
+    // Guild filters and other users are not limited by it
+    db.create_notification_filter(new_filter(None, "groups: []"))
+        .await
+        .unwrap();
+    db.create_notification_filter(NewNotificationFilter {
+        owner_id: Some(301),
+        ..personal()
+    })
+    .await
+    .unwrap();
+
+    for filter_id in &filter_ids[..MAX_WATCHES as usize] {
+        db.upsert_user_watch(300, *filter_id, false).await.unwrap();
+    }
+    let extra = filter_ids[MAX_WATCHES as usize];
+    assert!(db.upsert_user_watch(300, extra, false).await.is_err());
+    // Existing watches can still be changed
+    assert!(
+        db.upsert_user_watch(300, filter_ids[0], true)
+            .await
+            .unwrap()
+            .include_nsfw
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn channel_filters_keep_their_order(pool: PgPool) {
+    let db = setup(pool).await;
+    let first = db
+        .create_notification_filter(new_filter(None, "groups: []"))
+        .await
+        .unwrap();
+    let second = db
+        .create_notification_filter(new_filter(None, "groups: []"))
+        .await
+        .unwrap();
+
+    assert!(db.add_channel_filter(CHANNEL_ID, second.id).await.unwrap());
+    assert!(db.add_channel_filter(CHANNEL_ID, first.id).await.unwrap());
+    assert!(!db.add_channel_filter(CHANNEL_ID, first.id).await.unwrap());
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.filter_ids, vec![second.id, first.id]);
+
+    assert!(
+        db.update_channel_filter_roles(CHANNEL_ID, first.id, &[5, 6])
+            .await
+            .unwrap()
+    );
+    let bindings = db.get_channel_filters_by_guild(GUILD_ID).await.unwrap();
+    let binding = bindings
+        .iter()
+        .find(|binding| binding.filter_id == first.id)
+        .unwrap();
+    assert_eq!(binding.role_ids, vec![5, 6]);
+
+    assert!(
+        db.remove_channel_filter(CHANNEL_ID, second.id)
+            .await
+            .unwrap()
+    );
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.filter_ids, vec![first.id]);
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn subscription_roles_keep_the_first_one_set(pool: PgPool) {
+    let db = setup(pool).await;
+    let filter = db
+        .create_notification_filter(NewNotificationFilter {
+            description: Some("Avatar: Someone else".to_string()),
+            avatar: Some("Manuka".to_string()),
+            ..new_filter(None, "groups: []")
+        })
+        .await
+        .unwrap();
+    assert_eq!(filter.avatar.as_deref(), Some("Manuka"));
+
+    assert_eq!(
+        db.set_subscription_role(GUILD_ID, filter.id, 5, None)
+            .await
+            .unwrap(),
+        5
+    );
+    // Created at the same time by another subscribe
+    assert_eq!(
+        db.set_subscription_role(GUILD_ID, filter.id, 6, None)
+            .await
+            .unwrap(),
+        5
+    );
+    // Recreated after it was deleted in Discord
+    assert_eq!(
+        db.set_subscription_role(GUILD_ID, filter.id, 7, Some(5))
+            .await
+            .unwrap(),
+        7
+    );
+    assert_eq!(
+        db.set_subscription_role(GUILD_ID, filter.id, 8, Some(5))
+            .await
+            .unwrap(),
+        7
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn channel_settings_are_stored(pool: PgPool) {
+    let db = setup(pool).await;
+
+    assert!(
+        db.update_channel_content_policy(CHANNEL_ID, Some(ContentPolicy::NsfwOnly))
+            .await
+            .unwrap()
+    );
+    assert!(
+        db.update_channel_delivery_mode(CHANNEL_ID, DeliveryMode::Daily)
+            .await
+            .unwrap()
+    );
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.content_policy(), Some(ContentPolicy::NsfwOnly));
+    assert_eq!(channel.delivery_mode(), DeliveryMode::Daily);
+
+    assert!(
+        db.update_channel_content_policy(CHANNEL_ID, None)
+            .await
+            .unwrap()
+    );
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.content_policy(), None);
+
+    // Registering the channel again keeps what was chosen for it
+    assert!(
+        db.update_channel_crosspost(CHANNEL_ID, Some(false))
+            .await
+            .unwrap()
+    );
+    let channel = db
+        .upsert_discord_channel(NewDiscordChannel {
+            channel_id: CHANNEL_ID,
+            guild_id: GUILD_ID,
+            name: "announcements".to_string(),
+            filter_ids: vec![],
+            crosspost: true,
+        })
+        .await
+        .unwrap();
+    assert_eq!(channel.name, "announcements");
+    assert_eq!(channel.crosspost, Some(false));
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn deliveries_are_claimed_once_and_retried_after_backoff(pool: PgPool) {
+    let db = setup(pool).await;
+
+    let claimed = db
+        .claim_delivery(1, 0, CHANNEL_ID, None, &[5], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(claimed.status, "pending");
+    assert_eq!(claimed.attempts, 1);
+    // Being sent already
+    assert!(
+        db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+            .await
+            .unwrap()
+            .is_none()
+    );
+
+    // Not due before its backoff has passed
+    let later = OffsetDateTime::now_utc() + Duration::from_secs(60);
+    db.mark_delivery_failed(1, 0, CHANNEL_ID, "boom", Some(later))
+        .await
+        .unwrap();
+    assert!(
+        db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    assert!(
+        db.get_due_deliveries(stale_before(), 10)
+            .await
+            .unwrap()
+            .is_empty()
+    );
+
+    let due = OffsetDateTime::now_utc() - Duration::from_secs(1);
+    db.mark_delivery_failed(1, 0, CHANNEL_ID, "boom", Some(due))
+        .await
+        .unwrap();
+    let due = db.get_due_deliveries(stale_before(), 10).await.unwrap();
+    assert_eq!(due.len(), 1);
+    assert_eq!(due[0].error.as_deref(), Some("boom"));
+    // Retries mention the same roles
+    assert_eq!(due[0].role_ids, vec![5]);
+    let retried = db
+        .claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(retried.attempts, 2);
+
+    db.mark_delivery_sent(1, 0, CHANNEL_ID, 1000).await.unwrap();
+    assert!(
+        db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+            .await
+            .unwrap()
+            .is_none()
+    );
+    // A change of the same item is a delivery of its own
+    assert!(
+        db.claim_delivery(1, 7, CHANNEL_ID, None, &[], stale_before())
+            .await
+            .unwrap()
+            .is_some()
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn interrupted_deliveries_are_claimed_again(pool: PgPool) {
+    let db = setup(pool).await;
+
+    db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    // Everything pending counts as interrupted
+    let now = OffsetDateTime::now_utc() + Duration::from_secs(1);
+    assert_eq!(db.get_due_deliveries(now, 10).await.unwrap().len(), 1);
+    assert!(
+        db.claim_delivery(1, 0, CHANNEL_ID, None, &[], now)
+            .await
+            .unwrap()
+            .is_some()
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn deliveries_to_disabled_channels_are_not_retried(pool: PgPool) {
+    let db = setup(pool).await;
+
+    db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    let due = OffsetDateTime::now_utc() - Duration::from_secs(1);
+    db.mark_delivery_failed(1, 0, CHANNEL_ID, "Missing Access", Some(due))
+        .await
+        .unwrap();
+    // A fallback channel, which is not registered
+    db.claim_delivery(2, 0, 20, None, &[], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    db.mark_delivery_failed(2, 0, 20, "Missing Access", Some(due))
+        .await
+        .unwrap();
+
+    assert!(db.disable_channel(CHANNEL_ID).await.unwrap());
+    let due = db.get_due_deliveries(stale_before(), 10).await.unwrap();
+    assert_eq!(
+        due.iter()
+            .map(|delivery| delivery.item_id)
+            .collect::<Vec<_>>(),
+        vec![2]
+    );
+
+    // Retried again once the channel is enabled
+    assert!(db.enable_channel(CHANNEL_ID).await.unwrap());
+    assert_eq!(
+        db.get_due_deliveries(stale_before(), 10)
+            .await
+            .unwrap()
+            .len(),
+        2
+    );
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn deliveries_to_inactive_channels_and_guilds_are_not_retried(pool: PgPool) {
+    let db = setup(pool).await;
+    let due_item_ids = async || {
+        db.get_due_deliveries(stale_before(), 10)
+            .await
+            .unwrap()
+            .iter()
+            .map(|delivery| delivery.item_id)
+            .collect::<Vec<_>>()
+    };
+
+    db.claim_delivery(1, 0, CHANNEL_ID, None, &[], stale_before())
+        .await
+        .unwrap()
+        .unwrap();
+    let due = OffsetDateTime::now_utc() - Duration::from_secs(1);
+    db.mark_delivery_failed(1, 0, CHANNEL_ID, "Unknown Channel", Some(due))
+        .await
+        .unwrap();
+    assert_eq!(due_item_ids().await, vec![1]);
+
+    // Deleted in Discord
+    assert!(db.deactivate_channel(GUILD_ID, CHANNEL_ID).await.unwrap());
+    assert!(due_item_ids().await.is_empty());
+
+    // Registered again, but the bot was removed from the guild
+    db.upsert_discord_channel(NewDiscordChannel {
+        channel_id: CHANNEL_ID,
+        guild_id: GUILD_ID,
+        name: "channel".to_string(),
+        filter_ids: vec![],
+        crosspost: false,
+    })
+    .await
+    .unwrap();
+    assert_eq!(due_item_ids().await, vec![1]);
+    assert!(db.set_guild_active(GUILD_ID, None, false).await.unwrap());
+    assert!(due_item_ids().await.is_empty());
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn channel_failures_are_counted_until_reset(pool: PgPool) {
+    let db = setup(pool).await;
+
+    assert_eq!(db.record_channel_failure(CHANNEL_ID, "a").await.unwrap(), 1);
+    assert_eq!(db.record_channel_failure(CHANNEL_ID, "b").await.unwrap(), 2);
+    assert_eq!(db.record_channel_failure(99, "c").await.unwrap(), 0);
+    assert!(db.disable_channel(CHANNEL_ID).await.unwrap());
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.last_error.as_deref(), Some("b"));
+    assert!(channel.disabled_at.is_some());
+
+    assert!(db.enable_channel(CHANNEL_ID).await.unwrap());
+    db.reset_channel_failures(CHANNEL_ID).await.unwrap();
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert_eq!(channel.failure_count, 0);
+    assert!(channel.disabled_at.is_none());
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn reconcile_deactivates_missing_channels(pool: PgPool) {
+    let db = setup(pool).await;
+    let guild = db
+        .update_guild_special_channels(
+            GUILD_ID,
+            Some(CHANNEL_ID),
+            None,
+            None,
+            None,
+            Some(CHANNEL_ID),
+        )
+        .await
+        .unwrap();
+    assert_eq!(guild.admin_channel_id, Some(CHANNEL_ID));
+
+    let deactivated = db.reconcile_guild_channels(GUILD_ID, &[]).await.unwrap();
+    assert_eq!(deactivated, vec![CHANNEL_ID]);
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert!(!channel.active);
+    let guild = db.get_discord_guild(GUILD_ID).await.unwrap().unwrap();
+    assert_eq!(guild.fallback_channel_id, None);
+    assert_eq!(guild.admin_channel_id, None);
+
+    // The channel comes back, but the setting it was dropped from does not
+    assert!(
+        db.reconcile_guild_channels(GUILD_ID, &[CHANNEL_ID])
+            .await
+            .unwrap()
+            .is_empty()
+    );
+    let channel = db.get_discord_channel(CHANNEL_ID).await.unwrap().unwrap();
+    assert!(channel.active);
+
+    assert!(db.set_guild_active(GUILD_ID, None, false).await.unwrap());
+    assert!(!db.set_guild_active(2, None, false).await.unwrap());
+    let guild = db.get_discord_guild(GUILD_ID).await.unwrap().unwrap();
+    assert!(!guild.active);
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn digest_items_are_queued_once(pool: PgPool) {
+    let db = setup(pool).await;
+
+    assert!(db.queue_digest_item(CHANNEL_ID, 1, &[5]).await.unwrap());
+    assert!(!db.queue_digest_item(CHANNEL_ID, 1, &[5]).await.unwrap());
+    assert!(db.queue_digest_item(CHANNEL_ID, 2, &[]).await.unwrap());
+    let items = db.get_digest_items().await.unwrap();
+    assert_eq!(items.len(), 2);
+    assert_eq!(items[0].role_ids, vec![5]);
+
+    db.mark_digest_items_sent(CHANNEL_ID, &[1, 2], 1000)
+        .await
+        .unwrap();
+    assert!(db.get_digest_items().await.unwrap().is_empty());
+    // Already delivered by the digest
+    assert!(!db.queue_digest_item(CHANNEL_ID, 1, &[]).await.unwrap());
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn recently_seen_items_are_rechecked_least_recent_first(pool: PgPool) {
+    let db = setup(pool).await;
+    let snapshot = |item_id| NewItemSnapshot {
+        item_id,
+        name: format!("item {item_id}"),
+        payload: serde_json::json!({}),
+    };
+
+    db.create_item_snapshot(snapshot(1)).await.unwrap();
+    let since = OffsetDateTime::now_utc();
+    db.create_item_snapshot(snapshot(2)).await.unwrap();
+    db.create_item_snapshot(snapshot(3)).await.unwrap();
+    // Snapshotted again after a change, still first seen before `since`
+    db.create_item_snapshot(snapshot(1)).await.unwrap();
+
+    // Newest first while none was checked
+    assert_eq!(
+        db.get_items_to_recheck(since, 10).await.unwrap(),
+        vec![3, 2]
+    );
+    db.mark_items_checked(&[3]).await.unwrap();
+    assert_eq!(
+        db.get_items_to_recheck(since, 10).await.unwrap(),
+        vec![2, 3]
+    );
+    assert_eq!(db.get_items_to_recheck(since, 1).await.unwrap(), vec![2]);
+}
+
+#[sqlx::test]
+#[ignore = "needs a Postgres DATABASE_URL"]
+async fn changes_are_saved_with_their_snapshot(pool: PgPool) {
+    let db = setup(pool).await;
+    let snapshot = || NewItemSnapshot {
+        item_id: 1,
+        name: "item".to_string(),
+        payload: serde_json::json!({}),
+    };
+
+    db.create_item_snapshot(snapshot()).await.unwrap();
+    let change = db
+        .create_item_snapshot_with_change(
+            snapshot(),
+            NewItemChange {
+                events: vec![ItemEvent::PriceDrop],
+                summary: "💸 値下げ: ¥1500 → ¥1200".to_string(),
+            },
+        )
+        .await
+        .unwrap();
+
+    let latest = db.get_latest_snapshot(1).await.unwrap().unwrap();
+    assert_eq!((change.item_id, change.snapshot_id), (1, latest.id));
+    assert_eq!(change.events, vec!["price_drop".to_string()]);
+    let stored = db.get_item_change(change.id).await.unwrap().unwrap();
+    assert_eq!(stored.summary, change.summary);
+}
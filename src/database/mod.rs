@@ -2,5 +2,5 @@ pub mod client;
 pub mod models;
 
 // Re-export commonly used types
-pub use client::DatabaseClient;
+pub use client::{DatabaseClient, MAX_PERSONAL_FILTERS, MAX_WATCHES};
 pub use models::*;
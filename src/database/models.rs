@@ -1,6 +1,8 @@
 use serde::{Deserialize, Serialize};
-use sqlx::types::JsonValue;
 use sqlx::types::time::OffsetDateTime;
+use sqlx::types::{Json, JsonValue};
+
+use crate::{booth::change::ItemEvent, template::MessageTemplate};
 
 /// A record of a fetch run that stores which items were fetched
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
@@ -20,6 +22,28 @@ pub struct ItemSnapshot {
     pub payload: JsonValue,
 }
 
+/// What changed about a known item when it was fetched again
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ItemChange {
+    pub id: i64,
+    pub item_id: i64,
+    /// The snapshot the item was changed in
+    pub snapshot_id: i64,
+    pub events: Vec<String>,
+    /// One line per event, shown above the item
+    pub summary: String,
+    pub created_at: OffsetDateTime,
+}
+
+impl ItemChange {
+    pub fn events(&self) -> Vec<ItemEvent> {
+        self.events
+            .iter()
+            .filter_map(|event| event.parse().ok())
+            .collect()
+    }
+}
+
 /// A Discord guild (server)
 #[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
 pub struct DiscordGuild {
@@ -30,6 +54,22 @@ pub struct DiscordGuild {
     pub fallback_nsfw_channel_id: Option<i64>,
     pub general_category_id: Option<i64>,
     pub nsfw_category_id: Option<i64>,
+    /// False once the bot was removed from the guild
+    pub active: bool,
+    /// Default look of the guild's notifications
+    pub message_template: Option<Json<MessageTemplate>>,
+    /// Where problems such as disabled channels are reported; the owner is
+    /// told by DM if unset
+    pub admin_channel_id: Option<i64>,
+}
+
+impl DiscordGuild {
+    pub fn message_template(&self) -> MessageTemplate {
+        self.message_template
+            .as_ref()
+            .map(|template| template.0.clone())
+            .unwrap_or_default()
+    }
 }
 
 /// A notification filter rule stored as YAML
@@ -37,8 +77,64 @@ pub struct DiscordGuild {
 pub struct NotificationFilter {
     pub id: i64,
     pub guild_id: Option<i64>,
+    /// Discord user ID for personal filters, which only feed that user's
+    /// watches and are hidden from guild management.
+    pub owner_id: Option<i64>,
+    /// Unique within the guild; lets other filters include this one with
+    /// `ref: <name>`.
+    pub name: Option<String>,
+    pub description: Option<String>,
     pub rule_yaml: String,
+    /// The avatar the filter was made for by `/avatar add`
+    pub avatar: Option<String>,
     pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+}
+
+impl NotificationFilter {
+    /// How the filter is shown to users: its name with the ID, or just the
+    /// ID for unnamed filters.
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} (#{})", name, self.id),
+            None => format!("Filter #{}", self.id),
+        }
+    }
+
+    /// Whether guild `guild_id` may manage the filter: its own filters and
+    /// legacy filters without a guild, but never personal filters.
+    pub fn editable_in(&self, guild_id: i64) -> bool {
+        self.guild_id == Some(guild_id) || self.guild_id.is_none() && self.owner_id.is_none()
+    }
+}
+
+/// One saved version of a notification filter's definition
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct NotificationFilterRevision {
+    pub id: i64,
+    pub filter_id: i64,
+    pub rule_yaml: String,
+    /// Discord user ID of whoever saved this version, if known
+    pub editor_id: Option<i64>,
+    pub editor_name: Option<String>,
+    pub created_at: OffsetDateTime,
+}
+
+/// A user's subscription to a filter, delivered by direct message
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct UserWatch {
+    pub user_id: i64,
+    pub filter_id: i64,
+    /// Whether adult items are sent as well
+    pub include_nsfw: bool,
+    pub created_at: OffsetDateTime,
+}
+
+/// The Discord user making a change, recorded in filter revisions
+#[derive(Debug, Clone)]
+pub struct Editor {
+    pub user_id: i64,
+    pub name: String,
 }
 
 /// A Discord channel that can receive notifications
@@ -48,7 +144,200 @@ pub struct DiscordChannel {
     pub guild_id: i64,
     pub name: String,
     pub created_at: OffsetDateTime,
-    pub filter_id: Option<i64>,
+    /// Filters the channel is subscribed to; an item is posted once if any
+    /// of them matches.
+    pub filter_ids: Vec<i64>,
+    /// Stored [`ContentPolicy`]; `None` follows the channel's NSFW flag.
+    pub content_policy: Option<String>,
+    /// Failed deliveries in a row
+    pub failure_count: i32,
+    pub last_error: Option<String>,
+    /// Set when the channel was disabled after too many failures
+    pub disabled_at: Option<OffsetDateTime>,
+    /// False once the channel was deleted in Discord
+    pub active: bool,
+    /// Stored [`DeliveryMode`]
+    pub delivery_mode: String,
+    /// When the last digest was posted
+    pub last_digest_at: Option<OffsetDateTime>,
+    /// Look of the channel's notifications, over the guild's template
+    pub message_template: Option<Json<MessageTemplate>>,
+    /// Whether messages are crossposted; `None` does so in announcement
+    /// channels only.
+    pub crosspost: Option<bool>,
+}
+
+impl DiscordChannel {
+    /// The channel's explicit content policy, if it has a valid one.
+    pub fn content_policy(&self) -> Option<ContentPolicy> {
+        self.content_policy.as_deref()?.parse().ok()
+    }
+
+    pub fn delivery_mode(&self) -> DeliveryMode {
+        self.delivery_mode
+            .parse()
+            .unwrap_or(DeliveryMode::Immediate)
+    }
+
+    /// The channel's own template, without the guild's.
+    pub fn message_template(&self) -> MessageTemplate {
+        self.message_template
+            .as_ref()
+            .map(|template| template.0.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// Which items a channel receives by their adult flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ContentPolicy {
+    SfwOnly,
+    NsfwOnly,
+    Both,
+}
+
+impl ContentPolicy {
+    pub const ALL: [ContentPolicy; 3] = [Self::SfwOnly, Self::NsfwOnly, Self::Both];
+
+    /// The policy of channels without one: NSFW channels get adult items,
+    /// other channels the rest.
+    pub fn default_for(is_nsfw_channel: bool) -> Self {
+        if is_nsfw_channel {
+            Self::NsfwOnly
+        } else {
+            Self::SfwOnly
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::SfwOnly => "sfw_only",
+            Self::NsfwOnly => "nsfw_only",
+            Self::Both => "both",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::SfwOnly => "SFW only",
+            Self::NsfwOnly => "NSFW only",
+            Self::Both => "SFW and NSFW",
+        }
+    }
+
+    pub fn allows(self, is_adult: bool) -> bool {
+        match self {
+            Self::SfwOnly => !is_adult,
+            Self::NsfwOnly => is_adult,
+            Self::Both => true,
+        }
+    }
+}
+
+impl std::str::FromStr for ContentPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|policy| policy.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown content policy: {s}"))
+    }
+}
+
+/// When a channel gets its items
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeliveryMode {
+    Immediate,
+    Hourly,
+    Daily,
+}
+
+impl DeliveryMode {
+    pub const ALL: [DeliveryMode; 3] = [Self::Immediate, Self::Hourly, Self::Daily];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Immediate => "immediate",
+            Self::Hourly => "hourly",
+            Self::Daily => "daily",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            Self::Immediate => "Immediately",
+            Self::Hourly => "Hourly digest",
+            Self::Daily => "Daily digest",
+        }
+    }
+
+    /// Time between two digests; `None` posts every item right away.
+    pub fn interval(self) -> Option<std::time::Duration> {
+        match self {
+            Self::Immediate => None,
+            Self::Hourly => Some(std::time::Duration::from_secs(60 * 60)),
+            Self::Daily => Some(std::time::Duration::from_secs(60 * 60 * 24)),
+        }
+    }
+}
+
+impl std::str::FromStr for DeliveryMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|mode| mode.as_str() == s)
+            .ok_or_else(|| anyhow::anyhow!("unknown delivery mode: {s}"))
+    }
+}
+
+/// An item waiting for the next digest of a channel
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct DigestItem {
+    pub channel_id: i64,
+    pub item_id: i64,
+    pub created_at: OffsetDateTime,
+    /// Roles to mention for the item
+    pub role_ids: Vec<i64>,
+}
+
+/// A channel's subscription to a filter
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct ChannelFilter {
+    pub channel_id: i64,
+    pub filter_id: i64,
+    /// Roles mentioned when the filter matches
+    pub role_ids: Vec<i64>,
+    pub created_at: OffsetDateTime,
+}
+
+/// The delivery of one item to one channel (or DM channel), which makes
+/// sending idempotent and lets failed sends be retried
+#[derive(Debug, Clone, Serialize, Deserialize, sqlx::FromRow)]
+pub struct NotificationDelivery {
+    pub item_id: i64,
+    pub channel_id: i64,
+    /// Recipient of a direct message delivery
+    pub user_id: Option<i64>,
+    /// `pending`, `sent` or `failed`
+    pub status: String,
+    pub message_id: Option<i64>,
+    pub error: Option<String>,
+    pub attempts: i32,
+    /// When a failed delivery is retried; `None` once it is given up on
+    pub next_attempt_at: Option<OffsetDateTime>,
+    pub created_at: OffsetDateTime,
+    pub updated_at: OffsetDateTime,
+    /// Why the sent message could not be crossposted
+    pub crosspost_error: Option<String>,
+    /// The change the item was delivered for; 0 when it was delivered as new
+    pub change_id: i64,
+    /// Roles mentioned in the message
+    pub role_ids: Vec<i64>,
 }
 
 /// Input struct for creating a new fetch run
@@ -65,6 +354,13 @@ pub struct NewItemSnapshot {
     pub payload: JsonValue,
 }
 
+/// Input struct for recording an item change with the snapshot it was found in
+#[derive(Debug, Clone)]
+pub struct NewItemChange {
+    pub events: Vec<ItemEvent>,
+    pub summary: String,
+}
+
 /// Input struct for creating a new Discord guild
 #[derive(Debug, Clone)]
 pub struct NewDiscordGuild {
@@ -80,7 +376,12 @@ pub struct NewDiscordGuild {
 #[derive(Debug, Clone)]
 pub struct NewNotificationFilter {
     pub guild_id: Option<i64>,
+    pub owner_id: Option<i64>,
+    pub name: Option<String>,
+    pub description: Option<String>,
     pub rule_yaml: String,
+    pub avatar: Option<String>,
+    pub editor: Option<Editor>,
 }
 
 /// Input struct for creating a new Discord channel
@@ -89,5 +390,8 @@ pub struct NewDiscordChannel {
     pub channel_id: i64,
     pub guild_id: i64,
     pub name: String,
-    pub filter_id: Option<i64>,
+    pub filter_ids: Vec<i64>,
+    /// Whether messages are crossposted, i.e. it is an announcement channel;
+    /// only set when the channel is first registered
+    pub crosspost: bool,
 }
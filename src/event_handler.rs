@@ -1,9 +1,14 @@
+use std::sync::atomic::Ordering;
+
 use poise::serenity_prelude::{self as serenity, FullEvent};
 use tracing::{error, info};
 
 use crate::{
     Data, Error,
-    task::{NotifyTask, ScrapingTask},
+    task::{
+        NotifyTask, ScrapingTask, channel_deleted, channel_updated, guild_available, guild_removed,
+        reconcile_guilds,
+    },
 };
 
 pub async fn event_handler(
@@ -16,6 +21,40 @@ pub async fn event_handler(
         FullEvent::Ready { data_about_bot } => {
             ready_handler(ctx, data_about_bot, data).await?;
         }
+        FullEvent::ChannelDelete { channel, .. } => {
+            channel_deleted(
+                &data.db,
+                &data.nsfw_cache,
+                channel.guild_id.get() as i64,
+                channel.id.get() as i64,
+            )
+            .await?;
+        }
+        FullEvent::ChannelUpdate { new, .. } => {
+            channel_updated(&data.nsfw_cache, new);
+        }
+        // Also sent when a guild goes down in an outage, which changes nothing
+        FullEvent::GuildDelete { incomplete, .. } if !incomplete.unavailable => {
+            guild_removed(&data.db, incomplete.id.get() as i64).await?;
+        }
+        FullEvent::GuildCreate { guild, .. } => {
+            for channel in guild.channels.values() {
+                channel_updated(&data.nsfw_cache, channel);
+            }
+            let channel_ids = guild
+                .channels
+                .keys()
+                .map(|id| id.get() as i64)
+                .collect::<Vec<_>>();
+            guild_available(
+                &data.db,
+                &data.nsfw_cache,
+                guild.id.get() as i64,
+                Some(&guild.name),
+                &channel_ids,
+            )
+            .await?;
+        }
         _ => {}
     }
     Ok(())
@@ -28,17 +67,61 @@ async fn ready_handler(
 ) -> Result<(), Error> {
     info!("{} is connected!", ready.user.name);
 
+    if data.tasks_started.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+
     let ctx = ctx.clone();
     let database_client = data.db.clone();
     let booth_db = data.booth_db.clone();
+    let nsfw_cache = data.nsfw_cache.clone();
+    let queue_depth = data.queue_depth.clone();
+
+    tokio::spawn({
+        let ctx = ctx.clone();
+        let database_client = database_client.clone();
+        let nsfw_cache = nsfw_cache.clone();
+        async move {
+            let reconcile_interval = std::env::var("RECONCILE_INTERVAL_SECONDS")
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .unwrap_or(60 * 60);
+            let reconcile_interval = std::time::Duration::from_secs(reconcile_interval);
+
+            loop {
+                tokio::time::sleep(reconcile_interval).await;
+
+                if let Err(e) = reconcile_guilds(&ctx, &database_client, &nsfw_cache).await {
+                    error!("Error during reconciliation: {:?}", e);
+                }
+            }
+        }
+    });
+
     tokio::spawn(async move {
         let check_interval = std::env::var("CHECK_INTERVAL_SECONDS")
             .unwrap_or_else(|_| "30".to_string())
             .parse::<u64>()
             .unwrap_or(60);
         let check_interval = std::time::Duration::from_secs(check_interval);
-        let mut scraping_task = ScrapingTask::new(booth_db);
-        let mut notify_task = NotifyTask::new();
+        let recheck_interval = std::env::var("RECHECK_INTERVAL_SECONDS")
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .unwrap_or(60 * 60);
+        let recheck_batch_size = std::env::var("RECHECK_BATCH_SIZE")
+            .ok()
+            .and_then(|value| value.parse::<i64>().ok())
+            .unwrap_or(200);
+        let mut scraping_task = ScrapingTask::new(
+            booth_db,
+            std::time::Duration::from_secs(recheck_interval),
+            recheck_batch_size,
+        );
+        let notify_concurrency = std::env::var("NOTIFY_CONCURRENCY")
+            .ok()
+            .and_then(|value| value.parse::<usize>().ok())
+            .unwrap_or(8);
+        let mut notify_task = NotifyTask::new(nsfw_cache, queue_depth, notify_concurrency);
 
         loop {
             let items = match scraping_task.run(&database_client).await {
@@ -49,8 +132,25 @@ async fn ready_handler(
                 }
             };
 
-            if let Err(e) = notify_task.notify(&ctx, &database_client, &items).await {
-                error!("Error during notify task: {:?}", e);
+            if let Err(e) = scraping_task.recheck(&database_client).await {
+                error!("Error while rechecking known items: {:?}", e);
+            }
+
+            match notify_task
+                .notify(
+                    &ctx,
+                    &database_client,
+                    &items,
+                    scraping_task.pending_changes(),
+                )
+                .await
+            {
+                Ok(()) => {
+                    if let Err(e) = scraping_task.mark_notified(&database_client).await {
+                        error!("Error marking fetch run as notified: {:?}", e);
+                    }
+                }
+                Err(e) => error!("Error during notify task: {:?}", e),
             }
 
             tokio::time::sleep(check_interval).await;
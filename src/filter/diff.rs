@@ -0,0 +1,152 @@
+use std::fmt;
+
+/// One line of a line-based diff between two filter definitions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+impl fmt::Display for DiffLine<'_> {
+    /// Formats the line in unified diff style, e.g. for a ```diff block.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffLine::Same(line) => write!(f, "  {line}"),
+            DiffLine::Removed(line) => write!(f, "- {line}"),
+            DiffLine::Added(line) => write!(f, "+ {line}"),
+        }
+    }
+}
+
+/// Cells of the LCS table, beyond which the changed lines are shown as
+/// removed and added as a whole, keeping memory and time bounded for large
+/// filter definitions.
+const MAX_LCS_CELLS: usize = 250_000;
+
+/// Diffs `old` against `new` line by line using their longest common
+/// subsequence, listing removals before additions within a change.
+pub fn diff_lines<'a>(old: &'a str, new: &'a str) -> Vec<DiffLine<'a>> {
+    let old = old.lines().collect::<Vec<_>>();
+    let new = new.lines().collect::<Vec<_>>();
+
+    // Lines the two have in common at the start and the end need no table
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old == new)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old == new)
+        .count();
+
+    let mut lines = old[..prefix]
+        .iter()
+        .map(|line| DiffLine::Same(line))
+        .collect::<Vec<_>>();
+    lines.extend(diff_middle(
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    ));
+    lines.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| DiffLine::Same(line)),
+    );
+    lines
+}
+
+/// Diffs the lines between the common start and end of the two versions.
+fn diff_middle<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    if (old.len() + 1).saturating_mul(new.len() + 1) > MAX_LCS_CELLS {
+        return old
+            .iter()
+            .map(|line| DiffLine::Removed(line))
+            .chain(new.iter().map(|line| DiffLine::Added(line)))
+            .collect();
+    }
+
+    // lcs[i][j] is the LCS length of old[i..] and new[j..].
+    let mut lcs = vec![vec![0usize; new.len() + 1]; old.len() + 1];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lcs[i][j] = if old[i] == new[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut lines = vec![];
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i] == new[j] {
+            lines.push(DiffLine::Same(old[i]));
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            lines.push(DiffLine::Removed(old[i]));
+            i += 1;
+        } else {
+            lines.push(DiffLine::Added(new[j]));
+            j += 1;
+        }
+    }
+    lines.extend(old[i..].iter().map(|line| DiffLine::Removed(line)));
+    lines.extend(new[j..].iter().map(|line| DiffLine::Added(line)));
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diffs_changed_lines() {
+        let old = "expr:\n  all:\n  - ref: 1\n  - ref: 2\nschema_version: 2\n";
+        let new = "expr:\n  all:\n  - ref: 1\n  - ref: 3\n  - ref: 4\nschema_version: 2\n";
+
+        assert_eq!(
+            diff_lines(old, new),
+            vec![
+                DiffLine::Same("expr:"),
+                DiffLine::Same("  all:"),
+                DiffLine::Same("  - ref: 1"),
+                DiffLine::Removed("  - ref: 2"),
+                DiffLine::Added("  - ref: 3"),
+                DiffLine::Added("  - ref: 4"),
+                DiffLine::Same("schema_version: 2"),
+            ]
+        );
+        assert_eq!(DiffLine::Removed("a").to_string(), "- a");
+        assert!(
+            diff_lines(old, old)
+                .iter()
+                .all(|line| matches!(line, DiffLine::Same(_)))
+        );
+    }
+
+    #[test]
+    fn replaces_large_changes_as_a_whole() {
+        let lines = |prefix: &str| {
+            (0..1000)
+                .map(|i| format!("{prefix}{i}"))
+                .collect::<Vec<_>>()
+                .join("\n")
+        };
+        let old = format!("head\n{}\ntail", lines("a"));
+        let new = format!("head\n{}\ntail", lines("b"));
+
+        let diff = diff_lines(&old, &new);
+
+        assert_eq!(diff.len(), 2002);
+        assert_eq!(diff[0], DiffLine::Same("head"));
+        assert_eq!(diff[1], DiffLine::Removed("a0"));
+        assert_eq!(diff[1001], DiffLine::Added("b0"));
+        assert_eq!(diff[2001], DiffLine::Same("tail"));
+    }
+}
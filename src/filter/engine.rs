@@ -1,55 +1,222 @@
+use std::{borrow::Cow, collections::HashSet};
+
+use anyhow::Result;
+use regex::{Regex, RegexBuilder, RegexSet};
+
 use crate::{
-    booth::item::{BoothItem, Tag},
-    filter::{Field, Filter, FilterGroup, Rule, TagMode},
+    booth::{
+        change::ItemEvent,
+        item::{BoothItem, Tag},
+    },
+    filter::{Expr, Field, Filter, Op, Pattern, PriceMode, Rule, TagMode, library::FilterLibrary},
 };
 
+/// Evaluates a [`Filter`] against items.
+///
+/// The filter is compiled once on construction: v1 groups are upgraded to an
+/// expression tree, text patterns are lowercased up front and regexes are
+/// built a single time, so one engine can be reused for every item of every
+/// notify cycle. Text and regex rules of an `any` node that look at the same
+/// field are batched into one [`RegexSet`], scanning the field once.
+///
+/// `ref` nodes must be resolved first with [`FilteringEngine::with_library`];
+/// [`FilteringEngine::new`] compiles any remaining reference to a node that
+/// never matches.
 pub struct FilteringEngine {
-    filter: Filter,
+    root: CompiledExpr,
+    events: Vec<ItemEvent>,
+}
+
+enum CompiledExpr {
+    All(Vec<CompiledExpr>),
+    /// `children` in their original order for explanations; checking runs
+    /// the `batches` and then only the children at `rest`.
+    Any {
+        children: Vec<CompiledExpr>,
+        batches: Vec<RuleBatch>,
+        rest: Vec<usize>,
+    },
+    Not(Box<CompiledExpr>),
+    Rule(Box<CompiledRule>),
+}
+
+/// Include rules of one `any` node matched with a single [`RegexSet`]. Any
+/// match means the node matches.
+struct RuleBatch {
+    field: Field,
+    /// Whether the set runs on the lowercased field, as case-insensitive
+    /// text rules do.
+    lowercase: bool,
+    set: RegexSet,
+}
+
+struct CompiledRule {
+    source: Rule,
+    field: Field,
+    op: Op,
+    tag_mode: TagMode,
+    price_mode: PriceMode,
+    matcher: Matcher,
+}
+
+/// Outcome of evaluating one expression node against an item, mirroring the
+/// shape of the filter's expression tree.
+#[derive(Debug)]
+pub struct Explanation {
+    pub matched: bool,
+    pub node: ExplanationNode,
+}
+
+#[derive(Debug)]
+pub enum ExplanationNode {
+    All(Vec<Explanation>),
+    Any(Vec<Explanation>),
+    Not(Box<Explanation>),
+    Rule(Rule),
+}
+
+enum Matcher {
+    /// `pattern` is already lowercased when matching case-insensitively.
+    Text {
+        pattern: String,
+        case_sensitive: bool,
+    },
+    /// `None` when the pattern failed to compile; such rules never match.
+    Regex(Option<Regex>),
+    /// Entries are already lowercased when matching case-insensitively.
+    OneOf {
+        values: HashSet<String>,
+        case_sensitive: bool,
+    },
+    /// Inclusive numeric bounds.
+    Range { min: i64, max: i64 },
 }
 
 impl FilteringEngine {
     pub fn new(filter: Filter) -> Self {
-        Self { filter }
+        Self {
+            root: compile_expr(&filter.to_expr()),
+            events: ItemEvent::ALL
+                .into_iter()
+                .filter(|event| filter.subscribes(*event))
+                .collect(),
+        }
+    }
+
+    /// Compiles `filter` after inlining every filter it references from
+    /// `library`. `id` is the filter's own id, if saved, so that a reference
+    /// back to itself is reported as a cycle.
+    pub fn with_library(filter: &Filter, id: Option<i64>, library: &FilterLibrary) -> Result<Self> {
+        Ok(Self::new(library.resolve(filter, id)?))
+    }
+
+    /// Whether matching items are posted on `event`.
+    pub fn subscribes(&self, event: ItemEvent) -> bool {
+        self.events.contains(&event)
     }
 
     pub fn check(&self, item: &BoothItem) -> bool {
-        for group in &self.filter.groups {
-            if !self.check_group(group, item) {
-                return false;
+        self.check_expr(&self.root, item)
+    }
+
+    /// Evaluates every node without short-circuiting, recording which rules
+    /// and branches matched.
+    pub fn explain(&self, item: &BoothItem) -> Explanation {
+        self.explain_expr(&self.root, item)
+    }
+
+    fn check_expr(&self, expr: &CompiledExpr, item: &BoothItem) -> bool {
+        match expr {
+            CompiledExpr::All(children) => {
+                children.iter().all(|child| self.check_expr(child, item))
+            }
+            CompiledExpr::Any {
+                children,
+                batches,
+                rest,
+            } => {
+                batches.iter().any(|batch| self.check_batch(batch, item))
+                    || rest
+                        .iter()
+                        .any(|index| self.check_expr(&children[*index], item))
             }
+            CompiledExpr::Not(child) => !self.check_expr(child, item),
+            CompiledExpr::Rule(rule) => self.check_rule(rule, item),
         }
-        true
     }
 
-    fn check_group(&self, group: &FilterGroup, item: &BoothItem) -> bool {
-        for rule in &group.rules {
-            if self.check_rule(rule, item) {
-                return true;
+    fn explain_expr(&self, expr: &CompiledExpr, item: &BoothItem) -> Explanation {
+        match expr {
+            CompiledExpr::All(children) => {
+                let children = self.explain_children(children, item);
+                Explanation {
+                    matched: children.iter().all(|child| child.matched),
+                    node: ExplanationNode::All(children),
+                }
+            }
+            CompiledExpr::Any { children, .. } => {
+                let children = self.explain_children(children, item);
+                Explanation {
+                    matched: children.iter().any(|child| child.matched),
+                    node: ExplanationNode::Any(children),
+                }
+            }
+            CompiledExpr::Not(child) => {
+                let child = self.explain_expr(child, item);
+                Explanation {
+                    matched: !child.matched,
+                    node: ExplanationNode::Not(Box::new(child)),
+                }
             }
+            CompiledExpr::Rule(rule) => Explanation {
+                matched: self.check_rule(rule, item),
+                node: ExplanationNode::Rule(rule.source.clone()),
+            },
+        }
+    }
+
+    fn explain_children(&self, children: &[CompiledExpr], item: &BoothItem) -> Vec<Explanation> {
+        children
+            .iter()
+            .map(|child| self.explain_expr(child, item))
+            .collect()
+    }
+
+    fn check_batch(&self, batch: &RuleBatch, item: &BoothItem) -> bool {
+        let value = self.field_text(batch.field, item);
+        if batch.lowercase {
+            batch.set.is_match(&value.to_lowercase())
+        } else {
+            batch.set.is_match(&value)
         }
-        false
     }
 
-    fn check_rule(&self, rule: &Rule, item: &BoothItem) -> bool {
+    /// The text of a field that batched rules match against.
+    fn field_text<'a>(&self, field: Field, item: &'a BoothItem) -> Cow<'a, str> {
+        match field {
+            Field::Name => Cow::Borrowed(&item.name),
+            Field::Description => Cow::Borrowed(&item.description),
+            Field::Category => Cow::Owned(self.category_text(item)),
+            Field::ShopName => Cow::Borrowed(&item.shop.name),
+            Field::ShopUrl => Cow::Borrowed(&item.shop.url),
+            Field::Tags | Field::Price => Cow::Borrowed(""),
+        }
+    }
+
+    fn check_rule(&self, rule: &CompiledRule, item: &BoothItem) -> bool {
         let matched = match rule.field {
-            Field::Name | Field::Description | Field::Category => {
-                let category;
-                let target = match rule.field {
-                    Field::Name => &item.name,
-                    Field::Description => &item.description,
-                    Field::Category => {
-                        category = self.category_text(item);
-                        &category
-                    }
-                    Field::Tags => unreachable!("tags are handled separately"),
-                };
-
-                self.check_string_rule(rule, target)
-            }
+            Field::Name => rule.matcher.matches(&item.name),
+            Field::Description => rule.matcher.matches(&item.description),
+            Field::Category => rule.matcher.matches(&self.category_text(item)),
+            Field::ShopName => rule.matcher.matches(&item.shop.name),
+            Field::ShopUrl => self.check_shop_url_rule(rule, &item.shop.url),
             Field::Tags => self.check_tags_rule(rule, &item.tags),
+            Field::Price => rule
+                .matcher
+                .matches_number(self.price_value(rule.price_mode, item)),
         };
 
-        if rule.op == crate::filter::Op::Include {
+        if rule.op == Op::Include {
             matched
         } else {
             !matched
@@ -64,59 +231,265 @@ impl FilteringEngine {
         parts.join(" ")
     }
 
-    fn check_string_rule(&self, rule: &Rule, value: &str) -> bool {
-        match &rule.pattern {
-            crate::filter::Pattern::Text { value: pattern } => {
-                if rule.case_sensitive {
-                    value.contains(pattern)
-                } else {
-                    value.to_lowercase().contains(&pattern.to_lowercase())
-                }
-            }
-            crate::filter::Pattern::Regex { value: pattern } => {
-                let regex = if rule.case_sensitive {
-                    regex::Regex::new(pattern)
-                } else {
-                    regex::Regex::new(&format!("(?i){pattern}"))
-                };
-                match regex {
-                    Ok(re) => re.is_match(value),
-                    Err(_) => false,
-                }
-            }
+    fn check_shop_url_rule(&self, rule: &CompiledRule, url: &str) -> bool {
+        match &rule.matcher {
+            Matcher::OneOf { .. } => rule.matcher.matches(&normalize_shop_url(url)),
+            _ => rule.matcher.matches(url),
         }
     }
 
-    fn check_tags_rule(&self, rule: &Rule, tags: &Vec<Tag>) -> bool {
-        let tag_mode = rule.tag_mode.unwrap_or(TagMode::Any);
+    fn price_value(&self, mode: PriceMode, item: &BoothItem) -> i64 {
+        let variation_prices = item.variations.iter().map(|variation| variation.price);
+        match mode {
+            PriceMode::Item => item.price_amount,
+            PriceMode::Min => variation_prices.min().unwrap_or(item.price_amount),
+            PriceMode::Max => variation_prices.max().unwrap_or(item.price_amount),
+        }
+    }
 
+    fn check_tags_rule(&self, rule: &CompiledRule, tags: &[Tag]) -> bool {
         if tags.is_empty() {
             return false;
         }
 
-        if tag_mode == TagMode::Any {
-            for tag in tags {
-                if self.check_string_rule(rule, &tag.name) {
-                    return true;
-                }
-            }
-            false
+        if rule.tag_mode == TagMode::Any {
+            tags.iter().any(|tag| rule.matcher.matches(&tag.name))
         } else {
-            for tag in tags {
-                if !self.check_string_rule(rule, &tag.name) {
-                    return false;
+            tags.iter().all(|tag| rule.matcher.matches(&tag.name))
+        }
+    }
+}
+
+impl Matcher {
+    fn matches(&self, value: &str) -> bool {
+        match self {
+            Matcher::Text {
+                pattern,
+                case_sensitive: true,
+            } => value.contains(pattern.as_str()),
+            Matcher::Text {
+                pattern,
+                case_sensitive: false,
+            } => value.to_lowercase().contains(pattern.as_str()),
+            Matcher::Regex(regex) => regex.as_ref().is_some_and(|re| re.is_match(value)),
+            Matcher::OneOf {
+                values,
+                case_sensitive: true,
+            } => values.contains(value),
+            Matcher::OneOf {
+                values,
+                case_sensitive: false,
+            } => values.contains(&value.to_lowercase()),
+            // Numeric patterns only make sense for the price field.
+            Matcher::Range { .. } => false,
+        }
+    }
+
+    fn matches_number(&self, value: i64) -> bool {
+        match self {
+            Matcher::Range { min, max } => (*min..=*max).contains(&value),
+            _ => self.matches(&value.to_string()),
+        }
+    }
+}
+
+fn compile_expr(expr: &Expr) -> CompiledExpr {
+    match expr {
+        Expr::All(children) => CompiledExpr::All(children.iter().map(compile_expr).collect()),
+        Expr::Any(children) => compile_any(children.iter().map(compile_expr).collect()),
+        Expr::Not(child) => CompiledExpr::Not(Box::new(compile_expr(child))),
+        Expr::Rule(rule) => CompiledExpr::Rule(Box::new(compile_rule(rule))),
+        Expr::Ref(_) => compile_any(vec![]),
+    }
+}
+
+/// Batches the include rules among `children` that match text of the same
+/// field, where there are at least two of them.
+fn compile_any(children: Vec<CompiledExpr>) -> CompiledExpr {
+    // Indices of the children with their patterns, per field and input case
+    type Members = Vec<(usize, String)>;
+    let mut groups: Vec<((Field, bool), Members)> = vec![];
+    for (index, child) in children.iter().enumerate() {
+        let CompiledExpr::Rule(rule) = child else {
+            continue;
+        };
+        let Some((lowercase, pattern)) = batch_pattern(rule) else {
+            continue;
+        };
+        let key = (rule.field, lowercase);
+        match groups.iter_mut().find(|(existing, _)| *existing == key) {
+            Some((_, members)) => members.push((index, pattern)),
+            None => groups.push((key, vec![(index, pattern)])),
+        }
+    }
+
+    let mut batched = HashSet::new();
+    let mut batches = vec![];
+    for ((field, lowercase), members) in groups {
+        if members.len() < 2 {
+            continue;
+        }
+        // A set over the size limit is left to the rules themselves
+        let Ok(set) = RegexSet::new(members.iter().map(|(_, pattern)| pattern)) else {
+            continue;
+        };
+        batched.extend(members.iter().map(|(index, _)| *index));
+        batches.push(RuleBatch {
+            field,
+            lowercase,
+            set,
+        });
+    }
+
+    CompiledExpr::Any {
+        rest: (0..children.len())
+            .filter(|index| !batched.contains(index))
+            .collect(),
+        children,
+        batches,
+    }
+}
+
+/// The pattern a rule contributes to a [`RuleBatch`] and whether it matches
+/// the lowercased field, if it can be batched.
+fn batch_pattern(rule: &CompiledRule) -> Option<(bool, String)> {
+    if rule.op != Op::Include
+        || !matches!(
+            rule.field,
+            Field::Name | Field::Description | Field::Category | Field::ShopName | Field::ShopUrl
+        )
+    {
+        return None;
+    }
+
+    match &rule.matcher {
+        Matcher::Text {
+            pattern,
+            case_sensitive,
+        } => Some((!case_sensitive, regex::escape(pattern))),
+        Matcher::Regex(Some(_)) => {
+            let Pattern::Regex { value } = &rule.source.pattern else {
+                return None;
+            };
+            let flags = rule.source.parsed_regex_flags().ok()?;
+            let mut inline = String::new();
+            for (set, flag) in [
+                (!rule.source.case_sensitive || flags.case_insensitive, 'i'),
+                (flags.multi_line, 'm'),
+                (flags.dot_matches_new_line, 's'),
+                (flags.ignore_whitespace, 'x'),
+            ] {
+                if set {
+                    inline.push(flag);
                 }
             }
-            true
+            if flags.ascii_only {
+                inline.push_str("-u");
+            }
+            // Flags apply up to the end of the pattern, so no group is
+            // needed (one could be commented out by `x` anyway)
+            let prefix = if inline.is_empty() {
+                String::new()
+            } else {
+                format!("(?{inline})")
+            };
+            Some((false, format!("{prefix}{value}")))
         }
+        _ => None,
     }
 }
 
+fn compile_rule(rule: &Rule) -> CompiledRule {
+    CompiledRule {
+        source: rule.clone(),
+        field: rule.field,
+        op: rule.op,
+        tag_mode: rule.tag_mode.unwrap_or(TagMode::Any),
+        price_mode: rule.price_mode.unwrap_or(PriceMode::Item),
+        matcher: compile_matcher(rule),
+    }
+}
+
+fn compile_matcher(rule: &Rule) -> Matcher {
+    let case_sensitive = rule.case_sensitive;
+    let fold = |value: &str| {
+        if case_sensitive {
+            value.to_string()
+        } else {
+            value.to_lowercase()
+        }
+    };
+
+    match &rule.pattern {
+        Pattern::Text { value } => Matcher::Text {
+            pattern: fold(value),
+            case_sensitive,
+        },
+        Pattern::Regex { value } => Matcher::Regex(build_regex(value, rule).ok()),
+        // Shop URLs are compared by their normalized (lowercase) form.
+        Pattern::OneOf { values } if rule.field == Field::ShopUrl => Matcher::OneOf {
+            values: values
+                .iter()
+                .map(|value| normalize_shop_url(value))
+                .collect(),
+            case_sensitive: true,
+        },
+        Pattern::OneOf { values } => Matcher::OneOf {
+            values: values.iter().map(|value| fold(value)).collect(),
+            case_sensitive,
+        },
+        Pattern::Lt { value } => Matcher::Range {
+            min: i64::MIN,
+            max: value.saturating_sub(1),
+        },
+        Pattern::Lte { value } => Matcher::Range {
+            min: i64::MIN,
+            max: *value,
+        },
+        Pattern::Gt { value } => Matcher::Range {
+            min: value.saturating_add(1),
+            max: i64::MAX,
+        },
+        Pattern::Gte { value } => Matcher::Range {
+            min: *value,
+            max: i64::MAX,
+        },
+        Pattern::Between { min, max } => Matcher::Range {
+            min: *min,
+            max: *max,
+        },
+    }
+}
+
+/// Builds the regex for a rule, applying `case_sensitive` and `regex_flags`.
+pub(crate) fn build_regex(pattern: &str, rule: &Rule) -> Result<Regex> {
+    let flags = rule.parsed_regex_flags()?;
+    let regex = RegexBuilder::new(pattern)
+        .case_insensitive(!rule.case_sensitive || flags.case_insensitive)
+        .multi_line(flags.multi_line)
+        .dot_matches_new_line(flags.dot_matches_new_line)
+        .ignore_whitespace(flags.ignore_whitespace)
+        .unicode(!flags.ascii_only)
+        .build()?;
+    Ok(regex)
+}
+
+/// Reduces a shop URL, host or bare subdomain to a comparable key so that
+/// `https://foo.booth.pm/`, `foo.booth.pm` and `foo` are treated alike.
+fn normalize_shop_url(value: &str) -> String {
+    let value = value.trim().to_lowercase();
+    let host = value
+        .trim_start_matches("https://")
+        .trim_start_matches("http://")
+        .trim_end_matches('/');
+    host.strip_suffix(".booth.pm").unwrap_or(host).to_string()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::booth::item::{BoothItem, Category, CategoryParent, Tag};
-    use crate::filter::{Field, Filter, FilterGroup, Op, Pattern, Rule, TagMode};
+    use crate::booth::item::{BoothItem, Category, CategoryParent, Shop, Tag, Variation};
+    use crate::filter::{Expr, Field, Filter, FilterGroup, Op, Pattern, PriceMode, Rule, TagMode};
 
     #[test]
     fn test_text_pattern() {
@@ -131,8 +504,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -162,8 +538,11 @@ mod tests {
                     case_sensitive: true,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -193,8 +572,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -230,8 +612,11 @@ mod tests {
                     case_sensitive: true,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -270,8 +655,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: Some(TagMode::Any),
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -308,8 +696,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: Some(TagMode::All),
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -353,8 +744,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -386,8 +780,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -420,8 +817,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: None,
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -452,6 +852,7 @@ mod tests {
                         case_sensitive: false,
                         regex_flags: None,
                         tag_mode: None,
+                        price_mode: None,
                     }],
                 },
                 FilterGroup {
@@ -464,9 +865,12 @@ mod tests {
                         case_sensitive: false,
                         regex_flags: None,
                         tag_mode: None,
+                        price_mode: None,
                     }],
                 },
             ],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -508,6 +912,7 @@ mod tests {
                         case_sensitive: false,
                         regex_flags: None,
                         tag_mode: None,
+                        price_mode: None,
                     },
                     Rule {
                         field: Field::Name,
@@ -518,9 +923,12 @@ mod tests {
                         case_sensitive: false,
                         regex_flags: None,
                         tag_mode: None,
+                        price_mode: None,
                     },
                 ],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -553,8 +961,11 @@ mod tests {
                     case_sensitive: false,
                     regex_flags: None,
                     tag_mode: Some(TagMode::Any),
+                    price_mode: None,
                 }],
             }],
+            expr: None,
+            events: vec![],
             schema_version: 1,
         };
 
@@ -566,4 +977,494 @@ mod tests {
 
         assert!(!engine.check(&item));
     }
+
+    #[test]
+    fn test_price_comparison() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::Price,
+                    op: Op::Include,
+                    pattern: Pattern::Lt { value: 3000 },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let mut item = BoothItem {
+            price_amount: 2999,
+            ..Default::default()
+        };
+
+        assert!(engine.check(&item));
+
+        item.price_amount = 3000;
+        assert!(!engine.check(&item));
+    }
+
+    #[test]
+    fn test_price_between() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::Price,
+                    op: Op::Include,
+                    pattern: Pattern::Between {
+                        min: 1000,
+                        max: 3000,
+                    },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let mut item = BoothItem {
+            price_amount: 1000,
+            ..Default::default()
+        };
+
+        assert!(engine.check(&item));
+
+        item.price_amount = 3000;
+        assert!(engine.check(&item));
+
+        item.price_amount = 999;
+        assert!(!engine.check(&item));
+
+        item.price_amount = 3001;
+        assert!(!engine.check(&item));
+    }
+
+    #[test]
+    fn test_price_mode_uses_variations() {
+        let rule = |price_mode| Rule {
+            field: Field::Price,
+            op: Op::Include,
+            pattern: Pattern::Lte { value: 500 },
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode: None,
+            price_mode: Some(price_mode),
+        };
+        let filter = |price_mode| Filter {
+            groups: vec![FilterGroup {
+                rules: vec![rule(price_mode)],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let mut item = BoothItem {
+            price_amount: 500,
+            variations: vec![
+                Variation {
+                    price: 0,
+                    ..Default::default()
+                },
+                Variation {
+                    price: 1500,
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        };
+
+        assert!(FilteringEngine::new(filter(PriceMode::Min)).check(&item));
+        assert!(!FilteringEngine::new(filter(PriceMode::Max)).check(&item));
+
+        item.variations.clear();
+        assert!(FilteringEngine::new(filter(PriceMode::Max)).check(&item));
+    }
+
+    #[test]
+    fn test_shop_name_one_of() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::ShopName,
+                    op: Op::Include,
+                    pattern: Pattern::OneOf {
+                        values: vec!["Shop A".to_string(), "Shop B".to_string()],
+                    },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let mut item = BoothItem {
+            shop: Shop {
+                name: "shop b".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(engine.check(&item));
+
+        item.shop.name = "Shop Bee".to_string();
+        assert!(!engine.check(&item));
+    }
+
+    #[test]
+    fn test_shop_url_deny_list() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::ShopUrl,
+                    op: Op::Exclude,
+                    pattern: Pattern::OneOf {
+                        values: vec!["spam".to_string(), "https://other.booth.pm/".to_string()],
+                    },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let mut item = BoothItem {
+            shop: Shop {
+                url: "https://spam.booth.pm/".to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(!engine.check(&item));
+
+        item.shop.url = "https://other.booth.pm".to_string();
+        assert!(!engine.check(&item));
+
+        item.shop.url = "https://creator.booth.pm/".to_string();
+        assert!(engine.check(&item));
+    }
+
+    #[test]
+    fn test_invalid_regex_never_matches() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::Name,
+                    op: Op::Include,
+                    pattern: Pattern::Regex {
+                        value: "(unclosed".to_string(),
+                    },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let item = BoothItem {
+            name: "(unclosed".to_string(),
+            ..Default::default()
+        };
+
+        assert!(!engine.check(&item));
+    }
+
+    #[test]
+    fn test_regex_flags() {
+        let rule = |regex_flags: Option<&str>| Rule {
+            field: Field::Description,
+            op: Op::Include,
+            pattern: Pattern::Regex {
+                value: r"^price: \d+ .* end$".to_string(),
+            },
+            case_sensitive: true,
+            regex_flags: regex_flags.map(str::to_string),
+            tag_mode: None,
+            price_mode: None,
+        };
+        let engine = |regex_flags| {
+            FilteringEngine::new(Filter {
+                groups: vec![FilterGroup {
+                    rules: vec![rule(regex_flags)],
+                }],
+                expr: None,
+                events: vec![],
+                schema_version: 1,
+            })
+        };
+
+        let item = BoothItem {
+            description: "intro\nprice: 500 line\nbreak end\noutro".to_string(),
+            ..Default::default()
+        };
+
+        assert!(!engine(None).check(&item));
+        assert!(!engine(Some("m")).check(&item));
+        assert!(engine(Some("ms")).check(&item));
+        assert!(!engine(Some("mq")).check(&item));
+    }
+
+    #[test]
+    fn test_batched_any_rules() {
+        let rule = |field: Field, pattern: Pattern, case_sensitive: bool, flags: Option<&str>| {
+            Expr::Rule(Rule {
+                field,
+                op: Op::Include,
+                pattern,
+                case_sensitive,
+                regex_flags: flags.map(str::to_string),
+                tag_mode: None,
+                price_mode: None,
+            })
+        };
+        let text = |value: &str| Pattern::Text {
+            value: value.to_string(),
+        };
+        let regex = |value: &str| Pattern::Regex {
+            value: value.to_string(),
+        };
+        let engine = FilteringEngine::new(Filter {
+            expr: Some(Expr::Any(vec![
+                rule(Field::Name, text("Kikyo"), false, None),
+                rule(Field::Name, text("Manuka"), false, None),
+                rule(Field::Name, text("(Rusk)"), true, None),
+                rule(Field::Name, regex(r"^\d+ ?cm"), true, None),
+                rule(
+                    Field::Name,
+                    regex("# only a comment\nsel[e]stia"),
+                    false,
+                    Some("x"),
+                ),
+                rule(Field::Name, regex(r"\d点"), true, Some("a")),
+                rule(Field::ShopName, text("studio"), false, None),
+            ])),
+            schema_version: 2,
+            ..Default::default()
+        });
+        let CompiledExpr::Any { batches, rest, .. } = &engine.root else {
+            panic!("expected an any node");
+        };
+        assert_eq!(batches.len(), 2);
+        assert_eq!(rest, &vec![6]);
+
+        let cases = [
+            ("KIKYO outfit", "", true),
+            ("manuka outfit", "", true),
+            ("rusk outfit", "", false),
+            ("(Rusk) outfit", "", true),
+            ("150cm hair", "", true),
+            ("SELESTIA dress", "", true),
+            ("３点セット", "", false),
+            ("3点セット", "", true),
+            ("hair", "Studio A", true),
+            ("hair", "", false),
+        ];
+        for (name, shop_name, expected) in cases {
+            let item = BoothItem {
+                name: name.to_string(),
+                shop: Shop {
+                    name: shop_name.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            assert_eq!(engine.check(&item), expected, "{name}");
+            // Explanations still evaluate each rule on its own
+            assert_eq!(engine.explain(&item).matched, expected, "{name}");
+        }
+    }
+
+    #[test]
+    fn test_ascii_only_regex_flag() {
+        let rule = |value: &str, regex_flags: &str| Rule {
+            field: Field::Name,
+            op: Op::Include,
+            pattern: Pattern::Regex {
+                value: value.to_string(),
+            },
+            case_sensitive: true,
+            regex_flags: Some(regex_flags.to_string()),
+            tag_mode: None,
+            price_mode: None,
+        };
+        let check = |rule: Rule| {
+            FilteringEngine::new(Filter {
+                expr: Some(Expr::Rule(rule)),
+                schema_version: 2,
+                ..Default::default()
+            })
+            .check(&BoothItem {
+                name: "衣装 ３点セット".to_string(),
+                ..Default::default()
+            })
+        };
+
+        // Full-width digits are digits only to Unicode classes
+        assert!(check(rule(r"\d点", "")));
+        assert!(check(rule(r"\d点", "u")));
+        assert!(!check(rule(r"\d点", "a")));
+        // `.` could match inside a multi-byte character, so the regex is
+        // rejected and the rule never matches
+        assert!(build_regex("衣.", &rule("衣.", "a")).is_err());
+        assert!(!check(rule("衣.", "a")));
+        assert!(rule("x", "au").parsed_regex_flags().is_err());
+    }
+
+    #[test]
+    fn test_nested_expression() {
+        let text_rule = |field, value: &str| Rule {
+            field,
+            op: Op::Include,
+            pattern: Pattern::Text {
+                value: value.to_string(),
+            },
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode: None,
+            price_mode: None,
+        };
+        // (name has "outfit" and not "R-18") or shop name has "Shop X"
+        let filter = Filter {
+            expr: Some(Expr::Any(vec![
+                Expr::All(vec![
+                    Expr::Rule(text_rule(Field::Name, "outfit")),
+                    Expr::Not(Box::new(Expr::Rule(text_rule(Field::Name, "R-18")))),
+                ]),
+                Expr::Rule(text_rule(Field::ShopName, "Shop X")),
+            ])),
+            events: vec![],
+            schema_version: 2,
+            ..Default::default()
+        };
+        let engine = FilteringEngine::new(filter);
+        let item = |name: &str, shop: &str| BoothItem {
+            name: name.to_string(),
+            shop: Shop {
+                name: shop.to_string(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(engine.check(&item("Summer Outfit", "Other")));
+        assert!(!engine.check(&item("Summer Outfit R-18", "Other")));
+        assert!(engine.check(&item("Summer Outfit R-18", "Shop X")));
+        assert!(!engine.check(&item("Hair", "Other")));
+    }
+
+    #[test]
+    fn test_explain_reports_failing_group() {
+        let filter = Filter {
+            groups: vec![
+                FilterGroup {
+                    rules: vec![
+                        Rule {
+                            field: Field::Name,
+                            op: Op::Include,
+                            pattern: Pattern::Text {
+                                value: "outfit".to_string(),
+                            },
+                            case_sensitive: false,
+                            regex_flags: None,
+                            tag_mode: None,
+                            price_mode: None,
+                        },
+                        Rule {
+                            field: Field::Name,
+                            op: Op::Include,
+                            pattern: Pattern::Text {
+                                value: "hair".to_string(),
+                            },
+                            case_sensitive: false,
+                            regex_flags: None,
+                            tag_mode: None,
+                            price_mode: None,
+                        },
+                    ],
+                },
+                FilterGroup {
+                    rules: vec![Rule {
+                        field: Field::Tags,
+                        op: Op::Include,
+                        pattern: Pattern::Text {
+                            value: "VRChat".to_string(),
+                        },
+                        case_sensitive: false,
+                        regex_flags: None,
+                        tag_mode: Some(TagMode::Any),
+                        price_mode: None,
+                    }],
+                },
+            ],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        let engine = FilteringEngine::new(filter);
+        let item = BoothItem {
+            name: "Outfit".to_string(),
+            ..Default::default()
+        };
+
+        let explanation = engine.explain(&item);
+
+        assert!(!explanation.matched);
+        assert_eq!(explanation.matched, engine.check(&item));
+        let ExplanationNode::All(groups) = &explanation.node else {
+            panic!("v1 filters explain as an all node");
+        };
+        assert!(groups[0].matched);
+        let ExplanationNode::Any(rules) = &groups[0].node else {
+            panic!("v1 groups explain as any nodes");
+        };
+        assert_eq!(
+            rules.iter().map(|rule| rule.matched).collect::<Vec<_>>(),
+            vec![true, false]
+        );
+        assert!(!groups[1].matched);
+    }
+
+    #[test]
+    fn subscribes_to_new_items_by_default() {
+        let filter: Filter = serde_yaml::from_str("expr:\n  all: []\n").unwrap();
+        let engine = FilteringEngine::new(filter);
+        assert!(engine.subscribes(ItemEvent::NewItem));
+        assert!(!engine.subscribes(ItemEvent::PriceDrop));
+
+        let filter: Filter =
+            serde_yaml::from_str("expr:\n  all: []\nevents:\n- price_drop\n- back_in_stock\n")
+                .unwrap();
+        let engine = FilteringEngine::new(filter);
+        assert!(!engine.subscribes(ItemEvent::NewItem));
+        assert!(engine.subscribes(ItemEvent::PriceDrop));
+        assert!(engine.subscribes(ItemEvent::BackInStock));
+    }
 }
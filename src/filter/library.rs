@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+
+use anyhow::{Result, anyhow, bail};
+use sqlx::types::time::OffsetDateTime;
+
+use crate::{
+    database::{DatabaseClient, NotificationFilter},
+    filter::{Expr, Filter, FilterRef},
+};
+
+/// Id under which a filter that has not been saved yet takes part in
+/// resolution; database ids start at 1.
+pub const UNSAVED_FILTER_ID: i64 = 0;
+
+/// Longest accepted filter name.
+pub const MAX_NAME_LEN: usize = 64;
+
+/// The saved filters that `ref` nodes can point at, normally every filter
+/// of one guild.
+#[derive(Debug, Default)]
+pub struct FilterLibrary {
+    filters: HashMap<i64, Filter>,
+    names: HashMap<String, i64>,
+    saved: usize,
+    updated_at: Option<OffsetDateTime>,
+}
+
+impl FilterLibrary {
+    /// Builds a library from database rows, skipping rows whose YAML does not
+    /// parse; references to them are reported as unknown.
+    pub fn from_saved<'a>(saved: impl IntoIterator<Item = &'a NotificationFilter>) -> Self {
+        let mut library = Self::default();
+        for filter in saved {
+            library.saved += 1;
+            library.updated_at = library.updated_at.max(Some(filter.updated_at));
+            if let Ok(parsed) = serde_yaml::from_str::<Filter>(&filter.rule_yaml) {
+                library.insert(filter.id, filter.name.as_deref(), parsed);
+            }
+        }
+        library
+    }
+
+    /// Loads every filter of `guild_id`.
+    pub async fn load(db: &DatabaseClient, guild_id: i64) -> Result<Self> {
+        let filters = db.get_notification_filters_by_guild(guild_id).await?;
+        Ok(Self::from_saved(&filters))
+    }
+
+    /// How many rows the library was built from and when the latest of them
+    /// changed, which differs whenever a saved filter was added, edited or
+    /// deleted since.
+    pub fn version(&self) -> (usize, Option<OffsetDateTime>) {
+        (self.saved, self.updated_at)
+    }
+
+    /// Adds or replaces a filter, e.g. one that is about to be saved.
+    pub fn insert(&mut self, id: i64, name: Option<&str>, filter: Filter) {
+        self.names.retain(|_, existing| *existing != id);
+        if let Some(name) = name {
+            self.names.insert(name.to_string(), id);
+        }
+        self.filters.insert(id, filter);
+    }
+
+    /// Returns the id a reference points at, if it exists in the library.
+    pub fn lookup(&self, filter_ref: &FilterRef) -> Option<i64> {
+        match filter_ref {
+            FilterRef::Id(id) => self.filters.contains_key(id).then_some(*id),
+            FilterRef::Name(name) => self.names.get(name).copied(),
+        }
+    }
+
+    /// Returns `filter` with every reference replaced by the expression of
+    /// the filter it points at, failing on unknown references and cycles.
+    pub fn resolve(&self, filter: &Filter, id: Option<i64>) -> Result<Filter> {
+        let expr = filter.to_expr();
+        if expr.refs().is_empty() {
+            return Ok(filter.clone());
+        }
+
+        let mut stack = id.into_iter().collect();
+        let mut resolved = HashMap::new();
+        Ok(Filter {
+            groups: vec![],
+            expr: Some(self.resolve_expr(&expr, &mut stack, &mut resolved)?),
+            events: filter.events.clone(),
+            schema_version: 2,
+        })
+    }
+
+    /// `resolved` holds the expression of every filter resolved so far, so
+    /// that a filter included along several paths is only resolved once.
+    fn resolve_expr(
+        &self,
+        expr: &Expr,
+        stack: &mut Vec<i64>,
+        resolved: &mut HashMap<i64, Expr>,
+    ) -> Result<Expr> {
+        Ok(match expr {
+            Expr::All(children) => Expr::All(self.resolve_children(children, stack, resolved)?),
+            Expr::Any(children) => Expr::Any(self.resolve_children(children, stack, resolved)?),
+            Expr::Not(child) => Expr::Not(Box::new(self.resolve_expr(child, stack, resolved)?)),
+            Expr::Rule(rule) => Expr::Rule(rule.clone()),
+            Expr::Ref(filter_ref) => {
+                let id = self
+                    .lookup(filter_ref)
+                    .ok_or_else(|| anyhow!("unknown filter reference {filter_ref}"))?;
+                if stack.contains(&id) {
+                    let cycle = stack
+                        .iter()
+                        .skip_while(|entry| **entry != id)
+                        .chain([&id])
+                        .map(|entry| format!("#{entry}"))
+                        .collect::<Vec<_>>();
+                    bail!("filter reference cycle: {}", cycle.join(" -> "));
+                }
+                if let Some(expr) = resolved.get(&id) {
+                    return Ok(expr.clone());
+                }
+
+                stack.push(id);
+                let expr = self.resolve_expr(&self.filters[&id].to_expr(), stack, resolved)?;
+                stack.pop();
+                resolved.insert(id, expr.clone());
+                expr
+            }
+        })
+    }
+
+    fn resolve_children(
+        &self,
+        children: &[Expr],
+        stack: &mut Vec<i64>,
+        resolved: &mut HashMap<i64, Expr>,
+    ) -> Result<Vec<Expr>> {
+        children
+            .iter()
+            .map(|child| self.resolve_expr(child, stack, resolved))
+            .collect()
+    }
+
+    /// Ids of the filters that reference `id` directly, in ascending order.
+    pub fn dependents(&self, id: i64) -> Vec<i64> {
+        let mut dependents = self
+            .filters
+            .iter()
+            .filter(|(other, filter)| {
+                **other != id
+                    && filter
+                        .to_expr()
+                        .refs()
+                        .into_iter()
+                        .any(|filter_ref| self.lookup(filter_ref) == Some(id))
+            })
+            .map(|(other, _)| *other)
+            .collect::<Vec<_>>();
+        dependents.sort_unstable();
+        dependents
+    }
+
+    /// Checks that filter `id` may be called `name`: the name must be valid
+    /// and unused, and the current name must not be dropped while other
+    /// filters still include the filter by it.
+    pub fn check_rename(&self, id: i64, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            check_name(name)?;
+            if let Some(existing) = self.names.get(name)
+                && *existing != id
+            {
+                bail!("filter #{existing} is already named \"{name}\"");
+            }
+        }
+
+        let Some(current) = self
+            .names
+            .iter()
+            .find(|(_, existing)| **existing == id)
+            .map(|(current, _)| current.clone())
+        else {
+            return Ok(());
+        };
+        if name == Some(current.as_str()) {
+            return Ok(());
+        }
+
+        let by_name = FilterRef::Name(current.clone());
+        let mut dependents = self
+            .filters
+            .iter()
+            .filter(|(other, filter)| **other != id && filter.to_expr().refs().contains(&&by_name))
+            .map(|(other, _)| format!("#{other}"))
+            .collect::<Vec<_>>();
+        if !dependents.is_empty() {
+            dependents.sort_unstable();
+            bail!(
+                "\"{current}\" is still included by name from filter {}, update those references before renaming it",
+                dependents.join(", ")
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Checks a filter name, which must be usable as `ref: <name>` without
+/// being mistaken for an id.
+pub fn check_name(name: &str) -> Result<()> {
+    if name.trim().is_empty() || name.trim() != name {
+        bail!("filter name must not be empty or start or end with spaces");
+    }
+    if name.chars().count() > MAX_NAME_LEN {
+        bail!("filter name must be at most {MAX_NAME_LEN} characters");
+    }
+    if name.parse::<i64>().is_ok() {
+        bail!("filter name must not be a number, numbers refer to filter ids");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{Field, Op, Pattern, Rule};
+
+    fn leaf(value: &str) -> Expr {
+        Expr::Rule(Rule {
+            field: Field::Name,
+            op: Op::Include,
+            pattern: Pattern::Text {
+                value: value.to_string(),
+            },
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode: None,
+            price_mode: None,
+        })
+    }
+
+    fn filter(expr: Expr) -> Filter {
+        Filter {
+            expr: Some(expr),
+            schema_version: 2,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn resolves_references_by_id_and_name() {
+        let mut library = FilterLibrary::default();
+        library.insert(1, Some("base"), filter(leaf("outfit")));
+        library.insert(
+            2,
+            None,
+            filter(Expr::All(vec![
+                Expr::Ref(FilterRef::Name("base".to_string())),
+                leaf("hair"),
+            ])),
+        );
+        let top = filter(Expr::Not(Box::new(Expr::Ref(FilterRef::Id(2)))));
+
+        let resolved = library.resolve(&top, None).unwrap();
+
+        assert!(resolved.to_expr().refs().is_empty());
+        assert_eq!(
+            serde_json::to_value(&resolved).unwrap(),
+            serde_json::to_value(filter(Expr::Not(Box::new(Expr::All(vec![
+                leaf("outfit"),
+                leaf("hair"),
+            ])))))
+            .unwrap()
+        );
+        assert_eq!(library.dependents(1), vec![2]);
+        assert!(library.dependents(2).is_empty());
+    }
+
+    #[test]
+    fn reports_cycles_and_unknown_references() {
+        let mut library = FilterLibrary::default();
+        library.insert(1, Some("a"), filter(Expr::Ref(FilterRef::Id(2))));
+        library.insert(
+            2,
+            Some("b"),
+            filter(Expr::Ref(FilterRef::Name("a".to_string()))),
+        );
+
+        let error = library
+            .resolve(&filter(Expr::Ref(FilterRef::Id(1))), Some(3))
+            .unwrap_err();
+        assert_eq!(error.to_string(), "filter reference cycle: #1 -> #2 -> #1");
+
+        let error = library
+            .resolve(&filter(Expr::Ref(FilterRef::Name("c".to_string()))), None)
+            .unwrap_err();
+        assert_eq!(error.to_string(), "unknown filter reference \"c\"");
+    }
+
+    #[test]
+    fn resolves_shared_references_once() {
+        // Each level includes the one below twice
+        let mut library = FilterLibrary::default();
+        library.insert(1, None, filter(leaf("outfit")));
+        for id in 2..=12 {
+            library.insert(
+                id,
+                None,
+                filter(Expr::Any(vec![
+                    Expr::Ref(FilterRef::Id(id - 1)),
+                    Expr::Not(Box::new(Expr::Ref(FilterRef::Id(id - 1)))),
+                ])),
+            );
+        }
+
+        let mut resolved = HashMap::new();
+        let mut stack = vec![];
+        let expr = library
+            .resolve_expr(&Expr::Ref(FilterRef::Id(12)), &mut stack, &mut resolved)
+            .unwrap();
+
+        assert_eq!(resolved.len(), 12);
+        assert_eq!(
+            serde_json::to_value(&resolved[&12]).unwrap(),
+            serde_json::to_value(&expr).unwrap()
+        );
+        assert!(stack.is_empty());
+        assert!(expr.refs().is_empty());
+    }
+
+    #[test]
+    fn renaming_keeps_name_references_intact() {
+        let mut library = FilterLibrary::default();
+        library.insert(1, Some("base"), filter(leaf("outfit")));
+        library.insert(2, Some("other"), filter(leaf("hair")));
+        library.insert(
+            3,
+            None,
+            filter(Expr::Ref(FilterRef::Name("base".to_string()))),
+        );
+
+        assert!(library.check_rename(1, Some("base")).is_ok());
+        assert!(library.check_rename(2, Some("renamed")).is_ok());
+        assert!(library.check_rename(2, None).is_ok());
+        assert_eq!(
+            library
+                .check_rename(2, Some("base"))
+                .unwrap_err()
+                .to_string(),
+            "filter #1 is already named \"base\""
+        );
+        assert_eq!(
+            library.check_rename(1, None).unwrap_err().to_string(),
+            "\"base\" is still included by name from filter #3, update those references before renaming it"
+        );
+    }
+
+    #[test]
+    fn names_cannot_look_like_ids() {
+        assert!(check_name("vrchat-sfw").is_ok());
+        assert!(check_name("12").is_err());
+        assert!(check_name(" padded").is_err());
+        assert!(check_name("").is_err());
+    }
+}
@@ -1,20 +1,115 @@
+pub mod diff;
 pub mod engine;
+pub mod library;
+pub mod preview;
+pub mod validation;
 
 pub use engine::*;
 
+use std::fmt;
+
+use anyhow::{Result, anyhow};
 use serde::{
     Deserialize, Serialize,
     de::{self, Visitor},
 };
 
+use crate::booth::change::ItemEvent;
+
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Filter {
-    #[serde(default)]
+    /// schema_version 1: every group must match, and a group matches when
+    /// any of its rules does.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
     pub groups: Vec<FilterGroup>,
+    /// schema_version 2: an arbitrary boolean expression over rules.
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        with = "serde_yaml::with::singleton_map_recursive"
+    )]
+    pub expr: Option<Expr>,
+    /// What happening to a matching item is posted; only new items when
+    /// empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub events: Vec<ItemEvent>,
     #[serde(default = "default_schema_version")]
     pub schema_version: u32,
 }
 
+impl Filter {
+    /// Returns the filter as an expression tree, upgrading v1 `groups` into
+    /// an `all` of `any` nodes when no `expr` is present.
+    pub fn to_expr(&self) -> Expr {
+        match &self.expr {
+            Some(expr) => expr.clone(),
+            None => Expr::All(
+                self.groups
+                    .iter()
+                    .map(|group| Expr::Any(group.rules.iter().cloned().map(Expr::Rule).collect()))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Converts the filter into an equivalent schema_version 2 filter.
+    pub fn upgraded(&self) -> Filter {
+        Filter {
+            groups: vec![],
+            expr: Some(self.to_expr()),
+            events: self.events.clone(),
+            schema_version: 2,
+        }
+    }
+
+    /// Whether the filter posts items on `event`.
+    pub fn subscribes(&self, event: ItemEvent) -> bool {
+        if self.events.is_empty() {
+            event == ItemEvent::NewItem
+        } else {
+            self.events.contains(&event)
+        }
+    }
+}
+
+/// A node of a schema_version 2 filter expression.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Expr {
+    /// Matches when every child matches; an empty `all` always matches.
+    All(Vec<Expr>),
+    /// Matches when at least one child matches; an empty `any` never matches.
+    Any(Vec<Expr>),
+    Not(Box<Expr>),
+    Rule(Rule),
+    /// Includes another saved filter of the same guild, resolved through a
+    /// [`library::FilterLibrary`].
+    Ref(FilterRef),
+}
+
+impl Expr {
+    /// Returns every reference in the expression, in document order.
+    pub fn refs(&self) -> Vec<&FilterRef> {
+        match self {
+            Expr::All(children) | Expr::Any(children) => {
+                children.iter().flat_map(Expr::refs).collect()
+            }
+            Expr::Not(child) => child.refs(),
+            Expr::Rule(_) => vec![],
+            Expr::Ref(filter_ref) => vec![filter_ref],
+        }
+    }
+}
+
+/// Points at a saved filter, either by id (`ref: 12`) or by its name
+/// (`ref: vrchat-sfw`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum FilterRef {
+    Id(i64),
+    Name(String),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct FilterGroup {
     #[serde(default)]
@@ -32,6 +127,62 @@ pub struct Rule {
     pub regex_flags: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tag_mode: Option<TagMode>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub price_mode: Option<PriceMode>,
+}
+
+impl Rule {
+    /// Parses `regex_flags`, treating a missing value as no flags.
+    pub fn parsed_regex_flags(&self) -> Result<RegexFlags> {
+        self.regex_flags
+            .as_deref()
+            .map_or(Ok(RegexFlags::default()), str::parse)
+    }
+}
+
+/// Flags accepted in `Rule.regex_flags`, written as a string of letters
+/// such as `ms`:
+///
+/// - `i`: case-insensitive, regardless of `case_sensitive`
+/// - `m`: multi-line, `^`/`$` match at line boundaries
+/// - `s`: dot-all, `.` also matches `\n`
+/// - `x`: extended, whitespace and `#` comments in the pattern are ignored
+/// - `a`: ASCII-only, `\w`, `\d` and case folding ignore Unicode. As `.`
+///   could then match a lone byte of a multi-byte character, a pattern
+///   using `.` with `a` does not compile and is rejected when saving.
+/// - `u`: Unicode, as regex's own `(?u)`; the default, so it only conflicts
+///   with `a`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RegexFlags {
+    pub case_insensitive: bool,
+    pub multi_line: bool,
+    pub dot_matches_new_line: bool,
+    pub ignore_whitespace: bool,
+    pub ascii_only: bool,
+}
+
+impl std::str::FromStr for RegexFlags {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        let mut flags = Self::default();
+        let mut unicode = false;
+        for flag in value.chars().filter(|c| !c.is_whitespace() && *c != ',') {
+            match flag {
+                'i' => flags.case_insensitive = true,
+                'm' => flags.multi_line = true,
+                's' => flags.dot_matches_new_line = true,
+                'x' => flags.ignore_whitespace = true,
+                'a' => flags.ascii_only = true,
+                'u' => unicode = true,
+                other => return Err(anyhow!("unknown regex flag '{other}'")),
+            }
+        }
+        if flags.ascii_only && unicode {
+            return Err(anyhow!("regex flags 'a' and 'u' cannot be combined"));
+        }
+        Ok(flags)
+    }
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -41,6 +192,9 @@ pub enum Field {
     Name,
     Description,
     Category,
+    Price,
+    ShopName,
+    ShopUrl,
 }
 
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
@@ -57,6 +211,18 @@ pub enum TagMode {
     All,
 }
 
+/// Which price a price rule compares against.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PriceMode {
+    /// The listed item price.
+    Item,
+    /// The cheapest variation, falling back to the item price.
+    Min,
+    /// The most expensive variation, falling back to the item price.
+    Max,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Pattern {
@@ -68,6 +234,127 @@ pub enum Pattern {
         #[serde(deserialize_with = "deserialize_string_value")]
         value: String,
     },
+    /// Matches when the value equals any entry of the list.
+    OneOf {
+        #[serde(deserialize_with = "deserialize_string_values")]
+        values: Vec<String>,
+    },
+    Lt {
+        value: i64,
+    },
+    Lte {
+        value: i64,
+    },
+    Gt {
+        value: i64,
+    },
+    Gte {
+        value: i64,
+    },
+    Between {
+        min: i64,
+        max: i64,
+    },
+}
+
+impl Pattern {
+    /// Whether the pattern compares numbers rather than matching text.
+    pub fn is_numeric(&self) -> bool {
+        matches!(
+            self,
+            Pattern::Lt { .. }
+                | Pattern::Lte { .. }
+                | Pattern::Gt { .. }
+                | Pattern::Gte { .. }
+                | Pattern::Between { .. }
+        )
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.field)?;
+        if let Some(tag_mode) = self.tag_mode {
+            write!(f, " ({tag_mode})")?;
+        }
+        if let Some(price_mode) = self.price_mode {
+            write!(f, " ({price_mode})")?;
+        }
+        write!(f, " {} {}", self.op, self.pattern)?;
+        if let Some(regex_flags) = &self.regex_flags {
+            write!(f, " [{regex_flags}]")?;
+        }
+        if self.case_sensitive && !self.pattern.is_numeric() {
+            write!(f, " (case-sensitive)")?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for FilterRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FilterRef::Id(id) => write!(f, "#{id}"),
+            FilterRef::Name(name) => write!(f, "\"{name}\""),
+        }
+    }
+}
+
+impl fmt::Display for Field {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Field::Tags => "tags",
+            Field::Name => "name",
+            Field::Description => "description",
+            Field::Category => "category",
+            Field::Price => "price",
+            Field::ShopName => "shop_name",
+            Field::ShopUrl => "shop_url",
+        })
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Op::Include => "include",
+            Op::Exclude => "exclude",
+        })
+    }
+}
+
+impl fmt::Display for TagMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TagMode::Any => "any",
+            TagMode::All => "all",
+        })
+    }
+}
+
+impl fmt::Display for PriceMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PriceMode::Item => "item",
+            PriceMode::Min => "min",
+            PriceMode::Max => "max",
+        })
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Pattern::Text { value } => write!(f, "text {value:?}"),
+            Pattern::Regex { value } => write!(f, "regex {value:?}"),
+            Pattern::OneOf { values } => write!(f, "one_of [{}]", values.join(", ")),
+            Pattern::Lt { value } => write!(f, "< {value}"),
+            Pattern::Lte { value } => write!(f, "<= {value}"),
+            Pattern::Gt { value } => write!(f, "> {value}"),
+            Pattern::Gte { value } => write!(f, ">= {value}"),
+            Pattern::Between { min, max } => write!(f, "between {min} and {max}"),
+        }
+    }
 }
 
 fn deserialize_string_value<'de, D>(deserializer: D) -> Result<String, D::Error>
@@ -118,6 +405,17 @@ where
     deserializer.deserialize_any(StringValueVisitor)
 }
 
+fn deserialize_string_values<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    struct StringValue(#[serde(deserialize_with = "deserialize_string_value")] String);
+
+    let values = Vec::<StringValue>::deserialize(deserializer)?;
+    Ok(values.into_iter().map(|value| value.0).collect())
+}
+
 fn default_schema_version() -> u32 {
     1
 }
@@ -149,4 +447,199 @@ schema_version: 1
 
         assert_eq!(value, "3470989");
     }
+
+    #[test]
+    fn price_range_patterns_deserialize() {
+        let filter: Filter = serde_yaml::from_str(
+            r#"
+groups:
+- rules:
+  - field: price
+    op: include
+    pattern:
+      type: between
+      min: 500
+      max: 3000
+    price_mode: min
+schema_version: 1
+"#,
+        )
+        .unwrap();
+
+        let rule = &filter.groups[0].rules[0];
+        assert_eq!(rule.field, Field::Price);
+        assert_eq!(
+            rule.pattern,
+            Pattern::Between {
+                min: 500,
+                max: 3000
+            }
+        );
+        assert_eq!(rule.price_mode, Some(PriceMode::Min));
+    }
+
+    #[test]
+    fn one_of_values_deserialize_as_strings() {
+        let filter: Filter = serde_yaml::from_str(
+            r#"
+groups:
+- rules:
+  - field: shop_url
+    op: include
+    pattern:
+      type: one_of
+      values:
+      - example
+      - 12345
+schema_version: 1
+"#,
+        )
+        .unwrap();
+
+        let rule = &filter.groups[0].rules[0];
+        assert_eq!(rule.field, Field::ShopUrl);
+        assert_eq!(
+            rule.pattern,
+            Pattern::OneOf {
+                values: vec!["example".to_string(), "12345".to_string()]
+            }
+        );
+    }
+
+    #[test]
+    fn regex_flags_parse() {
+        let flags: RegexFlags = "m s,x".parse().unwrap();
+        assert_eq!(
+            flags,
+            RegexFlags {
+                multi_line: true,
+                dot_matches_new_line: true,
+                ignore_whitespace: true,
+                ..Default::default()
+            }
+        );
+
+        assert!("mq".parse::<RegexFlags>().is_err());
+    }
+
+    #[test]
+    fn rules_display_readably() {
+        let rule = Rule {
+            field: Field::Tags,
+            op: Op::Exclude,
+            pattern: Pattern::Regex {
+                value: "R-?18".to_string(),
+            },
+            case_sensitive: true,
+            regex_flags: Some("m".to_string()),
+            tag_mode: Some(TagMode::All),
+            price_mode: None,
+        };
+
+        assert_eq!(
+            rule.to_string(),
+            r#"tags (all) exclude regex "R-?18" [m] (case-sensitive)"#
+        );
+    }
+
+    #[test]
+    fn expression_filters_round_trip() {
+        let yaml = r#"
+expr:
+  any:
+  - all:
+    - rule:
+        field: tags
+        op: include
+        pattern:
+          type: text
+          value: Avatar A
+    - not:
+        rule:
+          field: name
+          op: include
+          pattern:
+            type: text
+            value: R-18
+  - rule:
+      field: shop_name
+      op: include
+      pattern:
+        type: text
+        value: Shop X
+schema_version: 2
+"#;
+        let filter: Filter = serde_yaml::from_str(yaml).unwrap();
+
+        let Some(Expr::Any(children)) = &filter.expr else {
+            panic!("expected any expression");
+        };
+        assert!(matches!(&children[0], Expr::All(inner) if matches!(inner[1], Expr::Not(_))));
+        assert!(matches!(&children[1], Expr::Rule(rule) if rule.field == Field::ShopName));
+
+        let serialized = serde_yaml::to_string(&filter).unwrap();
+        assert!(!serialized.contains('!'));
+        assert!(!serialized.contains("groups"));
+        let reparsed: Filter = serde_yaml::from_str(&serialized).unwrap();
+        assert_eq!(
+            serde_json::to_value(&reparsed).unwrap(),
+            serde_json::to_value(&filter).unwrap()
+        );
+    }
+
+    #[test]
+    fn references_deserialize_by_id_or_name() {
+        let filter: Filter = serde_yaml::from_str(
+            r#"
+expr:
+  all:
+  - ref: 12
+  - not:
+      ref: nsfw-bait
+  - ref: "34"
+schema_version: 2
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            filter.expr.as_ref().unwrap().refs(),
+            vec![
+                &FilterRef::Id(12),
+                &FilterRef::Name("nsfw-bait".to_string()),
+                &FilterRef::Name("34".to_string()),
+            ]
+        );
+        assert!(
+            serde_yaml::to_string(&filter)
+                .unwrap()
+                .contains("- ref: 12")
+        );
+    }
+
+    #[test]
+    fn v1_filters_upgrade_to_expressions() {
+        let filter: Filter = serde_yaml::from_str(
+            r#"
+groups:
+- rules:
+  - field: tags
+    op: include
+    pattern:
+      type: text
+      value: VRChat
+schema_version: 1
+"#,
+        )
+        .unwrap();
+
+        let upgraded = filter.upgraded();
+
+        assert_eq!(upgraded.schema_version, 2);
+        assert!(upgraded.groups.is_empty());
+        let Some(Expr::All(groups)) = &upgraded.expr else {
+            panic!("expected all expression");
+        };
+        assert!(matches!(&groups[0], Expr::Any(rules) if rules.len() == 1));
+    }
 }
@@ -0,0 +1,131 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::Result;
+use sqlx::types::time::OffsetDateTime;
+
+use crate::{
+    booth::item::BoothItem,
+    database::{DatabaseClient, ItemSnapshot},
+    filter::FilteringEngine,
+};
+
+/// How far back a preview looks for item snapshots.
+pub const PREVIEW_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 3);
+
+/// Default number of recent items a preview runs the filter over.
+pub const DEFAULT_PREVIEW_ITEMS: usize = 200;
+
+/// The result of running a filter over recently seen items.
+#[derive(Debug)]
+pub struct Preview {
+    /// Number of distinct items the filter was evaluated against.
+    pub scanned: usize,
+    /// Matching items, newest first.
+    pub matches: Vec<BoothItem>,
+}
+
+/// Runs `engine` over the latest snapshots of up to `limit` recently seen
+/// items, without notifying anything.
+pub async fn preview_filter(
+    db: &DatabaseClient,
+    engine: &FilteringEngine,
+    limit: usize,
+) -> Result<Preview> {
+    let since = OffsetDateTime::now_utc() - PREVIEW_WINDOW;
+    let snapshots = db.get_latest_snapshots(since, limit as i64).await?;
+
+    Ok(match_snapshots(engine, snapshots, limit))
+}
+
+fn match_snapshots(
+    engine: &FilteringEngine,
+    snapshots: Vec<ItemSnapshot>,
+    limit: usize,
+) -> Preview {
+    let mut seen = HashSet::new();
+    let mut matches = vec![];
+
+    // Snapshots arrive newest first, so the first one per item is its latest.
+    for snapshot in snapshots {
+        if seen.len() >= limit {
+            break;
+        }
+        if !seen.insert(snapshot.item_id) {
+            continue;
+        }
+        let Ok(item) = BoothItem::from_snapshot(snapshot.payload) else {
+            continue;
+        };
+        if engine.check(&item) {
+            matches.push(item);
+        }
+    }
+
+    Preview {
+        scanned: seen.len(),
+        matches,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{Field, Filter, FilterGroup, Op, Pattern, Rule};
+
+    fn snapshot(item_id: i64, name: &str) -> ItemSnapshot {
+        let item = BoothItem {
+            id: item_id as u64,
+            name: name.to_string(),
+            ..Default::default()
+        };
+        ItemSnapshot {
+            id: item_id,
+            fetched_at: OffsetDateTime::UNIX_EPOCH,
+            item_id,
+            name: name.to_string(),
+            payload: serde_json::to_value(&item).unwrap(),
+        }
+    }
+
+    #[test]
+    fn matches_latest_snapshot_per_item_up_to_limit() {
+        let engine = FilteringEngine::new(Filter {
+            groups: vec![FilterGroup {
+                rules: vec![Rule {
+                    field: Field::Name,
+                    op: Op::Include,
+                    pattern: Pattern::Text {
+                        value: "outfit".to_string(),
+                    },
+                    case_sensitive: false,
+                    regex_flags: None,
+                    tag_mode: None,
+                    price_mode: None,
+                }],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        });
+
+        let snapshots = vec![
+            snapshot(3, "Outfit C"),
+            snapshot(2, "Hair B"),
+            snapshot(3, "Old name"),
+            snapshot(1, "Outfit A"),
+            snapshot(0, "Outfit Z"),
+        ];
+
+        let preview = match_snapshots(&engine, snapshots, 3);
+
+        assert_eq!(preview.scanned, 3);
+        assert_eq!(
+            preview
+                .matches
+                .iter()
+                .map(|item| item.id)
+                .collect::<Vec<_>>(),
+            vec![3, 1]
+        );
+    }
+}
@@ -0,0 +1,400 @@
+use std::fmt;
+
+use crate::filter::{Expr, Field, Filter, FilterRef, Pattern, Rule, build_regex};
+
+/// Schema versions this build knows how to evaluate.
+pub const SUPPORTED_SCHEMA_VERSIONS: &[u32] = &[1, 2];
+
+/// A single problem found in a filter definition.
+///
+/// `group` and `rule` are zero-based indices into a schema_version 1
+/// filter; they are `None` when the problem concerns the filter or the group
+/// as a whole. `path` locates a node of a schema_version 2 `expr` by the
+/// zero-based child index at each level, with `Some(vec![])` for the root.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub group: Option<usize>,
+    pub rule: Option<usize>,
+    pub path: Option<Vec<usize>>,
+    pub message: String,
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(path) = &self.path {
+            if path.is_empty() {
+                return write!(f, "expr: {}", self.message);
+            }
+            let path = path
+                .iter()
+                .map(|index| (index + 1).to_string())
+                .collect::<Vec<_>>();
+            return write!(f, "expr node {}: {}", path.join("."), self.message);
+        }
+
+        match (self.group, self.rule) {
+            (Some(group), Some(rule)) => {
+                write!(
+                    f,
+                    "group {}, rule {}: {}",
+                    group + 1,
+                    rule + 1,
+                    self.message
+                )
+            }
+            (Some(group), None) => write!(f, "group {}: {}", group + 1, self.message),
+            _ => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Every problem found by [`Filter::validate`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationError {
+    pub issues: Vec<ValidationIssue>,
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let issues = self
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        write!(f, "{}", issues.join("\n"))
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+impl Filter {
+    /// Checks the filter for mistakes that would otherwise only show up as
+    /// rules silently never matching, and reports all of them at once.
+    pub fn validate(&self) -> Result<(), ValidationError> {
+        let mut issues = vec![];
+        let mut issue = |group, rule, message: String| {
+            issues.push(ValidationIssue {
+                group,
+                rule,
+                path: None,
+                message,
+            })
+        };
+
+        if !SUPPORTED_SCHEMA_VERSIONS.contains(&self.schema_version) {
+            issue(
+                None,
+                None,
+                format!("unknown schema_version {}", self.schema_version),
+            );
+        }
+
+        if self.schema_version == 2 {
+            if !self.groups.is_empty() {
+                issue(
+                    None,
+                    None,
+                    "groups are not used in schema_version 2, use expr".to_string(),
+                );
+            }
+            match &self.expr {
+                Some(expr) => expr_issues(expr, &mut vec![], &mut issues),
+                None => issue(
+                    None,
+                    None,
+                    "schema_version 2 filters need an expr".to_string(),
+                ),
+            }
+        } else {
+            if self.expr.is_some() {
+                issue(None, None, "expr requires schema_version 2".to_string());
+            }
+
+            if self.groups.is_empty() {
+                issue(
+                    None,
+                    None,
+                    "filter must have at least one group".to_string(),
+                );
+            }
+
+            for (group_index, group) in self.groups.iter().enumerate() {
+                if group.rules.is_empty() {
+                    issue(
+                        Some(group_index),
+                        None,
+                        "group must have at least one rule".to_string(),
+                    );
+                }
+
+                for (rule_index, rule) in group.rules.iter().enumerate() {
+                    for message in rule_issues(rule) {
+                        issue(Some(group_index), Some(rule_index), message);
+                    }
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(ValidationError { issues })
+        }
+    }
+}
+
+fn expr_issues(expr: &Expr, path: &mut Vec<usize>, issues: &mut Vec<ValidationIssue>) {
+    let mut issue = |message: String| {
+        issues.push(ValidationIssue {
+            group: None,
+            rule: None,
+            path: Some(path.clone()),
+            message,
+        })
+    };
+
+    let children = match expr {
+        Expr::All(children) | Expr::Any(children) => {
+            if children.is_empty() {
+                issue(format!(
+                    "{} must have at least one child",
+                    if matches!(expr, Expr::All(_)) {
+                        "all"
+                    } else {
+                        "any"
+                    }
+                ));
+            }
+            children.iter().collect::<Vec<_>>()
+        }
+        Expr::Not(child) => vec![child.as_ref()],
+        Expr::Rule(rule) => {
+            for message in rule_issues(rule) {
+                issue(message);
+            }
+            vec![]
+        }
+        Expr::Ref(FilterRef::Name(name)) => {
+            if name.trim().is_empty() {
+                issue("ref needs a filter id or name".to_string());
+            }
+            vec![]
+        }
+        Expr::Ref(FilterRef::Id(_)) => vec![],
+    };
+
+    for (index, child) in children.into_iter().enumerate() {
+        path.push(index);
+        expr_issues(child, path, issues);
+        path.pop();
+    }
+}
+
+fn rule_issues(rule: &Rule) -> Vec<String> {
+    let mut issues = vec![];
+
+    if rule.tag_mode.is_some() && rule.field != Field::Tags {
+        issues.push("tag_mode only applies to the tags field".to_string());
+    }
+    if rule.price_mode.is_some() && rule.field != Field::Price {
+        issues.push("price_mode only applies to the price field".to_string());
+    }
+    if rule.regex_flags.is_some() && !matches!(rule.pattern, Pattern::Regex { .. }) {
+        issues.push("regex_flags only applies to regex patterns".to_string());
+    }
+
+    match &rule.pattern {
+        Pattern::Text { value } => {
+            if value.is_empty() {
+                issues.push("pattern value is empty".to_string());
+            }
+        }
+        Pattern::Regex { value } => {
+            if value.is_empty() {
+                issues.push("pattern value is empty".to_string());
+            } else if let Err(e) = rule.parsed_regex_flags() {
+                issues.push(e.to_string());
+            } else if let Err(e) = build_regex(value, rule) {
+                issues.push(format!("invalid regex: {e}"));
+            }
+        }
+        Pattern::OneOf { values } => {
+            if values.is_empty() {
+                issues.push("one_of needs at least one value".to_string());
+            } else if values.iter().any(|value| value.trim().is_empty()) {
+                issues.push("one_of contains an empty value".to_string());
+            }
+        }
+        Pattern::Lt { .. } | Pattern::Lte { .. } | Pattern::Gt { .. } | Pattern::Gte { .. } => {}
+        Pattern::Between { min, max } => {
+            if min > max {
+                issues.push(format!("between has min {min} greater than max {max}"));
+            }
+        }
+    }
+
+    if rule.pattern.is_numeric() && rule.field != Field::Price {
+        issues.push("numeric patterns only apply to the price field".to_string());
+    }
+
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::{FilterGroup, Op, TagMode};
+
+    fn rule(field: Field, pattern: Pattern) -> Rule {
+        Rule {
+            field,
+            op: Op::Include,
+            pattern,
+            case_sensitive: false,
+            regex_flags: None,
+            tag_mode: None,
+            price_mode: None,
+        }
+    }
+
+    #[test]
+    fn valid_filter_passes() {
+        let filter = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![
+                    Rule {
+                        tag_mode: Some(TagMode::Any),
+                        ..rule(
+                            Field::Tags,
+                            Pattern::Text {
+                                value: "VRChat".to_string(),
+                            },
+                        )
+                    },
+                    rule(Field::Price, Pattern::Lt { value: 3000 }),
+                ],
+            }],
+            expr: None,
+            events: vec![],
+            schema_version: 1,
+        };
+
+        assert_eq!(filter.validate(), Ok(()));
+    }
+
+    #[test]
+    fn reports_expression_issues_by_path() {
+        let filter = Filter {
+            expr: Some(Expr::All(vec![
+                Expr::Rule(rule(Field::Price, Pattern::Lt { value: 3000 })),
+                Expr::Not(Box::new(Expr::Any(vec![]))),
+                Expr::Any(vec![Expr::Rule(rule(
+                    Field::Name,
+                    Pattern::Between { min: 10, max: 1 },
+                ))]),
+            ])),
+            schema_version: 2,
+            ..Default::default()
+        };
+
+        let issues = filter
+            .validate()
+            .unwrap_err()
+            .issues
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            issues,
+            vec![
+                "expr node 2.1: any must have at least one child",
+                "expr node 3.1: between has min 10 greater than max 1",
+                "expr node 3.1: numeric patterns only apply to the price field",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_mixed_schema_versions() {
+        let v1_with_expr = Filter {
+            groups: vec![FilterGroup {
+                rules: vec![rule(Field::Price, Pattern::Lt { value: 3000 })],
+            }],
+            expr: Some(Expr::All(vec![])),
+            events: vec![],
+            schema_version: 1,
+        };
+        let v2_without_expr = Filter {
+            schema_version: 2,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            v1_with_expr.validate().unwrap_err().to_string(),
+            "expr requires schema_version 2"
+        );
+        assert_eq!(
+            v2_without_expr.validate().unwrap_err().to_string(),
+            "schema_version 2 filters need an expr"
+        );
+        let v1 = Filter {
+            expr: None,
+            ..v1_with_expr
+        };
+        assert_eq!(v1.upgraded().validate(), Ok(()));
+    }
+
+    #[test]
+    fn reports_every_issue_with_location() {
+        let filter = Filter {
+            groups: vec![
+                FilterGroup {
+                    rules: vec![
+                        rule(
+                            Field::Name,
+                            Pattern::Regex {
+                                value: "(unclosed".to_string(),
+                            },
+                        ),
+                        Rule {
+                            tag_mode: Some(TagMode::All),
+                            ..rule(
+                                Field::Description,
+                                Pattern::Text {
+                                    value: String::new(),
+                                },
+                            )
+                        },
+                    ],
+                },
+                FilterGroup { rules: vec![] },
+            ],
+            expr: None,
+            events: vec![],
+            schema_version: 99,
+        };
+
+        let issues = filter.validate().unwrap_err().issues;
+        let locations = issues
+            .iter()
+            .map(|issue| (issue.group, issue.rule))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            locations,
+            vec![
+                (None, None),
+                (Some(0), Some(0)),
+                (Some(0), Some(1)),
+                (Some(0), Some(1)),
+                (Some(1), None),
+            ]
+        );
+        assert!(issues[1].message.starts_with("invalid regex"));
+        assert_eq!(
+            issues[4].to_string(),
+            "group 2: group must have at least one rule"
+        );
+    }
+}
@@ -4,8 +4,11 @@ mod database;
 mod event_handler;
 mod filter;
 mod task;
+mod template;
 mod web;
 
+use std::sync::atomic::AtomicBool;
+
 use anyhow::Result;
 use database::DatabaseClient;
 use event_handler::event_handler;
@@ -21,7 +24,9 @@ use crate::{
         avatar::avatar_command,
         notification::booth_command,
         register::{register, register_server},
+        watch::watch_command,
     },
+    task::{NsfwCache, QueueDepth},
 };
 
 type Error = Box<dyn std::error::Error + Send + Sync>;
@@ -30,6 +35,11 @@ type Context<'a> = poise::Context<'a, Data, Error>;
 pub struct Data {
     pub db: DatabaseClient,
     pub booth_db: BoothDbClient,
+    pub nsfw_cache: NsfwCache,
+    pub queue_depth: QueueDepth,
+    /// Set once the background loops are running; `Ready` fires again after
+    /// every reconnect.
+    pub tasks_started: AtomicBool,
 }
 
 #[tokio::main]
@@ -67,7 +77,14 @@ async fn main() -> Result<()> {
 
     info!("Database connected and migrations completed");
 
-    let web_task = match web::WebConfig::from_env(db.clone(), token.clone(), owner_ids.clone())? {
+    let queue_depth = QueueDepth::default();
+
+    let web_task = match web::WebConfig::from_env(
+        db.clone(),
+        token.clone(),
+        owner_ids.clone(),
+        queue_depth.clone(),
+    )? {
         Some(config) => Some(tokio::spawn(async move {
             if let Err(err) = web::serve(config).await {
                 error!(error = %err, "web UI stopped");
@@ -80,7 +97,17 @@ async fn main() -> Result<()> {
     };
 
     let framework = poise::Framework::builder()
-        .setup(move |_ctx, _ready, _framework| Box::pin(async move { Ok(Data { db, booth_db }) }))
+        .setup(move |_ctx, _ready, _framework| {
+            Box::pin(async move {
+                Ok(Data {
+                    db,
+                    booth_db,
+                    nsfw_cache: NsfwCache::default(),
+                    queue_depth,
+                    tasks_started: AtomicBool::new(false),
+                })
+            })
+        })
         .options(poise::FrameworkOptions {
             event_handler: |ctx, event, framework, data| {
                 Box::pin(event_handler(ctx, event, framework, data))
@@ -91,6 +118,7 @@ async fn main() -> Result<()> {
                 booth_command(),
                 register(),
                 register_server(),
+                watch_command(),
             ],
             prefix_options: PrefixFrameworkOptions {
                 prefix: Some(prefix),
@@ -1,5 +1,13 @@
 mod notify_task;
+mod nsfw_cache;
+mod queue_depth;
+mod reconcile;
 mod scraping_task;
 
 pub use notify_task::NotifyTask;
-pub use scraping_task::ScrapingTask;
+pub use nsfw_cache::NsfwCache;
+pub use queue_depth::QueueDepth;
+pub use reconcile::{
+    channel_deleted, channel_updated, guild_available, guild_removed, reconcile_guilds,
+};
+pub use scraping_task::{ChangedItem, ScrapingTask};
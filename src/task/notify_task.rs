@@ -1,168 +1,1292 @@
-use anyhow::Result;
-use poise::serenity_prelude::{self as serenity, CacheHttp, ChannelId, CreateEmbed, CreateMessage};
-use std::collections::HashMap;
-use tracing::{error, info};
+use anyhow::{Result, anyhow, bail};
+use futures::{StreamExt, stream};
+use poise::serenity_prelude::{
+    self as serenity, CacheHttp, ChannelId, ChannelType, CreateAllowedMentions, CreateEmbed,
+    CreateEmbedAuthor, CreateEmbedFooter, CreateMessage, GuildId, Message, Nonce, RoleId,
+    Timestamp,
+};
+use sqlx::types::time::OffsetDateTime;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, hash_map::Entry},
+    sync::Arc,
+    time::Duration,
+};
+use tracing::{error, info, warn};
 
 use crate::{
-    booth::item::BoothItem,
-    database::{DatabaseClient, DiscordChannel, DiscordGuild, models::NotificationFilter},
-    filter::{Filter, FilteringEngine},
+    booth::{change::ItemEvent, item::BoothItem},
+    database::{
+        ContentPolicy, DatabaseClient, DeliveryMode, DigestItem, DiscordChannel, DiscordGuild,
+        ItemChange, NotificationDelivery, UserWatch, models::NotificationFilter,
+    },
+    filter::{Filter, FilteringEngine, library::FilterLibrary},
+    task::{ChangedItem, NsfwCache, QueueDepth},
+    template::{MAX_EMBED_TOTAL_LEN, MessageTemplate, RenderedMessage},
 };
 
+/// How long a delivery may stay pending before it is taken as interrupted.
+const PENDING_DELIVERY_TIMEOUT: Duration = Duration::from_secs(60 * 2);
+
+/// Deliveries tried after a failure, including the first attempt.
+const MAX_DELIVERY_ATTEMPTS: i32 = 5;
+
+/// How many due deliveries are retried per notify cycle.
+const RETRY_BATCH_SIZE: i64 = 50;
+
+/// Failures in a row after which a channel is disabled.
+const CHANNEL_FAILURE_THRESHOLD: i32 = 5;
+
+/// How long a crosspost may wait out Discord's rate limit, 10 an hour per
+/// channel, before it is given up on.
+const CROSSPOST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Discord's limit of embeds in one message, which digests are paginated by
+/// along with `MAX_EMBED_TOTAL_LEN`.
+const MAX_DIGEST_EMBEDS: usize = 10;
+
 pub struct NotifyTask {
-    nsfw_cache: HashMap<i64, bool>,
+    nsfw_cache: NsfwCache,
+    queue_depth: QueueDepth,
+    concurrency: usize,
+    filter_cache: HashMap<i64, CachedFilter>,
+    /// Filters compiled this cycle; the others are dropped from the cache.
+    used_filters: HashSet<i64>,
+    dm_channels: HashMap<i64, ChannelId>,
+}
+
+/// What became of one delivery.
+enum DeliveryOutcome {
+    Sent,
+    Failed(String),
+}
+
+/// An item to notify of, as new or with one of its changes.
+#[derive(Clone, Copy)]
+struct Notification<'a> {
+    item: &'a BoothItem,
+    change: Option<&'a ItemChange>,
+}
+
+impl Notification<'_> {
+    /// The events filters must subscribe to for the notification.
+    fn events(&self) -> Vec<ItemEvent> {
+        match self.change {
+            Some(change) => change.events(),
+            None => vec![ItemEvent::NewItem],
+        }
+    }
+}
+
+/// An item waiting in the queue of the channel it is sent to.
+struct QueuedDelivery<'a> {
+    item: &'a BoothItem,
+    /// The change the item is sent for, if it is not sent as new
+    change: Option<&'a ItemChange>,
+    /// Set for DMs
+    user_id: Option<i64>,
+    /// The registered channel the item matched, which failures count against
+    channel: Option<(&'a DiscordGuild, Arc<DiscordChannel>)>,
+    template: Arc<MessageTemplate>,
+    /// Roles of the matched filters, mentioned on top of the template's
+    role_ids: Vec<i64>,
+    /// Attempt number, set once the delivery is claimed
+    attempts: i32,
+}
+
+/// A delivery due for a retry, with what it is sent with again.
+struct Retry {
+    delivery: NotificationDelivery,
+    item: BoothItem,
+    change: Option<ItemChange>,
+    template: Arc<MessageTemplate>,
+    /// The registered channel it goes to, `None` for fallback channels and
+    /// DMs
+    channel: Option<Arc<DiscordChannel>>,
+}
+
+/// Deliveries grouped by destination channel.
+type DispatchQueue<'a> = BTreeMap<ChannelId, Vec<QueuedDelivery<'a>>>;
+
+/// A compiled filter together with the versions of the row and of the
+/// library it was resolved against, so that a changed row (e.g. after
+/// `update_notification_filter`) or a changed included filter is recompiled.
+struct CachedFilter {
+    updated_at: OffsetDateTime,
+    library: (usize, Option<OffsetDateTime>),
+    engine: Option<Arc<FilteringEngine>>,
 }
 
 impl NotifyTask {
-    pub fn new() -> Self {
+    /// `concurrency` is how many channels are sent to at the same time.
+    pub fn new(nsfw_cache: NsfwCache, queue_depth: QueueDepth, concurrency: usize) -> Self {
         Self {
-            nsfw_cache: HashMap::new(),
+            nsfw_cache,
+            queue_depth,
+            concurrency: concurrency.max(1),
+            filter_cache: HashMap::new(),
+            used_filters: HashSet::new(),
+            dm_channels: HashMap::new(),
         }
     }
 
+    /// Works out where each new item and each change of a known item goes,
+    /// then sends them through the dispatch queue. Fails if any of them could
+    /// not be queued and claimed in the delivery log, so that the run is not
+    /// marked as notified and its items are offered again; those delivered
+    /// already are skipped then.
     pub async fn notify(
         &mut self,
         ctx: &serenity::Context,
         db: &DatabaseClient,
         items: &[BoothItem],
+        changes: &[ChangedItem],
     ) -> Result<()> {
         let guilds = db.get_all_discord_guilds().await?;
 
-        for guild in &guilds {
-            self.process_guild(ctx, db, guild, items).await?;
+        let retries = match self.due_retries(db, &guilds).await {
+            Ok(retries) => retries,
+            Err(e) => {
+                error!("Failed to load deliveries to retry: {:?}", e);
+                vec![]
+            }
+        };
+
+        let mut queue = DispatchQueue::new();
+        for retry in &retries {
+            let delivery = &retry.delivery;
+            info!(
+                "Retrying delivery of item {} to channel {} (attempt {})",
+                delivery.item_id,
+                delivery.channel_id,
+                delivery.attempts + 1
+            );
+            queue
+                .entry(ChannelId::new(delivery.channel_id as u64))
+                .or_default()
+                .push(QueuedDelivery {
+                    item: &retry.item,
+                    change: retry.change.as_ref(),
+                    user_id: delivery.user_id,
+                    channel: retry.channel.as_ref().and_then(|channel| {
+                        let guild = guilds
+                            .iter()
+                            .find(|guild| guild.guild_id == channel.guild_id)?;
+                        Some((guild, channel.clone()))
+                    }),
+                    template: retry.template.clone(),
+                    role_ids: delivery.role_ids.clone(),
+                    attempts: 0,
+                });
+        }
+
+        let notifications = items
+            .iter()
+            .map(|item| Notification { item, change: None })
+            .chain(changes.iter().map(|changed| Notification {
+                item: &changed.item,
+                change: Some(&changed.change),
+            }))
+            .collect::<Vec<_>>();
+
+        // One broken guild must not keep the others from being notified
+        let mut complete = true;
+        for guild in guilds.iter().filter(|guild| guild.active) {
+            if let Err(e) = self
+                .process_guild(ctx, db, guild, &notifications, &mut queue)
+                .await
+            {
+                error!("Failed to process guild '{}': {:?}", guild.guild_id, e);
+                complete = false;
+            }
+        }
+
+        if let Err(e) = self
+            .process_watches(ctx, db, &notifications, &mut queue)
+            .await
+        {
+            error!("Failed to process watches: {:?}", e);
+            complete = false;
+        }
+
+        let (queue, claimed) = self.claim(db, queue).await;
+        complete &= claimed;
+        self.dispatch(ctx, db, queue).await;
+
+        if let Err(e) = self.process_digests(ctx, db, &guilds).await {
+            error!("Failed to process digests: {:?}", e);
+        }
+
+        // Filters that were deleted or are no longer assigned anywhere
+        self.filter_cache
+            .retain(|id, _| self.used_filters.contains(id));
+        self.used_filters.clear();
+
+        if !complete {
+            bail!("not every notification could be queued, leaving the run to be notified again");
         }
         Ok(())
     }
 
-    async fn process_guild(
+    /// Claims every queued delivery in the delivery log before anything is
+    /// sent, so that none is lost if sending stops halfway; an interrupted
+    /// claim is retried once it goes stale. Drops the deliveries that were
+    /// sent already, are being sent or are waiting for their next retry.
+    /// Returns false if any claim failed.
+    async fn claim<'a>(
+        &self,
+        db: &DatabaseClient,
+        queue: DispatchQueue<'a>,
+    ) -> (DispatchQueue<'a>, bool) {
+        let stale_before = OffsetDateTime::now_utc() - PENDING_DELIVERY_TIMEOUT;
+        let mut claimed = DispatchQueue::new();
+        let mut complete = true;
+        for (channel, deliveries) in queue {
+            let channel_id = channel.get() as i64;
+            for mut delivery in deliveries {
+                let item_id = delivery.item.id as i64;
+                let change_id = delivery.change.map_or(0, |change| change.id);
+                match db
+                    .claim_delivery(
+                        item_id,
+                        change_id,
+                        channel_id,
+                        delivery.user_id,
+                        &delivery.role_ids,
+                        stale_before,
+                    )
+                    .await
+                {
+                    Ok(Some(claim)) => {
+                        delivery.attempts = claim.attempts;
+                        claimed.entry(channel).or_default().push(delivery);
+                    }
+                    Ok(None) => info!(
+                        "Item {} was already delivered to channel {}, skipping",
+                        item_id, channel_id
+                    ),
+                    Err(e) => {
+                        error!(
+                            "Failed to claim delivery of item {} to channel {}: {:?}",
+                            item_id, channel_id, e
+                        );
+                        complete = false;
+                    }
+                }
+            }
+        }
+        (claimed, complete)
+    }
+
+    /// Sends the queued deliveries, up to `concurrency` channels at a time.
+    /// Each channel gets one message at a time, oldest item first, which also
+    /// keeps requests within its rate limit bucket; serenity's ratelimiter
+    /// waits out both the per-channel and the global limits.
+    async fn dispatch(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        queue: DispatchQueue<'_>,
+    ) {
+        if queue.is_empty() {
+            return;
+        }
+
+        let deliveries = queue.values().map(Vec::len).sum();
+        self.queue_depth.add(deliveries);
+        info!(
+            "Dispatching {} deliveries to {} channels ({} queued in total)",
+            deliveries,
+            queue.len(),
+            self.queue_depth.get()
+        );
+
+        stream::iter(queue)
+            .for_each_concurrent(self.concurrency, |(channel, deliveries)| {
+                self.dispatch_channel(ctx, db, channel, deliveries)
+            })
+            .await;
+
+        info!("Finished dispatching {} deliveries", deliveries);
+    }
+
+    /// Sends the deliveries queued for one channel in order of publication.
+    /// After a failure the rest are not tried, as they would fail the same
+    /// way, but marked failed with their backoff so they are retried later.
+    async fn dispatch_channel(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        channel: ChannelId,
+        mut deliveries: Vec<QueuedDelivery<'_>>,
+    ) {
+        let failure = send_in_order(&mut deliveries, |delivery| async move {
+            let outcome = self.deliver(ctx, db, channel, delivery).await;
+            self.queue_depth.sub(1);
+            match outcome? {
+                DeliveryOutcome::Sent => Ok(()),
+                DeliveryOutcome::Failed(e) => Err(anyhow!(e)),
+            }
+        })
+        .await;
+
+        let sent = failure
+            .as_ref()
+            .map_or(deliveries.len(), |(index, _)| *index);
+        let registered = deliveries
+            .iter()
+            .find_map(|delivery| delivery.channel.as_ref());
+        if sent > 0
+            && let Some((_, registered)) = registered
+            && registered.failure_count > 0
+            && let Err(e) = db.reset_channel_failures(registered.channel_id).await
+        {
+            warn!(
+                "Failed to reset failures of channel {}: {:?}",
+                registered.channel_id, e
+            );
+        }
+
+        let Some((index, error)) = failure else {
+            return;
+        };
+        let unsent = &deliveries[index + 1..];
+        self.queue_depth.sub(unsent.len());
+        for delivery in unsent {
+            if let Err(e) = postpone_delivery(db, channel, delivery, &error).await {
+                error!(
+                    "Failed to postpone item {} for channel {}: {:?}",
+                    delivery.item.id,
+                    channel.get(),
+                    e
+                );
+            }
+        }
+
+        match registered {
+            Some((guild, registered)) => {
+                if let Err(e) = self
+                    .channel_failed(ctx, db, guild, registered, &error)
+                    .await
+                {
+                    error!(
+                        "Failed to record failure of channel {}: {:?}",
+                        registered.channel_id, e
+                    );
+                }
+            }
+            None => warn!(
+                "Stopped sending to channel {}, postponing {} deliveries: {:#}",
+                channel.get(),
+                unsent.len(),
+                error
+            ),
+        }
+    }
+
+    /// Queues the items for the guild's channels, or new items for its
+    /// fallback channel when none of them matched. Changes are posted right
+    /// away, in digest channels too, as they are about items seen before.
+    async fn process_guild<'a>(
         &mut self,
         ctx: &serenity::Context,
         db: &DatabaseClient,
-        guild: &DiscordGuild,
-        items: &[BoothItem],
+        guild: &'a DiscordGuild,
+        notifications: &[Notification<'a>],
+        queue: &mut DispatchQueue<'a>,
     ) -> Result<()> {
-        let channels = db.get_channels_by_guild(guild.guild_id).await?;
+        let mut channels = db.get_channels_by_guild(guild.guild_id).await?;
+        channels.retain(|channel| channel.active && channel.disabled_at.is_none());
 
-        let filter_ids: Vec<i64> = channels.iter().filter_map(|c| c.filter_id).collect();
-        let filters = db.get_notification_filters_by_ids(&filter_ids).await?;
+        let mut filter_ids: Vec<i64> = channels
+            .iter()
+            .flat_map(|c| c.filter_ids.iter().copied())
+            .collect();
+        filter_ids.sort_unstable();
+        filter_ids.dedup();
+        let mut filters = db.get_notification_filters_by_ids(&filter_ids).await?;
+        // Included filters need not be assigned to any channel
+        for filter in db.get_notification_filters_by_guild(guild.guild_id).await? {
+            filters.entry(filter.id).or_insert(filter);
+        }
+        let library = FilterLibrary::from_saved(filters.values());
+        let filters = self.compile_filters(&filter_ids, &filters, &library);
+
+        // Roles pinged per channel and filter: the binding's own roles, then
+        // the filter's `/booth subscribe` role
+        let subscription_roles = db.get_subscription_roles_by_guild(guild.guild_id).await?;
+        let filter_roles: HashMap<(i64, i64), Vec<i64>> = db
+            .get_channel_filters_by_guild(guild.guild_id)
+            .await?
+            .into_iter()
+            .map(|binding| {
+                let mut role_ids = binding.role_ids;
+                role_ids.extend(subscription_roles.get(&binding.filter_id));
+                ((binding.channel_id, binding.filter_id), role_ids)
+            })
+            .collect();
 
-        for item in items {
+        let guild_template = guild.message_template();
+        let channels = channels
+            .into_iter()
+            .map(|channel| {
+                let template = Arc::new(channel.message_template().or(&guild_template));
+                (Arc::new(channel), template)
+            })
+            .collect::<Vec<_>>();
+        let guild_template = Arc::new(guild_template);
+        let mut failed_channels = HashSet::new();
+        let mut unqueued_digest_items = 0;
+        for notification in notifications {
+            let Notification { item, change } = *notification;
             info!(
                 "Processing item '{}' for guild '{}'",
                 item.name, guild.guild_id
             );
+            let events = notification.events();
             let mut notified = false;
-            for channel in &channels {
-                notified |= self.process_channel(ctx, channel, item, &filters).await?;
+            for (channel, template) in &channels {
+                // A channel that failed is left alone for the rest of the run
+                if failed_channels.contains(&channel.channel_id) {
+                    continue;
+                }
+                let matched = match self
+                    .process_channel(ctx, channel, item, &events, &filters)
+                    .await
+                {
+                    Ok(matched) => matched,
+                    Err(e) => {
+                        failed_channels.insert(channel.channel_id);
+                        if let Err(e) = self.channel_failed(ctx, db, guild, channel, &e).await {
+                            error!(
+                                "Failed to record failure of channel {}: {:?}",
+                                channel.channel_id, e
+                            );
+                        }
+                        continue;
+                    }
+                };
+                if matched.is_empty() {
+                    continue;
+                }
+                notified = true;
+
+                let mut role_ids = vec![];
+                for filter_id in matched {
+                    for role_id in filter_roles
+                        .get(&(channel.channel_id, filter_id))
+                        .into_iter()
+                        .flatten()
+                    {
+                        if !role_ids.contains(role_id) {
+                            role_ids.push(*role_id);
+                        }
+                    }
+                }
+
+                if change.is_none() && channel.delivery_mode() != DeliveryMode::Immediate {
+                    if let Err(e) = db
+                        .queue_digest_item(channel.channel_id, item.id as i64, &role_ids)
+                        .await
+                    {
+                        error!(
+                            "Failed to queue item {} for the digest of channel {}: {:?}",
+                            item.id, channel.channel_id, e
+                        );
+                        unqueued_digest_items += 1;
+                    }
+                } else {
+                    queue
+                        .entry(ChannelId::new(channel.channel_id as u64))
+                        .or_default()
+                        .push(QueuedDelivery {
+                            item,
+                            change,
+                            user_id: None,
+                            channel: Some((guild, channel.clone())),
+                            template: template.clone(),
+                            role_ids,
+                            attempts: 0,
+                        });
+                }
             }
 
-            if notified {
+            if notified || change.is_some() {
                 continue;
             }
 
-            info!(
-                "No channels matched for item '{}' in guild '{}', sending to fallback channel",
-                item.name, guild.guild_id
-            );
-            self.notify_to_fallback_channel(ctx, guild, item).await?;
+            let fallback_channel_id = if item.is_adult {
+                guild.fallback_nsfw_channel_id
+            } else {
+                guild.fallback_channel_id
+            };
+            if let Some(channel_id) = fallback_channel_id {
+                info!(
+                    "No channels matched for item '{}' in guild '{}', sending to fallback channel",
+                    item.name, guild.guild_id
+                );
+                queue
+                    .entry(ChannelId::new(channel_id as u64))
+                    .or_default()
+                    .push(QueuedDelivery {
+                        item,
+                        change: None,
+                        user_id: None,
+                        channel: None,
+                        template: guild_template.clone(),
+                        role_ids: vec![],
+                        attempts: 0,
+                    });
+            }
         }
 
         info!("Finished processing items for guild '{}'", guild.guild_id);
 
+        // The rest of the guild is queued, but the run has to be offered
+        // again for these
+        if unqueued_digest_items > 0 {
+            bail!("failed to queue {unqueued_digest_items} digest items");
+        }
         Ok(())
     }
 
-    async fn notify_to_fallback_channel(
+    /// Posts the digests that are due, one `interval` of the channel's
+    /// delivery mode after its last digest or else its oldest waiting item.
+    /// Items still waiting after a channel went back to immediate delivery
+    /// are posted right away.
+    async fn process_digests(
         &self,
         ctx: &serenity::Context,
+        db: &DatabaseClient,
+        guilds: &[DiscordGuild],
+    ) -> Result<()> {
+        let mut waiting: BTreeMap<i64, Vec<DigestItem>> = BTreeMap::new();
+        for item in db.get_digest_items().await? {
+            waiting.entry(item.channel_id).or_default().push(item);
+        }
+
+        let now = OffsetDateTime::now_utc();
+        let mut due = vec![];
+        for (channel_id, items) in waiting {
+            let Some(channel) = db.get_discord_channel(channel_id).await? else {
+                continue;
+            };
+            let Some(guild) = guilds
+                .iter()
+                .find(|guild| guild.guild_id == channel.guild_id && guild.active)
+            else {
+                continue;
+            };
+            if !channel.active || channel.disabled_at.is_some() {
+                continue;
+            }
+            if let Some(interval) = channel.delivery_mode().interval() {
+                let since = channel.last_digest_at.unwrap_or(items[0].created_at);
+                if since + interval > now {
+                    continue;
+                }
+            }
+            due.push((guild, channel, items));
+        }
+
+        stream::iter(due)
+            .for_each_concurrent(self.concurrency, |(guild, channel, items)| async move {
+                if let Err(e) = self.post_digest(ctx, db, guild, &channel, &items).await
+                    && let Err(e) = self.channel_failed(ctx, db, guild, &channel, &e).await
+                {
+                    error!(
+                        "Failed to record failure of channel {}: {:?}",
+                        channel.channel_id, e
+                    );
+                }
+            })
+            .await;
+
+        Ok(())
+    }
+
+    /// Posts the items waiting for a channel's digest in order of
+    /// publication, `MAX_DIGEST_EMBEDS` to a message.
+    async fn post_digest(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
         guild: &DiscordGuild,
-        item: &BoothItem,
+        channel: &DiscordChannel,
+        waiting: &[DigestItem],
     ) -> Result<()> {
-        let is_nsfw = item.is_adult;
-        let fallback_channel_id = if is_nsfw {
-            guild.fallback_nsfw_channel_id
-        } else {
-            guild.fallback_channel_id
-        };
+        let mut items = vec![];
+        let mut missing = vec![];
+        for digest_item in waiting {
+            let item = db
+                .get_latest_snapshot(digest_item.item_id)
+                .await?
+                .and_then(|snapshot| BoothItem::from_snapshot(snapshot.payload).ok());
+            match item {
+                Some(item) => items.push(item),
+                None => missing.push(digest_item.item_id),
+            }
+        }
+        if !missing.is_empty() {
+            warn!(
+                "Dropping {} digest items without a snapshot from channel {}",
+                missing.len(),
+                channel.channel_id
+            );
+            db.remove_digest_items(channel.channel_id, &missing).await?;
+        }
+        if items.is_empty() {
+            return Ok(());
+        }
+
+        items.sort_by(|a, b| a.published_at.cmp(&b.published_at));
+        let template = channel.message_template().or(&guild.message_template());
+        let item_roles: HashMap<i64, &[i64]> = waiting
+            .iter()
+            .map(|digest_item| (digest_item.item_id, digest_item.role_ids.as_slice()))
+            .collect();
+        let channel_id = ChannelId::new(channel.channel_id as u64);
 
-        if let Some(channel_id) = fallback_channel_id {
-            let channel_id = ChannelId::new(channel_id as u64);
-            let message = self.create_message(item);
-            self.send_message(ctx, channel_id, message).await?;
+        // A page takes items until either of Discord's per-message caps
+        let mut pages: Vec<Vec<(&BoothItem, RenderedMessage)>> = vec![];
+        let mut page_len = 0;
+        for item in &items {
+            let rendered = template.render(item);
+            let len = rendered.embed_len();
+            match pages.last_mut() {
+                Some(page)
+                    if page.len() < MAX_DIGEST_EMBEDS && page_len + len <= MAX_EMBED_TOTAL_LEN =>
+                {
+                    page_len += len;
+                    page.push((item, rendered));
+                }
+                _ => {
+                    page_len = len;
+                    pages.push(vec![(item, rendered)]);
+                }
+            }
+        }
+
+        for (page, page_items) in pages.iter().enumerate() {
+            let item_ids = page_items
+                .iter()
+                .map(|(item, _)| item.id as i64)
+                .collect::<Vec<_>>();
+            // The template's roles, then those of the filters the page's items
+            // matched
+            let mut role_ids = template.role_ids.clone().unwrap_or_default();
+            for role_id in item_ids
+                .iter()
+                .flat_map(|item_id| item_roles.get(item_id).copied().unwrap_or_default())
+            {
+                if !role_ids.contains(&(*role_id as u64)) {
+                    role_ids.push(*role_id as u64);
+                }
+            }
+            let mentions = role_ids
+                .iter()
+                .map(|role_id| format!("<@&{role_id}> "))
+                .collect::<String>();
+            let mut content = format!("{}📬 新着アイテム {}件", mentions, items.len());
+            if pages.len() > 1 {
+                content.push_str(&format!(" ({}/{})", page + 1, pages.len()));
+            }
+
+            let message = CreateMessage::new()
+                .content(content)
+                .allowed_mentions(allowed_mentions(&role_ids))
+                .embeds(
+                    page_items
+                        .iter()
+                        .map(|(_, rendered)| self.item_embed(rendered))
+                        .collect(),
+                )
+                .nonce(Nonce::String(digest_nonce(channel.channel_id, &item_ids)))
+                .enforce_nonce(true);
+            let message = self.send_message(ctx, channel_id, message).await?;
+            db.mark_digest_items_sent(channel.channel_id, &item_ids, message.id.get() as i64)
+                .await?;
+            if let Err(e) = self
+                .crosspost(ctx, db, Some(channel), &message, 0, &item_ids)
+                .await
+            {
+                warn!(
+                    "Failed to crosspost digest to channel {}: {:?}",
+                    channel.channel_id, e
+                );
+            }
+        }
+
+        db.mark_digest_posted(channel.channel_id).await?;
+        if channel.failure_count > 0 {
+            db.reset_channel_failures(channel.channel_id).await?;
+        }
+        info!(
+            "Posted digest of {} items to channel {}",
+            items.len(),
+            channel.channel_id
+        );
+
+        Ok(())
+    }
+
+    /// Queues matching items for users who watch filters, once per user and
+    /// item or change however many of their watches match.
+    async fn process_watches<'a>(
+        &mut self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        notifications: &[Notification<'a>],
+        queue: &mut DispatchQueue<'a>,
+    ) -> Result<()> {
+        let watches = db.get_all_user_watches().await?;
+        if watches.is_empty() {
+            return Ok(());
+        }
+
+        let filter_ids: Vec<i64> = watches.iter().map(|w| w.filter_id).collect();
+        let filters = db.get_notification_filters_by_ids(&filter_ids).await?;
+        let engines = self.compile_watched_filters(db, &filters).await?;
+
+        let dm_template = Arc::new(MessageTemplate::default());
+        let mut watches_by_user: BTreeMap<i64, Vec<&UserWatch>> = BTreeMap::new();
+        for watch in &watches {
+            watches_by_user
+                .entry(watch.user_id)
+                .or_default()
+                .push(watch);
+        }
+
+        for (user_id, watches) in watches_by_user {
+            let matched = notifications
+                .iter()
+                .filter(|notification| {
+                    let item = notification.item;
+                    let events = notification.events();
+                    watches.iter().any(|watch| {
+                        (watch.include_nsfw || !item.is_adult)
+                            && engines.get(&watch.filter_id).is_some_and(|engine| {
+                                events.iter().any(|event| engine.subscribes(*event))
+                                    && engine.check(item)
+                            })
+                    })
+                })
+                .collect::<Vec<_>>();
+            if matched.is_empty() {
+                continue;
+            }
+
+            let channel = match self.dm_channel(ctx, user_id).await {
+                Ok(channel) => channel,
+                Err(e) => {
+                    warn!("Failed to open DM channel for user {}: {}", user_id, e);
+                    continue;
+                }
+            };
+            queue
+                .entry(channel)
+                .or_default()
+                .extend(matched.into_iter().map(|notification| QueuedDelivery {
+                    item: notification.item,
+                    change: notification.change,
+                    user_id: Some(user_id),
+                    channel: None,
+                    template: dm_template.clone(),
+                    role_ids: vec![],
+                    attempts: 0,
+                }));
         }
 
         Ok(())
     }
 
+    /// Compiles watched filters, resolving references among the filters of
+    /// the same guild or the same owner.
+    async fn compile_watched_filters(
+        &mut self,
+        db: &DatabaseClient,
+        filters: &HashMap<i64, NotificationFilter>,
+    ) -> Result<HashMap<i64, Arc<FilteringEngine>>> {
+        let mut libraries: HashMap<(Option<i64>, Option<i64>), FilterLibrary> = HashMap::new();
+        let mut engines = HashMap::new();
+
+        for filter in filters.values() {
+            let scope = (filter.guild_id, filter.owner_id);
+            let library = match libraries.entry(scope) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let saved = match scope {
+                        (Some(guild_id), _) => {
+                            db.get_notification_filters_by_guild(guild_id).await?
+                        }
+                        (None, Some(owner_id)) => {
+                            db.get_notification_filters_by_owner(owner_id).await?
+                        }
+                        (None, None) => vec![filter.clone()],
+                    };
+                    entry.insert(FilterLibrary::from_saved(&saved))
+                }
+            };
+
+            if let Some(engine) = self.compiled_filter(filter, library) {
+                engines.insert(filter.id, engine);
+            }
+        }
+
+        Ok(engines)
+    }
+
+    /// The filters of `channel` that subscribe to any of `events` and match
+    /// `item`, none if its content policy does not allow the item. It is
+    /// posted once, however many match.
     async fn process_channel(
         &mut self,
         ctx: &serenity::Context,
         channel: &DiscordChannel,
         item: &BoothItem,
+        events: &[ItemEvent],
+        filters: &HashMap<i64, Arc<FilteringEngine>>,
+    ) -> Result<Vec<i64>> {
+        let engines: Vec<_> = channel
+            .filter_ids
+            .iter()
+            .filter_map(|filter_id| Some((*filter_id, filters.get(filter_id)?)))
+            .filter(|(_, engine)| events.iter().any(|event| engine.subscribes(*event)))
+            .collect();
+        if engines.is_empty() {
+            return Ok(vec![]);
+        }
+
+        let is_nsfw_channel = self.is_nsfw_channel(ctx, channel.channel_id).await?;
+
+        let policy = channel
+            .content_policy()
+            .unwrap_or(ContentPolicy::default_for(is_nsfw_channel));
+        // Adult items never go to channels that are not age-restricted
+        if item.is_adult && !is_nsfw_channel || !policy.allows(item.is_adult) {
+            return Ok(vec![]);
+        }
+
+        Ok(engines
+            .into_iter()
+            .filter(|(_, engine)| engine.check(item))
+            .map(|(filter_id, _)| filter_id)
+            .collect())
+    }
+
+    fn compile_filters(
+        &mut self,
+        filter_ids: &[i64],
         filters: &HashMap<i64, NotificationFilter>,
-    ) -> Result<bool> {
-        let Some(filter_id) = channel.filter_id else {
-            return Ok(false);
-        };
+        library: &FilterLibrary,
+    ) -> HashMap<i64, Arc<FilteringEngine>> {
+        filter_ids
+            .iter()
+            .filter_map(|id| filters.get(id))
+            .filter_map(|filter| Some((filter.id, self.compiled_filter(filter, library)?)))
+            .collect()
+    }
 
-        let filter: Filter = if let Some(filter) = filters.get(&filter_id) {
-            if let Ok(filter) = serde_yaml::from_str(&filter.rule_yaml) {
-                filter
-            } else {
-                return Ok(false);
+    fn compiled_filter(
+        &mut self,
+        filter: &NotificationFilter,
+        library: &FilterLibrary,
+    ) -> Option<Arc<FilteringEngine>> {
+        self.used_filters.insert(filter.id);
+        if let Some(cached) = self.filter_cache.get(&filter.id)
+            && cached.updated_at == filter.updated_at
+            && cached.library == library.version()
+        {
+            return cached.engine.clone();
+        }
+
+        let resolved = serde_yaml::from_str::<Filter>(&filter.rule_yaml)
+            .map_err(anyhow::Error::from)
+            .and_then(|parsed| library.resolve(&parsed, Some(filter.id)));
+        let engine = match resolved {
+            Ok(resolved) => Some(Arc::new(FilteringEngine::new(resolved))),
+            Err(e) => {
+                warn!("Failed to load filter {}: {}", filter.id, e);
+                None
             }
-        } else {
-            return Ok(false);
         };
 
-        let engine = FilteringEngine::new(filter);
+        self.filter_cache.insert(
+            filter.id,
+            CachedFilter {
+                updated_at: filter.updated_at,
+                library: library.version(),
+                engine: engine.clone(),
+            },
+        );
 
-        let channel_id = ChannelId::new(channel.channel_id as u64);
-        let is_nsfw_channel = self.is_nsfw_channel(ctx, channel.channel_id).await?;
+        engine
+    }
+
+    /// Loads deliveries that failed or were interrupted, e.g. by a restart,
+    /// and are due for a retry, together with their items, the changes they
+    /// are for and the template they are sent with.
+    async fn due_retries(
+        &self,
+        db: &DatabaseClient,
+        guilds: &[DiscordGuild],
+    ) -> Result<Vec<Retry>> {
+        let stale_before = OffsetDateTime::now_utc() - PENDING_DELIVERY_TIMEOUT;
+        let deliveries = db
+            .get_due_deliveries(stale_before, RETRY_BATCH_SIZE)
+            .await?;
 
-        if !is_nsfw_channel && item.is_adult || is_nsfw_channel && !item.is_adult {
-            return Ok(false);
+        let mut retries = vec![];
+        for delivery in deliveries {
+            let item = db
+                .get_latest_snapshot(delivery.item_id)
+                .await?
+                .and_then(|snapshot| BoothItem::from_snapshot(snapshot.payload).ok())
+                .ok_or("item snapshot is missing");
+            let change = match delivery.change_id {
+                0 => Ok(None),
+                change_id => db
+                    .get_item_change(change_id)
+                    .await?
+                    .map(Some)
+                    .ok_or("item change is missing"),
+            };
+            let (item, change) = match (item, change) {
+                (Ok(item), Ok(change)) => (item, change),
+                (Err(error), _) | (_, Err(error)) => {
+                    db.mark_delivery_failed(
+                        delivery.item_id,
+                        delivery.change_id,
+                        delivery.channel_id,
+                        error,
+                        None,
+                    )
+                    .await?;
+                    continue;
+                }
+            };
+
+            let Some((template, channel)) = self.retry_destination(db, guilds, &delivery).await?
+            else {
+                db.mark_delivery_failed(
+                    delivery.item_id,
+                    delivery.change_id,
+                    delivery.channel_id,
+                    "channel is no longer a fallback channel",
+                    None,
+                )
+                .await?;
+                continue;
+            };
+            retries.push(Retry {
+                delivery,
+                item,
+                change,
+                template: Arc::new(template),
+                channel: channel.map(Arc::new),
+            });
         }
 
-        if engine.check(item) {
-            let message = self.create_message(item);
-            self.send_message(ctx, channel_id, message).await?;
+        Ok(retries)
+    }
 
-            Ok(true)
-        } else {
-            Ok(false)
+    /// The template of a retried delivery, with the registered channel it
+    /// goes to so that its failures count against the channel again: the
+    /// channel's template, or its guild's for a fallback channel. `None` for
+    /// a channel that is no longer an active guild's fallback, e.g. after it
+    /// was deleted, whose delivery is given up.
+    async fn retry_destination(
+        &self,
+        db: &DatabaseClient,
+        guilds: &[DiscordGuild],
+        delivery: &NotificationDelivery,
+    ) -> Result<Option<(MessageTemplate, Option<DiscordChannel>)>> {
+        if delivery.user_id.is_some() {
+            return Ok(Some((MessageTemplate::default(), None)));
         }
+
+        let channel_id = delivery.channel_id;
+        if let Some(channel) = db.get_discord_channel(channel_id).await? {
+            let guild_template = guilds
+                .iter()
+                .find(|guild| guild.guild_id == channel.guild_id)
+                .map(DiscordGuild::message_template)
+                .unwrap_or_default();
+            let template = channel.message_template().or(&guild_template);
+            return Ok(Some((template, Some(channel))));
+        }
+
+        Ok(guilds
+            .iter()
+            .find(|guild| {
+                guild.active
+                    && (guild.fallback_channel_id == Some(channel_id)
+                        || guild.fallback_nsfw_channel_id == Some(channel_id))
+            })
+            .map(|guild| (guild.message_template(), None)))
     }
 
-    async fn send_message(
+    /// Sends the claimed item to `channel`, recording the outcome in the
+    /// delivery log. A failed delivery is retried later with backoff.
+    async fn deliver(
         &self,
         ctx: &serenity::Context,
+        db: &DatabaseClient,
         channel: ChannelId,
+        queued: &QueuedDelivery<'_>,
+    ) -> Result<DeliveryOutcome> {
+        let QueuedDelivery {
+            item,
+            change,
+            user_id,
+            template,
+            role_ids,
+            attempts,
+            ..
+        } = queued;
+        let item_id = item.id as i64;
+        let change_id = change.map_or(0, |change| change.id);
+        let channel_id = channel.get() as i64;
+
+        // Discord drops a resend with the same nonce within a few minutes,
+        // covering sends that went through before a crash
+        let message = self
+            .create_message(item, *change, template, role_ids)
+            .nonce(Nonce::String(delivery_nonce(
+                item_id, change_id, channel_id,
+            )))
+            .enforce_nonce(true);
+        match self.send_message(ctx, channel, message).await {
+            Ok(message) => {
+                // Discord has the message, so this must not count as a failed
+                // send, which would post it again
+                if let Err(e) = db
+                    .mark_delivery_sent(item_id, change_id, channel_id, message.id.get() as i64)
+                    .await
+                {
+                    error!(
+                        "Sent item {} to channel {} but failed to record it: {:?}",
+                        item_id, channel_id, e
+                    );
+                }
+                let registered = queued.channel.as_ref().map(|(_, channel)| &**channel);
+                if user_id.is_none()
+                    && let Err(e) = self
+                        .crosspost(ctx, db, registered, &message, change_id, &[item_id])
+                        .await
+                {
+                    warn!(
+                        "Failed to crosspost item {} to channel {}: {:?}",
+                        item_id, channel_id, e
+                    );
+                }
+                Ok(DeliveryOutcome::Sent)
+            }
+            Err(e) => {
+                let next_attempt_at =
+                    retry_delay(*attempts).map(|delay| OffsetDateTime::now_utc() + delay);
+                warn!(
+                    "Failed to deliver item {} to channel {} (attempt {}): {}",
+                    item_id, channel_id, attempts, e
+                );
+                db.mark_delivery_failed(
+                    item_id,
+                    change_id,
+                    channel_id,
+                    &e.to_string(),
+                    next_attempt_at,
+                )
+                .await?;
+                Ok(DeliveryOutcome::Failed(e.to_string()))
+            }
+        }
+    }
+
+    /// Records a failure of `channel`. Once it failed too often in a row the
+    /// channel is disabled and the guild's admins are told.
+    async fn channel_failed(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        guild: &DiscordGuild,
+        channel: &DiscordChannel,
+        error: &anyhow::Error,
+    ) -> Result<()> {
+        warn!(
+            "Channel {} in guild '{}' failed: {:#}",
+            channel.channel_id, guild.guild_id, error
+        );
+        let failures = db
+            .record_channel_failure(channel.channel_id, &format!("{error:#}"))
+            .await?;
+        if failures < CHANNEL_FAILURE_THRESHOLD || !db.disable_channel(channel.channel_id).await? {
+            return Ok(());
+        }
+
+        warn!(
+            "Disabled channel {} in guild '{}' after {} failures",
+            channel.channel_id, guild.guild_id, failures
+        );
+        let message = CreateMessage::new().content(format!(
+            "⚠️ Notifications to <#{}> (`{}`) in **{}** were disabled after {} failed attempts in a row.\nLast error: `{:#}`\n\nCheck that the channel still exists and the bot can post there, then re-enable it with `/booth channel enable` or on the web channels page.",
+            channel.channel_id, channel.name, guild.name, failures, error
+        ));
+        if let Err(e) = self.notify_guild_admins(ctx, guild, message).await {
+            warn!(
+                "Failed to tell the admins of guild '{}' about disabled channel {}: {}",
+                guild.guild_id, channel.channel_id, e
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Posts to the guild's admin channel, or DMs the owner if it has none
+    /// or posting there fails.
+    async fn notify_guild_admins(
+        &self,
+        ctx: &serenity::Context,
+        guild: &DiscordGuild,
+        message: CreateMessage,
+    ) -> Result<()> {
+        if let Some(channel_id) = guild.admin_channel_id {
+            match self
+                .send_message(ctx, ChannelId::new(channel_id as u64), message.clone())
+                .await
+            {
+                Ok(_) => return Ok(()),
+                Err(e) => warn!(
+                    "Failed to post to admin channel {} of guild '{}': {}",
+                    channel_id, guild.guild_id, e
+                ),
+            }
+        }
+
+        self.notify_guild_owner(ctx, guild, message).await
+    }
+
+    async fn notify_guild_owner(
+        &self,
+        ctx: &serenity::Context,
+        guild: &DiscordGuild,
         message: CreateMessage,
     ) -> Result<()> {
+        let owner_id = GuildId::new(guild.guild_id as u64)
+            .to_partial_guild(&ctx.http())
+            .await?
+            .owner_id;
+        owner_id
+            .create_dm_channel(&ctx.http())
+            .await?
+            .send_message(&ctx.http(), message)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        ctx: &serenity::Context,
+        channel: ChannelId,
+        message: CreateMessage,
+    ) -> Result<Message> {
         info!("Sending message to channel {}", channel.get());
         let message = channel.send_message(&ctx.http(), message).await?;
 
-        tokio::spawn({
-            let message = message.clone();
-            let ctx = ctx.clone();
-            async move {
-                if let Err(e) = message.crosspost(&ctx.http()).await {
-                    error!("Failed to crosspost message: {}", e);
+        info!("Message sent to channel {}", channel.get());
+
+        Ok(message)
+    }
+
+    /// Whether messages to `channel` are crossposted: as set for the
+    /// registered channel, else if it is an announcement channel. `registered`
+    /// is looked up when not given, e.g. for retries.
+    async fn should_crosspost(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        channel: ChannelId,
+        registered: Option<&DiscordChannel>,
+    ) -> Result<bool> {
+        let setting = match registered {
+            Some(registered) => registered.crosspost,
+            None => db
+                .get_discord_channel(channel.get() as i64)
+                .await?
+                .and_then(|registered| registered.crosspost),
+        };
+        if let Some(crosspost) = setting {
+            return Ok(crosspost);
+        }
+
+        // From the cache when it has the channel
+        Ok(matches!(
+            channel.to_channel(ctx).await?,
+            serenity::Channel::Guild(channel) if channel.kind == ChannelType::News
+        ))
+    }
+
+    /// Publishes a sent message to the servers following its channel, if it
+    /// is crossposted. The message stays posted if that fails; the failure
+    /// is recorded in the delivery log of `item_ids` for `change_id`.
+    async fn crosspost(
+        &self,
+        ctx: &serenity::Context,
+        db: &DatabaseClient,
+        registered: Option<&DiscordChannel>,
+        message: &Message,
+        change_id: i64,
+        item_ids: &[i64],
+    ) -> Result<()> {
+        if !self
+            .should_crosspost(ctx, db, message.channel_id, registered)
+            .await?
+        {
+            return Ok(());
+        }
+
+        let error =
+            match tokio::time::timeout(CROSSPOST_TIMEOUT, message.crosspost(&ctx.http())).await {
+                Ok(Ok(_)) => {
+                    info!(
+                        "Message crossposted to channel {}",
+                        message.channel_id.get()
+                    );
+                    return Ok(());
                 }
-                info!("Message crossposted to channel {}", channel.get());
-            }
-        });
+                Ok(Err(e)) => e.to_string(),
+                Err(_) => "timed out waiting for the rate limit".to_string(),
+            };
 
-        info!("Message sent to channel {}", channel.get());
+        warn!(
+            "Failed to crosspost message {} in channel {}: {}",
+            message.id.get(),
+            message.channel_id.get(),
+            error
+        );
+        db.mark_crosspost_failed(message.channel_id.get() as i64, change_id, item_ids, &error)
+            .await?;
 
         Ok(())
     }
 
+    async fn dm_channel(&mut self, ctx: &serenity::Context, user_id: i64) -> Result<ChannelId> {
+        if let Some(channel) = self.dm_channels.get(&user_id) {
+            return Ok(*channel);
+        }
+
+        let channel = serenity::UserId::new(user_id as u64)
+            .create_dm_channel(&ctx.http())
+            .await?
+            .id;
+        self.dm_channels.insert(user_id, channel);
+
+        Ok(channel)
+    }
+
     async fn is_nsfw_channel(&mut self, ctx: &serenity::Context, channel_id: i64) -> Result<bool> {
-        if let Some(is_nsfw) = self.nsfw_cache.get(&channel_id) {
+        if let Some(is_nsfw) = self.nsfw_cache.get(channel_id) {
             info!("NSFW cache hit for channel {}: {}", channel_id, is_nsfw);
-            return Ok(*is_nsfw);
+            return Ok(is_nsfw);
         }
 
         let channel = ChannelId::new(channel_id as u64)
@@ -180,38 +1304,319 @@ impl NotifyTask {
         Ok(is_nsfw)
     }
 
-    fn create_message(&self, item: &BoothItem) -> CreateMessage {
-        CreateMessage::new().embed({
-            let mut embed = CreateEmbed::new()
-                .title(item.name.clone())
-                .url(item.url.clone())
-                .description(format!(
-                    "{}\n価格: {}\nタグ: {}",
-                    item.shop.name,
-                    item.price,
-                    self.get_tags_str(item)
-                ));
+    /// The message for `item`, with what changed above it when it is sent
+    /// for a change.
+    fn create_message(
+        &self,
+        item: &BoothItem,
+        change: Option<&ItemChange>,
+        template: &MessageTemplate,
+        role_ids: &[i64],
+    ) -> CreateMessage {
+        let mut rendered = template.render(item);
+        rendered.mention(role_ids.iter().map(|role_id| *role_id as u64));
+        if let Some(change) = change {
+            rendered.content = Some(match rendered.content.take() {
+                Some(content) => format!("{}\n{}", change.summary, content),
+                None => change.summary.clone(),
+            });
+        }
+        let mut message = CreateMessage::new()
+            .embeds(self.create_embeds(&rendered))
+            .allowed_mentions(allowed_mentions(&rendered.role_ids));
+        if let Some(content) = rendered.full_content() {
+            message = message.content(content);
+        }
+        message
+    }
 
-            if let Some(image) = item.images.first() {
-                embed = embed.image(image.original.clone());
-            }
+    /// The item's embed, then one embed per further image with the same URL,
+    /// which Discord shows together as a gallery.
+    fn create_embeds(&self, rendered: &RenderedMessage) -> Vec<CreateEmbed> {
+        let mut embeds = vec![self.item_embed(rendered)];
+        for image in rendered.images.iter().skip(1) {
+            embeds.push(CreateEmbed::new().url(rendered.url.clone()).image(image));
+        }
+        embeds
+    }
 
-            embed
+    fn item_embed(&self, rendered: &RenderedMessage) -> CreateEmbed {
+        let mut author = CreateEmbedAuthor::new(&rendered.author.name).url(&rendered.author.url);
+        if let Some(icon_url) = &rendered.author.icon_url {
+            author = author.icon_url(icon_url);
+        }
+        let mut embed = CreateEmbed::new()
+            .author(author)
+            .title(rendered.title.clone())
+            .url(rendered.url.clone())
+            .description(rendered.description.clone())
+            .fields(
+                rendered
+                    .fields
+                    .iter()
+                    .map(|field| (&field.name, &field.value, field.inline)),
+            );
+
+        if let Some(color) = rendered.color {
+            embed = embed.color(color);
+        }
+        if let Some(footer) = &rendered.footer {
+            embed = embed.footer(CreateEmbedFooter::new(footer));
+        }
+        if let Some(image) = rendered.images.first() {
+            embed = embed.image(image);
+        }
+        if let Some(timestamp) = rendered
+            .timestamp
+            .as_deref()
+            .and_then(|timestamp| Timestamp::parse(timestamp).ok())
+        {
+            embed = embed.timestamp(timestamp);
+        }
+
+        embed
+    }
+}
+
+/// Sends `deliveries` in order of publication until one fails, returning the
+/// index of the failed one, after which none were tried, and its error.
+async fn send_in_order<'d, 'a, F, Fut>(
+    deliveries: &'d mut [QueuedDelivery<'a>],
+    mut send: F,
+) -> Option<(usize, anyhow::Error)>
+where
+    F: FnMut(&'d QueuedDelivery<'a>) -> Fut,
+    Fut: Future<Output = Result<()>>,
+{
+    deliveries.sort_by(|a, b| a.item.published_at.cmp(&b.item.published_at));
+
+    for (index, delivery) in deliveries.iter().enumerate() {
+        if let Err(e) = send(delivery).await {
+            return Some((index, e));
+        }
+    }
+    None
+}
+
+/// Puts off a delivery left unsent after an earlier failure in its channel.
+/// It is retried with the backoff of its last real attempt, which its claim
+/// is not counted as, so a message that keeps failing does not use up the
+/// attempts of those queued behind it.
+async fn postpone_delivery(
+    db: &DatabaseClient,
+    channel: ChannelId,
+    delivery: &QueuedDelivery<'_>,
+    error: &anyhow::Error,
+) -> Result<()> {
+    let next_attempt_at =
+        retry_delay(delivery.attempts - 1).map(|delay| OffsetDateTime::now_utc() + delay);
+    db.postpone_delivery(
+        delivery.item.id as i64,
+        delivery.change.map_or(0, |change| change.id),
+        channel.get() as i64,
+        &format!("not sent after an earlier failure: {error:#}"),
+        next_attempt_at,
+    )
+    .await
+}
+
+/// Backoff before retrying a delivery that failed on attempt `attempts`:
+/// 1, 2, 4, ... minutes, or `None` once it is given up on.
+fn retry_delay(attempts: i32) -> Option<Duration> {
+    (attempts < MAX_DELIVERY_ATTEMPTS)
+        .then(|| Duration::from_secs(60 << (attempts.clamp(1, 16) - 1)))
+}
+
+/// Lets exactly the mentioned roles ping; mentions that come from item text,
+/// like `@everyone` in a name, stay inert.
+fn allowed_mentions(role_ids: &[u64]) -> CreateAllowedMentions {
+    CreateAllowedMentions::new().roles(role_ids.iter().map(|role_id| RoleId::new(*role_id)))
+}
+
+/// A nonce that is the same for every attempt of one delivery, short enough
+/// for Discord's 25 character limit.
+fn delivery_nonce(item_id: i64, change_id: i64, channel_id: i64) -> String {
+    format!("{:x}", fnv1a([item_id, change_id, channel_id]))
+}
+
+/// A nonce for one page of a digest, the same for every attempt to post it.
+fn digest_nonce(channel_id: i64, item_ids: &[i64]) -> String {
+    format!(
+        "{:x}",
+        fnv1a(std::iter::once(channel_id).chain(item_ids.iter().copied()))
+    )
+}
+
+/// 64-bit FNV-1a over the little-endian bytes of `values`. Unlike
+/// `DefaultHasher` it gives the same hash on every Rust release, so a retry
+/// after an upgrade still carries the nonce of the first attempt.
+fn fnv1a(values: impl IntoIterator<Item = i64>) -> u64 {
+    values
+        .into_iter()
+        .flat_map(i64::to_le_bytes)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
         })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queued(item: &BoothItem) -> QueuedDelivery<'_> {
+        QueuedDelivery {
+            item,
+            change: None,
+            user_id: None,
+            channel: None,
+            template: Arc::new(MessageTemplate::default()),
+            role_ids: vec![],
+            attempts: 0,
+        }
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a Postgres DATABASE_URL"]
+    async fn claims_every_queued_delivery_once(pool: sqlx::PgPool) {
+        let db = DatabaseClient::from_pool(pool);
+        let task = NotifyTask::new(NsfwCache::default(), QueueDepth::default(), 1);
+        let [first, second, sent] = [1, 2, 3].map(|id| BoothItem {
+            id,
+            ..Default::default()
+        });
+        let stale_before = OffsetDateTime::now_utc() - PENDING_DELIVERY_TIMEOUT;
+        db.claim_delivery(3, 0, 10, None, &[], stale_before)
+            .await
+            .unwrap();
+        db.mark_delivery_sent(3, 0, 10, 100).await.unwrap();
+
+        let mut queue = DispatchQueue::new();
+        queue.entry(ChannelId::new(10)).or_default().extend([
+            queued(&first),
+            queued(&second),
+            queued(&sent),
+            // e.g. matched in two guilds sharing a fallback channel
+            queued(&first),
+        ]);
+        let (claimed, complete) = task.claim(&db, queue).await;
+
+        assert!(complete);
+        let claimed = &claimed[&ChannelId::new(10)];
+        assert_eq!(
+            claimed
+                .iter()
+                .map(|delivery| (delivery.item.id, delivery.attempts))
+                .collect::<Vec<_>>(),
+            vec![(1, 1), (2, 1)]
+        );
+        // Claimed before sending, so an interrupted run retries them
+        let due = db
+            .get_due_deliveries(OffsetDateTime::now_utc() + Duration::from_secs(1), 10)
+            .await
+            .unwrap();
+        assert_eq!(due.len(), 2);
     }
 
-    fn get_tags_str(&self, item: &BoothItem) -> String {
-        let tags = item
-            .tags
+    #[tokio::test]
+    async fn sends_in_order_of_publication_until_a_failure() {
+        let items = [(1, "03"), (2, "01"), (3, "02")].map(|(id, minute)| BoothItem {
+            id,
+            published_at: format!("2026-01-01T00:{minute}:00+00:00"),
+            ..Default::default()
+        });
+        let mut deliveries = items.iter().map(queued).collect::<Vec<_>>();
+
+        let mut tried = vec![];
+        let failure = send_in_order(&mut deliveries, |delivery| {
+            tried.push(delivery.item.id);
+            let id = delivery.item.id;
+            async move {
+                if id == 3 {
+                    bail!("Missing Permissions");
+                }
+                Ok(())
+            }
+        })
+        .await;
+
+        assert_eq!(tried, vec![2, 3]);
+        let (index, error) = failure.unwrap();
+        assert_eq!(error.to_string(), "Missing Permissions");
+        let unsent = deliveries[index + 1..]
             .iter()
-            .map(|tag| tag.name.clone())
-            .collect::<Vec<String>>()
-            .join(", ");
+            .map(|delivery| delivery.item.id)
+            .collect::<Vec<_>>();
+        assert_eq!(unsent, vec![1]);
+    }
 
-        if tags.len() <= 100 {
-            tags
-        } else {
-            tags.chars().take(100).collect::<String>().to_string() + "..."
-        }
+    #[sqlx::test]
+    #[ignore = "needs a Postgres DATABASE_URL"]
+    async fn postponed_deliveries_are_retried_later(pool: sqlx::PgPool) {
+        let db = DatabaseClient::from_pool(pool.clone());
+        let item = BoothItem {
+            id: 1,
+            ..Default::default()
+        };
+        let stale_before = OffsetDateTime::now_utc() - PENDING_DELIVERY_TIMEOUT;
+        let attempts = db
+            .claim_delivery(1, 0, 10, None, &[], stale_before)
+            .await
+            .unwrap()
+            .unwrap()
+            .attempts;
+        let mut delivery = queued(&item);
+        delivery.attempts = attempts;
+
+        postpone_delivery(
+            &db,
+            ChannelId::new(10),
+            &delivery,
+            &anyhow!("Missing Access"),
+        )
+        .await
+        .unwrap();
+
+        // No longer pending, and not due before its backoff
+        let stale_before = OffsetDateTime::now_utc() + Duration::from_secs(1);
+        assert!(
+            db.get_due_deliveries(stale_before, 10)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let (status, error, next_attempt_at, attempts) =
+            sqlx::query_as::<_, (String, Option<String>, Option<OffsetDateTime>, i32)>(
+                "SELECT status, error, next_attempt_at, attempts FROM notification_deliveries",
+            )
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(status, "failed");
+        // Never tried, so the claim is not counted
+        assert_eq!(attempts, 0);
+        assert_eq!(
+            error.as_deref(),
+            Some("not sent after an earlier failure: Missing Access")
+        );
+        assert!(next_attempt_at.unwrap() > OffsetDateTime::now_utc() + Duration::from_secs(50));
+    }
+
+    #[test]
+    fn retries_back_off_until_given_up() {
+        let delays = (1..=MAX_DELIVERY_ATTEMPTS)
+            .map(|attempts| retry_delay(attempts).map(|delay| delay.as_secs() / 60))
+            .collect::<Vec<_>>();
+
+        assert_eq!(delays, vec![Some(1), Some(2), Some(4), Some(8), None]);
+        assert_eq!(retry_delay(0), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn nonces_are_stable_and_fit_discord() {
+        assert_eq!(fnv1a([]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(delivery_nonce(1, 0, 2), "992024ad58b0cce6");
+        assert_ne!(delivery_nonce(1, 2, 0), delivery_nonce(1, 0, 2));
+        assert_ne!(digest_nonce(1, &[2, 3]), digest_nonce(1, &[3, 2]));
+        assert!(delivery_nonce(i64::MAX, i64::MAX, i64::MAX).len() <= 25);
     }
 }
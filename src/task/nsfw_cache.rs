@@ -0,0 +1,28 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+/// Whether channels are age-restricted, shared between `NotifyTask` and the
+/// gateway event handler, which keeps it in step with channel updates and
+/// deletions.
+#[derive(Debug, Clone, Default)]
+pub struct NsfwCache {
+    channels: Arc<RwLock<HashMap<i64, bool>>>,
+}
+
+impl NsfwCache {
+    pub fn get(&self, channel_id: i64) -> Option<bool> {
+        self.channels.read().unwrap().get(&channel_id).copied()
+    }
+
+    /// Caches whether a channel is age-restricted, returning what was cached
+    /// before.
+    pub fn insert(&self, channel_id: i64, is_nsfw: bool) -> Option<bool> {
+        self.channels.write().unwrap().insert(channel_id, is_nsfw)
+    }
+
+    pub fn remove(&self, channel_id: i64) {
+        self.channels.write().unwrap().remove(&channel_id);
+    }
+}
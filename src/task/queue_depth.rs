@@ -0,0 +1,25 @@
+use std::sync::{
+    Arc,
+    atomic::{AtomicUsize, Ordering},
+};
+
+/// How many notifications are waiting to be sent, shared between `NotifyTask`
+/// and the web UI's status endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct QueueDepth {
+    queued: Arc<AtomicUsize>,
+}
+
+impl QueueDepth {
+    pub fn get(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    pub fn add(&self, count: usize) {
+        self.queued.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn sub(&self, count: usize) {
+        self.queued.fetch_sub(count, Ordering::Relaxed);
+    }
+}
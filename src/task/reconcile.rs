@@ -0,0 +1,110 @@
+use anyhow::Result;
+use poise::serenity_prelude::{self as serenity, CacheHttp, GuildChannel, GuildId, HttpError};
+use tracing::{info, warn};
+
+use crate::{database::DatabaseClient, task::NsfwCache};
+
+/// Handles a channel deleted in Discord.
+pub async fn channel_deleted(
+    db: &DatabaseClient,
+    nsfw_cache: &NsfwCache,
+    guild_id: i64,
+    channel_id: i64,
+) -> Result<()> {
+    nsfw_cache.remove(channel_id);
+    if db.deactivate_channel(guild_id, channel_id).await? {
+        info!(
+            "Channel {} in guild '{}' was deleted, marked inactive",
+            channel_id, guild_id
+        );
+    }
+    Ok(())
+}
+
+/// Handles a channel updated in Discord, e.g. its age restriction toggled, or
+/// seen for the first time, which fills the cache without logging anything.
+pub fn channel_updated(nsfw_cache: &NsfwCache, channel: &GuildChannel) {
+    let channel_id = channel.id.get() as i64;
+    let previous = nsfw_cache.insert(channel_id, channel.nsfw);
+    if previous.is_some_and(|was_nsfw| was_nsfw != channel.nsfw) {
+        info!(
+            "Channel {} is now {}",
+            channel_id,
+            if channel.nsfw { "NSFW" } else { "not NSFW" }
+        );
+    }
+}
+
+/// Handles the bot being removed from a guild.
+pub async fn guild_removed(db: &DatabaseClient, guild_id: i64) -> Result<()> {
+    if db.set_guild_active(guild_id, None, false).await? {
+        info!("Removed from guild '{}', marked inactive", guild_id);
+    }
+    Ok(())
+}
+
+/// Handles a guild the bot is in, e.g. on `GuildCreate`: marks it active and
+/// reconciles its registered channels with `channel_ids`, the channels that
+/// exist in Discord.
+pub async fn guild_available(
+    db: &DatabaseClient,
+    nsfw_cache: &NsfwCache,
+    guild_id: i64,
+    name: Option<&str>,
+    channel_ids: &[i64],
+) -> Result<()> {
+    if !db.set_guild_active(guild_id, name, true).await? {
+        return Ok(());
+    }
+
+    for channel_id in db.reconcile_guild_channels(guild_id, channel_ids).await? {
+        nsfw_cache.remove(channel_id);
+        info!(
+            "Channel {} in guild '{}' no longer exists, marked inactive",
+            channel_id, guild_id
+        );
+    }
+    Ok(())
+}
+
+/// Checks every active guild against Discord, for changes missed while the
+/// bot was offline or events that never arrived.
+pub async fn reconcile_guilds(
+    ctx: &serenity::Context,
+    db: &DatabaseClient,
+    nsfw_cache: &NsfwCache,
+) -> Result<()> {
+    for guild in db.get_all_discord_guilds().await? {
+        if !guild.active {
+            continue;
+        }
+
+        match GuildId::new(guild.guild_id as u64)
+            .channels(&ctx.http())
+            .await
+        {
+            Ok(channels) => {
+                let channel_ids = channels
+                    .keys()
+                    .map(|id| id.get() as i64)
+                    .collect::<Vec<_>>();
+                guild_available(db, nsfw_cache, guild.guild_id, None, &channel_ids).await?;
+            }
+            Err(e) if is_gone(&e) => guild_removed(db, guild.guild_id).await?,
+            Err(e) => warn!(
+                "Failed to fetch channels of guild '{}': {}",
+                guild.guild_id, e
+            ),
+        }
+    }
+    Ok(())
+}
+
+/// Whether Discord refused a guild request because the bot is not in it.
+fn is_gone(error: &serenity::Error) -> bool {
+    matches!(
+        error,
+        serenity::Error::Http(HttpError::UnsuccessfulRequest(response))
+            if matches!(response.status_code.as_u16(), 403 | 404)
+    )
+}
@@ -1,65 +1,207 @@
+use std::time::{Duration, Instant};
+
 use anyhow::Result;
-use tracing::{debug, info};
+use sqlx::types::time::OffsetDateTime;
+use tracing::{debug, info, warn};
 
 use crate::{
-    booth::item::{BoothDbClient, BoothItem},
-    database::{DatabaseClient, NewFetchRun, NewItemSnapshot},
+    booth::{
+        change::{detect_changes, tracked_state_changed},
+        item::{BoothDbClient, BoothItem},
+    },
+    database::{DatabaseClient, ItemChange, NewFetchRun, NewItemChange, NewItemSnapshot},
 };
 
+/// How long after they were first seen items are fetched again for changes.
+const RECHECK_WINDOW: Duration = Duration::from_secs(60 * 60 * 24 * 30);
+
+/// A known item that changed since its last snapshot.
+pub struct ChangedItem {
+    pub change: ItemChange,
+    pub item: BoothItem,
+}
+
 pub struct ScrapingTask {
     booth_db: BoothDbClient,
-    last_run_item_ids: Vec<u64>,
+    /// Items of the last run that was notified, which later runs are
+    /// compared against until they are notified themselves
+    notified_item_ids: Vec<u64>,
+    /// The run whose items are being notified, marked once that is done
+    pending_run_id: Option<i64>,
+    pending_item_ids: Vec<u64>,
+    /// Changes found by rechecks that were not notified yet
+    pending_changes: Vec<ChangedItem>,
+    /// How often known items are fetched again; zero never does
+    recheck_interval: Duration,
+    /// How many known items are fetched again at a time
+    recheck_batch_size: i64,
+    last_recheck_at: Option<Instant>,
 }
 
 impl ScrapingTask {
-    pub fn new(booth_db: BoothDbClient) -> Self {
+    pub fn new(
+        booth_db: BoothDbClient,
+        recheck_interval: Duration,
+        recheck_batch_size: i64,
+    ) -> Self {
         Self {
             booth_db,
-            last_run_item_ids: vec![],
+            notified_item_ids: vec![],
+            pending_run_id: None,
+            pending_item_ids: vec![],
+            pending_changes: vec![],
+            recheck_interval,
+            recheck_batch_size,
+            last_recheck_at: None,
         }
     }
 
     pub async fn run(&mut self, db: &DatabaseClient) -> Result<Vec<BoothItem>> {
         debug!("Starting scraping task");
-        if self.last_run_item_ids.is_empty()
-            && let Some(run) = db.get_latest_fetch_runs(1).await?.first()
+        // Nothing is marked as notified unless this run gets that far
+        self.pending_run_id = None;
+
+        // Compare against the last run that was notified, so that items of a
+        // run that failed to notify or was interrupted by a restart are
+        // notified again; the delivery log keeps them from being sent twice
+        if self.notified_item_ids.is_empty()
+            && let Some(run) = db.get_latest_notified_fetch_run().await?
         {
-            self.last_run_item_ids = run.item_ids.iter().map(|id| *id as u64).collect();
+            self.notified_item_ids = run.item_ids.iter().map(|id| *id as u64).collect();
         }
 
         let item_ids = self.booth_db.get_recent_item_ids().await?;
         let new_item_ids = self.calc_new_item_ids(&item_ids);
 
-        db.create_fetch_run(NewFetchRun {
-            item_ids: item_ids.iter().map(|id| *id as i64).collect(),
-        })
-        .await?;
-
-        self.last_run_item_ids = item_ids;
+        let run = db
+            .create_fetch_run(NewFetchRun {
+                item_ids: item_ids.iter().map(|id| *id as i64).collect(),
+            })
+            .await?;
 
         let mut items = vec![];
         for item_id in &new_item_ids {
             let item = self.booth_db.get_item(*item_id).await?;
 
-            db.create_item_snapshot(NewItemSnapshot {
-                item_id: *item_id as i64,
-                name: item.name.clone(),
-                payload: serde_json::to_value(&item)?,
-            })
-            .await?;
-
-            info!("New item found: {} - {}", item.name, item.url);
+            // Offered again after a failed notification, snapshotted already
+            if db.get_latest_snapshot(*item_id as i64).await?.is_none() {
+                db.create_item_snapshot(NewItemSnapshot {
+                    item_id: *item_id as i64,
+                    name: item.name.clone(),
+                    payload: serde_json::to_value(&item)?,
+                })
+                .await?;
+                info!("New item found: {} - {}", item.name, item.url);
+            }
 
             items.push(item);
         }
 
+        // Left unmarked if fetching failed, so a restart picks the run up again
+        self.pending_run_id = Some(run.id);
+        self.pending_item_ids = item_ids;
+
         Ok(items)
     }
 
+    /// Fetches the least recently checked known items again once the
+    /// recheck interval has passed, adding those that changed in a way
+    /// filters can subscribe to to [`Self::pending_changes`].
+    pub async fn recheck(&mut self, db: &DatabaseClient) -> Result<()> {
+        if self.recheck_interval.is_zero()
+            || self
+                .last_recheck_at
+                .is_some_and(|at| at.elapsed() < self.recheck_interval)
+        {
+            return Ok(());
+        }
+        self.last_recheck_at = Some(Instant::now());
+
+        let since = OffsetDateTime::now_utc() - RECHECK_WINDOW;
+        let item_ids = db
+            .get_items_to_recheck(since, self.recheck_batch_size)
+            .await?;
+        debug!("Rechecking {} known items", item_ids.len());
+
+        for item_id in &item_ids {
+            match self.recheck_item(db, *item_id).await {
+                Ok(Some(item)) => self.pending_changes.push(item),
+                Ok(None) => {}
+                // e.g. the item was deleted from booth-db
+                Err(e) => warn!("Failed to recheck item {}: {}", item_id, e),
+            }
+        }
+
+        db.mark_items_checked(&item_ids).await?;
+
+        Ok(())
+    }
+
+    /// Changes to notify of, kept until they were notified.
+    pub fn pending_changes(&self) -> &[ChangedItem] {
+        &self.pending_changes
+    }
+
+    /// Compares an item with its latest snapshot, taking a new snapshot if any
+    /// field that change events are detected from differs.
+    async fn recheck_item(&self, db: &DatabaseClient, item_id: i64) -> Result<Option<ChangedItem>> {
+        let Some(snapshot) = db.get_latest_snapshot(item_id).await? else {
+            return Ok(None);
+        };
+        let previous = BoothItem::from_snapshot(snapshot.payload)?;
+        let item = self.booth_db.get_item(item_id as u64).await?;
+        if !tracked_state_changed(&previous, &item) {
+            return Ok(None);
+        }
+
+        let new_snapshot = NewItemSnapshot {
+            item_id,
+            name: item.name.clone(),
+            payload: serde_json::to_value(&item)?,
+        };
+        let changes = detect_changes(&previous, &item);
+        if changes.is_empty() {
+            // Taken even without events, e.g. when an item sells out, so that
+            // it coming back in stock is noticed later
+            db.create_item_snapshot(new_snapshot).await?;
+            return Ok(None);
+        }
+
+        let (events, lines): (Vec<_>, Vec<_>) = changes.into_iter().unzip();
+        let change = db
+            .create_item_snapshot_with_change(
+                new_snapshot,
+                NewItemChange {
+                    events,
+                    summary: lines.join("\n"),
+                },
+            )
+            .await?;
+
+        info!(
+            "Item changed: {} - {} ({})",
+            item.name,
+            item.url,
+            change.events.join(", ")
+        );
+
+        Ok(Some(ChangedItem { change, item }))
+    }
+
+    /// Marks the items of the last run and the pending changes as notified.
+    pub async fn mark_notified(&mut self, db: &DatabaseClient) -> Result<()> {
+        self.pending_changes.clear();
+        if let Some(run_id) = self.pending_run_id.take() {
+            self.notified_item_ids = std::mem::take(&mut self.pending_item_ids);
+            db.mark_fetch_run_notified(run_id).await?;
+        }
+        Ok(())
+    }
+
     fn calc_new_item_ids(&self, item_ids: &[u64]) -> Vec<u64> {
         item_ids
             .iter()
-            .filter(|id| !self.last_run_item_ids.contains(id))
+            .filter(|id| !self.notified_item_ids.contains(id))
             .copied()
             .collect()
     }
@@ -0,0 +1,551 @@
+use anyhow::{Result, anyhow, bail};
+use serde::{Deserialize, Serialize};
+
+use crate::booth::item::{BoothItem, Variation, VariationType};
+
+/// Placeholders that can be used in template text, with what they expand to.
+pub const PLACEHOLDERS: &[(&str, &str)] = &[
+    ("name", "Item name"),
+    ("url", "Item page URL"),
+    ("shop", "Shop name"),
+    ("shop_url", "Shop page URL"),
+    ("price", "Price as shown on BOOTH"),
+    ("category", "Category, with its parent category"),
+    ("tags", "Tags, comma separated"),
+    ("wish_count", "Number of wish list entries"),
+    (
+        "variations",
+        "Variations with their kind and price, one per line",
+    ),
+];
+
+/// Most images one notification shows, as an image gallery.
+pub const MAX_IMAGES: usize = 4;
+
+pub const DEFAULT_TITLE: &str = "{name}";
+pub const DEFAULT_DESCRIPTION: &str = "価格: {price}\nタグ: {tags}";
+const DEFAULT_IMAGE_COUNT: usize = MAX_IMAGES;
+
+/// Characters of `{tags}`, beyond which the list is cut.
+const MAX_TAGS_LEN: usize = 100;
+
+// Discord's limits on message and embed text
+const MAX_CONTENT_LEN: usize = 2000;
+const MAX_TITLE_LEN: usize = 256;
+const MAX_DESCRIPTION_LEN: usize = 4096;
+const MAX_FOOTER_LEN: usize = 2048;
+const MAX_AUTHOR_LEN: usize = 256;
+const MAX_FIELD_VALUE_LEN: usize = 1024;
+/// Characters of all text in one embed, and of all embeds in one message.
+pub const MAX_EMBED_TOTAL_LEN: usize = 6000;
+
+/// How a notification message looks. A field left unset falls back to the
+/// guild's template and then to the built-in default, see [`Self::or`].
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageTemplate {
+    /// Message text above the embed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+    /// Roles mentioned at the start of the message
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub role_ids: Option<Vec<u64>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    /// Embed color as `0xRRGGBB`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub footer: Option<String>,
+    /// How many of the item's images are shown
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub image_count: Option<usize>,
+}
+
+/// A template filled in for one item.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RenderedMessage {
+    /// Message text, without the role mentions
+    pub content: Option<String>,
+    /// Roles mentioned at the start of the message
+    pub role_ids: Vec<u64>,
+    pub title: String,
+    pub url: String,
+    pub description: String,
+    pub color: Option<u32>,
+    pub footer: Option<String>,
+    pub images: Vec<String>,
+    /// The shop, with its thumbnail as the icon
+    pub author: EmbedAuthor,
+    /// Category breadcrumb and variations
+    pub fields: Vec<EmbedField>,
+    /// When the item was published, as RFC 3339
+    pub timestamp: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbedAuthor {
+    pub name: String,
+    pub url: String,
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EmbedField {
+    pub name: String,
+    pub value: String,
+    pub inline: bool,
+}
+
+impl MessageTemplate {
+    /// This template with its unset fields taken from `fallback`.
+    pub fn or(&self, fallback: &MessageTemplate) -> MessageTemplate {
+        MessageTemplate {
+            content: self.content.clone().or_else(|| fallback.content.clone()),
+            role_ids: self.role_ids.clone().or_else(|| fallback.role_ids.clone()),
+            title: self.title.clone().or_else(|| fallback.title.clone()),
+            description: self
+                .description
+                .clone()
+                .or_else(|| fallback.description.clone()),
+            color: self.color.or(fallback.color),
+            footer: self.footer.clone().or_else(|| fallback.footer.clone()),
+            image_count: self.image_count.or(fallback.image_count),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        *self == MessageTemplate::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        for (field, text) in [
+            ("content", &self.content),
+            ("title", &self.title),
+            ("description", &self.description),
+            ("footer", &self.footer),
+        ] {
+            let Some(text) = text else {
+                continue;
+            };
+            for name in placeholder_names(text) {
+                if !PLACEHOLDERS.iter().any(|(known, _)| *known == name) {
+                    bail!("unknown placeholder {{{name}}} in {field}");
+                }
+            }
+        }
+        if let Some(color) = self.color
+            && color > 0xFFFFFF
+        {
+            bail!("color must be between #000000 and #FFFFFF");
+        }
+        if self.role_ids.iter().flatten().any(|role_id| *role_id == 0) {
+            bail!("0 is not a role ID");
+        }
+        if let Some(image_count) = self.image_count
+            && image_count > MAX_IMAGES
+        {
+            bail!("at most {MAX_IMAGES} images can be shown");
+        }
+        Ok(())
+    }
+
+    /// Fills in the template for `item`, using the built-in default for
+    /// unset fields.
+    pub fn render(&self, item: &BoothItem) -> RenderedMessage {
+        let mut fields = vec![EmbedField {
+            name: "カテゴリ".to_string(),
+            value: category(item),
+            inline: true,
+        }];
+        if !item.variations.is_empty() {
+            fields.push(EmbedField {
+                name: format!("バリエーション ({})", item.variations.len()),
+                value: variation_lines(&item.variations, MAX_FIELD_VALUE_LEN),
+                inline: false,
+            });
+        }
+        fields.retain(|field| !field.value.is_empty());
+
+        let mut rendered = RenderedMessage {
+            content: self.content.as_deref().map(|text| expand(text, item)),
+            role_ids: self.role_ids.clone().unwrap_or_default(),
+            title: truncate(
+                &expand(self.title.as_deref().unwrap_or(DEFAULT_TITLE), item),
+                MAX_TITLE_LEN,
+            ),
+            url: item.url.clone(),
+            description: truncate(
+                &expand(
+                    self.description.as_deref().unwrap_or(DEFAULT_DESCRIPTION),
+                    item,
+                ),
+                MAX_DESCRIPTION_LEN,
+            ),
+            color: self.color,
+            footer: self
+                .footer
+                .as_deref()
+                .map(|footer| truncate(&expand(footer, item), MAX_FOOTER_LEN))
+                .filter(|footer| !footer.is_empty()),
+            images: item
+                .images
+                .iter()
+                .take(
+                    self.image_count
+                        .unwrap_or(DEFAULT_IMAGE_COUNT)
+                        .min(MAX_IMAGES),
+                )
+                .map(|image| image.original.clone())
+                .collect(),
+            author: EmbedAuthor {
+                name: truncate(&item.shop.name, MAX_AUTHOR_LEN),
+                url: item.shop.url.clone(),
+                icon_url: Some(item.shop.thumbnail_url.clone())
+                    .filter(|url| url.starts_with("https://")),
+            },
+            fields,
+            timestamp: Some(item.published_at.clone()).filter(|at| !at.is_empty()),
+        };
+        rendered.fit_embed();
+        rendered
+    }
+}
+
+impl RenderedMessage {
+    /// Characters of the embed's text, as Discord counts them against
+    /// `MAX_EMBED_TOTAL_LEN`.
+    pub fn embed_len(&self) -> usize {
+        let fields = self
+            .fields
+            .iter()
+            .flat_map(|field| [field.name.as_str(), field.value.as_str()]);
+        [
+            self.title.as_str(),
+            self.description.as_str(),
+            self.author.name.as_str(),
+            self.footer.as_deref().unwrap_or_default(),
+        ]
+        .into_iter()
+        .chain(fields)
+        .map(|text| text.chars().count())
+        .sum()
+    }
+
+    /// Shortens the description, then drops fields from the end, until the
+    /// embed is within `MAX_EMBED_TOTAL_LEN`.
+    fn fit_embed(&mut self) {
+        let excess = self.embed_len().saturating_sub(MAX_EMBED_TOTAL_LEN);
+        if excess > 0 {
+            let length = self.description.chars().count();
+            self.description = truncate(&self.description, length.saturating_sub(excess).max(3));
+        }
+        while self.embed_len() > MAX_EMBED_TOTAL_LEN && self.fields.pop().is_some() {}
+    }
+
+    /// Mentions `role_ids` too, unless they already are.
+    pub fn mention(&mut self, role_ids: impl IntoIterator<Item = u64>) {
+        for role_id in role_ids {
+            if !self.role_ids.contains(&role_id) {
+                self.role_ids.push(role_id);
+            }
+        }
+    }
+
+    /// The message text with the role mentions in front.
+    pub fn full_content(&self) -> Option<String> {
+        let mut parts = self
+            .role_ids
+            .iter()
+            .map(|role_id| format!("<@&{role_id}>"))
+            .collect::<Vec<_>>();
+        parts.extend(self.content.clone());
+        (!parts.is_empty()).then(|| truncate(&parts.join(" "), MAX_CONTENT_LEN))
+    }
+}
+
+/// Parses `#RRGGBB` (or `RRGGBB`) into a color.
+pub fn parse_color(value: &str) -> Result<u32> {
+    let hex = value.trim().trim_start_matches('#');
+    if hex.len() != 6 {
+        bail!("color must look like #RRGGBB");
+    }
+    Ok(u32::from_str_radix(hex, 16)?)
+}
+
+/// Parses role mentions (`<@&id>`) or bare role IDs, separated by spaces or
+/// commas, dropping duplicates.
+pub fn parse_role_ids(value: &str) -> Result<Vec<u64>> {
+    let mut role_ids = vec![];
+    for role in value
+        .split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|role| !role.is_empty())
+    {
+        let role_id = role
+            .trim_start_matches("<@&")
+            .trim_end_matches('>')
+            .parse::<u64>()
+            .ok()
+            .filter(|role_id| *role_id != 0)
+            .ok_or_else(|| anyhow!("invalid role ID: {role}"))?;
+        if !role_ids.contains(&role_id) {
+            role_ids.push(role_id);
+        }
+    }
+    Ok(role_ids)
+}
+
+/// Names of the `{placeholder}`s in `text`.
+fn placeholder_names(text: &str) -> impl Iterator<Item = &str> {
+    text.split('{')
+        .skip(1)
+        .filter_map(|part| part.split_once('}'))
+        .map(|(name, _)| name)
+}
+
+/// Replaces the placeholders in `text`; unknown ones are left as they are.
+fn expand(text: &str, item: &BoothItem) -> String {
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        match after
+            .split_once('}')
+            .and_then(|(name, tail)| Some((placeholder(name, item)?, tail)))
+        {
+            Some((value, tail)) => {
+                expanded.push_str(&value);
+                rest = tail;
+            }
+            None => {
+                expanded.push('{');
+                rest = after;
+            }
+        }
+    }
+    expanded.push_str(rest);
+    expanded
+}
+
+fn placeholder(name: &str, item: &BoothItem) -> Option<String> {
+    Some(match name {
+        "name" => item.name.clone(),
+        "url" => item.url.clone(),
+        "shop" => item.shop.name.clone(),
+        "shop_url" => item.shop.url.clone(),
+        "price" => item.price.clone(),
+        "category" => category(item),
+        "tags" => {
+            let tags = item
+                .tags
+                .iter()
+                .map(|tag| tag.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            if tags.chars().count() <= MAX_TAGS_LEN {
+                tags
+            } else {
+                tags.chars().take(MAX_TAGS_LEN).collect::<String>() + "..."
+            }
+        }
+        "wish_count" => item.wish_lists_count.to_string(),
+        "variations" => variation_lines(&item.variations, MAX_DESCRIPTION_LEN),
+        _ => return None,
+    })
+}
+
+/// The item's category with its parent, e.g. `3Dモデル > 3D衣装`.
+fn category(item: &BoothItem) -> String {
+    match &item.category.parent {
+        Some(parent) => format!("{} > {}", parent.name, item.category.name),
+        None => item.category.name.clone(),
+    }
+}
+
+/// One line per variation with its kind and price, as many as fit in `max`
+/// characters with a count of the rest.
+fn variation_lines(variations: &[Variation], max: usize) -> String {
+    let mut lines = String::new();
+    for (index, variation) in variations.iter().enumerate() {
+        let kind = match variation.kind {
+            VariationType::Digital => "DL",
+            VariationType::Other => "物販",
+        };
+        let line = match &variation.name {
+            Some(name) => format!("・{} [{}]: ¥{}", name, kind, variation.price),
+            None => format!("・[{}] ¥{}", kind, variation.price),
+        };
+        let rest = format!("\n…他{}件", variations.len() - index);
+        if lines.chars().count() + line.chars().count() + 1 + rest.chars().count() > max {
+            lines.push_str(if lines.is_empty() { &rest[1..] } else { &rest });
+            break;
+        }
+        if !lines.is_empty() {
+            lines.push('\n');
+        }
+        lines.push_str(&line);
+    }
+    lines
+}
+
+/// Cuts `text` to at most `max` characters, marking the cut with `...`.
+fn truncate(text: &str, max: usize) -> String {
+    if text.chars().count() <= max {
+        text.to_string()
+    } else {
+        text.chars().take(max - 3).collect::<String>() + "..."
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::booth::item::{Shop, Tag, Variation};
+
+    fn item() -> BoothItem {
+        BoothItem {
+            name: "Outfit".to_string(),
+            url: "https://booth.pm/ja/items/1".to_string(),
+            price: "¥ 1,500".to_string(),
+            shop: Shop {
+                name: "Shop".to_string(),
+                ..Default::default()
+            },
+            tags: vec![
+                Tag {
+                    name: "VRChat".to_string(),
+                    ..Default::default()
+                },
+                Tag {
+                    name: "Outfit".to_string(),
+                    ..Default::default()
+                },
+            ],
+            variations: vec![Variation {
+                name: Some("Full".to_string()),
+                price: 1500,
+                ..Default::default()
+            }],
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn default_template_shows_item_details() {
+        let rendered = MessageTemplate::default().render(&item());
+
+        assert_eq!(rendered.full_content(), None);
+        assert_eq!(rendered.title, "Outfit");
+        assert_eq!(rendered.author.name, "Shop");
+        assert_eq!(rendered.description, "価格: ¥ 1,500\nタグ: VRChat, Outfit");
+        assert_eq!(
+            rendered.fields,
+            vec![EmbedField {
+                name: "バリエーション (1)".to_string(),
+                value: "・Full [物販]: ¥1500".to_string(),
+                inline: false,
+            }]
+        );
+    }
+
+    #[test]
+    fn keeps_embeds_within_discord_limits() {
+        let mut item = item();
+        item.description = "x".repeat(10_000);
+        item.variations = (0..200)
+            .map(|i| Variation {
+                name: Some(format!("Variation {i}")),
+                price: 500,
+                kind: VariationType::Digital,
+                ..Default::default()
+            })
+            .collect();
+        let template = MessageTemplate {
+            description: Some("{variations}\n{variations}".to_string()),
+            ..Default::default()
+        };
+
+        let rendered = template.render(&item);
+
+        assert!(rendered.embed_len() <= MAX_EMBED_TOTAL_LEN);
+        let variations = &rendered.fields[0].value;
+        assert!(variations.chars().count() <= MAX_FIELD_VALUE_LEN);
+        assert!(variations.starts_with("・Variation 0 [DL]: ¥500"));
+        assert!(variations.ends_with("件"));
+    }
+
+    #[test]
+    fn expands_placeholders_and_mentions_roles() {
+        let template = MessageTemplate {
+            content: Some("New from {shop}".to_string()),
+            role_ids: Some(vec![42]),
+            description: Some("{variations}\n{unknown} {".to_string()),
+            footer: Some("♡ {wish_count}".to_string()),
+            ..Default::default()
+        };
+
+        let mut rendered = template.render(&item());
+        rendered.mention([7, 42]);
+
+        assert_eq!(
+            rendered.full_content().as_deref(),
+            Some("<@&42> <@&7> New from Shop")
+        );
+        assert_eq!(rendered.description, "・Full [物販]: ¥1500\n{unknown} {");
+        assert_eq!(rendered.footer.as_deref(), Some("♡ 0"));
+    }
+
+    #[test]
+    fn tags_are_truncated_by_chars() {
+        let mut item = item();
+        item.tags = ["衣装", "アクセサリー"]
+            .into_iter()
+            .map(|name| Tag {
+                name: name.repeat(10),
+                ..Default::default()
+            })
+            .collect();
+
+        // 82 chars, but far more bytes
+        let tags = placeholder("tags", &item).unwrap();
+        assert!(!tags.ends_with("..."));
+
+        item.tags[1].name = "アクセサリー".repeat(20);
+        let tags = placeholder("tags", &item).unwrap();
+        assert_eq!(tags.chars().count(), MAX_TAGS_LEN + 3);
+        assert!(tags.ends_with("..."));
+    }
+
+    #[test]
+    fn unset_fields_fall_back() {
+        let channel = MessageTemplate {
+            title: Some("{name}!".to_string()),
+            ..Default::default()
+        };
+        let guild = MessageTemplate {
+            title: Some("{shop}".to_string()),
+            color: Some(0xFF0000),
+            ..Default::default()
+        };
+
+        let merged = channel.or(&guild);
+
+        assert_eq!(merged.title.as_deref(), Some("{name}!"));
+        assert_eq!(merged.color, Some(0xFF0000));
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let template = MessageTemplate {
+            title: Some("{nme}".to_string()),
+            ..Default::default()
+        };
+
+        assert!(template.validate().is_err());
+        assert_eq!(parse_color("#1264a3").unwrap(), 0x1264A3);
+        assert_eq!(parse_role_ids("<@&42>, 7 42").unwrap(), vec![42, 7]);
+        assert!(parse_role_ids("@Role").is_err());
+    }
+}
@@ -10,18 +10,33 @@ use axum::{
     Router,
     extract::{Form, Path, Query, State},
     http::{HeaderMap, HeaderValue, StatusCode, header},
-    response::{Html, IntoResponse, Redirect, Response},
+    response::{Html, IntoResponse, Json, Redirect, Response},
     routing::{get, post},
 };
 use rand::{Rng, distributions::Alphanumeric};
 use serde::{Deserialize, Serialize};
+use sqlx::types::time::OffsetDateTime;
 use tokio::sync::Mutex;
 use tower_http::trace::TraceLayer;
 use tracing::{info, warn};
 
 use crate::{
-    database::{DatabaseClient, DiscordGuild, NewDiscordChannel, NewNotificationFilter},
-    filter::Filter,
+    booth::{change::ItemEvent, item::BoothItem},
+    database::{
+        ContentPolicy, DatabaseClient, DeliveryMode, DiscordChannel, DiscordGuild, Editor,
+        NewDiscordChannel, NewNotificationFilter,
+    },
+    filter::{
+        Filter, FilteringEngine,
+        diff::{DiffLine, diff_lines},
+        library::{FilterLibrary, MAX_NAME_LEN, UNSAVED_FILTER_ID},
+        preview::{DEFAULT_PREVIEW_ITEMS, PREVIEW_WINDOW, Preview, preview_filter},
+    },
+    task::QueueDepth,
+    template::{
+        DEFAULT_DESCRIPTION, DEFAULT_TITLE, MAX_IMAGES, MessageTemplate, PLACEHOLDERS, parse_color,
+        parse_role_ids,
+    },
 };
 
 const DISCORD_API_BASE: &str = "https://discord.com/api/v10";
@@ -41,6 +56,7 @@ pub struct WebConfig {
     pub db: DatabaseClient,
     pub owner_ids: HashSet<u64>,
     pub cookie_secure: bool,
+    pub queue_depth: QueueDepth,
 }
 
 impl WebConfig {
@@ -48,6 +64,7 @@ impl WebConfig {
         db: DatabaseClient,
         bot_token: String,
         owner_ids: HashSet<u64>,
+        queue_depth: QueueDepth,
     ) -> Result<Option<Self>> {
         let Some(bind) = optional_env("WEB_BIND") else {
             return Ok(None);
@@ -76,6 +93,7 @@ impl WebConfig {
             db,
             owner_ids,
             cookie_secure,
+            queue_depth,
         }))
     }
 }
@@ -97,6 +115,7 @@ struct AppState {
     bot_token: String,
     owner_ids: HashSet<u64>,
     cookie_secure: bool,
+    queue_depth: QueueDepth,
     sessions: Arc<Mutex<HashMap<String, WebSession>>>,
     oauth_states: Arc<Mutex<HashMap<String, SystemTime>>>,
 }
@@ -150,6 +169,10 @@ struct DiscordApiChannel {
 #[derive(Debug, Deserialize)]
 struct FilterForm {
     rule_yaml: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    description: String,
 }
 
 #[derive(Debug, Deserialize)]
@@ -158,16 +181,95 @@ struct RegisterChannelForm {
 }
 
 #[derive(Debug, Deserialize)]
-struct SetFilterForm {
+struct AddFilterForm {
     filter_id: String,
 }
 
+#[derive(Debug, Deserialize)]
+struct ContentPolicyForm {
+    content_policy: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct DeliveryModeForm {
+    delivery_mode: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CrosspostForm {
+    crosspost: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct FilterRolesForm {
+    role_ids: String,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct TemplateForm {
+    content: String,
+    role_ids: String,
+    title: String,
+    description: String,
+    color: String,
+    footer: String,
+    image_count: String,
+}
+
+impl TemplateForm {
+    fn from_template(template: &MessageTemplate) -> Self {
+        Self {
+            content: template.content.clone().unwrap_or_default(),
+            role_ids: template
+                .role_ids
+                .iter()
+                .flatten()
+                .map(u64::to_string)
+                .collect::<Vec<_>>()
+                .join(", "),
+            title: template.title.clone().unwrap_or_default(),
+            description: template.description.clone().unwrap_or_default(),
+            color: template
+                .color
+                .map(|color| format!("#{color:06X}"))
+                .unwrap_or_default(),
+            footer: template.footer.clone().unwrap_or_default(),
+            image_count: template
+                .image_count
+                .map(|count| count.to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn to_template(&self) -> Result<MessageTemplate> {
+        let role_ids = parse_role_ids(&self.role_ids)?;
+        let template = MessageTemplate {
+            content: optional_text(&self.content),
+            role_ids: (!role_ids.is_empty()).then_some(role_ids),
+            title: optional_text(&self.title),
+            description: optional_text(&self.description),
+            color: optional_text(&self.color)
+                .map(|color| parse_color(&color))
+                .transpose()?,
+            footer: optional_text(&self.footer),
+            image_count: optional_text(&self.image_count)
+                .map(|count| count.parse::<usize>())
+                .transpose()
+                .context("images must be a number")?,
+        };
+        template.validate()?;
+        Ok(template)
+    }
+}
+
 #[derive(Debug, Deserialize)]
 struct GuildSettingsForm {
     fallback_channel_id: String,
     fallback_nsfw_channel_id: String,
     general_category_id: String,
     nsfw_category_id: String,
+    admin_channel_id: String,
 }
 
 #[derive(Debug)]
@@ -188,7 +290,7 @@ impl IntoResponse for WebError {
         (
             StatusCode::INTERNAL_SERVER_ERROR,
             Html(page("Error", None, &format!(
-                r#"<section class="panel"><h1>Request failed</h1><p>{}</p><p><a href="/">Back</a></p></section>"#,
+                r#"<section class="panel"><h1>Request failed</h1><p class="error">{}</p><p><a href="/">Back</a></p></section>"#,
                 escape(&self.0.to_string())
             ))),
         )
@@ -207,12 +309,14 @@ pub async fn serve(config: WebConfig) -> Result<()> {
         bot_token: config.bot_token,
         owner_ids: config.owner_ids,
         cookie_secure: config.cookie_secure,
+        queue_depth: config.queue_depth,
         sessions: Arc::new(Mutex::new(HashMap::new())),
         oauth_states: Arc::new(Mutex::new(HashMap::new())),
     };
 
     let app = Router::new()
         .route("/healthz", get(healthz))
+        .route("/status", get(status))
         .route("/", get(index))
         .route("/login", get(login))
         .route("/auth/discord/login", get(discord_login))
@@ -227,10 +331,22 @@ pub async fn serve(config: WebConfig) -> Result<()> {
             "/guilds/:guild_id/filters/:filter_id",
             get(edit_filter_page).post(update_filter),
         )
+        .route(
+            "/guilds/:guild_id/filters/:filter_id/preview",
+            post(preview_filter_page),
+        )
         .route(
             "/guilds/:guild_id/filters/:filter_id/delete",
             post(delete_filter),
         )
+        .route(
+            "/guilds/:guild_id/filters/:filter_id/revisions",
+            get(revisions_page),
+        )
+        .route(
+            "/guilds/:guild_id/filters/:filter_id/revisions/:revision_id/rollback",
+            post(rollback_filter),
+        )
         .route("/guilds/:guild_id/channels", get(channels_page))
         .route(
             "/guilds/:guild_id/channels/register",
@@ -238,12 +354,36 @@ pub async fn serve(config: WebConfig) -> Result<()> {
         )
         .route(
             "/guilds/:guild_id/channels/:channel_id/filter",
-            post(set_channel_filter),
+            post(add_channel_filter),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/filters/:filter_id/remove",
+            post(remove_channel_filter),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/filters/:filter_id/roles",
+            post(set_channel_filter_roles),
         )
         .route(
             "/guilds/:guild_id/channels/:channel_id/clear-filter",
             post(clear_channel_filter),
         )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/content-policy",
+            post(set_channel_content_policy),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/delivery-mode",
+            post(set_channel_delivery_mode),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/crosspost",
+            post(set_channel_crosspost),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/enable",
+            post(enable_channel),
+        )
         .route(
             "/guilds/:guild_id/channels/:channel_id/delete",
             post(delete_channel),
@@ -252,6 +392,22 @@ pub async fn serve(config: WebConfig) -> Result<()> {
             "/guilds/:guild_id/settings",
             get(settings_page).post(update_settings),
         )
+        .route(
+            "/guilds/:guild_id/template",
+            get(guild_template_page).post(update_guild_template),
+        )
+        .route(
+            "/guilds/:guild_id/template/preview",
+            post(preview_guild_template),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/template",
+            get(channel_template_page).post(update_channel_template),
+        )
+        .route(
+            "/guilds/:guild_id/channels/:channel_id/template/preview",
+            post(preview_channel_template),
+        )
         .layer(TraceLayer::new_for_http())
         .with_state(state);
 
@@ -265,6 +421,12 @@ async fn healthz() -> &'static str {
     "ok"
 }
 
+async fn status(State(state): State<AppState>) -> Json<serde_json::Value> {
+    Json(serde_json::json!({
+        "queued_deliveries": state.queue_depth.get(),
+    }))
+}
+
 async fn login(State(state): State<AppState>, headers: HeaderMap) -> Result<Response, WebError> {
     if current_session(&state, &headers).await?.is_some() {
         return Ok(Redirect::to("/").into_response());
@@ -433,11 +595,17 @@ async fn filters_page(
     for filter in filters {
         let linked = channels
             .iter()
-            .filter(|c| c.filter_id == Some(filter.id))
+            .filter(|c| c.filter_ids.contains(&filter.id))
             .count();
         list.push_str(&format!(
-            r#"<article class="card"><div><h2>Filter #{id}</h2><p>{linked} linked channel(s)</p></div><pre>{yaml}</pre><div class="row-actions"><a href="/guilds/{guild_id}/filters/{id}">Edit</a><form method="post" action="/guilds/{guild_id}/filters/{id}/delete"><button class="danger" type="submit">Delete</button></form></div></article>"#,
+            r#"<article class="card"><div><h2>{label}</h2>{description}<p>{linked} linked channel(s)</p></div><pre>{yaml}</pre><div class="row-actions"><a href="/guilds/{guild_id}/filters/{id}">Edit</a><a href="/guilds/{guild_id}/filters/{id}/revisions">History</a><form method="post" action="/guilds/{guild_id}/filters/{id}/delete"><button class="danger" type="submit">Delete</button></form></div></article>"#,
             id = filter.id,
+            label = escape(&filter.label()),
+            description = filter
+                .description
+                .as_deref()
+                .map(|description| format!("<p>{}</p>", escape(description)))
+                .unwrap_or_default(),
             linked = linked,
             guild_id = guild_id,
             yaml = escape(&filter.rule_yaml)
@@ -450,9 +618,10 @@ async fn filters_page(
         "Filters",
         Some(&session),
         &format!(
-            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Filters</div><h1>Filters</h1><form id="new-filter-form" class="editor" method="post" action="/guilds/{guild_id}/filters">{builder}<details><summary>YAML source</summary><textarea name="rule_yaml" required rows="12">{sample}</textarea></details><button class="primary" type="submit">Create filter</button></form><div class="cards">{list}</div></section>"#,
+            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Filters</div><h1>Filters</h1><form id="new-filter-form" class="editor" method="post" action="/guilds/{guild_id}/filters">{details}{builder}<details><summary>YAML source</summary><textarea name="rule_yaml" required rows="12">{sample}</textarea></details><button class="primary" type="submit">Create filter</button></form><div class="cards">{list}</div></section>"#,
             guild_id = guild_id,
             guild_name = escape(&guild.name),
+            details = filter_details_fields("", ""),
             builder = filter_editor("new-filter-form", SAMPLE_FILTER)?,
             sample = escape(SAMPLE_FILTER),
             list = list
@@ -467,15 +636,20 @@ async fn create_filter(
     Path(guild_id): Path<i64>,
     Form(form): Form<FilterForm>,
 ) -> Result<Response, WebError> {
-    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+    let Some((session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
         return Ok(Redirect::to("/login").into_response());
     };
-    let rule_yaml = normalize_filter(&form.rule_yaml)?;
+    let (filter, _) = checked_filter(&state, guild_id, None, &form).await?;
     state
         .db
         .create_notification_filter(NewNotificationFilter {
             guild_id: Some(guild_id),
-            rule_yaml,
+            owner_id: None,
+            name: optional_text(&form.name),
+            description: optional_text(&form.description),
+            rule_yaml: serde_yaml::to_string(&filter)?,
+            avatar: None,
+            editor: Some(session_editor(&session)),
         })
         .await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/filters")).into_response())
@@ -492,41 +666,122 @@ async fn edit_filter_page(
     let Some(filter) = state.db.get_notification_filter(filter_id).await? else {
         return Ok(StatusCode::NOT_FOUND.into_response());
     };
-    if filter.guild_id != Some(guild_id) && filter.guild_id.is_some() {
+    if !filter.editable_in(guild_id) {
         return Ok(StatusCode::FORBIDDEN.into_response());
     }
     Ok(Html(page(
         "Edit Filter",
         Some(&session),
-        &format!(
-            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / <a href="/guilds/{guild_id}/filters">Filters</a> / #{filter_id}</div><h1>Edit filter #{filter_id}</h1><form id="edit-filter-form" class="editor" method="post" action="/guilds/{guild_id}/filters/{filter_id}">{builder}<details><summary>YAML source</summary><textarea name="rule_yaml" required rows="18">{yaml}</textarea></details><button class="primary" type="submit">Save filter</button></form></section>"#,
-            guild_id = guild_id,
-            guild_name = escape(&guild.name),
-            filter_id = filter_id,
-            builder = filter_editor("edit-filter-form", &filter.rule_yaml)?,
-            yaml = escape(&filter.rule_yaml)
-        ),
+        &edit_filter_body(
+            &guild,
+            filter_id,
+            &FilterForm {
+                rule_yaml: filter.rule_yaml,
+                name: filter.name.unwrap_or_default(),
+                description: filter.description.unwrap_or_default(),
+            },
+            "",
+        )?,
     ))
     .into_response())
 }
 
+async fn preview_filter_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, filter_id)): Path<(i64, i64)>,
+    Form(form): Form<FilterForm>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(filter) = state.db.get_notification_filter(filter_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if !filter.editable_in(guild_id) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let (_, engine) = checked_filter(&state, guild_id, Some(filter_id), &form).await?;
+    let preview = preview_filter(&state.db, &engine, DEFAULT_PREVIEW_ITEMS).await?;
+    Ok(Html(page(
+        "Edit Filter",
+        Some(&session),
+        &edit_filter_body(&guild, filter_id, &form, &preview_panel(&preview))?,
+    ))
+    .into_response())
+}
+
+fn edit_filter_body(
+    guild: &DiscordGuild,
+    filter_id: i64,
+    form: &FilterForm,
+    preview: &str,
+) -> Result<String> {
+    Ok(format!(
+        r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / <a href="/guilds/{guild_id}/filters">Filters</a> / #{filter_id}</div><h1>Edit filter #{filter_id}</h1><form id="edit-filter-form" class="editor" method="post" action="/guilds/{guild_id}/filters/{filter_id}">{details}{builder}<details><summary>YAML source</summary><textarea name="rule_yaml" required rows="18">{yaml}</textarea></details><div class="row-actions"><button class="primary" type="submit">Save filter</button><button type="submit" formaction="/guilds/{guild_id}/filters/{filter_id}/preview">Preview matches</button><a href="/guilds/{guild_id}/filters/{filter_id}/revisions">History</a></div></form>{preview}</section>"#,
+        guild_id = guild.guild_id,
+        guild_name = escape(&guild.name),
+        filter_id = filter_id,
+        details = filter_details_fields(&form.name, &form.description),
+        builder = filter_editor("edit-filter-form", &form.rule_yaml)?,
+        yaml = escape(&form.rule_yaml),
+        preview = preview
+    ))
+}
+
+fn preview_panel(preview: &Preview) -> String {
+    let mut rows = String::new();
+    for item in preview.matches.iter().take(50) {
+        rows.push_str(&format!(
+            r#"<li><a href="{url}" target="_blank" rel="noopener">{name}</a><small>{shop} · {price}</small></li>"#,
+            url = escape(&item.url),
+            name = escape(&item.name),
+            shop = escape(&item.shop.name),
+            price = escape(&item.price)
+        ));
+    }
+    if rows.is_empty() {
+        rows.push_str(r#"<li class="empty">No recent items matched.</li>"#);
+    }
+    format!(
+        r#"<div class="preview"><h2>Preview matches</h2><p>{matched} of the last {scanned} item(s) matched.</p><ul class="preview-list">{rows}</ul></div>"#,
+        matched = preview.matches.len(),
+        scanned = preview.scanned,
+        rows = rows
+    )
+}
+
 async fn update_filter(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path((guild_id, filter_id)): Path<(i64, i64)>,
     Form(form): Form<FilterForm>,
 ) -> Result<Response, WebError> {
-    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+    let Some((session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
         return Ok(Redirect::to("/login").into_response());
     };
-    let rule_yaml = normalize_filter(&form.rule_yaml)?;
+    let (filter, _) = checked_filter(&state, guild_id, Some(filter_id), &form).await?;
     let updated = state
         .db
-        .update_notification_filter(filter_id, guild_id, rule_yaml)
+        .update_notification_filter(
+            filter_id,
+            guild_id,
+            serde_yaml::to_string(&filter)?,
+            Some(&session_editor(&session)),
+        )
         .await?;
     if updated.is_none() {
         return Ok(StatusCode::NOT_FOUND.into_response());
     }
+    state
+        .db
+        .update_notification_filter_details(
+            filter_id,
+            guild_id,
+            optional_text(&form.name),
+            optional_text(&form.description),
+        )
+        .await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/filters")).into_response())
 }
 
@@ -541,13 +796,13 @@ async fn delete_filter(
     let Some(filter) = state.db.get_notification_filter(filter_id).await? else {
         return Ok(StatusCode::NOT_FOUND.into_response());
     };
-    if filter.guild_id != Some(guild_id) && filter.guild_id.is_some() {
+    if !filter.editable_in(guild_id) {
         return Ok(StatusCode::FORBIDDEN.into_response());
     }
     let channels = state.db.get_channels_by_guild(guild_id).await?;
     if channels
         .iter()
-        .any(|channel| channel.filter_id == Some(filter_id))
+        .any(|channel| channel.filter_ids.contains(&filter_id))
     {
         return Ok((
             StatusCode::CONFLICT,
@@ -555,10 +810,121 @@ async fn delete_filter(
         )
             .into_response());
     }
+    let dependents = FilterLibrary::load(&state.db, guild_id)
+        .await?
+        .dependents(filter_id);
+    if !dependents.is_empty() {
+        let dependents = dependents
+            .iter()
+            .map(|id| format!("#{id}"))
+            .collect::<Vec<_>>();
+        return Ok((
+            StatusCode::CONFLICT,
+            format!(
+                "filter is still included by filter {}",
+                dependents.join(", ")
+            ),
+        )
+            .into_response());
+    }
     state.db.delete_notification_filter(filter_id).await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/filters")).into_response())
 }
 
+async fn revisions_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, filter_id)): Path<(i64, i64)>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(filter) = state.db.get_notification_filter(filter_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if !filter.editable_in(guild_id) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let revisions = state.db.get_filter_revisions(filter_id).await?;
+    let mut list = String::new();
+    for (index, revision) in revisions.iter().enumerate() {
+        // Each revision is shown as a change against the one saved before it.
+        let previous = revisions
+            .get(index + 1)
+            .map_or("", |previous| previous.rule_yaml.as_str());
+        let restore = if index == 0 {
+            r#"<span class="muted">Current version</span>"#.to_string()
+        } else {
+            format!(
+                r#"<form method="post" action="/guilds/{guild_id}/filters/{filter_id}/revisions/{id}/rollback"><button type="submit">Restore this version</button></form>"#,
+                guild_id = guild_id,
+                filter_id = filter_id,
+                id = revision.id
+            )
+        };
+        list.push_str(&format!(
+            r#"<article class="card"><div><h2>Revision #{id}</h2><p>{time} by {editor}</p></div><pre class="diff">{diff}</pre><div class="row-actions">{restore}</div></article>"#,
+            id = revision.id,
+            time = format_time(revision.created_at),
+            editor = escape(revision.editor_name.as_deref().unwrap_or("unknown")),
+            diff = diff_html(previous, &revision.rule_yaml),
+            restore = restore
+        ));
+    }
+    if list.is_empty() {
+        list.push_str(r#"<div class="empty">No revisions recorded.</div>"#);
+    }
+    Ok(Html(page(
+        "Filter History",
+        Some(&session),
+        &format!(
+            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / <a href="/guilds/{guild_id}/filters">Filters</a> / <a href="/guilds/{guild_id}/filters/{filter_id}">#{filter_id}</a> / History</div><h1>History of {label}</h1><div class="cards">{list}</div></section>"#,
+            guild_id = guild_id,
+            guild_name = escape(&guild.name),
+            filter_id = filter_id,
+            label = escape(&filter.label()),
+            list = list
+        ),
+    ))
+    .into_response())
+}
+
+async fn rollback_filter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, filter_id, revision_id)): Path<(i64, i64, i64)>,
+) -> Result<Response, WebError> {
+    let Some((session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(filter) = state.db.get_notification_filter(filter_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    if !filter.editable_in(guild_id) {
+        return Ok(StatusCode::FORBIDDEN.into_response());
+    }
+    let Some(revision) = state.db.get_filter_revision(filter_id, revision_id).await? else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    // The old version has to be valid against the guild's filters as they are now.
+    let form = FilterForm {
+        rule_yaml: revision.rule_yaml,
+        name: filter.name.unwrap_or_default(),
+        description: filter.description.unwrap_or_default(),
+    };
+    let (restored, _) = checked_filter(&state, guild_id, Some(filter_id), &form).await?;
+    state
+        .db
+        .update_notification_filter(
+            filter_id,
+            guild_id,
+            serde_yaml::to_string(&restored)?,
+            Some(&session_editor(&session)),
+        )
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/filters/{filter_id}/revisions")).into_response())
+}
+
 async fn channels_page(
     State(state): State<AppState>,
     headers: HeaderMap,
@@ -569,6 +935,13 @@ async fn channels_page(
     };
     let registered = state.db.get_channels_by_guild(guild_id).await?;
     let filters = state.db.get_notification_filters_by_guild(guild_id).await?;
+    let filter_roles = state
+        .db
+        .get_channel_filters_by_guild(guild_id)
+        .await?
+        .into_iter()
+        .map(|binding| ((binding.channel_id, binding.filter_id), binding.role_ids))
+        .collect::<HashMap<_, _>>();
     let discord_channels = fetch_discord_channels(&state, guild_id)
         .await
         .unwrap_or_default();
@@ -588,36 +961,170 @@ async fn channels_page(
 
     let mut rows = String::new();
     for channel in registered {
-        let mut filter_options = String::from(r#"<option value="">No filter</option>"#);
-        for filter in &filters {
-            let selected = if channel.filter_id == Some(filter.id) {
-                " selected"
-            } else {
-                ""
-            };
+        let mut assigned = String::new();
+        let mut role_forms = String::new();
+        for filter_id in &channel.filter_ids {
+            let label = filters
+                .iter()
+                .find(|filter| filter.id == *filter_id)
+                .map_or_else(|| format!("Filter #{filter_id}"), |filter| filter.label());
+            let role_ids = filter_roles
+                .get(&(channel.channel_id, *filter_id))
+                .map(Vec::as_slice)
+                .unwrap_or_default();
+            assigned.push_str(&format!(
+                r#"<form method="post" action="/guilds/{guild_id}/channels/{id}/filters/{filter_id}/remove" class="inline chip"><span>{label}{pings}</span><button class="subtle" type="submit" title="Remove filter">×</button></form>"#,
+                id = channel.channel_id,
+                label = escape(&label),
+                pings = if role_ids.is_empty() {
+                    String::new()
+                } else {
+                    format!(" 🔔{}", role_ids.len())
+                }
+            ));
+            role_forms.push_str(&format!(
+                r#"<form method="post" action="/guilds/{guild_id}/channels/{id}/filters/{filter_id}/roles" class="inline"><label>{label}<input type="text" name="role_ids" value="{role_ids}" placeholder="Role IDs to ping"></label><button type="submit">Save</button></form>"#,
+                id = channel.channel_id,
+                label = escape(&label),
+                role_ids = role_ids
+                    .iter()
+                    .map(i64::to_string)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        let role_forms = if role_forms.is_empty() {
+            role_forms
+        } else {
+            format!(
+                r#"<details class="role-pings"><summary>Role pings</summary>{role_forms}</details>"#
+            )
+        };
+        let mut filter_options = String::new();
+        for filter in filters
+            .iter()
+            .filter(|filter| !channel.filter_ids.contains(&filter.id))
+        {
             filter_options.push_str(&format!(
-                r#"<option value="{id}"{selected}>Filter #{id}</option>"#,
+                r#"<option value="{id}">{label}</option>"#,
                 id = filter.id,
-                selected = selected
+                label = escape(&filter.label())
             ));
         }
+        let add_form = if filter_options.is_empty() {
+            String::new()
+        } else {
+            format!(
+                r#"<form method="post" action="/guilds/{guild_id}/channels/{id}/filter" class="inline"><select name="filter_id">{filter_options}</select><button type="submit">Add</button></form>"#,
+                id = channel.channel_id
+            )
+        };
+        let is_nsfw_channel = discord_channels
+            .iter()
+            .any(|c| c.id == channel.channel_id.to_string() && c.nsfw);
+        let current_policy = channel.content_policy();
+        let mut policy_options = format!(
+            r#"<option value="">Default ({label})</option>"#,
+            label = ContentPolicy::default_for(is_nsfw_channel).label()
+        );
+        for policy in ContentPolicy::ALL {
+            policy_options.push_str(&format!(
+                r#"<option value="{value}"{selected}>{label}</option>"#,
+                value = policy.as_str(),
+                selected = if current_policy == Some(policy) {
+                    " selected"
+                } else {
+                    ""
+                },
+                label = policy.label()
+            ));
+        }
+        let policy_note = if current_policy.is_some_and(|policy| policy.allows(true))
+            && !is_nsfw_channel
+        {
+            r#"<small class="muted">NSFW items are held back until the channel is age-restricted.</small>"#
+        } else {
+            ""
+        };
+        let current_mode = channel.delivery_mode();
+        let mut mode_options = String::new();
+        for mode in DeliveryMode::ALL {
+            mode_options.push_str(&format!(
+                r#"<option value="{value}"{selected}>{label}</option>"#,
+                value = mode.as_str(),
+                selected = if current_mode == mode {
+                    " selected"
+                } else {
+                    ""
+                },
+                label = mode.label()
+            ));
+        }
+        let is_announcement_channel = discord_channels
+            .iter()
+            .any(|c| c.id == channel.channel_id.to_string() && c.kind == 5);
+        let mut crosspost_options = format!(
+            r#"<option value="">Default ({label})</option>"#,
+            label = if is_announcement_channel {
+                "crosspost"
+            } else {
+                "don't crosspost"
+            }
+        );
+        for (value, crosspost, label) in [
+            ("on", Some(true), "Crosspost"),
+            ("off", Some(false), "Don't crosspost"),
+        ] {
+            crosspost_options.push_str(&format!(
+                r#"<option value="{value}"{selected}>{label}</option>"#,
+                selected = if channel.crosspost == crosspost {
+                    " selected"
+                } else {
+                    ""
+                }
+            ));
+        }
+        let status = match channel.disabled_at {
+            _ if !channel.active => {
+                r#"<div class="error">Deleted in Discord, remove it or register the channel again</div>"#.to_string()
+            }
+            Some(_) => format!(
+                r#"<div class="error">Disabled after {failures} failures: {error}</div><form method="post" action="/guilds/{guild_id}/channels/{id}/enable" class="inline"><button class="primary" type="submit">Enable</button></form>"#,
+                failures = channel.failure_count,
+                error = escape(channel.last_error.as_deref().unwrap_or("unknown error")),
+                id = channel.channel_id
+            ),
+            None if channel.failure_count > 0 => format!(
+                r#"<div class="muted">{failures} failures in a row: {error}</div>"#,
+                failures = channel.failure_count,
+                error = escape(channel.last_error.as_deref().unwrap_or("unknown error"))
+            ),
+            None => String::new(),
+        };
         rows.push_str(&format!(
-            r#"<tr><td>#{name}</td><td><code>{id}</code></td><td><form method="post" action="/guilds/{guild_id}/channels/{id}/filter" class="inline"><select name="filter_id">{filter_options}</select><button type="submit">Save</button></form></td><td><form method="post" action="/guilds/{guild_id}/channels/{id}/clear-filter" class="inline"><button type="submit">Clear</button></form><form method="post" action="/guilds/{guild_id}/channels/{id}/delete" class="inline"><button class="danger" type="submit">Remove</button></form></td></tr>"#,
+            r#"<tr><td>#{name}{status}</td><td><code>{id}</code></td><td><div class="chips">{assigned}</div>{add_form}{role_forms}</td><td><form method="post" action="/guilds/{guild_id}/channels/{id}/content-policy" class="inline"><select name="content_policy">{policy_options}</select><button type="submit">Save</button></form>{policy_note}<form method="post" action="/guilds/{guild_id}/channels/{id}/delivery-mode" class="inline"><select name="delivery_mode">{mode_options}</select><button type="submit">Save</button></form><form method="post" action="/guilds/{guild_id}/channels/{id}/crosspost" class="inline"><select name="crosspost">{crosspost_options}</select><button type="submit">Save</button></form></td><td><form method="post" action="/guilds/{guild_id}/channels/{id}/clear-filter" class="inline"><button type="submit">Clear</button></form><form method="post" action="/guilds/{guild_id}/channels/{id}/delete" class="inline"><button class="danger" type="submit">Remove</button></form></td></tr>"#,
             id = channel.channel_id,
             guild_id = guild_id,
             name = escape(&channel.name),
-            filter_options = filter_options
+            assigned = assigned,
+            add_form = add_form,
+            role_forms = role_forms,
+            policy_options = policy_options,
+            policy_note = policy_note,
+            mode_options = mode_options,
+            crosspost_options = crosspost_options,
+            status = status
         ));
     }
     if rows.is_empty() {
-        rows.push_str(r#"<tr><td colspan="4" class="empty">No registered channels.</td></tr>"#);
+        rows.push_str(r#"<tr><td colspan="5" class="empty">No registered channels.</td></tr>"#);
     }
 
     Ok(Html(page(
         "Channels",
         Some(&session),
         &format!(
-            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Channels</div><h1>Channels</h1><form class="toolbar" method="post" action="/guilds/{guild_id}/channels/register"><select name="channel_id" required>{register_options}</select><button class="primary" type="submit">Register channel</button></form><table><thead><tr><th>Channel</th><th>ID</th><th>Filter</th><th></th></tr></thead><tbody>{rows}</tbody></table></section>"#,
+            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Channels</div><h1>Channels</h1><form class="toolbar" method="post" action="/guilds/{guild_id}/channels/register"><select name="channel_id" required>{register_options}</select><button class="primary" type="submit">Register channel</button></form><table><thead><tr><th>Channel</th><th>ID</th><th>Filters</th><th>Content &amp; delivery</th><th></th></tr></thead><tbody>{rows}</tbody></table></section>"#,
             guild_id = guild_id,
             guild_name = escape(&guild.name),
             register_options = register_options,
@@ -651,35 +1158,75 @@ async fn register_channel(
             channel_id,
             guild_id,
             name: channel.name,
-            filter_id: None,
+            filter_ids: vec![],
+            // Announcement channels
+            crosspost: channel.kind == 5,
         })
         .await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
 }
 
-async fn set_channel_filter(
+async fn add_channel_filter(
     State(state): State<AppState>,
     headers: HeaderMap,
     Path((guild_id, channel_id)): Path<(i64, i64)>,
-    Form(form): Form<SetFilterForm>,
+    Form(form): Form<AddFilterForm>,
 ) -> Result<Response, WebError> {
     let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
         return Ok(Redirect::to("/login").into_response());
     };
-    let filter_id = parse_optional_i64(&form.filter_id, "filter_id")?;
-    if let Some(filter_id) = filter_id {
-        let filter = state.db.get_notification_filter(filter_id).await?;
-        if !matches!(filter, Some(ref f) if f.guild_id == Some(guild_id) || f.guild_id.is_none()) {
-            return Ok(StatusCode::NOT_FOUND.into_response());
-        }
+    let filter_id = form.filter_id.parse::<i64>().context("invalid filter_id")?;
+    let filter = state.db.get_notification_filter(filter_id).await?;
+    if !matches!(filter, Some(ref f) if f.editable_in(guild_id)) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state.db.add_channel_filter(channel_id, filter_id).await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn remove_channel_filter(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id, filter_id)): Path<(i64, i64, i64)>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
     }
+    state
+        .db
+        .remove_channel_filter(channel_id, filter_id)
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn set_channel_filter_roles(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id, filter_id)): Path<(i64, i64, i64)>,
+    Form(form): Form<FilterRolesForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let role_ids = parse_role_ids(&form.role_ids)?
+        .into_iter()
+        .map(|role_id| role_id as i64)
+        .collect::<Vec<_>>();
     let channel = state.db.get_discord_channel(channel_id).await?;
     if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
         return Ok(StatusCode::NOT_FOUND.into_response());
     }
     state
         .db
-        .update_channel_filter(channel_id, filter_id)
+        .update_channel_filter_roles(channel_id, filter_id, &role_ids)
         .await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
 }
@@ -696,7 +1243,94 @@ async fn clear_channel_filter(
     if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
         return Ok(StatusCode::NOT_FOUND.into_response());
     }
-    state.db.update_channel_filter(channel_id, None).await?;
+    state.db.clear_channel_filters(channel_id).await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn set_channel_content_policy(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+    Form(form): Form<ContentPolicyForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let content_policy = match form.content_policy.trim() {
+        "" => None,
+        value => Some(value.parse::<ContentPolicy>()?),
+    };
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state
+        .db
+        .update_channel_content_policy(channel_id, content_policy)
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn set_channel_delivery_mode(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+    Form(form): Form<DeliveryModeForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let delivery_mode = form.delivery_mode.trim().parse::<DeliveryMode>()?;
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state
+        .db
+        .update_channel_delivery_mode(channel_id, delivery_mode)
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn set_channel_crosspost(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+    Form(form): Form<CrosspostForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let crosspost = match form.crosspost.trim() {
+        "" => None,
+        "on" => Some(true),
+        "off" => Some(false),
+        value => return Err(anyhow!("unknown crosspost setting: {value}").into()),
+    };
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state
+        .db
+        .update_channel_crosspost(channel_id, crosspost)
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
+}
+
+async fn enable_channel(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state.db.enable_channel(channel_id).await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/channels")).into_response())
 }
 
@@ -728,17 +1362,39 @@ async fn settings_page(
         .unwrap_or_default();
     let channel_options = options_for_channels(&discord_channels, &[0, 5]);
     let category_options = options_for_channels(&discord_channels, &[4]);
+    let mut templates = format!(
+        r#"<li><a href="/guilds/{guild_id}/template">Server default</a>{customized}</li>"#,
+        customized = if guild.message_template.is_some() {
+            ""
+        } else {
+            r#" <small class="muted">built-in</small>"#
+        }
+    );
+    for channel in state.db.get_channels_by_guild(guild_id).await? {
+        templates.push_str(&format!(
+            r#"<li><a href="/guilds/{guild_id}/channels/{id}/template">#{name}</a>{customized}</li>"#,
+            id = channel.channel_id,
+            name = escape(&channel.name),
+            customized = if channel.message_template.is_some() {
+                ""
+            } else {
+                r#" <small class="muted">server default</small>"#
+            }
+        ));
+    }
     Ok(Html(page(
         "Settings",
         Some(&session),
         &format!(
-            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Settings</div><h1>Settings</h1><form class="settings" method="post" action="/guilds/{guild_id}/settings"><label>Fallback channel<select name="fallback_channel_id"><option value="">Unset</option>{fallback_options}</select></label><label>Fallback NSFW channel<select name="fallback_nsfw_channel_id"><option value="">Unset</option>{fallback_nsfw_options}</select></label><label>General category<select name="general_category_id"><option value="">Unset</option>{general_category_options}</select></label><label>NSFW category<select name="nsfw_category_id"><option value="">Unset</option>{nsfw_category_options}</select></label><button class="primary" type="submit">Save settings</button></form></section>"#,
+            r#"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / Settings</div><h1>Settings</h1><form class="settings" method="post" action="/guilds/{guild_id}/settings"><label>Fallback channel<select name="fallback_channel_id"><option value="">Unset</option>{fallback_options}</select></label><label>Fallback NSFW channel<select name="fallback_nsfw_channel_id"><option value="">Unset</option>{fallback_nsfw_options}</select></label><label>General category<select name="general_category_id"><option value="">Unset</option>{general_category_options}</select></label><label>NSFW category<select name="nsfw_category_id"><option value="">Unset</option>{nsfw_category_options}</select></label><label>Admin channel<select name="admin_channel_id"><option value="">Unset, DM the owner</option>{admin_options}</select></label><button class="primary" type="submit">Save settings</button></form><h2>Message templates</h2><ul class="template-list">{templates}</ul></section>"#,
             guild_id = guild_id,
             guild_name = escape(&guild.name),
             fallback_options = mark_selected(&channel_options, guild.fallback_channel_id),
             fallback_nsfw_options = mark_selected(&channel_options, guild.fallback_nsfw_channel_id),
             general_category_options = mark_selected(&category_options, guild.general_category_id),
             nsfw_category_options = mark_selected(&category_options, guild.nsfw_category_id),
+            admin_options = mark_selected(&channel_options, guild.admin_channel_id),
+            templates = templates,
         ),
     ))
     .into_response())
@@ -761,11 +1417,255 @@ async fn update_settings(
             parse_optional_i64(&form.fallback_nsfw_channel_id, "fallback_nsfw_channel_id")?,
             parse_optional_i64(&form.general_category_id, "general_category_id")?,
             parse_optional_i64(&form.nsfw_category_id, "nsfw_category_id")?,
+            parse_optional_i64(&form.admin_channel_id, "admin_channel_id")?,
         )
         .await?;
     Ok(Redirect::to(&format!("/guilds/{guild_id}/settings")).into_response())
 }
 
+async fn guild_template_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<i64>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let form = TemplateForm::from_template(&guild.message_template());
+    Ok(Html(page(
+        "Message Template",
+        Some(&session),
+        &template_body(&guild, None, &form, ""),
+    ))
+    .into_response())
+}
+
+async fn preview_guild_template(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<i64>,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let template = form.to_template()?;
+    let preview = template_preview(&state, &template).await?;
+    Ok(Html(page(
+        "Message Template",
+        Some(&session),
+        &template_body(&guild, None, &form, &preview),
+    ))
+    .into_response())
+}
+
+async fn update_guild_template(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path(guild_id): Path<i64>,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let template = form.to_template()?;
+    state
+        .db
+        .update_guild_message_template(guild_id, (!template.is_empty()).then_some(&template))
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/settings")).into_response())
+}
+
+async fn channel_template_page(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(channel) = state
+        .db
+        .get_discord_channel(channel_id)
+        .await?
+        .filter(|c| c.guild_id == guild_id)
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let form = TemplateForm::from_template(&channel.message_template());
+    Ok(Html(page(
+        "Message Template",
+        Some(&session),
+        &template_body(&guild, Some(&channel), &form, ""),
+    ))
+    .into_response())
+}
+
+async fn preview_channel_template(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response, WebError> {
+    let Some((session, guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let Some(channel) = state
+        .db
+        .get_discord_channel(channel_id)
+        .await?
+        .filter(|c| c.guild_id == guild_id)
+    else {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    };
+    let template = form.to_template()?.or(&guild.message_template());
+    let preview = template_preview(&state, &template).await?;
+    Ok(Html(page(
+        "Message Template",
+        Some(&session),
+        &template_body(&guild, Some(&channel), &form, &preview),
+    ))
+    .into_response())
+}
+
+async fn update_channel_template(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Path((guild_id, channel_id)): Path<(i64, i64)>,
+    Form(form): Form<TemplateForm>,
+) -> Result<Response, WebError> {
+    let Some((_session, _guild)) = require_guild(&state, &headers, guild_id).await? else {
+        return Ok(Redirect::to("/login").into_response());
+    };
+    let template = form.to_template()?;
+    let channel = state.db.get_discord_channel(channel_id).await?;
+    if !matches!(channel, Some(ref c) if c.guild_id == guild_id) {
+        return Ok(StatusCode::NOT_FOUND.into_response());
+    }
+    state
+        .db
+        .update_channel_message_template(channel_id, (!template.is_empty()).then_some(&template))
+        .await?;
+    Ok(Redirect::to(&format!("/guilds/{guild_id}/settings")).into_response())
+}
+
+fn template_body(
+    guild: &DiscordGuild,
+    channel: Option<&DiscordChannel>,
+    form: &TemplateForm,
+    preview: &str,
+) -> String {
+    let (action, heading, fallback) = match channel {
+        Some(channel) => (
+            format!(
+                "/guilds/{}/channels/{}/template",
+                guild.guild_id, channel.channel_id
+            ),
+            format!("Message template for #{}", escape(&channel.name)),
+            "the server default",
+        ),
+        None => (
+            format!("/guilds/{}/template", guild.guild_id),
+            "Server default message template".to_string(),
+            "the built-in default",
+        ),
+    };
+    let placeholders = PLACEHOLDERS
+        .iter()
+        .map(|(name, description)| {
+            format!(
+                "<li><code>{{{name}}}</code> {description}</li>",
+                description = escape(description)
+            )
+        })
+        .collect::<String>();
+    format!(
+        r##"<section class="panel"><div class="crumb"><a href="/guilds/{guild_id}">{guild_name}</a> / <a href="/guilds/{guild_id}/settings">Settings</a> / Template</div><h1>{heading}</h1><p class="muted">Empty fields use {fallback}. Save with every field empty to reset.</p><form class="settings" method="post" action="{action}"><label>Mentioned role IDs<input type="text" name="role_ids" value="{role_ids}" placeholder="Comma separated, e.g. 123456789012345678"></label><label>Message text<textarea name="content" rows="2">{content}</textarea></label><label>Title<input type="text" name="title" value="{title}" placeholder="{default_title}"></label><label>Description<textarea name="description" rows="4" placeholder="{default_description}">{description}</textarea></label><label>Color<input type="text" name="color" value="{color}" placeholder="#RRGGBB"></label><label>Footer<input type="text" name="footer" value="{footer}"></label><label>Images<input type="text" name="image_count" value="{image_count}" placeholder="1, up to {max_images}"></label><div class="row-actions"><button class="primary" type="submit">Save template</button><button type="submit" formaction="{action}/preview">Preview</button></div></form><details><summary>Placeholders</summary><ul>{placeholders}</ul></details>{preview}</section>"##,
+        guild_id = guild.guild_id,
+        guild_name = escape(&guild.name),
+        heading = heading,
+        fallback = fallback,
+        action = action,
+        role_ids = escape(&form.role_ids),
+        content = escape(&form.content),
+        title = escape(&form.title),
+        default_title = escape(DEFAULT_TITLE),
+        description = escape(&form.description),
+        default_description = escape(DEFAULT_DESCRIPTION),
+        color = escape(&form.color),
+        footer = escape(&form.footer),
+        image_count = escape(&form.image_count),
+        max_images = MAX_IMAGES,
+        placeholders = placeholders,
+        preview = preview
+    )
+}
+
+/// The template rendered for the latest item seen, laid out like a Discord
+/// embed.
+async fn template_preview(state: &AppState, template: &MessageTemplate) -> Result<String> {
+    let end = OffsetDateTime::now_utc();
+    let item = state
+        .db
+        .get_snapshots_by_time_range(end - PREVIEW_WINDOW, end)
+        .await?
+        .into_iter()
+        .find_map(|snapshot| BoothItem::from_snapshot(snapshot.payload).ok());
+    let Some(item) = item else {
+        return Ok(
+            r#"<div class="preview"><h2>Preview</h2><p>No recent items to preview with.</p></div>"#
+                .to_string(),
+        );
+    };
+
+    let rendered = template.render(&item);
+    let images = rendered
+        .images
+        .iter()
+        .map(|image| format!(r#"<img src="{}" alt="">"#, escape(image)))
+        .collect::<String>();
+    let fields = rendered
+        .fields
+        .iter()
+        .map(|field| {
+            format!(
+                r#"<div class="embed-field"><strong>{name}</strong><div class="embed-description">{value}</div></div>"#,
+                name = escape(&field.name),
+                value = escape(&field.value)
+            )
+        })
+        .collect::<String>();
+    Ok(format!(
+        r#"<div class="preview"><h2>Preview</h2>{content}<div class="embed-preview" style="border-left-color:#{color:06x}"><div class="embed-author">{icon}<span>{author}</span></div><a href="{url}" target="_blank" rel="noopener">{title}</a><div class="embed-description">{description}</div>{fields}<div class="embed-images">{images}</div><small>{footer}{timestamp}</small></div></div>"#,
+        icon = rendered
+            .author
+            .icon_url
+            .as_deref()
+            .map(|icon| format!(r#"<img src="{}" alt="">"#, escape(icon)))
+            .unwrap_or_default(),
+        author = escape(&rendered.author.name),
+        fields = fields,
+        timestamp = rendered
+            .timestamp
+            .as_deref()
+            .map(|timestamp| format!(" • {}", escape(timestamp)))
+            .unwrap_or_default(),
+        content = rendered
+            .full_content()
+            .map(|content| format!("<p>{}</p>", escape(&content)))
+            .unwrap_or_default(),
+        color = rendered.color.unwrap_or(0xD8DEE8),
+        url = escape(&rendered.url),
+        title = escape(&rendered.title),
+        description = escape(&rendered.description),
+        images = images,
+        footer = rendered
+            .footer
+            .map(|footer| escape(&footer))
+            .unwrap_or_default()
+    ))
+}
+
 async fn current_session(
     state: &AppState,
     headers: &HeaderMap,
@@ -847,12 +1747,24 @@ fn filter_editor(form_id: &str, initial_yaml: &str) -> Result<String> {
     let filter = serde_yaml::from_str::<Filter>(initial_yaml).or_else(|yaml_error| {
         serde_json::from_str::<Filter>(initial_yaml).map_err(|_| yaml_error)
     })?;
-    let filter_json = script_json(&serde_json::to_string(&filter)?);
+    let filter_json = script_json(&serde_json::to_string(&filter.upgraded())?);
+    let events = ItemEvent::ALL
+        .into_iter()
+        .map(|event| {
+            format!(
+                r#"<label class="check-label"><input type="checkbox" data-event="{}"{}> {}</label>"#,
+                event.as_str(),
+                if filter.subscribes(event) { " checked" } else { "" },
+                event.label()
+            )
+        })
+        .collect::<String>();
     Ok(format!(
         r#"<div class="filter-builder" data-form="{form_id}">
 <script type="application/json" class="filter-data">{filter_json}</script>
-<div class="builder-head"><h2>Visual editor</h2><button type="button" data-action="add-group">Add AND group</button></div>
-<div class="builder-groups"></div>
+<div class="builder-head"><h2>Visual editor</h2></div>
+<div class="builder-events"><span>Post on</span>{events}</div>
+<div class="builder-root"></div>
 </div>"#,
         form_id = escape(form_id),
         filter_json = filter_json
@@ -866,16 +1778,73 @@ fn script_json(value: &str) -> String {
         .replace('>', "\\u003e")
 }
 
-fn normalize_filter(input: &str) -> Result<String> {
+fn parse_filter(input: &str) -> Result<Filter> {
     let filter = serde_yaml::from_str::<Filter>(input)
         .or_else(|yaml_error| serde_json::from_str::<Filter>(input).map_err(|_| yaml_error))?;
-    if filter.groups.is_empty() {
-        return Err(anyhow!("filter must have at least one group"));
-    }
-    if filter.groups.iter().any(|group| group.rules.is_empty()) {
-        return Err(anyhow!("filter groups must have at least one rule"));
-    }
-    Ok(serde_yaml::to_string(&filter)?)
+    filter.validate()?;
+    Ok(filter)
+}
+
+/// Parses a submitted filter and resolves its references against the
+/// guild's other filters, standing in for `filter_id` when editing one.
+async fn checked_filter(
+    state: &AppState,
+    guild_id: i64,
+    filter_id: Option<i64>,
+    form: &FilterForm,
+) -> Result<(Filter, FilteringEngine)> {
+    let filter = parse_filter(&form.rule_yaml)?;
+    let name = optional_text(&form.name);
+    let id = filter_id.unwrap_or(UNSAVED_FILTER_ID);
+    let mut library = FilterLibrary::load(&state.db, guild_id).await?;
+    library.check_rename(id, name.as_deref())?;
+    library.insert(id, name.as_deref(), filter.clone());
+    let engine = FilteringEngine::with_library(&filter, Some(id), &library)?;
+    Ok((filter, engine))
+}
+
+fn filter_details_fields(name: &str, description: &str) -> String {
+    format!(
+        r#"<div class="settings filter-details"><label>Name<input type="text" name="name" value="{name}" maxlength="{max_name}" placeholder="Optional, lets other filters include this one"></label><label>Description<input type="text" name="description" value="{description}" placeholder="Optional"></label></div>"#,
+        name = escape(name),
+        description = escape(description),
+        max_name = MAX_NAME_LEN
+    )
+}
+
+fn diff_html(old: &str, new: &str) -> String {
+    diff_lines(old, new)
+        .iter()
+        .map(|line| {
+            let class = match line {
+                DiffLine::Same(_) => "diff-same",
+                DiffLine::Removed(_) => "diff-removed",
+                DiffLine::Added(_) => "diff-added",
+            };
+            format!(
+                r#"<span class="{class}">{line}</span>"#,
+                class = class,
+                line = escape(&line.to_string())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn format_time(time: OffsetDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02} UTC",
+        time.year(),
+        u8::from(time.month()),
+        time.day(),
+        time.hour(),
+        time.minute()
+    )
+}
+
+fn optional_text(value: &str) -> Option<String> {
+    let value = value.trim();
+    (!value.is_empty()).then(|| value.to_string())
 }
 
 fn parse_optional_i64(value: &str, field: &str) -> Result<Option<i64>> {
@@ -974,9 +1943,16 @@ fn display_name(session: &WebSession) -> String {
         .unwrap_or_else(|| session.user.username.clone())
 }
 
+fn session_editor(session: &WebSession) -> Editor {
+    Editor {
+        user_id: session.user.id.parse().unwrap_or_default(),
+        name: display_name(session),
+    }
+}
+
 fn page(title: &str, session: Option<&WebSession>, body: &str) -> String {
     let user_nav = session.map_or_else(
-        || String::new(),
+        String::new,
         |session| {
             format!(
                 r#"<form method="post" action="/logout"><span>{}</span><button type="submit">Logout</button></form>"#,
@@ -994,46 +1970,71 @@ fn page(title: &str, session: Option<&WebSession>, body: &str) -> String {
     )
 }
 
-const SAMPLE_FILTER: &str = r#"groups:
-- rules:
-  - field: tags
-    op: include
-    pattern:
-      type: text
-      value: VRChat
-    case_sensitive: false
-    tag_mode: any
-schema_version: 1
+const SAMPLE_FILTER: &str = r#"expr:
+  all:
+  - rule:
+      field: tags
+      op: include
+      pattern:
+        type: text
+        value: VRChat
+      case_sensitive: false
+      tag_mode: any
+schema_version: 2
 "#;
 
 const CSS: &str = r#"
-:root{color-scheme:light;--bg:#f7f8fa;--panel:#fff;--text:#20242a;--muted:#687384;--line:#d8dee8;--accent:#1264a3;--danger:#b42318;--control:#eef2f7;--soft:#f9fbfd}*{box-sizing:border-box}body{margin:0;background:var(--bg);color:var(--text);font-family:Inter,ui-sans-serif,system-ui,-apple-system,BlinkMacSystemFont,"Segoe UI",sans-serif;font-size:15px;line-height:1.45}header{height:56px;display:flex;align-items:center;justify-content:space-between;padding:0 24px;border-bottom:1px solid var(--line);background:#fff;position:sticky;top:0;z-index:2}.brand{font-weight:700;color:var(--text);text-decoration:none}nav form{display:flex;align-items:center;gap:12px;color:var(--muted)}main{max-width:1120px;margin:0 auto;padding:32px 20px}.login{max-width:460px;margin:12vh auto;padding:40px 0}.login h1{font-size:34px;margin:0 0 12px}.login p{color:var(--muted);margin:0 0 24px}.panel{background:var(--panel);border:1px solid var(--line);border-radius:8px;padding:24px}.panel h1{font-size:26px;margin:0 0 20px}.panel h2{font-size:17px;margin:0}.crumb{font-size:13px;color:var(--muted);margin-bottom:8px}.crumb a{color:var(--accent);text-decoration:none}.actions{display:flex;gap:10px;flex-wrap:wrap}.actions a,.primary,button,a.primary{appearance:none;border:1px solid var(--accent);background:var(--accent);color:#fff;text-decoration:none;border-radius:6px;padding:9px 12px;font:inherit;line-height:1.2;cursor:pointer}button{appearance:none;border:1px solid var(--line);background:var(--control);border-radius:6px;padding:8px 10px;font:inherit;cursor:pointer;color:var(--text)}button.danger{border-color:#f3b7b2;background:#fff1f0;color:var(--danger)}button.subtle{background:#fff;color:var(--text);border-color:var(--line)}.guild-list{display:grid;gap:8px}.guild-row{display:flex;justify-content:space-between;align-items:center;gap:16px;border:1px solid var(--line);border-radius:6px;padding:14px 16px;color:var(--text);text-decoration:none}.guild-row:hover{border-color:var(--accent)}.guild-row small{color:var(--muted)}.editor,.settings{display:grid;gap:12px;margin-bottom:24px}.editor textarea{width:100%;min-height:220px;resize:vertical;border:1px solid var(--line);border-radius:6px;padding:12px;font:14px/1.45 ui-monospace,SFMono-Regular,Menlo,monospace}.settings label{display:grid;gap:6px;color:var(--muted)}select,input[type=text]{min-width:160px;max-width:100%;border:1px solid var(--line);border-radius:6px;background:#fff;padding:8px 10px;font:inherit}input[type=checkbox]{width:16px;height:16px}.toolbar{display:flex;gap:10px;flex-wrap:wrap;margin-bottom:18px}.cards{display:grid;gap:12px}.card{border:1px solid var(--line);border-radius:8px;padding:16px;display:grid;gap:12px}.card h2{font-size:18px;margin:0}.card p{margin:4px 0 0;color:var(--muted)}pre{margin:0;max-height:220px;overflow:auto;background:#101820;color:#eef6ff;border-radius:6px;padding:12px;font:13px/1.45 ui-monospace,SFMono-Regular,Menlo,monospace}.row-actions{display:flex;gap:8px;align-items:center}.row-actions a{color:var(--accent);text-decoration:none;padding:8px 0}table{width:100%;border-collapse:collapse}th,td{text-align:left;border-bottom:1px solid var(--line);padding:11px 8px;vertical-align:middle}th{font-size:13px;color:var(--muted);font-weight:600}.inline{display:inline-flex;align-items:center;gap:8px;margin-right:8px}.empty{color:var(--muted);padding:18px 0}details{border:1px solid var(--line);border-radius:8px;background:#fff}summary{cursor:pointer;padding:10px 12px;color:var(--muted)}details textarea{border:0;border-top:1px solid var(--line);border-radius:0 0 8px 8px}.filter-builder{display:grid;gap:12px;border:1px solid var(--line);background:var(--soft);border-radius:8px;padding:14px}.builder-head,.group-head{display:flex;align-items:center;justify-content:space-between;gap:10px}.builder-groups{display:grid;gap:12px}.filter-group{border:1px solid var(--line);background:#fff;border-radius:8px;padding:12px;display:grid;gap:10px}.filter-group h3{font-size:14px;margin:0;color:var(--muted);font-weight:600}.rule-list{display:grid;gap:8px}.filter-rule{display:grid;grid-template-columns:minmax(120px,1fr) minmax(120px,1fr) minmax(120px,1fr) minmax(180px,2fr) auto auto;gap:8px;align-items:end;border:1px solid #e8edf4;background:#fff;border-radius:6px;padding:10px}.filter-rule label{display:grid;gap:4px;color:var(--muted);font-size:12px;min-width:0}.filter-rule select,.filter-rule input[type=text]{min-width:0;width:100%}.check-label{display:flex!important;align-items:center;gap:6px;min-height:38px}.tag-mode-wrap.is-hidden{visibility:hidden}.rule-footer{display:flex;justify-content:flex-start;grid-column:1/-1}@media(max-width:860px){.filter-rule{grid-template-columns:1fr 1fr}.filter-rule .rule-footer{justify-content:flex-start}}@media(max-width:720px){header{padding:0 14px}main{padding:18px 12px}.panel{padding:16px}.guild-row,td,th{display:block}.toolbar,.inline{display:flex;width:100%}select,.toolbar button,.inline button{width:100%}table,thead,tbody,tr{display:block}thead{display:none}tr{border-bottom:1px solid var(--line);padding:10px 0}td{border:0;padding:6px 0}.builder-head,.group-head{align-items:flex-start;flex-direction:column}.filter-rule{grid-template-columns:1fr}select,input[type=text]{width:100%}}
+:root{color-scheme:light;--bg:#f7f8fa;--panel:#fff;--text:#20242a;--muted:#687384;--line:#d8dee8;--accent:#1264a3;--danger:#b42318;--control:#eef2f7;--soft:#f9fbfd}*{box-sizing:border-box}body{margin:0;background:var(--bg);color:var(--text);font-family:Inter,ui-sans-serif,system-ui,-apple-system,BlinkMacSystemFont,"Segoe UI",sans-serif;font-size:15px;line-height:1.45}header{height:56px;display:flex;align-items:center;justify-content:space-between;padding:0 24px;border-bottom:1px solid var(--line);background:#fff;position:sticky;top:0;z-index:2}.brand{font-weight:700;color:var(--text);text-decoration:none}nav form{display:flex;align-items:center;gap:12px;color:var(--muted)}main{max-width:1120px;margin:0 auto;padding:32px 20px}.login{max-width:460px;margin:12vh auto;padding:40px 0}.login h1{font-size:34px;margin:0 0 12px}.login p{color:var(--muted);margin:0 0 24px}.panel{background:var(--panel);border:1px solid var(--line);border-radius:8px;padding:24px}.panel h1{font-size:26px;margin:0 0 20px}.panel h2{font-size:17px;margin:0}.crumb{font-size:13px;color:var(--muted);margin-bottom:8px}.crumb a{color:var(--accent);text-decoration:none}.actions{display:flex;gap:10px;flex-wrap:wrap}.actions a,.primary,button,a.primary{appearance:none;border:1px solid var(--accent);background:var(--accent);color:#fff;text-decoration:none;border-radius:6px;padding:9px 12px;font:inherit;line-height:1.2;cursor:pointer}button{appearance:none;border:1px solid var(--line);background:var(--control);border-radius:6px;padding:8px 10px;font:inherit;cursor:pointer;color:var(--text)}button.danger{border-color:#f3b7b2;background:#fff1f0;color:var(--danger)}button.subtle{background:#fff;color:var(--text);border-color:var(--line)}.guild-list{display:grid;gap:8px}.guild-row{display:flex;justify-content:space-between;align-items:center;gap:16px;border:1px solid var(--line);border-radius:6px;padding:14px 16px;color:var(--text);text-decoration:none}.guild-row:hover{border-color:var(--accent)}.guild-row small{color:var(--muted)}.editor,.settings{display:grid;gap:12px;margin-bottom:24px}.editor textarea{width:100%;min-height:220px;resize:vertical;border:1px solid var(--line);border-radius:6px;padding:12px;font:14px/1.45 ui-monospace,SFMono-Regular,Menlo,monospace}.settings label{display:grid;gap:6px;color:var(--muted)}select,input[type=text]{min-width:160px;max-width:100%;border:1px solid var(--line);border-radius:6px;background:#fff;padding:8px 10px;font:inherit}input[type=checkbox]{width:16px;height:16px}.toolbar{display:flex;gap:10px;flex-wrap:wrap;margin-bottom:18px}.cards{display:grid;gap:12px}.card{border:1px solid var(--line);border-radius:8px;padding:16px;display:grid;gap:12px}.card h2{font-size:18px;margin:0}.card p{margin:4px 0 0;color:var(--muted)}pre{margin:0;max-height:220px;overflow:auto;background:#101820;color:#eef6ff;border-radius:6px;padding:12px;font:13px/1.45 ui-monospace,SFMono-Regular,Menlo,monospace}.row-actions{display:flex;gap:8px;align-items:center}.row-actions a{color:var(--accent);text-decoration:none;padding:8px 0}table{width:100%;border-collapse:collapse}th,td{text-align:left;border-bottom:1px solid var(--line);padding:11px 8px;vertical-align:middle}th{font-size:13px;color:var(--muted);font-weight:600}.inline{display:inline-flex;align-items:center;gap:8px;margin-right:8px}.empty{color:var(--muted);padding:18px 0}.error{white-space:pre-line}.filter-details{grid-template-columns:1fr 2fr;margin-bottom:0}.diff .diff-removed{color:#ff9b94}.diff .diff-added{color:#8fe3a1}.diff .diff-same{color:#9aa7b6}.muted{color:var(--muted)}.chips{display:flex;flex-wrap:wrap;gap:6px;margin-bottom:6px}.chip{margin:0;gap:4px;border:1px solid var(--line);border-radius:6px;padding:2px 2px 2px 8px;background:var(--soft)}.chip button{padding:2px 8px}.filter-details input[type=text]{width:100%}.preview{display:grid;gap:8px;margin-top:24px}.preview p{margin:0;color:var(--muted)}.preview-list{list-style:none;margin:0;padding:0;display:grid;gap:6px}.preview-list li{display:flex;justify-content:space-between;gap:16px;border:1px solid var(--line);border-radius:6px;padding:10px 12px}.preview-list a{color:var(--accent);text-decoration:none}.preview-list small{color:var(--muted);white-space:nowrap}details{border:1px solid var(--line);border-radius:8px;background:#fff}summary{cursor:pointer;padding:10px 12px;color:var(--muted)}details textarea{border:0;border-top:1px solid var(--line);border-radius:0 0 8px 8px}.filter-builder{display:grid;gap:12px;border:1px solid var(--line);background:var(--soft);border-radius:8px;padding:14px}.builder-head{display:flex;align-items:center;justify-content:space-between;gap:10px}.builder-root{display:grid;gap:12px}.filter-node{border:1px solid var(--line);background:#fff;border-radius:8px;padding:12px;display:grid;gap:10px}.node-head{display:flex;align-items:flex-end;justify-content:space-between;gap:10px}.node-head label{display:grid;gap:4px;color:var(--muted);font-size:12px}.node-children{display:grid;gap:8px;padding-left:12px;border-left:2px solid var(--line)}.filter-ref{display:flex;gap:8px;align-items:end;border:1px solid #e8edf4;background:#fff;border-radius:6px;padding:10px}.filter-ref label{display:grid;gap:4px;color:var(--muted);font-size:12px;flex:1}.filter-ref input[type=text]{width:100%}.filter-rule{display:grid;grid-template-columns:minmax(120px,1fr) minmax(120px,1fr) minmax(120px,1fr) minmax(180px,2fr) auto auto;gap:8px;align-items:end;border:1px solid #e8edf4;background:#fff;border-radius:6px;padding:10px}.filter-rule label{display:grid;gap:4px;color:var(--muted);font-size:12px;min-width:0}.filter-rule select,.filter-rule input[type=text]{min-width:0;width:100%}.check-label{display:flex!important;align-items:center;gap:6px;min-height:38px}.value-wrap,.mode-wrap{display:flex;gap:8px;min-width:0}.value-wrap label,.mode-wrap label{flex:1}.is-collapsed{display:none!important}.rule-footer{display:flex;justify-content:flex-start;align-items:center;gap:12px;grid-column:1/-1}.flags-wrap{display:flex;gap:12px;flex-wrap:wrap}.settings textarea{width:100%;resize:vertical;border:1px solid var(--line);border-radius:6px;padding:8px 10px;font:inherit}.template-list{display:grid;gap:6px;padding-left:18px}.template-list a{color:var(--accent);text-decoration:none}.embed-preview{display:grid;gap:8px;max-width:520px;border:1px solid var(--line);border-left:4px solid;border-radius:6px;padding:12px;background:var(--soft)}.embed-preview a{color:var(--accent);font-weight:600;text-decoration:none}.embed-description{white-space:pre-line}.embed-images{display:grid;grid-template-columns:repeat(2,1fr);gap:4px}.embed-images img{width:100%;border-radius:4px}.embed-preview small{color:var(--muted)}.embed-author{display:flex;align-items:center;gap:6px;font-size:13px}.embed-author img{width:20px;height:20px;border-radius:50%}.embed-field strong{font-size:13px}.role-pings{margin-top:6px}.role-pings form{display:flex;padding:0 12px 10px}.role-pings label{display:grid;gap:4px;color:var(--muted);font-size:12px}.builder-events{display:flex;align-items:center;gap:12px;flex-wrap:wrap;color:var(--muted)}.builder-events .check-label{min-height:0}@media(max-width:860px){.filter-rule{grid-template-columns:1fr 1fr}.filter-rule .rule-footer{justify-content:flex-start}}@media(max-width:720px){header{padding:0 14px}.filter-details{grid-template-columns:1fr}main{padding:18px 12px}.panel{padding:16px}.guild-row,td,th{display:block}.toolbar,.inline{display:flex;width:100%}select,.toolbar button,.inline button{width:100%}table,thead,tbody,tr{display:block}thead{display:none}tr{border-bottom:1px solid var(--line);padding:10px 0}td{border:0;padding:6px 0}.builder-head,.node-head{align-items:flex-start;flex-direction:column}.filter-rule{grid-template-columns:1fr}select,input[type=text]{width:100%}}
 "#;
 
 const JS: &str = r#"
 (function(){
-  const fields = ['tags','name','description','category'];
+  const fields = ['tags','name','description','category','price','shop_name','shop_url'];
   const ops = ['include','exclude'];
-  const patternTypes = ['text','regex'];
+  const patternTypes = ['text','regex','one_of','lt','lte','gt','gte','between'];
+  const numericTypes = ['lt','lte','gt','gte','between'];
   const tagModes = ['any','all'];
+  const priceModes = ['item','min','max'];
+  const kinds = ['all','any','not'];
+  const regexFlags = {m:'Multi-line',s:'Dot-all',x:'Extended',a:'ASCII only'};
+  const labels = {all:'All of (AND)',any:'Any of (OR)',not:'Not',lt:'Less than',lte:'At most',gt:'More than',gte:'At least',min:'Cheapest variation',max:'Priciest variation'};
 
   function option(value, selected){
     return `<option value="${value}"${value === selected ? ' selected' : ''}>${label(value)}</option>`;
   }
   function label(value){
+    if (labels[value]) return labels[value];
     return value.split('_').map((part) => part.charAt(0).toUpperCase() + part.slice(1)).join(' ');
   }
   function defaultRule(){
     return {field:'tags',op:'include',pattern:{type:'text',value:''},case_sensitive:false,tag_mode:'any'};
   }
-  function defaultFilter(){
-    return {groups:[{rules:[defaultRule()]}],schema_version:1};
+  function defaultExpr(){
+    return {all:[{rule:defaultRule()}]};
+  }
+  function nodeKind(node){
+    return kinds.concat(['rule','ref']).find((kind) => node && typeof node === 'object' && kind in node) || '';
+  }
+  function childrenOf(node){
+    const kind = nodeKind(node);
+    if (kind === 'not') return [node.not];
+    return kind === 'rule' || kind === 'ref' ? [] : node[kind];
   }
   function normalizeFilter(filter){
-    if (!filter || !Array.isArray(filter.groups) || filter.groups.length === 0) return defaultFilter();
-    filter.schema_version = filter.schema_version || 1;
-    filter.groups = filter.groups.map((group) => ({rules:(Array.isArray(group.rules) && group.rules.length ? group.rules : [defaultRule()]).map(normalizeRule)}));
-    return filter;
+    let expr = filter && filter.expr;
+    if (!expr && filter && Array.isArray(filter.groups) && filter.groups.length) {
+      expr = {all: filter.groups.map((group) => ({any: (Array.isArray(group.rules) ? group.rules : []).map((rule) => ({rule}))}))};
+    }
+    const events = filter && Array.isArray(filter.events) ? filter.events : [];
+    return {expr: expr ? normalizeExpr(expr) : defaultExpr(), events, schema_version: 2};
+  }
+  function normalizeExpr(node){
+    const kind = nodeKind(node);
+    if (kind === 'rule') return {rule: normalizeRule(node.rule)};
+    if (kind === 'ref') return {ref: readRef(node.ref)};
+    if (kind === 'not') return {not: normalizeExpr(node.not)};
+    if (kind && Array.isArray(node[kind])) return {[kind]: node[kind].map(normalizeExpr)};
+    return {rule: defaultRule()};
   }
   function normalizeRule(rule){
     const next = Object.assign(defaultRule(), rule || {});
@@ -1042,50 +2043,115 @@ const JS: &str = r#"
     if (!ops.includes(next.op)) next.op = 'include';
     if (!patternTypes.includes(next.pattern.type)) next.pattern.type = 'text';
     if (next.field === 'tags' && !tagModes.includes(next.tag_mode)) next.tag_mode = 'any';
+    if (next.field === 'price' && !priceModes.includes(next.price_mode)) next.price_mode = 'item';
+    if (next.regex_flags && next.regex_flags.includes('i')) next.case_sensitive = false;
+    next.regex_flags = String(next.regex_flags || '').split('').filter((flag) => regexFlags[flag]).join('');
     return next;
   }
   function readRule(node){
     const field = node.querySelector('[data-name="field"]').value;
+    const type = node.querySelector('[data-name="pattern_type"]').value;
+    const value = node.querySelector('[data-name="pattern_value"]').value;
     const rule = {
       field,
       op: node.querySelector('[data-name="op"]').value,
-      pattern: {
-        type: node.querySelector('[data-name="pattern_type"]').value,
-        value: node.querySelector('[data-name="pattern_value"]').value
-      },
+      pattern: readPattern(type, value, node.querySelector('[data-name="pattern_max"]').value),
       case_sensitive: node.querySelector('[data-name="case_sensitive"]').checked
     };
     if (field === 'tags') rule.tag_mode = node.querySelector('[data-name="tag_mode"]').value;
+    if (field === 'price') rule.price_mode = node.querySelector('[data-name="price_mode"]').value;
+    if (type === 'regex') {
+      const flags = Array.from(node.querySelectorAll('[data-flag]')).filter((input) => input.checked).map((input) => input.dataset.flag).join('');
+      if (flags) rule.regex_flags = flags;
+    }
     return rule;
   }
+  function readPattern(type, value, max){
+    if (type === 'between') return {type, min: toNumber(value), max: toNumber(max)};
+    if (numericTypes.includes(type)) return {type, value: toNumber(value)};
+    if (type === 'one_of') return {type, values: value.split(',').map((entry) => entry.trim()).filter((entry) => entry !== '')};
+    return {type, value};
+  }
+  function toNumber(value){
+    const number = parseInt(String(value).trim(), 10);
+    return Number.isFinite(number) ? number : 0;
+  }
+  function readNode(node){
+    if (node.classList.contains('filter-rule')) return {rule: readRule(node)};
+    if (node.classList.contains('filter-ref')) return {ref: readRef(node.querySelector('[data-name="ref"]').value)};
+    const kind = node.querySelector('[data-name="kind"]').value;
+    const children = Array.from(node.querySelector('.node-children').children).map(readNode);
+    if (kind !== 'not') return {[kind]: children};
+    if (children.length === 1) return {not: children[0]};
+    return {not: children.length ? {all: children} : {rule: defaultRule()}};
+  }
+  function readRef(value){
+    const text = String(value ?? '').trim();
+    return /^-?[0-9]+$/.test(text) ? Number(text) : text;
+  }
   function readFilter(builder){
-    const groups = Array.from(builder.querySelectorAll('.filter-group')).map((group) => ({
-      rules: Array.from(group.querySelectorAll('.filter-rule')).map(readRule)
-    })).filter((group) => group.rules.length > 0);
-    return {groups: groups.length ? groups : [{rules:[defaultRule()]}], schema_version: 1};
+    const root = builder.querySelector('.builder-root').firstElementChild;
+    const events = Array.from(builder.querySelectorAll('[data-event]:checked')).map((input) => input.dataset.event);
+    return {expr: root ? readNode(root) : defaultExpr(), events, schema_version: 2};
   }
   function yamlScalar(value){
     const text = String(value || '');
     if (/^(?:[-+]?(?:0|[1-9][0-9]*)(?:\.[0-9]+)?|true|false|null|~)$/i.test(text)) return JSON.stringify(text);
-    if (/^[A-Za-z0-9 _.,:+#@/-]+$/.test(text) && text.trim() === text && text !== '') return text;
+    if (/^[A-Za-z0-9 _.,:+#@/-]+$/.test(text) && !/: | #|:$|^[-#]/.test(text) && text.trim() === text && text !== '') return text;
     return JSON.stringify(text);
   }
-  function toYaml(filter){
-    let yaml = 'groups:\n';
-    filter.groups.forEach((group) => {
-      yaml += '- rules:\n';
-      group.rules.forEach((rule) => {
-        yaml += `  - field: ${rule.field}\n`;
-        yaml += `    op: ${rule.op}\n`;
-        yaml += '    pattern:\n';
-        yaml += `      type: ${rule.pattern.type}\n`;
-        yaml += `      value: ${yamlScalar(rule.pattern.value)}\n`;
-        yaml += `    case_sensitive: ${rule.case_sensitive ? 'true' : 'false'}\n`;
-        if (rule.field === 'tags') yaml += `    tag_mode: ${rule.tag_mode || 'any'}\n`;
+  function cleanRule(rule){
+    const type = rule.pattern.type;
+    let pattern = {type, value: rule.pattern.value ?? ''};
+    if (type === 'between') pattern = {type, min: toNumber(rule.pattern.min), max: toNumber(rule.pattern.max)};
+    else if (type === 'one_of') pattern = {type, values: rule.pattern.values || []};
+    else if (numericTypes.includes(type)) pattern = {type, value: toNumber(rule.pattern.value)};
+    const clean = {field: rule.field, op: rule.op, pattern, case_sensitive: !!rule.case_sensitive};
+    if (type === 'regex' && rule.regex_flags) clean.regex_flags = rule.regex_flags;
+    if (rule.field === 'tags') clean.tag_mode = rule.tag_mode || 'any';
+    if (rule.field === 'price') clean.price_mode = rule.price_mode || 'item';
+    return clean;
+  }
+  function cleanExpr(node){
+    const kind = nodeKind(node);
+    if (kind === 'rule') return {rule: cleanRule(node.rule)};
+    if (kind === 'ref') return {ref: node.ref};
+    if (kind === 'not') return {not: cleanExpr(node.not)};
+    return {[kind]: node[kind].map(cleanExpr)};
+  }
+  function isScalar(value){
+    return value === null || typeof value !== 'object';
+  }
+  function yamlValue(value){
+    if (typeof value === 'number' || typeof value === 'boolean') return String(value);
+    return yamlScalar(value);
+  }
+  function yamlLines(value){
+    const lines = [];
+    if (Array.isArray(value)) {
+      value.forEach((entry) => {
+        if (isScalar(entry)) return lines.push(`- ${yamlValue(entry)}`);
+        if (Array.isArray(entry) && !entry.length) return lines.push('- []');
+        yamlLines(entry).forEach((line, index) => lines.push(index ? `  ${line}` : `- ${line}`));
       });
+      return lines;
+    }
+    Object.keys(value).forEach((key) => {
+      const entry = value[key];
+      if (isScalar(entry)) lines.push(`${key}: ${yamlValue(entry)}`);
+      else if (Array.isArray(entry) && !entry.length) lines.push(`${key}: []`);
+      else if (Array.isArray(entry)) lines.push(`${key}:`, ...yamlLines(entry));
+      else lines.push(`${key}:`, ...yamlLines(entry).map((line) => `  ${line}`));
     });
-    yaml += 'schema_version: 1\n';
-    return yaml;
+    return lines;
+  }
+  function toYaml(filter){
+    const clean = {expr: cleanExpr(filter.expr)};
+    // Only new items are posted when no events are listed
+    const events = filter.events || [];
+    if (events.length && events.join() !== 'new_item') clean.events = events;
+    clean.schema_version = 2;
+    return yamlLines(clean).join('\n') + '\n';
   }
   function syncYaml(builder){
     const form = document.getElementById(builder.dataset.form);
@@ -1093,29 +2159,47 @@ const JS: &str = r#"
     const textarea = form.querySelector('textarea[name="rule_yaml"]');
     if (textarea) textarea.value = toYaml(readFilter(builder));
   }
-  function renderRule(rule, groupIndex, ruleIndex){
+  function renderRule(rule, path, removable){
     rule = normalizeRule(rule);
-    const tagHidden = rule.field === 'tags' ? '' : ' is-hidden';
-    return `<div class="filter-rule" data-rule-index="${ruleIndex}">
+    const tagHidden = rule.field === 'tags' ? '' : ' is-collapsed';
+    const priceHidden = rule.field === 'price' ? '' : ' is-collapsed';
+    const isBetween = rule.pattern.type === 'between';
+    const value = isBetween ? rule.pattern.min : rule.pattern.type === 'one_of' ? (rule.pattern.values || []).join(', ') : rule.pattern.value;
+    return `<div class="filter-rule" data-path="${path}">
       <label>Field<select data-name="field">${fields.map((value) => option(value, rule.field)).join('')}</select></label>
       <label>Operation<select data-name="op">${ops.map((value) => option(value, rule.op)).join('')}</select></label>
       <label>Match<select data-name="pattern_type">${patternTypes.map((value) => option(value, rule.pattern.type)).join('')}</select></label>
-      <label>Value<input data-name="pattern_value" type="text" value="${escapeAttr(rule.pattern.value || '')}"></label>
+      <div class="value-wrap"><label>Value<input data-name="pattern_value" type="text" value="${escapeAttr(value ?? '')}"></label><label class="max-wrap${isBetween ? '' : ' is-collapsed'}">Max<input data-name="pattern_max" type="text" value="${escapeAttr(isBetween ? rule.pattern.max ?? '' : '')}"></label></div>
       <label class="check-label"><input data-name="case_sensitive" type="checkbox"${rule.case_sensitive ? ' checked' : ''}> Case</label>
-      <label class="tag-mode-wrap${tagHidden}">Tags<select data-name="tag_mode">${tagModes.map((value) => option(value, rule.tag_mode || 'any')).join('')}</select></label>
-      <div class="rule-footer"><button type="button" class="danger" data-action="remove-rule">Remove</button></div>
+      <div class="mode-wrap"><label class="tag-mode-wrap${tagHidden}">Tags<select data-name="tag_mode">${tagModes.map((value) => option(value, rule.tag_mode || 'any')).join('')}</select></label><label class="price-mode-wrap${priceHidden}">Price<select data-name="price_mode">${priceModes.map((value) => option(value, rule.price_mode || 'item')).join('')}</select></label></div>
+      <div class="rule-footer"><div class="flags-wrap${rule.pattern.type === 'regex' ? '' : ' is-collapsed'}">${Object.keys(regexFlags).map((flag) => `<label class="check-label"><input data-flag="${flag}" type="checkbox"${(rule.regex_flags || '').includes(flag) ? ' checked' : ''}> ${regexFlags[flag]}</label>`).join('')}</div>${removable ? '<button type="button" class="danger" data-action="remove-node">Remove</button>' : ''}</div>
+    </div>`;
+  }
+  function renderRef(ref, path, removable){
+    return `<div class="filter-ref" data-path="${path}">
+      <label>Include filter (ID or name)<input data-name="ref" type="text" value="${escapeAttr(ref ?? '')}"></label>
+      ${removable ? '<button type="button" class="danger" data-action="remove-node">Remove</button>' : ''}
+    </div>`;
+  }
+  function renderNode(node, path, removable){
+    const kind = nodeKind(node);
+    if (kind === 'rule') return renderRule(node.rule, path, removable);
+    if (kind === 'ref') return renderRef(node.ref, path, removable);
+    const actions = kind === 'not' ? '' : '<button type="button" data-action="add-rule">Add rule</button><button type="button" data-action="add-group">Add group</button><button type="button" data-action="add-ref">Include filter</button>';
+    const remove = removable ? '<button type="button" class="danger" data-action="remove-node">Remove group</button>' : '';
+    return `<div class="filter-node" data-path="${path}">
+      <div class="node-head"><label>Match<select data-name="kind">${kinds.map((value) => option(value, kind)).join('')}</select></label><div class="row-actions">${actions}${remove}</div></div>
+      <div class="node-children">${childrenOf(node).map((child, index) => renderNode(child, path === '' ? String(index) : `${path}.${index}`, kind !== 'not')).join('')}</div>
     </div>`;
   }
   function render(builder, filter){
     filter = normalizeFilter(filter);
-    const root = builder.querySelector('.builder-groups');
-    root.innerHTML = filter.groups.map((group, groupIndex) => `
-      <div class="filter-group" data-group-index="${groupIndex}">
-        <div class="group-head"><h3>AND group ${groupIndex + 1}</h3><div class="row-actions"><button type="button" data-action="add-rule">Add OR rule</button><button type="button" class="danger" data-action="remove-group">Remove group</button></div></div>
-        <div class="rule-list">${group.rules.map((rule, ruleIndex) => renderRule(rule, groupIndex, ruleIndex)).join('')}</div>
-      </div>`).join('');
+    builder.querySelector('.builder-root').innerHTML = renderNode(filter.expr, '', false);
     syncYaml(builder);
   }
+  function nodeAt(expr, path){
+    return path === '' ? expr : path.split('.').reduce((node, index) => childrenOf(node)[Number(index)], expr);
+  }
   function escapeAttr(value){
     return String(value).replace(/&/g,'&amp;').replace(/"/g,'&quot;').replace(/</g,'&lt;').replace(/>/g,'&gt;');
   }
@@ -1127,21 +2211,34 @@ const JS: &str = r#"
       if (!button) return;
       const action = button.dataset.action;
       filter = readFilter(builder);
-      const groupNode = button.closest('.filter-group');
-      const ruleNode = button.closest('.filter-rule');
-      const groupIndex = groupNode ? Number(groupNode.dataset.groupIndex) : -1;
-      const ruleIndex = ruleNode ? Number(ruleNode.dataset.ruleIndex) : -1;
-      if (action === 'add-group') filter.groups.push({rules:[defaultRule()]});
-      if (action === 'remove-group' && filter.groups.length > 1) filter.groups.splice(groupIndex, 1);
-      if (action === 'add-rule' && groupIndex >= 0) filter.groups[groupIndex].rules.push(defaultRule());
-      if (action === 'remove-rule' && groupIndex >= 0 && ruleIndex >= 0 && filter.groups[groupIndex].rules.length > 1) filter.groups[groupIndex].rules.splice(ruleIndex, 1);
+      const path = button.closest('[data-path]').dataset.path;
+      const node = nodeAt(filter.expr, path);
+      if (action === 'add-rule') node[nodeKind(node)].push({rule: defaultRule()});
+      if (action === 'add-group') node[nodeKind(node)].push({any: [{rule: defaultRule()}]});
+      if (action === 'add-ref') node[nodeKind(node)].push({ref: ''});
+      if (action === 'remove-node' && path !== '') {
+        const parts = path.split('.');
+        const index = Number(parts.pop());
+        const parent = nodeAt(filter.expr, parts.join('.'));
+        parent[nodeKind(parent)].splice(index, 1);
+      }
       render(builder, filter);
     });
     builder.addEventListener('input', () => syncYaml(builder));
     builder.addEventListener('change', (event) => {
+      if (event.target.dataset.name === 'kind') {
+        filter = readFilter(builder);
+        render(builder, filter);
+        return;
+      }
       const rule = event.target.closest('.filter-rule');
       if (rule && event.target.dataset.name === 'field') {
-        rule.querySelector('.tag-mode-wrap').classList.toggle('is-hidden', event.target.value !== 'tags');
+        rule.querySelector('.tag-mode-wrap').classList.toggle('is-collapsed', event.target.value !== 'tags');
+        rule.querySelector('.price-mode-wrap').classList.toggle('is-collapsed', event.target.value !== 'price');
+      }
+      if (rule && event.target.dataset.name === 'pattern_type') {
+        rule.querySelector('.max-wrap').classList.toggle('is-collapsed', event.target.value !== 'between');
+        rule.querySelector('.flags-wrap').classList.toggle('is-collapsed', event.target.value !== 'regex');
       }
       syncYaml(builder);
     });